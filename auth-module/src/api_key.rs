@@ -0,0 +1,248 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Authentication via API keys sent in a request header
+
+use http::{header, HeaderName, StatusCode};
+use log::{error, info, trace};
+use pandora_module_utils::pingora::{
+    AuthCredentials, Error, ErrorType, ResponseHeader, SessionWrapper,
+};
+use pandora_module_utils::standard_response::error_response;
+use pandora_module_utils::RequestFilterResult;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    common::{constant_time_eq, is_rate_limited},
+    AuthConf,
+};
+
+/// API key settings prepared when the configuration is loaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ApiKeys {
+    header: HeaderName,
+    digests: Vec<[u8; 32]>,
+}
+
+impl ApiKeys {
+    /// Parses the `auth_api_key_header` setting and hashes the keys in `auth_api_keys`.
+    pub(crate) fn new(conf: &AuthConf) -> Result<Self, Box<Error>> {
+        let name = &conf.auth_api_key_header;
+        let Ok(header) = HeaderName::from_bytes(name.as_bytes()) else {
+            error!("invalid header name configured in auth_api_key_header: {name}");
+            return Err(Error::new(ErrorType::InternalError));
+        };
+
+        let digests = conf
+            .auth_api_keys
+            .iter()
+            .map(|key| Sha256::digest(key).into())
+            .collect();
+        Ok(Self { header, digests })
+    }
+
+    /// Checks whether the key is among the accepted keys. Hashes are compared rather than the
+    /// keys themselves, so that neither the contents nor the length of the accepted keys leak via
+    /// timing. All keys are checked, regardless of whether a match has been found already.
+    fn is_valid_key(&self, key: &[u8]) -> bool {
+        let key: [u8; 32] = Sha256::digest(key).into();
+        self.digests.iter().fold(false, |valid, accepted| {
+            constant_time_eq(accepted, key) | valid
+        })
+    }
+}
+
+/// Rejects the request with an empty `401 Unauthorized` response, there is no challenge to
+/// respond to.
+async fn unauthorized_response(session: &mut impl SessionWrapper) -> Result<(), Box<Error>> {
+    let mut header = ResponseHeader::build(StatusCode::UNAUTHORIZED, Some(1))?;
+    header.append_header(header::CONTENT_LENGTH, "0")?;
+    session.write_response_header(Box::new(header), true).await
+}
+
+pub(crate) async fn api_key_auth(
+    conf: &AuthConf,
+    api_keys: &ApiKeys,
+    session: &mut impl SessionWrapper,
+) -> Result<RequestFilterResult, Box<Error>> {
+    let name = &api_keys.header;
+    let Some(key) = session.req_header().headers.get(name) else {
+        trace!("Rejecting request, no {name} header");
+        unauthorized_response(session).await?;
+        return Ok(RequestFilterResult::ResponseSent);
+    };
+
+    if is_rate_limited(session, &conf.auth_rate_limits, None) {
+        error_response(session, StatusCode::TOO_MANY_REQUESTS).await?;
+        return Ok(RequestFilterResult::ResponseSent);
+    }
+
+    if api_keys.is_valid_key(key.as_bytes()) {
+        // Keys aren’t associated with users, so no authenticated user is recorded
+        trace!("Found valid API key, allowing request");
+        session.set_auth_credentials(AuthCredentials::Header(name.clone()));
+        Ok(RequestFilterResult::Unhandled)
+    } else {
+        info!("Rejecting request, wrong API key");
+        unauthorized_response(session).await?;
+        Ok(RequestFilterResult::ResponseSent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{create_test_session, RequestHeader, Session};
+    use pandora_module_utils::{FromYaml, RequestFilter};
+    use startup_module::{AppResult, DefaultApp};
+    use test_log::test;
+
+    use crate::AuthHandler;
+
+    fn default_conf() -> &'static str {
+        r#"
+auth_mode: apikey
+auth_api_keys:
+- 0123456789abcdef
+- another-key
+auth_rate_limits:
+    total: 0
+    per_ip: 0
+    per_user: 0
+        "#
+    }
+
+    fn make_app(conf: &str) -> DefaultApp<AuthHandler> {
+        DefaultApp::new(
+            <AuthHandler as RequestFilter>::Conf::from_yaml(conf)
+                .unwrap()
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    async fn make_session(header: Option<(&str, &str)>) -> Session {
+        let mut request = RequestHeader::build("GET", b"/", None).unwrap();
+        if let Some((name, value)) = header {
+            request.insert_header(name.to_owned(), value).unwrap();
+        }
+        create_test_session(request).await
+    }
+
+    fn assert_unauthorized(result: &mut AppResult) {
+        assert!(result.err().is_none());
+        {
+            let session = result.session();
+            assert_eq!(session.auth_credentials(), None);
+            let response = session.response_written().unwrap();
+            assert_eq!(response.status, 401);
+            assert_eq!(response.headers.get("Content-Length").unwrap(), "0");
+            assert!(response.headers.get("WWW-Authenticate").is_none());
+            assert!(response.headers.get("Set-Cookie").is_none());
+        }
+        assert!(result.body().is_empty());
+    }
+
+    #[test(tokio::test)]
+    async fn no_key() {
+        let mut app = make_app(default_conf());
+        let session = make_session(None).await;
+        let mut result = app.handle_request(session).await;
+        assert_unauthorized(&mut result);
+    }
+
+    #[test(tokio::test)]
+    async fn wrong_key() {
+        let mut app = make_app(default_conf());
+        for key in [
+            "0123456789abcdeg",
+            "0123456789abcde",
+            "0123456789abcdef0",
+            "",
+        ] {
+            let session = make_session(Some(("X-API-Key", key))).await;
+            let mut result = app.handle_request(session).await;
+            assert_unauthorized(&mut result);
+        }
+
+        // Keys are only accepted in the configured header
+        let session = make_session(Some(("Authorization", "0123456789abcdef"))).await;
+        let mut result = app.handle_request(session).await;
+        assert_unauthorized(&mut result);
+    }
+
+    #[test(tokio::test)]
+    async fn correct_key() {
+        let mut app = make_app(default_conf());
+        for key in ["0123456789abcdef", "another-key"] {
+            let session = make_session(Some(("x-api-key", key))).await;
+            let mut result = app.handle_request(session).await;
+            assert_eq!(
+                result.err().as_ref().map(|err| &err.etype),
+                Some(&ErrorType::HTTPStatus(404))
+            );
+            assert_eq!(
+                result.session().auth_credentials(),
+                Some(&AuthCredentials::Header(HeaderName::from_static(
+                    "x-api-key"
+                )))
+            );
+            assert!(result.session().authenticated_user().is_none());
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn custom_header() {
+        let mut conf = default_conf().to_owned();
+        conf.push_str("\nauth_api_key_header: X-Token\n");
+        let mut app = make_app(&conf);
+
+        let session = make_session(Some(("X-API-Key", "another-key"))).await;
+        let mut result = app.handle_request(session).await;
+        assert_unauthorized(&mut result);
+
+        let session = make_session(Some(("X-Token", "another-key"))).await;
+        let result = app.handle_request(session).await;
+        assert_eq!(
+            result.err().as_ref().map(|err| &err.etype),
+            Some(&ErrorType::HTTPStatus(404))
+        );
+
+        let conf = conf.replace("X-Token", "\"X Token\"");
+        let conf = <AuthHandler as RequestFilter>::Conf::from_yaml(conf).unwrap();
+        assert!(AuthHandler::try_from(conf).is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn rate_limiting() {
+        let conf = default_conf().replace("total: 0", "total: 2");
+        let mut app = make_app(&conf);
+
+        for _ in 0..2 {
+            let session = make_session(Some(("X-API-Key", "wrong"))).await;
+            let mut result = app.handle_request(session).await;
+            assert_unauthorized(&mut result);
+        }
+
+        let session = make_session(Some(("X-API-Key", "another-key"))).await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(result.session().auth_credentials(), None);
+        assert_eq!(
+            result.session().response_written().unwrap().status,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+}
@@ -16,18 +16,18 @@ use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine as _};
 use http::{header, Method, StatusCode};
 use log::{info, trace};
 use maud::{html, DOCTYPE};
-use pandora_module_utils::pingora::{Error, ResponseHeader, SessionWrapper};
+use pandora_module_utils::pingora::{AuthCredentials, Error, ResponseHeader, SessionWrapper};
 use pandora_module_utils::standard_response::error_response;
 use pandora_module_utils::RequestFilterResult;
 
 use crate::{
-    common::{is_rate_limited, validate_login},
+    common::{is_rate_limited, set_authenticated_user, validate_login},
     AuthConf,
 };
 
-async fn unauthorized_response(
+pub(crate) async fn unauthorized_response(
     session: &mut impl SessionWrapper,
-    realm: &str,
+    challenges: Vec<String>,
     suggestion: Option<String>,
 ) -> Result<(), Box<Error>> {
     let text = html! {
@@ -61,7 +61,9 @@ async fn unauthorized_response(
     let mut header = ResponseHeader::build(StatusCode::UNAUTHORIZED, Some(3))?;
     header.append_header(header::CONTENT_LENGTH, text.len().to_string())?;
     header.append_header(header::CONTENT_TYPE, "text/html;charset=utf-8")?;
-    header.append_header(header::WWW_AUTHENTICATE, format!("Basic realm=\"{realm}\""))?;
+    for challenge in challenges {
+        header.append_header(header::WWW_AUTHENTICATE, challenge)?;
+    }
 
     let send_body = session.req_header().method != Method::HEAD;
     session
@@ -75,6 +77,10 @@ async fn unauthorized_response(
     Ok(())
 }
 
+fn basic_challenge(conf: &AuthConf) -> Vec<String> {
+    vec![format!("Basic realm=\"{}\"", conf.auth_realm)]
+}
+
 pub(crate) async fn basic_auth(
     conf: &AuthConf,
     session: &mut impl SessionWrapper,
@@ -83,7 +89,7 @@ pub(crate) async fn basic_auth(
         Some(auth) => auth,
         None => {
             trace!("Rejecting request, no Authorization header");
-            unauthorized_response(session, &conf.auth_realm, None).await?;
+            unauthorized_response(session, basic_challenge(conf), None).await?;
             return Ok(RequestFilterResult::ResponseSent);
         }
     };
@@ -92,7 +98,7 @@ pub(crate) async fn basic_auth(
         Ok(auth) => auth,
         Err(err) => {
             info!("Rejecting request, Authorization header cannot be converted to string: {err}");
-            unauthorized_response(session, &conf.auth_realm, None).await?;
+            unauthorized_response(session, basic_challenge(conf), None).await?;
             return Ok(RequestFilterResult::ResponseSent);
         }
     };
@@ -100,7 +106,7 @@ pub(crate) async fn basic_auth(
     let (scheme, credentials) = auth.split_once(' ').unwrap_or(("", ""));
     if scheme != "Basic" {
         info!("Rejecting request, unsupported authorization scheme: {scheme}");
-        unauthorized_response(session, &conf.auth_realm, None).await?;
+        unauthorized_response(session, basic_challenge(conf), None).await?;
         return Ok(RequestFilterResult::ResponseSent);
     }
 
@@ -108,7 +114,7 @@ pub(crate) async fn basic_auth(
         Ok(credentials) => credentials,
         Err(err) => {
             info!("Rejecting request, failed decoding base64: {err}");
-            unauthorized_response(session, &conf.auth_realm, None).await?;
+            unauthorized_response(session, basic_challenge(conf), None).await?;
             return Ok(RequestFilterResult::ResponseSent);
         }
     };
@@ -123,17 +129,18 @@ pub(crate) async fn basic_auth(
         ("".to_owned(), "".as_bytes())
     };
 
-    if is_rate_limited(session, &conf.auth_rate_limits, &user) {
+    if is_rate_limited(session, &conf.auth_rate_limits, Some(&user)) {
         error_response(session, StatusCode::TOO_MANY_REQUESTS).await?;
         return Ok(RequestFilterResult::ResponseSent);
     }
 
     let (valid, suggestion) = validate_login(conf, &user, password);
     if valid {
-        session.set_remote_user(user);
+        set_authenticated_user(session, conf, user);
+        session.set_auth_credentials(AuthCredentials::Header(header::AUTHORIZATION));
         Ok(RequestFilterResult::Unhandled)
     } else {
-        unauthorized_response(session, &conf.auth_realm, suggestion).await?;
+        unauthorized_response(session, basic_challenge(conf), suggestion).await?;
         Ok(RequestFilterResult::ResponseSent)
     }
 }
@@ -142,7 +149,9 @@ pub(crate) async fn basic_auth(
 mod tests {
     use super::*;
 
-    use pandora_module_utils::pingora::{create_test_session, ErrorType, RequestHeader, Session};
+    use pandora_module_utils::pingora::{
+        create_test_session, AuthenticatedUser, ErrorType, RequestHeader, Session,
+    };
     use pandora_module_utils::standard_response::response_text;
     use pandora_module_utils::{FromYaml, RequestFilter};
     use startup_module::{AppResult, DefaultApp};
@@ -324,6 +333,47 @@ auth_rate_limits:
             Some(&ErrorType::HTTPStatus(404))
         );
         assert_eq!(result.session().remote_user(), Some("me"));
+        let user = result.session().authenticated_user().cloned();
+        assert_eq!(
+            user,
+            Some(AuthenticatedUser {
+                name: "me".to_owned(),
+                roles: Vec::new(),
+            })
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn roles() {
+        let mut conf = default_conf().to_owned();
+        conf.push_str("\nauth_user_roles: {me: [admin, editor], another: viewer}");
+        let mut app = make_app(&conf);
+
+        for (credentials, name, roles) in [
+            ("bWU6dGVzdA==", "me", vec!["admin", "editor"]),
+            ("YW5vdGhlcjp0ZXN0Mg==", "another", vec!["viewer"]),
+        ] {
+            let mut session = make_session().await;
+            session
+                .req_header_mut()
+                .insert_header("Authorization", format!("Basic {credentials}"))
+                .unwrap();
+            let mut result = app.handle_request(session).await;
+            let user = result.session().authenticated_user().cloned().unwrap();
+            assert_eq!(user.name, name);
+            assert_eq!(user.roles, roles);
+            assert!(user.has_role(roles[0]));
+            assert!(!user.has_role("owner"));
+        }
+
+        // Failed logins don’t produce an authenticated user
+        let mut session = make_session().await;
+        session
+            .req_header_mut()
+            .insert_header("Authorization", "Basic bWU6dGVzdDI=")
+            .unwrap();
+        let mut result = app.handle_request(session).await;
+        assert_eq!(result.session().authenticated_user(), None);
     }
 
     #[test(tokio::test)]
@@ -342,6 +392,54 @@ auth_rate_limits:
         assert!(result.body_str().contains("&quot;'&lt;me&gt;'&quot;: $2b$"));
     }
 
+    #[test(tokio::test)]
+    async fn argon2() {
+        async fn remote_user(app: &mut DefaultApp<AuthHandler>, auth: &str) -> Option<String> {
+            let mut session = make_session().await;
+            session
+                .req_header_mut()
+                .insert_header("Authorization", auth)
+                .unwrap();
+            let mut result = app.handle_request(session).await;
+            let user = result.session().remote_user().map(|user| user.to_owned());
+            user
+        }
+
+        let mut conf = default_conf().to_owned();
+        conf.push_str(
+            r#"
+auth_credentials:
+    # test
+    modern: $argon2id$v=19$m=1024,t=1,p=1$cGFuZG9yYS10ZXN0LXNhbHQ$ThWkPGuJpklNoR8z4X3gOzeNwrHaoaZglgaKTh9oMME
+    broken: $argon2id$invalid
+            "#,
+        );
+        let mut app = make_app(&conf);
+        assert_eq!(
+            remote_user(&mut app, "Basic bW9kZXJuOnRlc3Q=").await,
+            Some("modern".to_owned())
+        );
+        assert_eq!(remote_user(&mut app, "Basic bW9kZXJuOnRlc3Qy").await, None);
+        assert_eq!(remote_user(&mut app, "Basic YnJva2VuOnRlc3Q=").await, None);
+
+        // bcrypt hashes keep working
+        assert_eq!(
+            remote_user(&mut app, "Basic bWU6dGVzdA==").await,
+            Some("me".to_owned())
+        );
+
+        conf.push_str("\nauth_display_hash: true\nauth_hash_algorithm: argon2");
+        let mut app = make_app(&conf);
+        let mut session = make_session().await;
+        session
+            .req_header_mut()
+            .insert_header("Authorization", "Basic bW9kZXJuOnRlc3Qy")
+            .unwrap();
+        let result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert!(result.body_str().contains("modern&quot;: $argon2id$"));
+    }
+
     #[test(tokio::test)]
     async fn rate_limiting() {
         let mut conf = default_conf().to_owned();
@@ -375,4 +473,42 @@ auth_rate_limits:
             StatusCode::TOO_MANY_REQUESTS
         );
     }
+
+    #[test(tokio::test)]
+    async fn rate_limiting_argon2() {
+        async fn status(app: &mut DefaultApp<AuthHandler>, auth: &str) -> Option<StatusCode> {
+            let mut session = make_session().await;
+            session
+                .req_header_mut()
+                .insert_header("Authorization", auth)
+                .unwrap();
+            let mut result = app.handle_request(session).await;
+            let status = result.session().response_written().map(|r| r.status);
+            status
+        }
+
+        // The rate limit store is shared, so this relies on a user name unique to this test.
+        let mut app = make_app(
+            r#"
+auth_mode: http
+auth_credentials:
+    # test
+    modern: $argon2id$v=19$m=1024,t=1,p=1$cGFuZG9yYS10ZXN0LXNhbHQ$ThWkPGuJpklNoR8z4X3gOzeNwrHaoaZglgaKTh9oMME
+auth_rate_limits:
+    total: 0
+    per_ip: 0
+    per_user: 2
+            "#,
+        );
+
+        assert_eq!(status(&mut app, "Basic bW9kZXJuOnRlc3Q=").await, None);
+        assert_eq!(
+            status(&mut app, "Basic bW9kZXJuOnRlc3Qy").await,
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            status(&mut app, "Basic bW9kZXJuOnRlc3Q=").await,
+            Some(StatusCode::TOO_MANY_REQUESTS)
+        );
+    }
 }
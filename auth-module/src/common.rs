@@ -12,40 +12,62 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use bcrypt::{hash, verify, DEFAULT_COST};
+use argon2::password_hash::{self, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use bcrypt::DEFAULT_COST;
 use log::{error, info, trace};
-use once_cell::sync::Lazy;
-use pandora_module_utils::pingora::{SessionWrapper, SocketAddr};
-use pingora_limits::rate::Rate;
-use std::{net::Ipv4Addr, sync::Mutex, time::Duration};
+use pandora_module_utils::pingora::{AuthenticatedUser, SessionWrapper, SocketAddr};
+use percent_encoding::percent_decode_str;
+use std::net::Ipv4Addr;
 
-use crate::{AuthConf, AuthRateLimits};
+use crate::{AuthConf, AuthRateLimits, HashAlgorithm, RateLimitKey};
 
+/// Percent-decodes a URI path and resolves `.` and `..` segments. Rules are looked up on this
+/// path, so that encoded variants like `/%61dmin/` cannot be used to access a protected file.
+pub(crate) fn normalize_path(path: &str) -> Vec<u8> {
+    let decoded = percent_decode_str(path).collect::<Vec<_>>();
+    let mut segments = Vec::new();
+    for segment in decoded.split(|byte| *byte == b'/') {
+        match segment {
+            b"" | b"." => {}
+            b".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut normalized = Vec::with_capacity(decoded.len() + 1);
+    for segment in segments {
+        normalized.push(b'/');
+        normalized.extend_from_slice(segment);
+    }
+    if normalized.is_empty() || decoded.ends_with(b"/") {
+        normalized.push(b'/');
+    }
+    normalized
+}
+
+/// Checks whether a login attempt exceeds the rate limits. Without a user name, the per-user
+/// limit doesn’t apply.
 pub(crate) fn is_rate_limited(
     session: &impl SessionWrapper,
     limits: &AuthRateLimits,
-    user: &str,
+    user: Option<&str>,
 ) -> bool {
     if limits.total == 0 && limits.per_user == 0 && limits.per_ip == 0 {
         return false;
     }
 
-    static RATE_LIMITER: Lazy<Mutex<Rate>> =
-        Lazy::new(|| Mutex::new(Rate::new(Duration::new(1, 0))));
-    let rate_limiter = match RATE_LIMITER.lock() {
-        Ok(rate_limiter) => rate_limiter,
-        Err(err) => {
-            error!("Failed acquiring rate mutex, rejecting login attempt: {err}");
-            return true;
-        }
-    };
-
+    let store = &limits.store;
     let mut limited = false;
-    if limits.total > 0 && rate_limiter.observe(&(), 1) > limits.total {
+    if limits.total > 0 && store.observe(&RateLimitKey::Total, 1) > limits.total {
         limited = true;
     }
-    if limits.per_user > 0 && rate_limiter.observe(&user, 1) > limits.per_user {
-        limited = true;
+    if let Some(user) = user.filter(|_| limits.per_user > 0) {
+        if store.observe(&RateLimitKey::User(user), 1) > limits.per_user {
+            limited = true;
+        }
     }
     if limits.per_ip > 0 {
         let ip = session
@@ -56,13 +78,81 @@ pub(crate) fn is_rate_limited(
             })
             .map(|addr| addr.ip())
             .unwrap_or(Ipv4Addr::new(255, 255, 255, 255).into());
-        if rate_limiter.observe(&ip, 1) > limits.per_ip {
+        if store.observe(&RateLimitKey::Ip(ip), 1) > limits.per_ip {
             limited = true;
         }
     }
     limited
 }
 
+/// Compares two strings or byte sequences in constant time
+pub(crate) fn constant_time_eq(a: impl AsRef<[u8]>, b: impl AsRef<[u8]>) -> bool {
+    let (a, b) = (a.as_ref(), b.as_ref());
+    a.len() == b.len()
+        && a.iter()
+            .zip(b.iter())
+            .fold(0, |result, (a, b)| result | (a ^ b))
+            == 0
+}
+
+/// Records a successfully authenticated user along with the roles configured for them.
+pub(crate) fn set_authenticated_user(
+    session: &mut impl SessionWrapper,
+    conf: &AuthConf,
+    user: String,
+) {
+    let roles = conf
+        .auth_user_roles
+        .get(&user)
+        .map(|roles| roles.to_vec())
+        .unwrap_or_default();
+    session.set_remote_user(user.clone());
+    session.set_authenticated_user(AuthenticatedUser { name: user, roles });
+}
+
+/// bcrypt hash of the string "test" with the default cost, verified for unknown users if no
+/// credentials are configured
+const DUMMY_HASH_BCRYPT: &str = "$2y$12$/GSb/xs3Ss/Jq0zv5qBZWeH3oz8RzEi.PuOhPJ8qiP6yCc2dtDbnK";
+
+/// Argon2 hash of the string "test" with the default parameters, verified for unknown users if no
+/// credentials are configured
+const DUMMY_HASH_ARGON2: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$jQ2D2rbpeB4vRXLWzc6O9w$js2bVJ6OZW+cI2xdlvzqZzhg5vkK8IYsp602kW23yeg";
+
+/// Verifies a password against a hash, choosing the algorithm based on the hash prefix. Argon2
+/// hashes use the PHC string format like `$argon2id$v=19$...`, anything else is considered a
+/// bcrypt hash.
+fn verify(password: &[u8], hash: &str) -> Result<bool, String> {
+    if hash.starts_with("$argon2") {
+        let hash = PasswordHash::new(hash).map_err(|err| format!("argon2 failure: {err}"))?;
+        match Argon2::default().verify_password(password, &hash) {
+            Ok(()) => Ok(true),
+            Err(password_hash::Error::Password) => Ok(false),
+            Err(err) => Err(format!("argon2 failure: {err}")),
+        }
+    } else {
+        bcrypt::verify(password, hash).map_err(|err| format!("bcrypt failure: {err}"))
+    }
+}
+
+/// Produces a hash of the password with the given algorithm.
+fn hash(password: &[u8], algorithm: HashAlgorithm) -> Result<String, String> {
+    match algorithm {
+        HashAlgorithm::Bcrypt => {
+            bcrypt::hash(password, DEFAULT_COST).map_err(|err| err.to_string())
+        }
+        HashAlgorithm::Argon2 => {
+            let mut salt = [0; 16];
+            getrandom::getrandom(&mut salt).map_err(|err| err.to_string())?;
+            let salt = SaltString::encode_b64(&salt).map_err(|err| err.to_string())?;
+            Argon2::default()
+                .hash_password(password, &salt)
+                .map(|hash| hash.to_string())
+                .map_err(|err| err.to_string())
+        }
+    }
+}
+
 pub(crate) fn validate_login(
     conf: &AuthConf,
     user: &str,
@@ -72,18 +162,25 @@ pub(crate) fn validate_login(
         verify(password, expected)
     } else {
         // This user name is unknown. We still go through verification to prevent timing
-        // attacks. But we test an empty password against bcrypt-hashed string "test", this is
-        // guaranteed to fail.
-        verify(
-            b"",
-            "$2y$12$/GSb/xs3Ss/Jq0zv5qBZWeH3oz8RzEi.PuOhPJ8qiP6yCc2dtDbnK",
-        )
+        // attacks, against one of the configured hashes so that the algorithm and its parameters
+        // match. The result is discarded, the password might be valid for that other user.
+        let template = conf
+            .auth_credentials
+            .values()
+            .next()
+            .map(String::as_str)
+            .unwrap_or(match conf.auth_hash_algorithm {
+                HashAlgorithm::Bcrypt => DUMMY_HASH_BCRYPT,
+                HashAlgorithm::Argon2 => DUMMY_HASH_ARGON2,
+            });
+        let _ = verify(password, template);
+        Ok(false)
     };
 
     let valid = match result {
         Ok(valid) => valid,
         Err(err) => {
-            info!("Rejecting login, bcrypt failure: {err}");
+            info!("Rejecting login, {err}");
             false
         }
     };
@@ -93,12 +190,15 @@ pub(crate) fn validate_login(
     }
 
     if !valid && conf.auth_display_hash && !password.is_empty() {
-        if let Ok(hash) = hash(password, DEFAULT_COST) {
-            trace!("Generated configuration suggestion");
-            return (
-                valid,
-                Some(format!("auth_credentials:\n    \"{user}\": {hash}")),
-            );
+        match hash(password, conf.auth_hash_algorithm) {
+            Ok(hash) => {
+                trace!("Generated configuration suggestion");
+                return (
+                    valid,
+                    Some(format!("auth_credentials:\n    \"{user}\": {hash}")),
+                );
+            }
+            Err(err) => error!("Failed generating password hash: {err}"),
         }
     }
     (valid, None)
@@ -0,0 +1,743 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HTTP Digest access authentication as defined in RFC 7616
+
+use hmac::{Hmac, Mac};
+use http::{header, Method, StatusCode};
+use log::{error, info, trace};
+use md5::Md5;
+use pandora_module_utils::pingora::{AuthCredentials, Error, ErrorType, SessionWrapper};
+use pandora_module_utils::standard_response::error_response;
+use pandora_module_utils::RequestFilterResult;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    basic::unauthorized_response,
+    common::{constant_time_eq, is_rate_limited, set_authenticated_user},
+    AuthConf,
+};
+
+/// Number of seconds a nonce stays valid. Clients using an expired nonce are asked to retry with
+/// a new one.
+const NONCE_LIFETIME: u64 = 5 * 60;
+
+/// Number of HMAC bytes included in a nonce
+const NONCE_MAC_LENGTH: usize = 16;
+
+/// Maximal number of nonces to keep track of. Beyond that, the oldest nonces are forgotten and
+/// considered stale.
+const MAX_TRACKED_NONCES: usize = 10_000;
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Hash algorithm used for a digest credential, determined by the length of the `HA1` value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DigestAlgorithm {
+    Sha256,
+    Md5,
+}
+
+impl DigestAlgorithm {
+    fn from_ha1(ha1: &str) -> Option<Self> {
+        if !ha1.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        match ha1.len() {
+            64 => Some(Self::Sha256),
+            32 => Some(Self::Md5),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Sha256 => "SHA-256",
+            Self::Md5 => "MD5",
+        }
+    }
+
+    fn hash(&self, data: &str) -> String {
+        match self {
+            Self::Sha256 => to_hex(&Sha256::digest(data)),
+            Self::Md5 => to_hex(&Md5::digest(data)),
+        }
+    }
+}
+
+/// Splits a credentials entry like `user:realm:HA1` into its parts
+fn parse_entry(entry: &str) -> Option<(&str, &str, &str)> {
+    let (user, rest) = entry.split_once(':')?;
+    let (realm, ha1) = rest.rsplit_once(':')?;
+    Some((user, realm, ha1))
+}
+
+/// Makes sure that all entries of the `auth_credentials_digest` setting are valid.
+pub(crate) fn validate_credentials(entries: &[String]) -> Result<(), Box<Error>> {
+    for entry in entries {
+        let valid =
+            parse_entry(entry).is_some_and(|(_, _, ha1)| DigestAlgorithm::from_ha1(ha1).is_some());
+        if !valid {
+            error!("invalid digest credentials, expected user:realm:HA1 with hex-encoded MD5 or SHA-256 hash: {entry}");
+            return Err(Error::new(ErrorType::InternalError));
+        }
+    }
+    Ok(())
+}
+
+/// Looks up the `HA1` value for a user in the given realm.
+fn lookup_ha1<'a>(conf: &'a AuthConf, user: &str) -> Option<(DigestAlgorithm, &'a str)> {
+    conf.auth_credentials_digest
+        .iter()
+        .filter_map(|entry| parse_entry(entry))
+        .find(|(entry_user, realm, _)| *entry_user == user && *realm == conf.auth_realm)
+        .and_then(|(_, _, ha1)| Some((DigestAlgorithm::from_ha1(ha1)?, ha1)))
+}
+
+fn nonce_mac(secret: &[u8], timestamp: u64, realm: &str) -> Result<Hmac<Sha256>, Box<Error>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).map_err(|err| {
+        error!("Failed creating HMAC for nonce: {err}");
+        Error::new(ErrorType::InternalError)
+    })?;
+    mac.update(&timestamp.to_be_bytes());
+    mac.update(realm.as_bytes());
+    Ok(mac)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Produces a nonce for the given point in time. The nonce contains the timestamp and is signed
+/// with the token secret, so that it can be validated without keeping track of issued nonces.
+fn make_nonce(conf: &AuthConf, timestamp: u64) -> Result<String, Box<Error>> {
+    let secret = conf
+        .auth_page_session
+        .token_secret
+        .as_deref()
+        .unwrap_or_default();
+    let mac = nonce_mac(secret, timestamp, &conf.auth_realm)?.finalize();
+    Ok(format!(
+        "{timestamp:016x}{}",
+        to_hex(&mac.into_bytes()[..NONCE_MAC_LENGTH])
+    ))
+}
+
+/// Nonce validation result
+#[derive(Debug, PartialEq, Eq)]
+enum NonceStatus {
+    Valid,
+    Stale,
+    Invalid,
+}
+
+fn check_nonce(conf: &AuthConf, nonce: &str) -> NonceStatus {
+    if nonce.len() != 16 + NONCE_MAC_LENGTH * 2 || !nonce.is_ascii() {
+        return NonceStatus::Invalid;
+    }
+
+    let (timestamp, tag) = nonce.split_at(16);
+    let Ok(timestamp) = u64::from_str_radix(timestamp, 16) else {
+        return NonceStatus::Invalid;
+    };
+    let Some(tag) = (0..tag.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&tag[i..i + 2], 16).ok())
+        .collect::<Option<Vec<_>>>()
+    else {
+        return NonceStatus::Invalid;
+    };
+
+    let secret = conf
+        .auth_page_session
+        .token_secret
+        .as_deref()
+        .unwrap_or_default();
+    let Ok(mac) = nonce_mac(secret, timestamp, &conf.auth_realm) else {
+        return NonceStatus::Invalid;
+    };
+    if mac.verify_truncated_left(&tag).is_err() {
+        return NonceStatus::Invalid;
+    }
+
+    if timestamp + NONCE_LIFETIME < now() {
+        NonceStatus::Stale
+    } else {
+        NonceStatus::Valid
+    }
+}
+
+#[derive(Debug, Default)]
+struct NonceCountsInner {
+    /// Highest nonce count accepted for each nonce. Nonces start with their timestamp, so the
+    /// oldest nonces come first.
+    counts: BTreeMap<String, u32>,
+    /// Most recent nonce dropped due to the size limit, any nonces up to it are stale
+    dropped: Option<String>,
+}
+
+/// Keeps track of the nonce counts seen for recent nonces, so that captured requests cannot be
+/// replayed
+#[derive(Debug, Clone, Default)]
+pub(crate) struct NonceCounts(Arc<Mutex<NonceCountsInner>>);
+
+impl NonceCounts {
+    /// Records the nonce count of an authenticated request. The count has to be higher than the
+    /// one of any previous request with the same nonce.
+    fn record(&self, nonce: &str, nc: u32) -> NonceStatus {
+        let mut inner = self.0.lock().unwrap_or_else(|err| err.into_inner());
+
+        // Nonces that expired cannot be used any more, no need to keep track of them.
+        let now = now();
+        while let Some(entry) = inner.counts.first_entry() {
+            let timestamp = u64::from_str_radix(&entry.key()[..16], 16).unwrap_or_default();
+            if timestamp + NONCE_LIFETIME >= now {
+                break;
+            }
+            entry.remove();
+        }
+
+        if let Some(count) = inner.counts.get_mut(nonce) {
+            if nc <= *count {
+                return NonceStatus::Invalid;
+            }
+            *count = nc;
+            return NonceStatus::Valid;
+        }
+
+        if inner
+            .dropped
+            .as_deref()
+            .is_some_and(|dropped| nonce <= dropped)
+        {
+            return NonceStatus::Stale;
+        }
+
+        inner.counts.insert(nonce.to_owned(), nc);
+        if inner.counts.len() > MAX_TRACKED_NONCES {
+            inner.dropped = inner.counts.pop_first().map(|(nonce, _)| nonce);
+        }
+        NonceStatus::Valid
+    }
+}
+
+impl PartialEq for NonceCounts {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for NonceCounts {}
+
+/// Parses the parameters of an `Authorization: Digest` header like `username="me", nc=00000001`.
+fn parse_params(value: &str) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    let mut rest = value.trim_start();
+    while !rest.is_empty() {
+        let (name, after_name) = rest.split_once('=')?;
+        let name = name.trim().to_ascii_lowercase();
+        let after_name = after_name.trim_start();
+
+        let (value, after_value) = if let Some(quoted) = after_name.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let end = loop {
+                match chars.next()? {
+                    (_, '\\') => value.push(chars.next()?.1),
+                    (index, '"') => break index,
+                    (_, c) => value.push(c),
+                }
+            };
+            (value, &quoted[end + 1..])
+        } else {
+            let end = after_name.find(',').unwrap_or(after_name.len());
+            (after_name[..end].trim_end().to_owned(), &after_name[end..])
+        };
+
+        params.insert(name, value);
+        rest = after_value.trim_start();
+        if let Some(after_comma) = rest.strip_prefix(',') {
+            rest = after_comma.trim_start();
+        } else if !rest.is_empty() {
+            return None;
+        }
+    }
+    Some(params)
+}
+
+async fn challenge_response(
+    conf: &AuthConf,
+    session: &mut impl SessionWrapper,
+    stale: bool,
+) -> Result<RequestFilterResult, Box<Error>> {
+    let nonce = make_nonce(conf, now())?;
+    let realm = &conf.auth_realm;
+
+    // Offer the algorithms of the configured credentials, the stronger one first
+    let mut challenges = Vec::new();
+    for algorithm in [DigestAlgorithm::Sha256, DigestAlgorithm::Md5] {
+        let configured = conf
+            .auth_credentials_digest
+            .iter()
+            .filter_map(|entry| parse_entry(entry))
+            .any(|(_, _, ha1)| DigestAlgorithm::from_ha1(ha1) == Some(algorithm));
+        if configured {
+            let mut challenge = format!(
+                "Digest realm=\"{realm}\", qop=\"auth\", algorithm={}, nonce=\"{nonce}\"",
+                algorithm.name()
+            );
+            if stale {
+                challenge.push_str(", stale=true");
+            }
+            challenges.push(challenge);
+        }
+    }
+
+    unauthorized_response(session, challenges, None).await?;
+    Ok(RequestFilterResult::ResponseSent)
+}
+
+pub(crate) async fn digest_auth(
+    conf: &AuthConf,
+    nonce_counts: &NonceCounts,
+    session: &mut impl SessionWrapper,
+) -> Result<RequestFilterResult, Box<Error>> {
+    let Some(auth) = session.req_header().headers.get(header::AUTHORIZATION) else {
+        trace!("Rejecting request, no Authorization header");
+        return challenge_response(conf, session, false).await;
+    };
+
+    let Ok(auth) = auth.to_str() else {
+        info!("Rejecting request, Authorization header cannot be converted to string");
+        return challenge_response(conf, session, false).await;
+    };
+
+    let (scheme, params) = auth.split_once(' ').unwrap_or((auth, ""));
+    if !scheme.eq_ignore_ascii_case("Digest") {
+        info!("Rejecting request, unsupported authorization scheme: {scheme}");
+        return challenge_response(conf, session, false).await;
+    }
+
+    let Some(params) = parse_params(params) else {
+        info!("Rejecting request, failed parsing digest parameters");
+        return challenge_response(conf, session, false).await;
+    };
+    let param = |name: &str| params.get(name).map(String::as_str).unwrap_or_default();
+
+    // Every attempt counts, including retries after a stale nonce
+    let user = param("username");
+    if is_rate_limited(session, &conf.auth_rate_limits, Some(user)) {
+        error_response(session, StatusCode::TOO_MANY_REQUESTS).await?;
+        return Ok(RequestFilterResult::ResponseSent);
+    }
+
+    let nonce = param("nonce");
+    match check_nonce(conf, nonce) {
+        NonceStatus::Valid => {}
+        NonceStatus::Stale => {
+            trace!("Rejecting request, stale nonce");
+            return challenge_response(conf, session, true).await;
+        }
+        NonceStatus::Invalid => {
+            info!("Rejecting request, invalid nonce");
+            return challenge_response(conf, session, false).await;
+        }
+    }
+
+    let uri = session.original_uri();
+    let request_uri = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    if param("realm") != conf.auth_realm || param("uri") != request_uri {
+        info!("Rejecting request, realm or URI mismatch");
+        return challenge_response(conf, session, false).await;
+    }
+
+    if param("qop") != "auth" || params.get("userhash").is_some_and(|v| v == "true") {
+        info!("Rejecting request, unsupported qop or userhash parameter");
+        return challenge_response(conf, session, false).await;
+    }
+
+    let valid = if let Some((algorithm, ha1)) = lookup_ha1(conf, user) {
+        let requested = params.get("algorithm").map(String::as_str).unwrap_or("MD5");
+        let expected_response = |method: &Method| {
+            let ha2 = algorithm.hash(&format!("{method}:{}", param("uri")));
+            algorithm.hash(&format!(
+                "{}:{nonce}:{}:{}:auth:{ha2}",
+                ha1.to_ascii_lowercase(),
+                param("nc"),
+                param("cnonce")
+            ))
+        };
+
+        requested.eq_ignore_ascii_case(algorithm.name())
+            && constant_time_eq(
+                expected_response(&session.req_header().method),
+                param("response"),
+            )
+    } else {
+        false
+    };
+
+    if !valid {
+        info!("Rejecting login, wrong credentials");
+        return challenge_response(conf, session, false).await;
+    }
+
+    let Ok(nc) = u32::from_str_radix(param("nc"), 16) else {
+        info!("Rejecting request, invalid nonce count");
+        return challenge_response(conf, session, false).await;
+    };
+    match nonce_counts.record(nonce, nc) {
+        NonceStatus::Valid => {
+            set_authenticated_user(session, conf, user.to_owned());
+            session.set_auth_credentials(AuthCredentials::Header(header::AUTHORIZATION));
+            Ok(RequestFilterResult::Unhandled)
+        }
+        NonceStatus::Stale => {
+            trace!("Rejecting request, nonce no longer tracked");
+            challenge_response(conf, session, true).await
+        }
+        NonceStatus::Invalid => {
+            info!("Rejecting request, nonce count {nc} already used");
+            challenge_response(conf, session, false).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{create_test_session, ErrorType, RequestHeader, Session};
+    use pandora_module_utils::{FromYaml, RequestFilter};
+    use startup_module::{AppResult, DefaultApp};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use test_log::test;
+
+    use crate::AuthHandler;
+
+    // HA1 values for the password "test"
+    fn default_conf() -> &'static str {
+        r#"
+auth_mode: digest
+auth_realm: Protected area
+auth_credentials_digest:
+- "me:Protected area:fa5ed6f3b9fed4b43be732eda9cd6b697e97c1d2e2c28cb9a1b465939fe5d4db"
+- "old:Protected area:e0711b595d72f3ad5723265d8acd20ba"
+- "other:Another realm:51f7c8a3519b33295390708ca49c9838"
+auth_page_session:
+    token_secret: abcd
+auth_rate_limits:
+    total: 0
+    per_ip: 0
+    per_user: 0
+        "#
+    }
+
+    fn make_app(conf: &str) -> DefaultApp<AuthHandler> {
+        DefaultApp::new(
+            <AuthHandler as RequestFilter>::Conf::from_yaml(conf)
+                .unwrap()
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    async fn make_session(method: &str, authorization: Option<String>) -> Session {
+        let mut header = RequestHeader::build(method, b"/file?x=1", None).unwrap();
+        if let Some(authorization) = authorization {
+            header
+                .insert_header(header::AUTHORIZATION, authorization)
+                .unwrap();
+        }
+        create_test_session(header).await
+    }
+
+    fn challenges(result: &mut AppResult) -> Vec<String> {
+        let session = result.session();
+        let response = session.response_written().unwrap();
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        response
+            .headers
+            .get_all(header::WWW_AUTHENTICATE)
+            .iter()
+            .map(|value| value.to_str().unwrap().to_owned())
+            .collect()
+    }
+
+    fn challenge_nonce(challenge: &str) -> String {
+        parse_params(challenge.strip_prefix("Digest ").unwrap()).unwrap()["nonce"].clone()
+    }
+
+    fn authorization(
+        algorithm: DigestAlgorithm,
+        user: &str,
+        password: &str,
+        method: &str,
+        nonce: &str,
+    ) -> String {
+        let ha1 = algorithm.hash(&format!("{user}:Protected area:{password}"));
+        let ha2 = algorithm.hash(&format!("{method}:/file?x=1"));
+        let response = algorithm.hash(&format!("{ha1}:{nonce}:00000001:abcdef:auth:{ha2}"));
+        format!(
+            r#"Digest username="{user}", realm="Protected area", uri="/file?x=1", algorithm={}, nonce="{nonce}", nc=00000001, cnonce="abcdef", qop=auth, response="{response}""#,
+            algorithm.name()
+        )
+    }
+
+    async fn remote_user(app: &mut DefaultApp<AuthHandler>, session: Session) -> Option<String> {
+        let mut result = app.handle_request(session).await;
+        let user = result.session().remote_user().map(|user| user.to_owned());
+        user
+    }
+
+    #[test]
+    fn ha1_values() {
+        assert_eq!(
+            DigestAlgorithm::Sha256.hash("me:Protected area:test"),
+            "fa5ed6f3b9fed4b43be732eda9cd6b697e97c1d2e2c28cb9a1b465939fe5d4db"
+        );
+        assert_eq!(
+            DigestAlgorithm::Md5.hash("old:Protected area:test"),
+            "e0711b595d72f3ad5723265d8acd20ba"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn challenge() {
+        let mut app = make_app(default_conf());
+        let session = make_session("GET", None).await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        let challenges = challenges(&mut result);
+        assert_eq!(challenges.len(), 2);
+        assert!(challenges[0].starts_with(
+            "Digest realm=\"Protected area\", qop=\"auth\", algorithm=SHA-256, nonce=\""
+        ));
+        assert!(challenges[1].contains("algorithm=MD5"));
+        assert_eq!(
+            check_nonce(&app_conf(), &challenge_nonce(&challenges[0])),
+            NonceStatus::Valid
+        );
+    }
+
+    fn app_conf() -> AuthConf {
+        AuthConf::from_yaml(default_conf()).unwrap()
+    }
+
+    /// Produces a different nonce on each call
+    fn fresh_nonce() -> String {
+        static AGE: AtomicU64 = AtomicU64::new(0);
+        make_nonce(&app_conf(), now() - AGE.fetch_add(1, Ordering::Relaxed)).unwrap()
+    }
+
+    #[test(tokio::test)]
+    async fn login() {
+        let mut app = make_app(default_conf());
+        let nonce = fresh_nonce();
+
+        let auth = authorization(DigestAlgorithm::Sha256, "me", "test", "GET", &fresh_nonce());
+        let session = make_session("GET", Some(auth)).await;
+        assert_eq!(remote_user(&mut app, session).await.as_deref(), Some("me"));
+
+        let auth = authorization(DigestAlgorithm::Md5, "old", "test", "GET", &fresh_nonce());
+        let session = make_session("GET", Some(auth)).await;
+        assert_eq!(remote_user(&mut app, session).await.as_deref(), Some("old"));
+
+        // HEAD requests are validated against the original method
+        let auth = authorization(
+            DigestAlgorithm::Sha256,
+            "me",
+            "test",
+            "HEAD",
+            &fresh_nonce(),
+        );
+        let session = make_session("HEAD", Some(auth)).await;
+        assert_eq!(remote_user(&mut app, session).await.as_deref(), Some("me"));
+
+        // Wrong password
+        let auth = authorization(DigestAlgorithm::Sha256, "me", "test2", "GET", &nonce);
+        let session = make_session("GET", Some(auth)).await;
+        assert_eq!(remote_user(&mut app, session).await, None);
+
+        // Algorithm not matching the configured credentials
+        let auth = authorization(DigestAlgorithm::Md5, "me", "test", "GET", &nonce);
+        let session = make_session("GET", Some(auth)).await;
+        assert_eq!(remote_user(&mut app, session).await, None);
+
+        // Credentials for another realm
+        let auth = authorization(DigestAlgorithm::Md5, "other", "test", "GET", &nonce);
+        let session = make_session("GET", Some(auth)).await;
+        assert_eq!(remote_user(&mut app, session).await, None);
+
+        // Method mismatch
+        let auth = authorization(DigestAlgorithm::Sha256, "me", "test", "POST", &nonce);
+        let session = make_session("GET", Some(auth)).await;
+        assert_eq!(remote_user(&mut app, session).await, None);
+
+        // Forged nonce
+        let mut forged = nonce.clone();
+        forged.replace_range(16..18, if &nonce[16..18] == "00" { "01" } else { "00" });
+        let auth = authorization(DigestAlgorithm::Sha256, "me", "test", "GET", &forged);
+        let session = make_session("GET", Some(auth)).await;
+        assert_eq!(remote_user(&mut app, session).await, None);
+
+        // Successful login passes on to the next handler
+        let auth = authorization(DigestAlgorithm::Sha256, "me", "test", "GET", &nonce);
+        let session = make_session("GET", Some(auth)).await;
+        let result = app.handle_request(session).await;
+        assert_eq!(
+            result.err().as_ref().map(|err| &err.etype),
+            Some(&ErrorType::HTTPStatus(404))
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn replay() {
+        let mut app = make_app(default_conf());
+        let nonce = fresh_nonce();
+
+        let auth = authorization(DigestAlgorithm::Sha256, "me", "test", "GET", &nonce);
+        let session = make_session("GET", Some(auth.clone())).await;
+        assert_eq!(remote_user(&mut app, session).await.as_deref(), Some("me"));
+
+        // Same request again is rejected
+        let session = make_session("GET", Some(auth.clone())).await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(result.session().remote_user(), None);
+        assert!(!challenges(&mut result)[0].contains("stale=true"));
+
+        // Higher nonce count is accepted, lower one isn’t
+        let with_nc = |nc: &str| {
+            let ha1 = DigestAlgorithm::Sha256.hash("me:Protected area:test");
+            let ha2 = DigestAlgorithm::Sha256.hash("GET:/file?x=1");
+            let response =
+                DigestAlgorithm::Sha256.hash(&format!("{ha1}:{nonce}:{nc}:abcdef:auth:{ha2}"));
+            auth.replace("nc=00000001", &format!("nc={nc}")).replace(
+                &auth[auth.find("response=").unwrap()..],
+                &format!("response=\"{response}\""),
+            )
+        };
+        let session = make_session("GET", Some(with_nc("00000003"))).await;
+        assert_eq!(remote_user(&mut app, session).await.as_deref(), Some("me"));
+        let session = make_session("GET", Some(with_nc("00000002"))).await;
+        assert_eq!(remote_user(&mut app, session).await, None);
+        let session = make_session("GET", Some(with_nc("0000000a"))).await;
+        assert_eq!(remote_user(&mut app, session).await.as_deref(), Some("me"));
+
+        // Failed login attempts don’t count
+        let nonce = fresh_nonce();
+        let auth = authorization(DigestAlgorithm::Sha256, "me", "wrong", "GET", &nonce);
+        let session = make_session("GET", Some(auth)).await;
+        assert_eq!(remote_user(&mut app, session).await, None);
+        let auth = authorization(DigestAlgorithm::Sha256, "me", "test", "GET", &nonce);
+        let session = make_session("GET", Some(auth)).await;
+        assert_eq!(remote_user(&mut app, session).await.as_deref(), Some("me"));
+    }
+
+    #[test]
+    fn nonce_counts_limit() {
+        let counts = NonceCounts::default();
+        // Only the timestamp part of the nonce matters here
+        let timestamp = now() - 100;
+        let nonces = (0..=MAX_TRACKED_NONCES)
+            .map(|i| format!("{timestamp:016x}{i:032x}"))
+            .collect::<Vec<_>>();
+
+        for nonce in &nonces {
+            assert_eq!(counts.record(nonce, 1), NonceStatus::Valid);
+        }
+
+        // Oldest nonce has been dropped and is stale now, all others are still tracked
+        assert_eq!(counts.record(&nonces[0], 2), NonceStatus::Stale);
+        assert_eq!(counts.record(&nonces[1], 1), NonceStatus::Invalid);
+        assert_eq!(counts.record(&nonces[1], 2), NonceStatus::Valid);
+
+        // Expired nonces are removed
+        let expired = format!("{:016x}{:032x}", now() - NONCE_LIFETIME - 1, 0);
+        assert_eq!(counts.record(&expired, 1), NonceStatus::Stale);
+        let fresh = format!("{:016x}{:032x}", now(), 0);
+        assert_eq!(counts.record(&fresh, 1), NonceStatus::Valid);
+        assert_eq!(counts.0.lock().unwrap().counts.len(), MAX_TRACKED_NONCES);
+    }
+
+    #[test(tokio::test)]
+    async fn rule_without_credentials() {
+        let mut app = make_app(&format!(
+            "{}\nauth_rules:\n- path: /file\n  auth_credentials: {{}}",
+            default_conf()
+        ));
+        let session = make_session("GET", None).await;
+        let result = app.handle_request(session).await;
+        assert_eq!(
+            result.err().as_ref().map(|err| &err.etype),
+            Some(&ErrorType::HTTPStatus(404))
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn stale_nonce() {
+        let mut app = make_app(default_conf());
+        let nonce = make_nonce(&app_conf(), now() - NONCE_LIFETIME - 1).unwrap();
+        let auth = authorization(DigestAlgorithm::Sha256, "me", "test", "GET", &nonce);
+        let session = make_session("GET", Some(auth)).await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        let challenges = challenges(&mut result);
+        assert!(challenges[0].ends_with(", stale=true"));
+        assert_ne!(challenge_nonce(&challenges[0]), nonce);
+    }
+
+    #[test(tokio::test)]
+    async fn rate_limiting() {
+        let conf = default_conf().replace("per_user: 0", "per_user: 1");
+        let mut app = make_app(&conf);
+        let nonce = make_nonce(&app_conf(), now() - NONCE_LIFETIME - 1).unwrap();
+
+        // Retries after a stale nonce count towards the limit
+        let auth = authorization(DigestAlgorithm::Sha256, "me", "test", "GET", &nonce);
+        let session = make_session("GET", Some(auth.clone())).await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(
+            result.session().response_written().map(|r| r.status),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+
+        let session = make_session("GET", Some(auth)).await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(
+            result.session().response_written().map(|r| r.status),
+            Some(StatusCode::TOO_MANY_REQUESTS)
+        );
+    }
+
+    #[test]
+    fn invalid_credentials() {
+        let conf = AuthConf::from_yaml(
+            r#"
+                auth_mode: digest
+                auth_credentials_digest: "me:realm:xyz"
+            "#,
+        )
+        .unwrap();
+        assert!(AuthHandler::try_from(conf).is_err());
+    }
+}
@@ -14,23 +14,37 @@
 
 #![doc = include_str!("../README.md")]
 
+mod api_key;
 mod basic;
 mod common;
+mod digest;
 mod page;
+mod rate_limit;
 
 use async_trait::async_trait;
 use clap::Parser;
 use http::Uri;
-use log::{error, info};
-use pandora_module_utils::pingora::{Error, ErrorType, SessionWrapper};
-use pandora_module_utils::{DeserializeMap, RequestFilter, RequestFilterResult};
+use log::{error, info, trace};
+use pandora_module_utils::merger::{Merger, PathMatcher};
+use pandora_module_utils::pingora::{
+    add_module_once, Error, ErrorType, HttpModules, SessionWrapper,
+};
+use pandora_module_utils::router::Router;
+use pandora_module_utils::{
+    DeserializeMap, OneOrMany, PathCaseUsage, RequestFilter, RequestFilterResult,
+};
 use serde::{de::Unexpected, Deserialize, Deserializer};
 use std::collections::HashMap;
 use std::str::FromStr;
 use std::time::Duration;
 
+pub use rate_limit::{InMemoryRateLimitStore, RateLimitKey, RateLimitStore, SharedRateLimitStore};
+
+use api_key::{api_key_auth, ApiKeys};
 use basic::basic_auth;
-use page::page_auth;
+use common::normalize_path;
+use digest::{digest_auth, NonceCounts};
+use page::{page_auth, SessionCookieHttpModuleBuilder};
 
 /// Authentication mode
 #[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
@@ -41,6 +55,16 @@ pub enum AuthMode {
     /// Webpage-based authentication
     #[default]
     Page,
+    /// HTTP Digest authentication
+    Digest,
+    /// API keys sent in a request header
+    ApiKey,
+}
+
+impl AuthMode {
+    fn needs_token_secret(&self) -> bool {
+        matches!(self, Self::Page | Self::Digest)
+    }
 }
 
 impl FromStr for AuthMode {
@@ -50,6 +74,8 @@ impl FromStr for AuthMode {
         match s {
             "http" => Ok(Self::HTTP),
             "page" => Ok(Self::Page),
+            "digest" => Ok(Self::Digest),
+            "apikey" => Ok(Self::ApiKey),
             _ => Err(Error::explain(
                 ErrorType::InternalError,
                 "invalid auth mode value",
@@ -58,6 +84,32 @@ impl FromStr for AuthMode {
     }
 }
 
+/// Algorithm used to hash passwords for configuration suggestions
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    /// bcrypt, hashes start with `$2b$`
+    #[default]
+    Bcrypt,
+    /// Argon2id, hashes start with `$argon2id$`
+    Argon2,
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = Box<Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bcrypt" => Ok(Self::Bcrypt),
+            "argon2" => Ok(Self::Argon2),
+            _ => Err(Error::explain(
+                ErrorType::InternalError,
+                "invalid hash algorithm value",
+            )),
+        }
+    }
+}
+
 /// Command line options of the auth module
 #[derive(Debug, Parser)]
 pub struct AuthOpt {
@@ -67,17 +119,27 @@ pub struct AuthOpt {
     /// This allows you to produce a hash for your password without using any third-party tools.
     #[clap(long)]
     pub auth_display_hash: bool,
+    /// Algorithm of the hashes produced by --auth-display-hash, either "bcrypt" or "argon2"
+    #[clap(long)]
+    pub auth_hash_algorithm: Option<HashAlgorithm>,
     /// Authorization credentials using the format user:hash. This command line flag can be
     /// specified multiple times.
     ///
-    /// Supported hashes use the bcrypt format and start with $2b$ or $2y$. Use --auth-display-hash
-    /// command line flag to generate a password hash without third-party tools.
+    /// Supported hashes use the bcrypt format and start with $2b$ or $2y$ or the Argon2 format
+    /// and start with $argon2id$. Use --auth-display-hash command line flag to generate a
+    /// password hash without third-party tools.
     #[clap(long)]
     pub auth_credentials: Option<Vec<String>>,
-    /// Authentication mode, either "http" or "page"
+    /// Digest authentication credentials using the format user:realm:HA1. This command line flag
+    /// can be specified multiple times.
+    ///
+    /// HA1 is the hex-encoded MD5 or SHA-256 hash of user:realm:password.
+    #[clap(long)]
+    pub auth_credentials_digest: Option<Vec<String>>,
+    /// Authentication mode, either "http", "page", "digest" or "apikey"
     #[clap(long)]
     pub auth_mode: Option<AuthMode>,
-    /// The authentication realm to communicate to the browser (HTTP mode only)
+    /// The authentication realm to communicate to the browser (HTTP and digest modes only)
     #[clap(long)]
     pub auth_realm: Option<String>,
 }
@@ -97,6 +159,12 @@ pub struct AuthRateLimits {
     ///
     /// The value 0 disables rate limiting here.
     per_user: isize,
+    /// Storage keeping track of login attempts
+    ///
+    /// This cannot be set in the configuration file. It has to be replaced programmatically to
+    /// share state between multiple server processes.
+    #[pandora(skip)]
+    pub store: SharedRateLimitStore,
 }
 
 impl Default for AuthRateLimits {
@@ -105,10 +173,28 @@ impl Default for AuthRateLimits {
             total: 16,
             per_ip: 4,
             per_user: 4,
+            store: Default::default(),
         }
     }
 }
 
+/// Binding of sessions to the client’s network (page mode only)
+///
+/// Sessions are bound to the network of the client’s IP address determined by the prefix length.
+/// Using shorter prefixes allows clients to keep their sessions when their address changes
+/// within their provider’s network.
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct AuthBindIp {
+    /// Prefix length for IPv4 addresses, up to 32
+    ///
+    /// The value 0 disables binding for IPv4 clients.
+    pub ipv4_prefix: u8,
+    /// Prefix length for IPv6 addresses, up to 128
+    ///
+    /// The value 0 disables binding for IPv6 clients.
+    pub ipv6_prefix: u8,
+}
+
 /// Texts used on the auth page
 #[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct AuthPageStrings {
@@ -198,6 +284,13 @@ where
     Ok(Duration::new(interval * factor, 0))
 }
 
+fn deserialize_optional_interval<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_interval(deserializer).map(Some)
+}
+
 /// Session settings (page mode only)
 #[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct AuthPageSession {
@@ -205,6 +298,14 @@ pub struct AuthPageSession {
     #[pandora(deserialize_with = "deserialize_uri")]
     pub login_page: Option<Uri>,
 
+    /// Action URL of the default login page’s form, e.g. `/app/login` when the server is behind a
+    /// reverse proxy adding a path prefix. By default, the form is sent to the current URL.
+    pub form_action: Option<String>,
+
+    /// Request path accepting the login form data, e.g. `/login`. Defaults to the path of
+    /// `form_action`, login form data is accepted on any path if neither is set.
+    pub form_path: Option<String>,
+
     /// Hex-encoded token secret
     ///
     /// If missing, a random token secret will be generated at startup. A server restart will
@@ -227,16 +328,107 @@ pub struct AuthPageSession {
     /// (2 hours).
     #[pandora(deserialize_with = "deserialize_interval")]
     pub session_expiration: Duration,
+
+    /// If `true`, the session expiration will be extended on activity. Once more than half of the
+    /// expiration interval passed, a new token is issued with the next successful request.
+    pub sliding: bool,
+
+    /// Maximal lifetime of a login session regardless of activity, only relevant with sliding
+    /// expiration. No limit by default.
+    ///
+    /// Same format as `session_expiration`.
+    #[pandora(deserialize_with = "deserialize_optional_interval")]
+    pub session_max_lifetime: Option<Duration>,
 }
 
 impl Default for AuthPageSession {
     fn default() -> Self {
         Self {
             login_page: None,
+            form_action: None,
+            form_path: None,
             token_secret: None,
             cookie_name: "token".to_owned(),
             secure_cookie: None,
             session_expiration: Duration::from_secs(7 * 24 * 60 * 60),
+            sliding: false,
+            session_max_lifetime: None,
+        }
+    }
+}
+
+/// Authentication settings applying to a part of the webspace
+///
+/// Settings not present in the rule are taken from the general authentication configuration.
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct AuthRule {
+    /// Path that the rule applies to
+    ///
+    /// By default, an exact path match is required. A value like `/path/*` indicates a prefix
+    /// match, both `/path/` and `/path/subdir/file.txt` will be matched.
+    ///
+    /// When multiple rules apply to a location, the closest match is used. Rules with a longer
+    /// path are considered closer matches than shorter paths. Exact matches are considered closer
+    /// matches than prefix matches for the same path.
+    pub path: PathMatcher,
+
+    /// Authentication mode for this location
+    pub auth_mode: Option<AuthMode>,
+
+    /// Accepted credentials for this location, an empty list disables authentication here
+    /// regardless of the authentication mode
+    pub auth_credentials: Option<HashMap<String, String>>,
+
+    /// Roles by user name for this location
+    pub auth_user_roles: Option<HashMap<String, OneOrMany<String>>>,
+
+    /// Accepted digest credentials for this location (digest mode only)
+    pub auth_credentials_digest: Option<OneOrMany<String>>,
+
+    /// Request header containing the API key for this location (API key mode only)
+    pub auth_api_key_header: Option<String>,
+
+    /// Accepted API keys for this location (API key mode only)
+    pub auth_api_keys: Option<OneOrMany<String>>,
+
+    /// Realm for the authentication challenge (Basic HTTP and digest modes only)
+    pub auth_realm: Option<String>,
+
+    /// Texts used on the auth page
+    pub auth_page_strings: Option<AuthPageStrings>,
+
+    /// Session settings (page mode only)
+    ///
+    /// If present, these replace the general session settings. A separate token secret will be
+    /// generated for the rule if none is configured.
+    pub auth_page_session: Option<AuthPageSession>,
+
+    /// Binding of sessions to the client’s network (page mode only)
+    pub auth_bind_ip: Option<AuthBindIp>,
+
+    /// Request path ending the session (page mode only)
+    pub auth_logout_path: Option<String>,
+
+    /// Redirect target after logging out (page mode only)
+    pub auth_logout_redirect: Option<String>,
+}
+
+impl Default for AuthRule {
+    fn default() -> Self {
+        Self {
+            path: "/*".into(),
+            auth_mode: None,
+            auth_credentials: None,
+            auth_user_roles: None,
+            auth_credentials_digest: None,
+            auth_api_key_header: None,
+            auth_api_keys: None,
+            auth_realm: None,
+            auth_page_strings: None,
+            auth_page_session: None,
+            auth_bind_ip: None,
+            auth_logout_path: None,
+            auth_logout_redirect: None,
         }
     }
 }
@@ -248,18 +440,40 @@ pub struct AuthConf {
     /// 401 Unauthorized page.
     pub auth_display_hash: bool,
 
+    /// Algorithm of the hashes displayed with `auth_display_hash`, either bcrypt or Argon2
+    pub auth_hash_algorithm: HashAlgorithm,
+
     /// Accepted credentials by user name
     pub auth_credentials: HashMap<String, String>,
 
+    /// Roles by user name
+    ///
+    /// After a successful login, the user name and roles are available to subsequent handlers via
+    /// `SessionWrapper::authenticated_user()`.
+    pub auth_user_roles: HashMap<String, OneOrMany<String>>,
+
+    /// Accepted digest credentials as `user:realm:HA1` entries (digest mode only)
+    ///
+    /// `HA1` is the hex-encoded MD5 or SHA-256 hash of `user:realm:password`. Only entries
+    /// matching `auth_realm` are used.
+    pub auth_credentials_digest: OneOrMany<String>,
+
+    /// Request header containing the API key (API key mode only)
+    pub auth_api_key_header: String,
+
+    /// Accepted API keys (API key mode only)
+    pub auth_api_keys: OneOrMany<String>,
+
     /// Login rate limits
     ///
     /// Note that in Basic HTTP mode each request is a “login”
     pub auth_rate_limits: AuthRateLimits,
 
-    /// Authentication mode, either Basic HTTP authentication or web page
+    /// Authentication mode, either Basic HTTP authentication, web page, HTTP Digest
+    /// authentication or API keys
     pub auth_mode: AuthMode,
 
-    /// Realm for the authentication challenge (Basic HTTP mode only)
+    /// Realm for the authentication challenge (Basic HTTP and digest modes only)
     pub auth_realm: String,
 
     /// Texts used on the auth page
@@ -267,6 +481,24 @@ pub struct AuthConf {
 
     /// Session settings (page mode only)
     pub auth_page_session: AuthPageSession,
+
+    /// Binding of sessions to the client’s network (page mode only)
+    pub auth_bind_ip: AuthBindIp,
+
+    /// Request path ending the session (page mode only)
+    ///
+    /// Requests to this path carrying a valid session clear the login cookie and redirect to
+    /// `auth_logout_redirect`. An empty value disables logging out.
+    pub auth_logout_path: String,
+
+    /// Redirect target after logging out (page mode only)
+    ///
+    /// Defaults to `form_action` of the session settings if configured, otherwise to the root of
+    /// the directory the module is mounted in.
+    pub auth_logout_redirect: Option<String>,
+
+    /// Rules overriding the settings above for particular paths
+    pub auth_rules: OneOrMany<AuthRule>,
 }
 
 impl AuthConf {
@@ -278,6 +510,10 @@ impl AuthConf {
             self.auth_display_hash = true;
         }
 
+        if let Some(auth_hash_algorithm) = opt.auth_hash_algorithm {
+            self.auth_hash_algorithm = auth_hash_algorithm;
+        }
+
         if let Some(auth_credentials) = opt.auth_credentials {
             for entry in auth_credentials {
                 if let Some((user, hash)) = entry.split_once(':') {
@@ -289,6 +525,10 @@ impl AuthConf {
             }
         }
 
+        if let Some(auth_credentials_digest) = opt.auth_credentials_digest {
+            self.auth_credentials_digest.extend(auth_credentials_digest);
+        }
+
         if let Some(auth_mode) = opt.auth_mode {
             self.auth_mode = auth_mode;
         }
@@ -303,12 +543,21 @@ impl Default for AuthConf {
     fn default() -> Self {
         Self {
             auth_display_hash: false,
+            auth_hash_algorithm: HashAlgorithm::Bcrypt,
             auth_credentials: HashMap::new(),
+            auth_user_roles: HashMap::new(),
+            auth_credentials_digest: Default::default(),
+            auth_api_key_header: "X-API-Key".to_owned(),
+            auth_api_keys: Default::default(),
             auth_rate_limits: Default::default(),
             auth_mode: AuthMode::Page,
             auth_realm: "Server authentication".to_owned(),
             auth_page_strings: Default::default(),
             auth_page_session: Default::default(),
+            auth_bind_ip: Default::default(),
+            auth_logout_path: "/logout".to_owned(),
+            auth_logout_redirect: None,
+            auth_rules: Default::default(),
         }
     }
 }
@@ -316,29 +565,149 @@ impl Default for AuthConf {
 /// Auth module handler
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct AuthHandler {
-    conf: AuthConf,
+    conf: (AuthConf, ApiKeys),
+    rules: Vec<(AuthConf, ApiKeys)>,
+    router: Router<Option<usize>>,
+    nonce_counts: NonceCounts,
+}
+
+fn generate_token_secret() -> Result<Vec<u8>, Box<Error>> {
+    const TOKEN_LENGTH: usize = 16;
+    let mut token = vec![0; TOKEN_LENGTH];
+    if let Err(err) = getrandom::getrandom(&mut token) {
+        return Err(Error::because(
+            ErrorType::InternalError,
+            "failed generating new random auth token",
+            err,
+        ));
+    }
+
+    info!("No auth token in configuration, generated a random one. Server restart will invalidate existing sessions.");
+    Ok(token)
 }
 
 impl TryFrom<AuthConf> for AuthHandler {
     type Error = Box<Error>;
 
     fn try_from(mut conf: AuthConf) -> Result<Self, Self::Error> {
-        if conf.auth_mode == AuthMode::Page && conf.auth_page_session.token_secret.is_none() {
-            const TOKEN_LENGTH: usize = 16;
-            let mut token = vec![0; TOKEN_LENGTH];
-            if let Err(err) = getrandom::getrandom(&mut token) {
-                return Err(Error::because(
-                    ErrorType::InternalError,
-                    "failed generating new random auth token",
-                    err,
-                ));
+        let rules: Vec<_> = std::mem::take(&mut conf.auth_rules).into();
+
+        for bind_ip in std::iter::once(&conf.auth_bind_ip)
+            .chain(rules.iter().filter_map(|rule| rule.auth_bind_ip.as_ref()))
+        {
+            if bind_ip.ipv4_prefix > 32 || bind_ip.ipv6_prefix > 128 {
+                error!(
+                    "invalid auth_bind_ip prefix length, expected up to 32 for IPv4 and up to 128 for IPv6: {bind_ip:?}"
+                );
+                return Err(Error::new(ErrorType::InternalError));
+            }
+        }
+
+        for entries in std::iter::once(&conf.auth_credentials_digest).chain(
+            rules
+                .iter()
+                .filter_map(|rule| rule.auth_credentials_digest.as_ref()),
+        ) {
+            digest::validate_credentials(entries)?;
+        }
+
+        // Rules without their own session settings share the token secret with the general
+        // configuration, so it has to be generated if any of these use page mode. Digest mode
+        // uses the token secret to sign nonces.
+        let needs_token_secret = conf.auth_mode.needs_token_secret()
+            || rules.iter().any(|rule| {
+                rule.auth_page_session.is_none()
+                    && rule
+                        .auth_mode
+                        .as_ref()
+                        .is_some_and(AuthMode::needs_token_secret)
+            });
+        if needs_token_secret && conf.auth_page_session.token_secret.is_none() {
+            conf.auth_page_session.token_secret = Some(generate_token_secret()?);
+        }
+
+        let mut paths = Vec::with_capacity(rules.len());
+        let mut rule_confs = Vec::with_capacity(rules.len());
+        for rule in rules {
+            let mut rule_conf = conf.clone();
+            if let Some(auth_mode) = rule.auth_mode {
+                rule_conf.auth_mode = auth_mode;
+            }
+            if let Some(auth_credentials) = rule.auth_credentials {
+                // An empty list disables authentication in any mode, unless the rule configures
+                // digest credentials or API keys explicitly.
+                if auth_credentials.is_empty() {
+                    rule_conf.auth_credentials_digest = Default::default();
+                    rule_conf.auth_api_keys = Default::default();
+                }
+                rule_conf.auth_credentials = auth_credentials;
+            }
+            if let Some(auth_user_roles) = rule.auth_user_roles {
+                rule_conf.auth_user_roles = auth_user_roles;
+            }
+            if let Some(auth_credentials_digest) = rule.auth_credentials_digest {
+                rule_conf.auth_credentials_digest = auth_credentials_digest;
+            }
+            if let Some(auth_api_key_header) = rule.auth_api_key_header {
+                rule_conf.auth_api_key_header = auth_api_key_header;
+            }
+            if let Some(auth_api_keys) = rule.auth_api_keys {
+                rule_conf.auth_api_keys = auth_api_keys;
+            }
+            if let Some(auth_realm) = rule.auth_realm {
+                rule_conf.auth_realm = auth_realm;
+            }
+            if let Some(auth_page_strings) = rule.auth_page_strings {
+                rule_conf.auth_page_strings = auth_page_strings;
+            }
+            if let Some(auth_bind_ip) = rule.auth_bind_ip {
+                rule_conf.auth_bind_ip = auth_bind_ip;
+            }
+            if let Some(auth_logout_path) = rule.auth_logout_path {
+                rule_conf.auth_logout_path = auth_logout_path;
+            }
+            if let Some(auth_logout_redirect) = rule.auth_logout_redirect {
+                rule_conf.auth_logout_redirect = Some(auth_logout_redirect);
+            }
+            if let Some(auth_page_session) = rule.auth_page_session {
+                rule_conf.auth_page_session = auth_page_session;
+                if rule_conf.auth_mode.needs_token_secret()
+                    && rule_conf.auth_page_session.token_secret.is_none()
+                {
+                    rule_conf.auth_page_session.token_secret = Some(generate_token_secret()?);
+                }
             }
 
-            info!("No auth token in configuration, generated a random one. Server restart will invalidate existing sessions.");
-            conf.auth_page_session.token_secret = Some(token);
+            paths.push(rule.path);
+            let api_keys = ApiKeys::new(&rule_conf)?;
+            rule_confs.push((rule_conf, api_keys));
         }
 
-        Ok(Self { conf })
+        // Add in reverse order, so that the first rule listed in configuration takes precedence
+        // for identical paths. Closest matches come last after merging.
+        let mut merger = Merger::new();
+        for (index, path) in paths.into_iter().enumerate().rev() {
+            merger.push(path, index);
+        }
+        let router = merger.merge(|indices| indices.last().copied());
+
+        let api_keys = ApiKeys::new(&conf)?;
+        Ok(Self {
+            conf: (conf, api_keys),
+            rules: rule_confs,
+            router,
+            nonce_counts: Default::default(),
+        })
+    }
+}
+
+/// Checks whether credentials are configured for the authentication mode, authentication is
+/// disabled otherwise.
+fn has_credentials(conf: &AuthConf) -> bool {
+    match conf.auth_mode {
+        AuthMode::Digest => !conf.auth_credentials_digest.is_empty(),
+        AuthMode::ApiKey => !conf.auth_api_keys.is_empty(),
+        _ => !conf.auth_credentials.is_empty(),
     }
 }
 
@@ -350,18 +719,369 @@ impl RequestFilter for AuthHandler {
 
     fn new_ctx() -> Self::CTX {}
 
+    fn init_downstream_modules(modules: &mut HttpModules) {
+        // Rules can configure page mode for a part of the webspace only
+        add_module_once(modules, Box::new(SessionCookieHttpModuleBuilder {}));
+    }
+
+    fn handles_head() -> bool {
+        // Digest authentication needs the original request method
+        true
+    }
+
+    fn path_case_usage(&self) -> PathCaseUsage {
+        PathCaseUsage {
+            case_insensitive: false,
+            case_sensitive_rules: !self.rules.is_empty(),
+            restricts_access: has_credentials(&self.conf.0)
+                || self.rules.iter().any(|(conf, _)| has_credentials(conf)),
+        }
+    }
+
     async fn request_filter(
         &self,
         session: &mut impl SessionWrapper,
         _ctx: &mut Self::CTX,
     ) -> Result<RequestFilterResult, Box<Error>> {
-        if self.conf.auth_credentials.is_empty() {
+        let (conf, api_keys) = match self
+            .router
+            .lookup("", &normalize_path(session.uri().path()))
+            .and_then(|index| *index)
+        {
+            Some(index) => {
+                trace!("Applying auth rule {index}");
+                &self.rules[index]
+            }
+            None => &self.conf,
+        };
+
+        if !has_credentials(conf) {
             return Ok(RequestFilterResult::Unhandled);
         }
 
-        match self.conf.auth_mode {
-            AuthMode::HTTP => basic_auth(&self.conf, session).await,
-            AuthMode::Page => page_auth(&self.conf, session).await,
+        match conf.auth_mode {
+            AuthMode::HTTP => basic_auth(conf, session).await,
+            AuthMode::Page => page_auth(conf, session).await,
+            AuthMode::Digest => digest_auth(conf, &self.nonce_counts, session).await,
+            AuthMode::ApiKey => api_key_auth(conf, api_keys, session).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine as _};
+    use http::{header, StatusCode};
+    use pandora_module_utils::pingora::{
+        create_test_session, create_test_session_with_body, RequestHeader, Session,
+    };
+    use pandora_module_utils::FromYaml;
+    use startup_module::{AppResult, DefaultApp};
+    use test_log::test;
+
+    fn make_app(conf: &str) -> DefaultApp<AuthHandler> {
+        DefaultApp::new(
+            <AuthHandler as RequestFilter>::Conf::from_yaml(conf)
+                .unwrap()
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    async fn make_session(path: &str, credentials: Option<&str>) -> Session {
+        let mut header = RequestHeader::build("GET", path.as_bytes(), None).unwrap();
+        if let Some(credentials) = credentials {
+            header
+                .insert_header(
+                    header::AUTHORIZATION,
+                    format!("Basic {}", BASE64_STANDARD.encode(credentials)),
+                )
+                .unwrap();
+        }
+        create_test_session(header).await
+    }
+
+    fn status(result: &mut AppResult) -> Option<u16> {
+        if let Some(ErrorType::HTTPStatus(status)) = result.err().as_ref().map(|err| &err.etype) {
+            return Some(*status);
+        }
+        result
+            .session()
+            .response_written()
+            .map(|response| response.status.as_u16())
+    }
+
+    #[test(tokio::test)]
+    async fn rules() {
+        let mut app = make_app(
+            r#"
+auth_rate_limits:
+    total: 0
+    per_ip: 0
+    per_user: 0
+auth_rules:
+- path: /api/*
+  auth_mode: http
+  auth_realm: API
+  auth_credentials:
+    # test
+    me: $2y$04$V15kxj8/a7JsIb6lXkcK7ex.IiNSM3.nbLJaLbkAi10iVXUip/JoC
+- path: /api/public/*
+  auth_credentials: {}
+- path: /admin/*
+  auth_mode: page
+  auth_credentials:
+    # test2
+    another: $2y$04$s/KAIlzQM8VfPsf9.YKAGOfZhMp44lcXHLB9avFGnON3D1QKG9clS
+  auth_page_strings:
+    title: Admin login
+  auth_page_session:
+    cookie_name: admin_token
+            "#,
+        );
+
+        // No rule applies, no credentials configured
+        let mut result = app.handle_request(make_session("/", None).await).await;
+        assert_eq!(status(&mut result), Some(404));
+
+        // Basic authentication for the API
+        let mut result = app
+            .handle_request(make_session("/api/data", None).await)
+            .await;
+        assert_eq!(status(&mut result), Some(401));
+        assert_eq!(
+            result
+                .session()
+                .response_written()
+                .unwrap()
+                .headers
+                .get(header::WWW_AUTHENTICATE)
+                .unwrap(),
+            "Basic realm=\"API\""
+        );
+
+        let mut result = app
+            .handle_request(make_session("/api/data", Some("another:test2")).await)
+            .await;
+        assert_eq!(status(&mut result), Some(401));
+
+        let mut result = app
+            .handle_request(make_session("/api/data", Some("me:test")).await)
+            .await;
+        assert_eq!(status(&mut result), Some(404));
+        assert_eq!(result.session().remote_user(), Some("me"));
+
+        // Closer rule disables authentication
+        let mut result = app
+            .handle_request(make_session("/api/public/data", None).await)
+            .await;
+        assert_eq!(status(&mut result), Some(404));
+        assert_eq!(result.session().remote_user(), None);
+
+        // Login page for the admin area
+        let mut result = app
+            .handle_request(make_session("/admin/", Some("me:test")).await)
+            .await;
+        assert_eq!(status(&mut result), Some(200));
+        assert!(result
+            .session()
+            .response_written()
+            .unwrap()
+            .headers
+            .get(header::WWW_AUTHENTICATE)
+            .is_none());
+        assert!(result.body_str().contains("Admin login"));
+        assert_eq!(result.session().remote_user(), None);
+
+        let mut header = RequestHeader::build("POST", b"/admin/", None).unwrap();
+        header
+            .insert_header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .unwrap();
+        let session = create_test_session_with_body(header, "username=me&password=test").await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(status(&mut result), Some(200));
+        assert!(result.body_str().contains("Admin login"));
+
+        let mut header = RequestHeader::build("POST", b"/admin/", None).unwrap();
+        header
+            .insert_header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .unwrap();
+        let session =
+            create_test_session_with_body(header, "username=another&password=test2").await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(status(&mut result), Some(StatusCode::FOUND.as_u16()));
+        assert!(result
+            .session()
+            .response_written()
+            .unwrap()
+            .headers
+            .get(header::SET_COOKIE)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("admin_token="));
+    }
+
+    #[test(tokio::test)]
+    async fn rules_normalized_path() {
+        let mut app = make_app(
+            r#"
+auth_rate_limits:
+    total: 0
+    per_ip: 0
+    per_user: 0
+auth_rules:
+- path: /admin/*
+  auth_mode: http
+  auth_credentials:
+    # test
+    me: $2y$04$V15kxj8/a7JsIb6lXkcK7ex.IiNSM3.nbLJaLbkAi10iVXUip/JoC
+            "#,
+        );
+
+        for path in [
+            "/admin",
+            "/admin/file.txt",
+            "/%61dmin/file.txt",
+            "/admin%2ffile.txt",
+            "/public/../admin/file.txt",
+            "/./admin/file.txt",
+        ] {
+            let mut result = app.handle_request(make_session(path, None).await).await;
+            assert_eq!(status(&mut result), Some(401), "{path}");
         }
+
+        let mut result = app
+            .handle_request(make_session("/%61dmin/file.txt", Some("me:test")).await)
+            .await;
+        assert_eq!(status(&mut result), Some(404));
+
+        let mut result = app
+            .handle_request(make_session("/admin/../file.txt", None).await)
+            .await;
+        assert_eq!(status(&mut result), Some(404));
+    }
+
+    #[test(tokio::test)]
+    async fn rules_page_tokens() {
+        let mut app = make_app(
+            r#"
+auth_rate_limits:
+    total: 0
+    per_ip: 0
+    per_user: 0
+auth_mode: page
+auth_rules:
+- path: /first/*
+  auth_credentials:
+    # test
+    me: $2y$04$V15kxj8/a7JsIb6lXkcK7ex.IiNSM3.nbLJaLbkAi10iVXUip/JoC
+- path: /second/*
+  auth_credentials:
+    # test2
+    another: $2y$04$s/KAIlzQM8VfPsf9.YKAGOfZhMp44lcXHLB9avFGnON3D1QKG9clS
+            "#,
+        );
+
+        let mut header = RequestHeader::build("POST", b"/first/", None).unwrap();
+        header
+            .insert_header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .unwrap();
+        let session = create_test_session_with_body(header, "username=me&password=test").await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(status(&mut result), Some(StatusCode::FOUND.as_u16()));
+        let cookie = result
+            .session()
+            .response_written()
+            .unwrap()
+            .headers
+            .get(header::SET_COOKIE)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_owned();
+
+        let mut session = make_session("/first/file.txt", None).await;
+        session
+            .req_header_mut()
+            .insert_header(header::COOKIE, &cookie)
+            .unwrap();
+        let mut result = app.handle_request(session).await;
+        assert_eq!(status(&mut result), Some(404));
+        assert_eq!(result.session().remote_user(), Some("me"));
+
+        // The token is valid but the user isn't known to the second rule
+        let mut session = make_session("/second/file.txt", None).await;
+        session
+            .req_header_mut()
+            .insert_header(header::COOKIE, &cookie)
+            .unwrap();
+        let mut result = app.handle_request(session).await;
+        assert_eq!(status(&mut result), Some(200));
+        assert_eq!(result.session().remote_user(), None);
+    }
+
+    #[test]
+    fn merge_with_opt() {
+        let mut conf = AuthConf {
+            auth_credentials: HashMap::from([("me".to_owned(), "hash1".to_owned())]),
+            auth_mode: AuthMode::HTTP,
+            auth_realm: "realm".to_owned(),
+            ..Default::default()
+        };
+        let orig_conf = conf.clone();
+
+        conf.merge_with_opt(AuthOpt::parse_from(["test"]));
+        assert_eq!(conf, orig_conf);
+
+        conf.merge_with_opt(AuthOpt::parse_from([
+            "test",
+            "--auth-display-hash",
+            "--auth-hash-algorithm",
+            "argon2",
+            "--auth-credentials",
+            "another:hash2",
+            "--auth-credentials-digest",
+            "me:realm:0123",
+            "--auth-mode",
+            "page",
+            "--auth-realm",
+            "other realm",
+        ]));
+        assert!(conf.auth_display_hash);
+        assert_eq!(conf.auth_hash_algorithm, HashAlgorithm::Argon2);
+        assert_eq!(conf.auth_mode, AuthMode::Page);
+        assert_eq!(conf.auth_realm, "other realm");
+        assert_eq!(
+            *conf.auth_credentials_digest,
+            vec!["me:realm:0123".to_owned()]
+        );
+
+        // Credentials from the command line are added to the configured ones
+        assert_eq!(
+            conf.auth_credentials,
+            HashMap::from([
+                ("me".to_owned(), "hash1".to_owned()),
+                ("another".to_owned(), "hash2".to_owned()),
+            ])
+        );
+
+        conf.merge_with_opt(AuthOpt::parse_from([
+            "test",
+            "--auth-credentials",
+            "me:hash3",
+        ]));
+        assert_eq!(
+            conf.auth_credentials,
+            HashMap::from([
+                ("me".to_owned(), "hash3".to_owned()),
+                ("another".to_owned(), "hash2".to_owned()),
+            ])
+        );
     }
 }
@@ -0,0 +1,177 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storage of login rate limiting state
+
+use once_cell::sync::Lazy;
+use pingora_limits::rate::Rate;
+use std::fmt::Debug;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The entity a login attempt is counted for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RateLimitKey<'a> {
+    /// All login attempts
+    Total,
+    /// Login attempts for a user name
+    User(&'a str),
+    /// Login attempts from an IP address
+    Ip(IpAddr),
+}
+
+/// Storage keeping track of login attempts
+///
+/// The default [`InMemoryRateLimitStore`] keeps this state in the memory of the server process.
+/// If multiple server processes are running, e.g. behind a load balancer, each of them will
+/// enforce the limits separately. An implementation backed by shared storage like Redis can
+/// enforce the limits across all processes.
+pub trait RateLimitStore: Debug + Send + Sync {
+    /// Records a number of login attempts for the key. Returns the number of attempts within the
+    /// current one second interval, including the ones just recorded.
+    fn observe(&self, key: &RateLimitKey<'_>, events: isize) -> isize;
+}
+
+/// Rate limit storage in the memory of the current process
+pub struct InMemoryRateLimitStore {
+    rate: Rate,
+}
+
+impl Debug for InMemoryRateLimitStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InMemoryRateLimitStore")
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for InMemoryRateLimitStore {
+    fn default() -> Self {
+        Self {
+            rate: Rate::new(Duration::new(1, 0)),
+        }
+    }
+}
+
+impl RateLimitStore for InMemoryRateLimitStore {
+    fn observe(&self, key: &RateLimitKey<'_>, events: isize) -> isize {
+        self.rate.observe(key, events)
+    }
+}
+
+/// Reference to the rate limit storage used by a handler
+///
+/// By default, all handlers share the same [`InMemoryRateLimitStore`] instance.
+#[derive(Debug, Clone)]
+pub struct SharedRateLimitStore(Arc<dyn RateLimitStore>);
+
+impl SharedRateLimitStore {
+    /// Wraps a rate limit storage implementation
+    pub fn new(store: impl RateLimitStore + 'static) -> Self {
+        Self(Arc::new(store))
+    }
+
+    pub(crate) fn observe(&self, key: &RateLimitKey<'_>, events: isize) -> isize {
+        self.0.observe(key, events)
+    }
+}
+
+impl Default for SharedRateLimitStore {
+    fn default() -> Self {
+        static DEFAULT_STORE: Lazy<SharedRateLimitStore> =
+            Lazy::new(|| SharedRateLimitStore::new(InMemoryRateLimitStore::default()));
+        DEFAULT_STORE.clone()
+    }
+}
+
+impl PartialEq for SharedRateLimitStore {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SharedRateLimitStore {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine as _};
+    use http::{header, StatusCode};
+    use pandora_module_utils::pingora::{create_test_session, RequestHeader};
+    use pandora_module_utils::FromYaml;
+    use startup_module::DefaultApp;
+    use std::sync::Mutex;
+    use test_log::test;
+
+    use crate::{AuthConf, AuthHandler};
+
+    /// Records all keys and reports the limit as exceeded for the user `blocked`
+    #[derive(Debug, Default)]
+    struct CustomStore {
+        keys: Mutex<Vec<String>>,
+    }
+
+    impl RateLimitStore for Arc<CustomStore> {
+        fn observe(&self, key: &RateLimitKey<'_>, events: isize) -> isize {
+            self.keys.lock().unwrap().push(format!("{key:?}"));
+            if *key == RateLimitKey::User("blocked") {
+                100
+            } else {
+                events
+            }
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn custom_store() {
+        let store = Arc::new(CustomStore::default());
+        let mut conf = AuthConf::from_yaml(
+            r#"
+                auth_mode: http
+                auth_credentials:
+                    me: $2y$04$V15kxj8/a7JsIb6lXkcK7ex.IiNSM3.nbLJaLbkAi10iVXUip/JoC
+                auth_rate_limits:
+                    total: 10
+                    per_ip: 0
+                    per_user: 1
+            "#,
+        )
+        .unwrap();
+        conf.auth_rate_limits.store = SharedRateLimitStore::new(store.clone());
+        let mut app = DefaultApp::<AuthHandler>::from_conf(conf).unwrap();
+
+        for (user, expected) in [
+            ("me", StatusCode::UNAUTHORIZED),
+            ("blocked", StatusCode::TOO_MANY_REQUESTS),
+        ] {
+            let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+            header
+                .insert_header(
+                    header::AUTHORIZATION,
+                    format!("Basic {}", BASE64_STANDARD.encode(format!("{user}:wrong"))),
+                )
+                .unwrap();
+            let session = create_test_session(header).await;
+            let mut result = app.handle_request(session).await;
+            let status = result.session().response_written().map(|r| r.status);
+            assert_eq!(status, Some(expected), "{user}");
+        }
+
+        assert_eq!(
+            *store.keys.lock().unwrap(),
+            ["Total", "User(\"me\")", "Total", "User(\"blocked\")"]
+        );
+    }
+}
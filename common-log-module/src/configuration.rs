@@ -17,8 +17,7 @@
 use clap::Parser;
 use http::HeaderName;
 use pandora_module_utils::{DeserializeMap, OneOrMany};
-use serde::Deserialize;
-use std::ffi::OsString;
+use serde::{de::Unexpected, Deserialize, Deserializer};
 use std::path::PathBuf;
 
 /// Command line options of the common log module
@@ -27,7 +26,7 @@ pub struct CommonLogOpt {
     /// Access log file path
     ///
     /// Special values are an empty string (disable logging) and - (write to standard output).
-    #[clap(long, value_parser = clap::value_parser!(OsString))]
+    #[clap(long)]
     pub log_file: Option<PathBuf>,
 }
 
@@ -41,7 +40,7 @@ pub enum LogField {
     RemoteAddr,
     /// Client port, `remote_port` in config file
     RemotePort,
-    /// Name of the authorized user
+    /// Name of the authorized user, `remote_name` or `remote_user` in config file
     RemoteName,
     /// Local time in the Common Log Format, `time_local` in config file
     TimeLocal,
@@ -53,6 +52,8 @@ pub enum LogField {
     Status,
     /// Number of bytes sent as response, `bytes_sent` in config file
     BytesSent,
+    /// Number of request body bytes received, `body_bytes_received` in config file
+    BodyBytesReceived,
     /// Time it took to process the request, `processing_time` in config file
     ProcessingTime,
     /// A request header, `http_<header>` in config file
@@ -69,12 +70,13 @@ impl TryFrom<&str> for LogField {
             "-" => Ok(Self::None),
             "remote_addr" => Ok(Self::RemoteAddr),
             "remote_port" => Ok(Self::RemotePort),
-            "remote_name" => Ok(Self::RemoteName),
+            "remote_name" | "remote_user" => Ok(Self::RemoteName),
             "time_local" => Ok(Self::TimeLocal),
             "time_iso8601" => Ok(Self::TimeISO),
             "request" => Ok(Self::Request),
             "status" => Ok(Self::Status),
             "bytes_sent" => Ok(Self::BytesSent),
+            "body_bytes_received" => Ok(Self::BodyBytesReceived),
             "processing_time" => Ok(Self::ProcessingTime),
             name => {
                 if let Some(header) = name.strip_prefix("http_") {
@@ -95,6 +97,30 @@ impl TryFrom<&str> for LogField {
     }
 }
 
+impl LogField {
+    /// Returns the key identifying this field in JSON logs, the same as its name in the config
+    /// file. `None` for skipped fields.
+    pub fn key(&self) -> Option<String> {
+        let header_key =
+            |prefix, name: &HeaderName| format!("{prefix}{}", name.as_str().replace('-', "_"));
+        match self {
+            Self::None => None,
+            Self::RemoteAddr => Some("remote_addr".to_owned()),
+            Self::RemotePort => Some("remote_port".to_owned()),
+            Self::RemoteName => Some("remote_name".to_owned()),
+            Self::TimeLocal => Some("time_local".to_owned()),
+            Self::TimeISO => Some("time_iso8601".to_owned()),
+            Self::Request => Some("request".to_owned()),
+            Self::Status => Some("status".to_owned()),
+            Self::BytesSent => Some("bytes_sent".to_owned()),
+            Self::BodyBytesReceived => Some("body_bytes_received".to_owned()),
+            Self::ProcessingTime => Some("processing_time".to_owned()),
+            Self::RequestHeader(name) => Some(header_key("http_", name)),
+            Self::ResponseHeader(name) => Some(header_key("sent_http_", name)),
+        }
+    }
+}
+
 impl TryFrom<String> for LogField {
     type Error = String;
 
@@ -103,6 +129,75 @@ impl TryFrom<String> for LogField {
     }
 }
 
+/// Format of the log lines
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogStyle {
+    /// Common Log Format, fields separated by spaces
+    #[default]
+    Clf,
+    /// One JSON object per line, with field names as keys
+    Json,
+}
+
+/// Fraction of requests to be logged, a number between `0` and `1`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SampleRate {
+    per_million: u32,
+}
+
+impl SampleRate {
+    const ALL: u32 = 1_000_000;
+
+    /// Returns the number of requests logged out of a million
+    pub fn per_million(&self) -> u32 {
+        self.per_million
+    }
+
+    /// Checks whether all requests are logged
+    pub fn is_all(&self) -> bool {
+        self.per_million >= Self::ALL
+    }
+}
+
+impl Default for SampleRate {
+    fn default() -> Self {
+        Self {
+            per_million: Self::ALL,
+        }
+    }
+}
+
+impl TryFrom<f64> for SampleRate {
+    type Error = String;
+
+    fn try_from(value: f64) -> Result<Self, Self::Error> {
+        if (0.0..=1.0).contains(&value) {
+            Ok(Self {
+                per_million: (value * f64::from(Self::ALL)).round() as u32,
+            })
+        } else {
+            Err(format!(
+                "sample rate {value} is outside of the range 0 to 1"
+            ))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SampleRate {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let value = f64::deserialize(deserializer)?;
+        Self::try_from(value).map_err(|_| {
+            D::Error::invalid_value(Unexpected::Float(value), &"number between 0 and 1")
+        })
+    }
+}
+
 /// Configuration settings of the common log module
 #[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct CommonLogConf {
@@ -118,6 +213,13 @@ pub struct CommonLogConf {
     /// [remote_addr, -, -, time_local, request, status, bytes_sent, http_referer, http_user_agent]
     /// ```
     pub log_format: OneOrMany<LogField>,
+    /// Format of the log lines, either `clf` (default) or `json`
+    pub log_style: LogStyle,
+    /// Fraction of requests to be logged, e.g. `0.1` to log every tenth request
+    pub log_sample_rate: SampleRate,
+    /// If `true`, failed requests and error responses (status code 400 or above) are always
+    /// logged regardless of `log_sample_rate`
+    pub log_all_errors: bool,
 }
 
 impl Default for CommonLogConf {
@@ -125,6 +227,9 @@ impl Default for CommonLogConf {
         Self {
             log_file: PathBuf::from("-"),
             log_format: Default::default(),
+            log_style: Default::default(),
+            log_sample_rate: Default::default(),
+            log_all_errors: true,
         }
     }
 }
@@ -147,7 +252,7 @@ mod tests {
 
     #[test]
     fn log_field_parsing() {
-        let log_fields: Vec<_> = "remote_addr - remote_name time_local request status bytes_sent http_referer http_user_agent processing_time sent_http_content_type remote_port time_iso8601".split_ascii_whitespace().map(|s| {
+        let log_fields: Vec<_> = "remote_addr - remote_name time_local request status bytes_sent http_referer http_user_agent processing_time sent_http_content_type remote_port time_iso8601 body_bytes_received".split_ascii_whitespace().map(|s| {
             LogField::try_from(s).unwrap()
         }).collect();
         assert_eq!(
@@ -166,8 +271,64 @@ mod tests {
                 LogField::ResponseHeader(header::CONTENT_TYPE),
                 LogField::RemotePort,
                 LogField::TimeISO,
+                LogField::BodyBytesReceived,
             ]
         );
+        assert_eq!(
+            LogField::try_from("remote_user").unwrap(),
+            LogField::RemoteName
+        );
         assert!(LogField::try_from("unsupported_field").is_err());
     }
+
+    #[test]
+    fn log_field_keys() {
+        for name in [
+            "remote_addr",
+            "remote_name",
+            "time_iso8601",
+            "body_bytes_received",
+            "http_user_agent",
+            "sent_http_content_type",
+        ] {
+            assert_eq!(
+                LogField::try_from(name).unwrap().key().as_deref(),
+                Some(name)
+            );
+        }
+        assert_eq!(
+            LogField::try_from("remote_user").unwrap().key().as_deref(),
+            Some("remote_name")
+        );
+        assert_eq!(LogField::None.key(), None);
+    }
+
+    #[test]
+    fn sample_rate_parsing() {
+        assert_eq!(SampleRate::try_from(0.1).unwrap().per_million(), 100_000);
+        assert_eq!(SampleRate::try_from(0.0).unwrap().per_million(), 0);
+        assert!(SampleRate::try_from(1.0).unwrap().is_all());
+        assert!(SampleRate::default().is_all());
+        assert!(SampleRate::try_from(1.5).is_err());
+        assert!(SampleRate::try_from(-0.1).is_err());
+    }
+
+    #[test]
+    fn merge_with_opt() {
+        let mut conf = CommonLogConf {
+            log_file: PathBuf::from("access.log"),
+            log_format: vec![LogField::Status].into(),
+            ..Default::default()
+        };
+        conf.merge_with_opt(CommonLogOpt::parse_from(["test"]));
+        assert_eq!(conf.log_file, PathBuf::from("access.log"));
+
+        conf.merge_with_opt(CommonLogOpt::parse_from([
+            "test",
+            "--log-file",
+            "other.log",
+        ]));
+        assert_eq!(conf.log_file, PathBuf::from("other.log"));
+        assert_eq!(conf.log_format, vec![LogField::Status].into());
+    }
 }
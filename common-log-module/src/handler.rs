@@ -15,17 +15,22 @@
 //! Common Log module handler
 
 use async_trait::async_trait;
+use bytes::Bytes;
 use http::header;
 use log::error;
 use once_cell::sync::Lazy;
-use pandora_module_utils::pingora::{Error, ErrorType, SessionWrapper};
+use pandora_module_utils::pingora::{
+    Error, ErrorType, HttpModule, HttpModuleBuilder, HttpModules, SessionWrapper,
+};
 use pandora_module_utils::{RequestFilter, RequestFilterResult};
+use std::any::Any;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::SystemTime;
 use tokio::sync::mpsc::{channel, Sender};
 
-use crate::configuration::{CommonLogConf, LogField};
+use crate::configuration::{CommonLogConf, LogField, SampleRate};
 use crate::writer::{log_writer, LogToken, WriterMessage};
 
 fn normalize_path(path: PathBuf) -> Result<PathBuf, Box<Error>> {
@@ -57,10 +62,137 @@ fn normalize_path(path: PathBuf) -> Result<PathBuf, Box<Error>> {
     }
 }
 
+struct BytesSentHttpModuleBuilder {}
+
+impl HttpModuleBuilder for BytesSentHttpModuleBuilder {
+    fn order(&self) -> i16 {
+        // Run after all other modules, compression in particular, to see the actual response
+        // body. Pingora negates this value for sorting, so `i16::MIN` itself would overflow.
+        i16::MIN + 1
+    }
+
+    fn init(&self) -> Box<dyn HttpModule + Sync + Send> {
+        Box::new(BytesSentHttpModule { bytes_sent: 0 })
+    }
+}
+
+/// Counts response body bytes as they are passed on to the client
+struct BytesSentHttpModule {
+    bytes_sent: usize,
+}
+
+#[async_trait]
+impl HttpModule for BytesSentHttpModule {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn response_body_filter(
+        &mut self,
+        body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+    ) -> Result<(), Box<Error>> {
+        self.bytes_sent += body.as_ref().map_or(0, Bytes::len);
+        Ok(())
+    }
+}
+
+/// Counts requests subject to sampling, shared by all clones of a handler
+#[derive(Debug, Clone, Default)]
+struct SampleCounter(Arc<AtomicU64>);
+
+impl SampleCounter {
+    /// Decides whether the next request should be logged. The decisions are spread evenly, e.g.
+    /// every tenth request is logged for the sample rate `0.1`.
+    fn sample(&self, rate: SampleRate) -> bool {
+        if rate.is_all() {
+            return true;
+        }
+
+        let rate = u128::from(rate.per_million());
+        let count = u128::from(self.0.fetch_add(1, Ordering::Relaxed));
+        (count + 1) * rate / 1_000_000 > count * rate / 1_000_000
+    }
+}
+
+impl PartialEq for SampleCounter {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SampleCounter {}
+
 /// Common Log module handler
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CommonLogHandler {
     conf: CommonLogConf,
+    keys: Arc<[Option<String>]>,
+    sample_counter: SampleCounter,
+}
+
+impl CommonLogHandler {
+    /// Decides whether a request should be logged according to the sampling settings.
+    fn sampled(&self, is_error: bool) -> bool {
+        (is_error && self.conf.log_all_errors)
+            || self.sample_counter.sample(self.conf.log_sample_rate)
+    }
+
+    /// Produces the log tokens which have to be determined before the request is processed.
+    fn request_tokens(&self, session: &impl SessionWrapper) -> Vec<LogToken> {
+        let mut tokens = Vec::new();
+        for field in &self.conf.log_format {
+            tokens.push(match field {
+                LogField::None => LogToken::None,
+                LogField::RemoteAddr => {
+                    if let Some(client_addr) = session.client_addr() {
+                        LogToken::RemoteAddr(client_addr.clone())
+                    } else {
+                        LogToken::None
+                    }
+                }
+                LogField::RemotePort => {
+                    if let Some(client_addr) = session.client_addr() {
+                        LogToken::RemotePort(client_addr.clone())
+                    } else {
+                        LogToken::None
+                    }
+                }
+                LogField::TimeLocal => LogToken::TimeLocal,
+                LogField::TimeISO => LogToken::TimeISO,
+                LogField::Request => {
+                    let header = session.req_header();
+                    let method = &header.method;
+
+                    let uri = session
+                        .original_uri()
+                        .path_and_query()
+                        .map(|p| p.as_str())
+                        .unwrap_or("");
+                    let version = &header.version;
+                    LogToken::Request(format!("{method} {uri} {version:?}"))
+                }
+                LogField::RequestHeader(name) => {
+                    if let Some(value) = session.req_header().headers.get(name) {
+                        LogToken::Header(value.clone())
+                    } else {
+                        LogToken::None
+                    }
+                }
+                LogField::RemoteName
+                | LogField::Status
+                | LogField::BytesSent
+                | LogField::BodyBytesReceived
+                | LogField::ProcessingTime
+                | LogField::ResponseHeader(_) => continue,
+            });
+        }
+        tokens
+    }
 }
 
 impl TryFrom<CommonLogConf> for CommonLogHandler {
@@ -86,7 +218,13 @@ impl TryFrom<CommonLogConf> for CommonLogHandler {
             .into();
         }
 
-        Ok(Self { conf })
+        let keys = conf.log_format.iter().map(LogField::key).collect();
+
+        Ok(Self {
+            conf,
+            keys,
+            sample_counter: Default::default(),
+        })
     }
 }
 
@@ -94,7 +232,7 @@ impl TryFrom<CommonLogConf> for CommonLogHandler {
 #[derive(Debug)]
 pub struct RequestCtx {
     time: SystemTime,
-    tokens: Vec<LogToken>,
+    tokens: Option<Vec<LogToken>>,
 }
 
 #[async_trait]
@@ -104,10 +242,14 @@ impl RequestFilter for CommonLogHandler {
     fn new_ctx() -> Self::CTX {
         RequestCtx {
             time: SystemTime::now(),
-            tokens: Vec::new(),
+            tokens: None,
         }
     }
 
+    fn init_downstream_modules(modules: &mut HttpModules) {
+        modules.add_module(Box::new(BytesSentHttpModuleBuilder {}));
+    }
+
     async fn request_filter(
         &self,
         session: &mut impl SessionWrapper,
@@ -118,51 +260,7 @@ impl RequestFilter for CommonLogHandler {
             return Ok(RequestFilterResult::Unhandled);
         }
 
-        for field in &self.conf.log_format {
-            ctx.tokens.push(match field {
-                LogField::None => LogToken::None,
-                LogField::RemoteAddr => {
-                    if let Some(client_addr) = session.client_addr() {
-                        LogToken::RemoteAddr(client_addr.clone())
-                    } else {
-                        LogToken::None
-                    }
-                }
-                LogField::RemotePort => {
-                    if let Some(client_addr) = session.client_addr() {
-                        LogToken::RemotePort(client_addr.clone())
-                    } else {
-                        LogToken::None
-                    }
-                }
-                LogField::TimeLocal => LogToken::TimeLocal,
-                LogField::TimeISO => LogToken::TimeISO,
-                LogField::Request => {
-                    let header = session.req_header();
-                    let method = &header.method;
-
-                    let uri = session
-                        .original_uri()
-                        .path_and_query()
-                        .map(|p| p.as_str())
-                        .unwrap_or("");
-                    let version = &header.version;
-                    LogToken::Request(format!("{method} {uri} {version:?}"))
-                }
-                LogField::RequestHeader(name) => {
-                    if let Some(value) = session.req_header().headers.get(name) {
-                        LogToken::Header(value.clone())
-                    } else {
-                        LogToken::None
-                    }
-                }
-                LogField::RemoteName
-                | LogField::Status
-                | LogField::BytesSent
-                | LogField::ProcessingTime
-                | LogField::ResponseHeader(_) => continue,
-            });
-        }
+        ctx.tokens = Some(self.request_tokens(session));
 
         Ok(RequestFilterResult::Unhandled)
     }
@@ -170,7 +268,7 @@ impl RequestFilter for CommonLogHandler {
     async fn logging(
         &self,
         session: &mut impl SessionWrapper,
-        _e: Option<&Error>,
+        e: Option<&Error>,
         ctx: &mut RequestCtx,
     ) {
         if self.conf.log_file.as_os_str().is_empty() {
@@ -178,7 +276,20 @@ impl RequestFilter for CommonLogHandler {
             return;
         }
 
-        let mut existing_tokens = ctx.tokens.split_off(0).into_iter();
+        let is_error = e.is_some()
+            || session
+                .response_written()
+                .is_some_and(|header| header.status.as_u16() >= 400);
+        if !self.sampled(is_error) {
+            return;
+        }
+
+        // Request filter won’t run if an earlier handler in the chain produced a response
+        let mut existing_tokens = ctx
+            .tokens
+            .take()
+            .unwrap_or_else(|| self.request_tokens(session))
+            .into_iter();
         let mut tokens = Vec::new();
 
         for field in &self.conf.log_format {
@@ -208,7 +319,13 @@ impl RequestFilter for CommonLogHandler {
                         LogToken::None
                     }
                 }
-                LogField::BytesSent => LogToken::BytesSent(session.body_bytes_sent()),
+                LogField::BytesSent => LogToken::BytesSent(
+                    session
+                        .downstream_modules_ctx
+                        .get::<BytesSentHttpModule>()
+                        .map_or_else(|| session.body_bytes_sent(), |module| module.bytes_sent),
+                ),
+                LogField::BodyBytesReceived => LogToken::BytesReceived(session.body_bytes_read()),
                 LogField::ProcessingTime => {
                     if let Ok(time) = SystemTime::now().duration_since(ctx.time) {
                         LogToken::ProcessingTime(time)
@@ -239,7 +356,13 @@ impl RequestFilter for CommonLogHandler {
             Arc::new(sender)
         });
 
-        let message = WriterMessage::log_data(ctx.time, &self.conf.log_file, tokens);
+        let message = WriterMessage::log_data(
+            ctx.time,
+            &self.conf.log_file,
+            self.conf.log_style,
+            self.keys.clone(),
+            tokens,
+        );
         if let Err(err) = Arc::make_mut(&mut (*LOG_SENDER).clone())
             .send(message)
             .await
@@ -253,7 +376,75 @@ impl RequestFilter for CommonLogHandler {
 mod tests {
     use super::*;
 
+    use compression_module::CompressionHandler;
+    use pandora_module_utils::pingora::{http_proxy_service, ServerConf};
+    use pandora_module_utils::{DeserializeMap, FromYaml};
+    use pingora::services::Service;
+    use response_module::ResponseHandler;
+    use startup_module::DefaultApp;
     use std::env::current_dir;
+    use std::net::TcpListener;
+    use std::time::Duration;
+    use test_log::test;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+    struct UploadConf {}
+
+    /// Reads the complete request body of `POST` requests, then leaves them to other handlers.
+    #[derive(Debug)]
+    struct UploadHandler {}
+
+    impl TryFrom<UploadConf> for UploadHandler {
+        type Error = Box<Error>;
+
+        fn try_from(_conf: UploadConf) -> Result<Self, Self::Error> {
+            Ok(Self {})
+        }
+    }
+
+    #[async_trait]
+    impl RequestFilter for UploadHandler {
+        type Conf = UploadConf;
+        type CTX = ();
+        fn new_ctx() -> Self::CTX {}
+
+        async fn request_filter(
+            &self,
+            session: &mut impl SessionWrapper,
+            _ctx: &mut Self::CTX,
+        ) -> Result<RequestFilterResult, Box<Error>> {
+            if session.req_header().method == "POST" {
+                while session.read_request_body().await?.is_some() {}
+            }
+            Ok(RequestFilterResult::Unhandled)
+        }
+    }
+
+    #[derive(Debug, RequestFilter)]
+    struct Handler {
+        compression: CompressionHandler,
+        log: CommonLogHandler,
+        upload: UploadHandler,
+        response: ResponseHandler,
+    }
+
+    /// Decodes a response body using chunked transfer encoding.
+    fn decode_chunked(mut data: &[u8]) -> Vec<u8> {
+        let mut result = Vec::new();
+        loop {
+            let line_end = data.windows(2).position(|w| w == b"\r\n").unwrap();
+            let size = std::str::from_utf8(&data[..line_end]).unwrap();
+            let size = usize::from_str_radix(size.trim(), 16).unwrap();
+            data = &data[line_end + 2..];
+            if size == 0 {
+                return result;
+            }
+            result.extend_from_slice(&data[..size]);
+            data = &data[size + 2..];
+        }
+    }
 
     #[test]
     fn path_normalization() {
@@ -286,4 +477,152 @@ mod tests {
             root.join("file.txt")
         );
     }
+
+    #[test]
+    fn sampling() {
+        fn logged(handler: &CommonLogHandler, is_error: bool, requests: usize) -> usize {
+            (0..requests).filter(|_| handler.sampled(is_error)).count()
+        }
+
+        let make_handler = |conf: &str| -> CommonLogHandler {
+            <CommonLogHandler as RequestFilter>::Conf::from_yaml(conf)
+                .unwrap()
+                .try_into()
+                .unwrap()
+        };
+
+        let handler = make_handler("log_sample_rate: 1");
+        assert_eq!(logged(&handler, false, 1000), 1000);
+
+        let handler = make_handler("log_sample_rate: 0.1");
+        assert_eq!(logged(&handler, false, 1000), 100);
+        assert_eq!(logged(&handler, true, 1000), 1000);
+
+        // Clones share the request counter
+        let clone = handler.clone();
+        assert_eq!(
+            (0..100)
+                .filter(|_| handler.sampled(false) || clone.sampled(false))
+                .count(),
+            20
+        );
+
+        let handler = make_handler("log_sample_rate: 0.25\nlog_all_errors: false");
+        assert_eq!(logged(&handler, false, 1000), 250);
+        assert_eq!(logged(&handler, true, 1000), 250);
+
+        let handler = make_handler("log_sample_rate: 0");
+        assert_eq!(logged(&handler, false, 1000), 0);
+        assert_eq!(logged(&handler, true, 10), 10);
+
+        assert!(
+            <CommonLogHandler as RequestFilter>::Conf::from_yaml("log_sample_rate: 2").is_err()
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn byte_counts() {
+        let log_file = std::env::temp_dir().join(format!(
+            "pandora-common-log-test-{}.log",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&log_file);
+
+        let text = "Hello, world! ".repeat(1000);
+        let conf = <Handler as RequestFilter>::Conf::from_yaml(format!(
+            r#"
+                compression_level_gzip: 6
+                log_file: {}
+                log_format: [status, bytes_sent, body_bytes_received]
+                response: "{text}"
+                response_headers:
+                    Content-Type: text/plain
+            "#,
+            log_file.display()
+        ))
+        .unwrap();
+
+        // Pick a free port
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+
+        let mut service = http_proxy_service(
+            &Arc::new(ServerConf::default()),
+            DefaultApp::<Handler>::new(conf.try_into().unwrap()),
+        );
+        service.add_tcp(&addr);
+
+        let (shutdown_sender, shutdown) = tokio::sync::watch::channel(false);
+        let task = tokio::spawn(async move { service.start_service(None, shutdown).await });
+
+        let connect = || async {
+            loop {
+                if let Ok(stream) = TcpStream::connect(&addr).await {
+                    break stream;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        let send_request = |mut stream: TcpStream, request: &'static [u8]| async move {
+            stream.write_all(request).await.unwrap();
+            let mut response = Vec::new();
+            tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+                .await
+                .expect("no response received")
+                .unwrap();
+            response
+        };
+
+        // Compressed response, no request body
+        let response = send_request(
+            connect().await,
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+
+        let header_end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let header = String::from_utf8_lossy(&response[..header_end]).to_ascii_lowercase();
+        assert!(header.starts_with("http/1.1 200"), "{header}");
+        assert!(header.contains("content-encoding: gzip"), "{header}");
+        assert!(header.contains("transfer-encoding: chunked"), "{header}");
+        let body = decode_chunked(&response[header_end + 4..]);
+        assert!(body.len() < text.len());
+
+        // Uncompressed response, request body
+        let response = send_request(
+            connect().await,
+            b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 11\r\nConnection: close\r\n\r\nhello world",
+        )
+        .await;
+        let header_end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let header = String::from_utf8_lossy(&response[..header_end]).to_ascii_lowercase();
+        assert!(header.starts_with("http/1.1 200"), "{header}");
+        assert_eq!(response.len() - header_end - 4, text.len());
+
+        // Logging happens asynchronously, wait for the log lines to appear
+        let mut log = String::new();
+        for _ in 0..500 {
+            log = std::fs::read_to_string(&log_file).unwrap_or_default();
+            if log.matches('\n').count() >= 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let _ = std::fs::remove_file(&log_file);
+        // Requests are processed concurrently, so the order of log lines isn’t guaranteed
+        let mut lines = log.lines().collect::<Vec<_>>();
+        lines.sort();
+        let mut expected = [
+            format!("200 {} 0", body.len()),
+            format!("200 {} 11", text.len()),
+        ];
+        expected.sort();
+        assert_eq!(lines, expected);
+
+        shutdown_sender.send(true).unwrap();
+        task.await.unwrap();
+    }
 }
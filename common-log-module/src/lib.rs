@@ -20,5 +20,5 @@ mod handler;
 mod signal;
 mod writer;
 
-pub use configuration::{CommonLogConf, CommonLogOpt};
+pub use configuration::{CommonLogConf, CommonLogOpt, LogStyle};
 pub use handler::CommonLogHandler;
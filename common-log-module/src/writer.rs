@@ -22,9 +22,12 @@ use std::collections::HashMap;
 use std::fs::File;
 use std::io::{stdout, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use tokio::sync::mpsc::Receiver;
 
+use crate::configuration::LogStyle;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) enum LogToken {
     None,
@@ -36,6 +39,7 @@ pub(crate) enum LogToken {
     Request(String),
     Status(u16),
     BytesSent(usize),
+    BytesReceived(usize),
     ProcessingTime(Duration),
     Header(HeaderValue),
 }
@@ -44,6 +48,8 @@ pub(crate) enum LogToken {
 pub(crate) struct LogData {
     time: SystemTime,
     log_file: PathBuf,
+    style: LogStyle,
+    keys: Arc<[Option<String>]>,
     tokens: Vec<LogToken>,
 }
 
@@ -54,10 +60,18 @@ pub(crate) enum WriterMessage {
 }
 
 impl WriterMessage {
-    pub(crate) fn log_data(time: SystemTime, log_file: &Path, tokens: Vec<LogToken>) -> Self {
+    pub(crate) fn log_data(
+        time: SystemTime,
+        log_file: &Path,
+        style: LogStyle,
+        keys: Arc<[Option<String>]>,
+        tokens: Vec<LogToken>,
+    ) -> Self {
         Self::LogData(LogData {
             time,
             log_file: log_file.to_owned(),
+            style,
+            keys,
             tokens,
         })
     }
@@ -96,6 +110,82 @@ fn write_escaped(buf: &mut Vec<u8>, data: impl AsRef<[u8]>) -> Result<(), std::i
     Ok(())
 }
 
+fn write_json_string(buf: &mut Vec<u8>, data: impl AsRef<[u8]>) -> Result<(), std::io::Error> {
+    buf.push(b'"');
+    for c in String::from_utf8_lossy(data.as_ref()).chars() {
+        match c {
+            '"' => buf.extend_from_slice(b"\\\""),
+            '\\' => buf.extend_from_slice(b"\\\\"),
+            c if c.is_control() => {
+                let _ = write!(buf, "\\u{:04x}", u32::from(c));
+            }
+            c => {
+                let _ = write!(buf, "{c}");
+            }
+        }
+    }
+    buf.push(b'"');
+
+    Ok(())
+}
+
+fn stringify_json(
+    buf: &mut Vec<u8>,
+    time: SystemTime,
+    keys: &[Option<String>],
+    tokens: Vec<LogToken>,
+) {
+    buf.truncate(0);
+    buf.push(b'{');
+
+    for (key, token) in keys.iter().zip(tokens) {
+        let Some(key) = key else {
+            // Skipped field
+            continue;
+        };
+
+        if buf.len() > 1 {
+            buf.push(b',');
+        }
+        let _ = write_json_string(buf, key);
+        buf.push(b':');
+
+        let _ = match token {
+            LogToken::None => write!(buf, "null"),
+            LogToken::RemoteAddr(SocketAddr::Inet(addr)) => {
+                write_json_string(buf, addr.ip().to_string())
+            }
+            LogToken::RemoteAddr(SocketAddr::Unix(addr)) => {
+                if let Some(path) = addr.as_pathname().and_then(|p| p.as_os_str().to_str()) {
+                    write_json_string(buf, path)
+                } else {
+                    write!(buf, "null")
+                }
+            }
+            LogToken::RemotePort(SocketAddr::Inet(addr)) => write!(buf, "{}", addr.port()),
+            LogToken::RemotePort(SocketAddr::Unix(_)) => write!(buf, "null"),
+            LogToken::RemoteName(remote_name) => write_json_string(buf, remote_name),
+            LogToken::TimeLocal => write_json_string(
+                buf,
+                DateTime::<Local>::from(time)
+                    .format("%d/%b/%Y:%H:%M:%S %z")
+                    .to_string(),
+            ),
+            LogToken::TimeISO => write_json_string(buf, DateTime::<Local>::from(time).to_rfc3339()),
+            LogToken::Request(request) => write_json_string(buf, request),
+            LogToken::Status(status) => write!(buf, "{status}"),
+            LogToken::BytesSent(bytes) | LogToken::BytesReceived(bytes) => write!(buf, "{bytes}"),
+            LogToken::ProcessingTime(time) => {
+                write!(buf, "{:.3}", time.as_secs_f32() * 1000.0)
+            }
+            LogToken::Header(value) => write_json_string(buf, value),
+        };
+    }
+
+    buf.push(b'}');
+    let _ = writeln!(buf);
+}
+
 fn stringify_data(buf: &mut Vec<u8>, time: SystemTime, tokens: Vec<LogToken>) {
     buf.truncate(0);
 
@@ -130,7 +220,7 @@ fn stringify_data(buf: &mut Vec<u8>, time: SystemTime, tokens: Vec<LogToken>) {
             }
             LogToken::Request(request) => write_escaped(buf, request),
             LogToken::Status(status) => write!(buf, "{status}"),
-            LogToken::BytesSent(bytes) => write!(buf, "{bytes}"),
+            LogToken::BytesSent(bytes) | LogToken::BytesReceived(bytes) => write!(buf, "{bytes}"),
             LogToken::ProcessingTime(time) => {
                 write!(buf, "{:.3}", time.as_secs_f32() * 1000.0)
             }
@@ -151,7 +241,10 @@ pub(crate) async fn log_writer(mut receiver: Receiver<WriterMessage>) {
                 files = HashMap::new();
             }
             WriterMessage::LogData(data) => {
-                stringify_data(&mut buf, data.time, data.tokens);
+                match data.style {
+                    LogStyle::Clf => stringify_data(&mut buf, data.time, data.tokens),
+                    LogStyle::Json => stringify_json(&mut buf, data.time, &data.keys, data.tokens),
+                }
                 let writer = files.entry(data.log_file).or_insert_with_key(open_file);
                 let _ = writer.write_all(&buf);
             }
@@ -201,13 +294,56 @@ mod tests {
             LogToken::ProcessingTime(Duration::from_nanos(1234567)),
             LogToken::RemotePort(SocketAddr::Inet("127.0.0.1:8080".parse().unwrap())),
             LogToken::TimeISO,
+            LogToken::BytesReceived(42),
         ];
 
         let mut buf = Vec::new();
         stringify_data(&mut buf, time, tokens);
         assert_eq!(
             String::from_utf8(buf).unwrap(),
-            "127.0.0.1 - \"me\" [29/May/2024:09:53:19 -0100] \"GET /test\\x0a/\\x22 HTTP/1.1\" 200 876 \"https://example.com/\" \"Mozilla/1.0 \\x5c\\x22invalid data\\x80\" 1.235 8080 [2024-05-29T09:53:19-01:00]\n"
+            "127.0.0.1 - \"me\" [29/May/2024:09:53:19 -0100] \"GET /test\\x0a/\\x22 HTTP/1.1\" 200 876 \"https://example.com/\" \"Mozilla/1.0 \\x5c\\x22invalid data\\x80\" 1.235 8080 [2024-05-29T09:53:19-01:00] 42\n"
+        );
+    }
+
+    #[test]
+    fn tokens_to_json() {
+        std::env::set_var("TZ", "UTC+1");
+
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1716979999); // 2024-05-29 10:53:19 UTC
+        let keys = [
+            Some("remote_addr"),
+            None,
+            Some("remote_name"),
+            Some("time_local"),
+            Some("request"),
+            Some("status"),
+            Some("http_user_agent"),
+            Some("sent_http_content_type"),
+            Some("processing_time"),
+        ]
+        .map(|key| key.map(str::to_owned));
+        let tokens = vec![
+            LogToken::RemoteAddr(SocketAddr::Inet("127.0.0.1:8080".parse().unwrap())),
+            LogToken::None,
+            LogToken::RemoteName("me".to_owned()),
+            LogToken::TimeLocal,
+            LogToken::Request("GET /test\n/\" HTTP/1.1".into()),
+            LogToken::Status(200),
+            LogToken::Header(
+                b"Mozilla/1.0 \\\"invalid data\x80"
+                    .as_ref()
+                    .try_into()
+                    .unwrap(),
+            ),
+            LogToken::None,
+            LogToken::ProcessingTime(Duration::from_nanos(1234567)),
+        ];
+
+        let mut buf = Vec::new();
+        stringify_json(&mut buf, time, &keys, tokens);
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "{\"remote_addr\":\"127.0.0.1\",\"remote_name\":\"me\",\"time_local\":\"29/May/2024:09:53:19 -0100\",\"request\":\"GET /test\\u000a/\\\" HTTP/1.1\",\"status\":200,\"http_user_agent\":\"Mozilla/1.0 \\\\\\\"invalid data\u{fffd}\",\"sent_http_content_type\":null,\"processing_time\":1.235}\n"
         );
     }
 }
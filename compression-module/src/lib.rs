@@ -17,11 +17,67 @@
 use async_trait::async_trait;
 use clap::Parser;
 use log::trace;
+use pandora_module_utils::merger::{Merger, PathMatcher};
 use pandora_module_utils::pingora::{
-    CompressionAlgorithm, Error, HttpModules, ResponseCompression, ResponseCompressionBuilder,
-    SessionWrapper,
+    add_module_once, CompressionAlgorithm, Error, HttpModules, ResponseCompression,
+    ResponseCompressionBuilder, SessionWrapper,
 };
-use pandora_module_utils::{DeserializeMap, RequestFilter};
+use pandora_module_utils::router::Router;
+use pandora_module_utils::serde::{de::Error as _, Deserialize, Deserializer};
+use pandora_module_utils::{DeserializeMap, OneOrMany, RequestFilter};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+/// A response status code like `301` or a class of status codes like `2xx`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusMatch(RangeInclusive<u16>);
+
+impl StatusMatch {
+    /// Status codes matched by this entry
+    pub fn range(&self) -> &RangeInclusive<u16> {
+        &self.0
+    }
+}
+
+impl FromStr for StatusMatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{s} is neither a status code nor a status class like 2xx");
+        let range = if let Some(class) = s.strip_suffix("xx").or_else(|| s.strip_suffix("XX")) {
+            let class = class.parse::<u16>().map_err(|_| invalid())?;
+            class * 100..=class * 100 + 99
+        } else {
+            let status = s.parse::<u16>().map_err(|_| invalid())?;
+            status..=status
+        };
+        if *range.start() < 100 || *range.end() > 599 {
+            return Err(invalid());
+        }
+        Ok(Self(range))
+    }
+}
+
+impl<'de> Deserialize<'de> for StatusMatch {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        // Status codes might be specified as numbers in YAML
+        #[derive(Deserialize)]
+        #[serde(crate = "pandora_module_utils::serde", untagged)]
+        enum Value {
+            Number(u16),
+            String(String),
+        }
+
+        let value = match Value::deserialize(deserializer)? {
+            Value::Number(status) => status.to_string(),
+            Value::String(value) => value,
+        };
+        value.parse().map_err(D::Error::custom)
+    }
+}
 
 /// Command line options of the compression module
 #[derive(Debug, Default, Parser)]
@@ -42,6 +98,17 @@ pub struct CompressionOpt {
     /// Decompress upstream responses before passing them on
     #[clap(long)]
     pub decompress_upstream: bool,
+
+    /// Response status code like 200 or status class like 2xx that dynamic compression should
+    /// apply to. This command line flag can be specified multiple times.
+    #[clap(long)]
+    pub compression_statuses: Option<Vec<StatusMatch>>,
+
+    /// Path of a streaming endpoint like Server-Sent Events that should never be compressed
+    /// dynamically, e.g. /events or /stream/*. This command line flag can be specified multiple
+    /// times.
+    #[clap(long)]
+    pub streaming_paths: Option<Vec<PathMatcher>>,
 }
 
 /// Configuration settings of the compression module
@@ -56,8 +123,17 @@ pub struct CompressionConf {
     /// Compression level to be used for dynamic Zstandard compression (omit to disable compression).
     pub compression_level_zstd: Option<u32>,
 
-    /// If `true`, upstream responses will be decompressed
-    pub decompress_upstream: bool,
+    /// If `true`, upstream responses will be decompressed (omit to keep the current setting).
+    pub decompress_upstream: Option<bool>,
+
+    /// Response status codes like `200` or status classes like `2xx` that dynamic compression
+    /// should apply to (omit to keep the current setting, `2xx` by default).
+    pub compression_statuses: Option<OneOrMany<StatusMatch>>,
+
+    /// Paths of streaming endpoints like Server-Sent Events or long polling, e.g. `/events` or
+    /// `/stream/*`. Responses for these paths are never compressed dynamically, so that each
+    /// chunk is passed on immediately rather than held in the compressor’s buffer.
+    pub streaming_paths: OneOrMany<PathMatcher>,
 }
 
 impl CompressionConf {
@@ -77,7 +153,15 @@ impl CompressionConf {
         }
 
         if opt.decompress_upstream {
-            self.decompress_upstream = opt.decompress_upstream;
+            self.decompress_upstream = Some(true);
+        }
+
+        if let Some(compression_statuses) = opt.compression_statuses {
+            self.compression_statuses = Some(compression_statuses.into());
+        }
+
+        if let Some(streaming_paths) = opt.streaming_paths {
+            self.streaming_paths.extend(streaming_paths);
         }
     }
 }
@@ -86,13 +170,20 @@ impl CompressionConf {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CompressionHandler {
     conf: CompressionConf,
+    streaming: Router<bool>,
 }
 
 impl TryFrom<CompressionConf> for CompressionHandler {
     type Error = Box<Error>;
 
-    fn try_from(conf: CompressionConf) -> Result<Self, Self::Error> {
-        Ok(Self { conf })
+    fn try_from(mut conf: CompressionConf) -> Result<Self, Self::Error> {
+        let mut merger = Merger::new();
+        for path in std::mem::take(&mut conf.streaming_paths) {
+            merger.push(path, ());
+        }
+        let streaming = merger.merge(|mut entries| entries.next().is_some());
+
+        Ok(Self { conf, streaming })
     }
 }
 
@@ -103,7 +194,7 @@ impl RequestFilter for CompressionHandler {
     fn new_ctx() -> Self::CTX {}
 
     fn init_downstream_modules(modules: &mut HttpModules) {
-        modules.add_module(ResponseCompressionBuilder::enable(0));
+        add_module_once(modules, ResponseCompressionBuilder::enable(0));
     }
 
     async fn early_request_filter(
@@ -135,8 +226,34 @@ impl RequestFilter for CompressionHandler {
         enable_compression!(compression_level_brotli => Brotli);
         enable_compression!(compression_level_zstd => Zstd);
 
-        if self.conf.decompress_upstream {
-            session.upstream_compression.adjust_decompression(true);
+        if let Some(statuses) = &self.conf.compression_statuses {
+            session.set_compression_statuses(
+                statuses
+                    .iter()
+                    .map(|status| status.range().clone())
+                    .collect(),
+            );
+        } else if session.compression_statuses().is_none() {
+            session.set_compression_statuses(vec![200..=299]);
+        }
+
+        if let Some(decompress) = self.conf.decompress_upstream {
+            session
+                .upstream_compression
+                .adjust_decompression(decompress);
+        }
+
+        if self
+            .streaming
+            .lookup("", session.uri().path())
+            .is_some_and(|streaming| *streaming)
+        {
+            trace!("Disabled compression for streaming endpoint");
+            session
+                .downstream_modules_ctx
+                .get_mut::<ResponseCompression>()
+                .unwrap()
+                .adjust_level(0);
         }
 
         Ok(())
@@ -147,10 +264,15 @@ impl RequestFilter for CompressionHandler {
 mod tests {
     use super::*;
 
-    use pandora_module_utils::pingora::{create_test_session, RequestHeader, Session};
+    use pandora_module_utils::handler_order::HandlerOrder;
+    use pandora_module_utils::pingora::{
+        create_test_session, HttpPeer, RequestHeader, ResponseHeader, Session,
+    };
     use pandora_module_utils::FromYaml;
+    use pandora_module_utils::RequestFilterResult;
     use startup_module::{AppResult, DefaultApp};
     use test_log::test;
+    use virtual_hosts_module::VirtualHostsHandler;
 
     fn make_app(configured: bool) -> DefaultApp<CompressionHandler> {
         let conf = if configured {
@@ -199,4 +321,435 @@ mod tests {
         let mut result = app.handle_request(session).await;
         assert_compression(&mut result, true, true);
     }
+
+    #[test(tokio::test)]
+    async fn per_host_inheritance() {
+        #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
+        struct HostHandler {
+            compression: CompressionHandler,
+        }
+
+        #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
+        struct Handler {
+            compression: CompressionHandler,
+            virtual_hosts: VirtualHostsHandler<HostHandler>,
+        }
+
+        let mut app = DefaultApp::<Handler>::from_conf(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                    compression_level_gzip: 6
+                    decompress_upstream: true
+                    vhosts:
+                        inherit.example.com:
+                            default: true
+                        gzip.example.com:
+                            compression_level_gzip: 0
+                        decompress.example.com:
+                            decompress_upstream: false
+                "#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        for (host, downstream, upstream) in [
+            ("inherit.example.com", true, true),
+            ("gzip.example.com", false, true),
+            ("decompress.example.com", true, false),
+        ] {
+            let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+            header.insert_header("Host", host).unwrap();
+            let session = create_test_session(header).await;
+            let mut result = app.handle_request(session).await;
+            assert_compression(&mut result, downstream, upstream);
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn disabled() {
+        #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
+        struct Handler {
+            #[pandora(handler_order)]
+            order: HandlerOrder,
+            compression: CompressionHandler,
+        }
+
+        let mut app = DefaultApp::<Handler>::from_conf(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                    compression_level_gzip: 6
+                    decompress_upstream: true
+                    enabled:
+                        compression: false
+                "#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let session = make_session().await;
+        let mut result = app.handle_request(session).await;
+        assert_compression(&mut result, false, false);
+    }
+
+    #[test(tokio::test)]
+    async fn no_transform() {
+        #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+        struct ResponderConf {}
+
+        /// Produces a response with the `Cache-Control` header given as query string, either
+        /// locally or via an upstream server for the `/upstream` path
+        #[derive(Debug)]
+        struct Responder;
+
+        impl Responder {
+            fn response_header(request: &RequestHeader) -> ResponseHeader {
+                let mut header = ResponseHeader::build(200, None).unwrap();
+                header.insert_header("Content-Type", "text/html").unwrap();
+                if let Some(cache_control) = request.uri.query() {
+                    header
+                        .insert_header("Cache-Control", cache_control)
+                        .unwrap();
+                }
+                header
+            }
+        }
+
+        impl TryFrom<ResponderConf> for Responder {
+            type Error = Box<Error>;
+
+            fn try_from(_conf: ResponderConf) -> Result<Self, Self::Error> {
+                Ok(Self)
+            }
+        }
+
+        #[async_trait]
+        impl RequestFilter for Responder {
+            type Conf = ResponderConf;
+            type CTX = ();
+            fn new_ctx() -> Self::CTX {}
+
+            async fn request_filter(
+                &self,
+                session: &mut impl SessionWrapper,
+                _ctx: &mut Self::CTX,
+            ) -> Result<RequestFilterResult, Box<Error>> {
+                if session.req_header().uri.path() == "/upstream" {
+                    return Ok(RequestFilterResult::Handled);
+                }
+
+                let header = Self::response_header(session.req_header());
+                session
+                    .write_response_header(Box::new(header), false)
+                    .await?;
+                session
+                    .write_response_body(Some("<p>Hi!</p>".into()), true)
+                    .await?;
+                Ok(RequestFilterResult::ResponseSent)
+            }
+
+            async fn upstream_peer(
+                &self,
+                _session: &mut impl SessionWrapper,
+                _ctx: &mut Self::CTX,
+            ) -> Result<Option<Box<HttpPeer>>, Box<Error>> {
+                Ok(Some(Box::new(HttpPeer::new(
+                    "127.0.0.1:8080",
+                    false,
+                    String::new(),
+                ))))
+            }
+        }
+
+        #[derive(Debug, RequestFilter)]
+        struct Handler {
+            compression: CompressionHandler,
+            responder: Responder,
+        }
+
+        let mut app = DefaultApp::<Handler>::from_conf(
+            <Handler as RequestFilter>::Conf::from_yaml("compression_level_gzip: 6").unwrap(),
+        )
+        .unwrap();
+
+        for path in ["/", "/upstream"] {
+            for (cache_control, expected) in [
+                (None, Some("gzip")),
+                (Some("max-age=60"), Some("gzip")),
+                (Some("max-age=60,No-Transform"), None),
+            ] {
+                let uri = match cache_control {
+                    Some(cache_control) => format!("{path}?{cache_control}"),
+                    None => path.to_owned(),
+                };
+                let mut header = RequestHeader::build("GET", uri.as_bytes(), None).unwrap();
+                header.insert_header("Accept-Encoding", "gzip").unwrap();
+                let session = create_test_session(header).await;
+                let mut result = app
+                    .handle_request_with_upstream(session, |session, _| {
+                        Ok(Responder::response_header(session.req_header()))
+                    })
+                    .await;
+                assert!(result.err().is_none(), "{uri}");
+                let session = result.session();
+                let response = session.response_written().unwrap();
+                assert_eq!(
+                    response
+                        .headers
+                        .get("Content-Encoding")
+                        .map(|value| value.to_str().unwrap()),
+                    expected,
+                    "{uri}"
+                );
+            }
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn statuses() {
+        #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+        struct ResponderConf {}
+
+        /// Produces a response with the status code given as query string
+        #[derive(Debug)]
+        struct Responder;
+
+        impl TryFrom<ResponderConf> for Responder {
+            type Error = Box<Error>;
+
+            fn try_from(_conf: ResponderConf) -> Result<Self, Self::Error> {
+                Ok(Self)
+            }
+        }
+
+        #[async_trait]
+        impl RequestFilter for Responder {
+            type Conf = ResponderConf;
+            type CTX = ();
+            fn new_ctx() -> Self::CTX {}
+
+            async fn request_filter(
+                &self,
+                session: &mut impl SessionWrapper,
+                _ctx: &mut Self::CTX,
+            ) -> Result<RequestFilterResult, Box<Error>> {
+                let status = session
+                    .req_header()
+                    .uri
+                    .query()
+                    .and_then(|query| query.parse().ok())
+                    .unwrap_or(200);
+                let mut header = ResponseHeader::build(status, None)?;
+                header.insert_header("Content-Type", "text/html")?;
+                if status == 301 {
+                    header.insert_header("Location", "/")?;
+                }
+                session
+                    .write_response_header(Box::new(header), false)
+                    .await?;
+                session
+                    .write_response_body(Some("<p>Hi!</p>".into()), true)
+                    .await?;
+                Ok(RequestFilterResult::ResponseSent)
+            }
+        }
+
+        #[derive(Debug, RequestFilter)]
+        struct Handler {
+            compression: CompressionHandler,
+            responder: Responder,
+        }
+
+        async fn content_encoding(app: &mut DefaultApp<Handler>, status: u16) -> Option<String> {
+            let uri = format!("/?{status}");
+            let mut header = RequestHeader::build("GET", uri.as_bytes(), None).unwrap();
+            header.insert_header("Accept-Encoding", "gzip").unwrap();
+            let session = create_test_session(header).await;
+            let mut result = app.handle_request(session).await;
+            assert!(result.err().is_none());
+            let session = result.session();
+            let response = session.response_written().unwrap();
+            assert_eq!(response.status.as_u16(), status);
+            response
+                .headers
+                .get("Content-Encoding")
+                .map(|value| value.to_str().unwrap().to_owned())
+        }
+
+        // Only successful responses are compressed by default
+        let mut app = DefaultApp::<Handler>::from_conf(
+            <Handler as RequestFilter>::Conf::from_yaml("compression_level_gzip: 6").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            content_encoding(&mut app, 200).await.as_deref(),
+            Some("gzip")
+        );
+        assert_eq!(content_encoding(&mut app, 301).await, None);
+        assert_eq!(content_encoding(&mut app, 404).await, None);
+
+        let mut app = DefaultApp::<Handler>::from_conf(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                    compression_level_gzip: 6
+                    compression_statuses: [200, 4xx]
+                "#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            content_encoding(&mut app, 200).await.as_deref(),
+            Some("gzip")
+        );
+        assert_eq!(content_encoding(&mut app, 206).await, None);
+        assert_eq!(content_encoding(&mut app, 301).await, None);
+        assert_eq!(
+            content_encoding(&mut app, 404).await.as_deref(),
+            Some("gzip")
+        );
+
+        // Invalid status codes are rejected
+        for value in ["600", "6xx", "x", "20x"] {
+            assert!(StatusMatch::from_str(value).is_err(), "{value}");
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn streaming() {
+        #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+        struct ResponderConf {}
+
+        /// Produces a response in two chunks with the `Content-Type` header given as query string
+        #[derive(Debug)]
+        struct Responder;
+
+        impl TryFrom<ResponderConf> for Responder {
+            type Error = Box<Error>;
+
+            fn try_from(_conf: ResponderConf) -> Result<Self, Self::Error> {
+                Ok(Self)
+            }
+        }
+
+        #[async_trait]
+        impl RequestFilter for Responder {
+            type Conf = ResponderConf;
+            type CTX = ();
+            fn new_ctx() -> Self::CTX {}
+
+            async fn request_filter(
+                &self,
+                session: &mut impl SessionWrapper,
+                _ctx: &mut Self::CTX,
+            ) -> Result<RequestFilterResult, Box<Error>> {
+                let content_type = session.req_header().uri.query().unwrap_or_default();
+                let mut header = ResponseHeader::build(200, None)?;
+                header.insert_header("Content-Type", content_type.replace("%20", " "))?;
+                session
+                    .write_response_header(Box::new(header), false)
+                    .await?;
+                session
+                    .write_response_body(Some("data: 1\n\n".into()), false)
+                    .await?;
+                session
+                    .write_response_body(Some("data: 2\n\n".into()), true)
+                    .await?;
+                Ok(RequestFilterResult::ResponseSent)
+            }
+        }
+
+        #[derive(Debug, RequestFilter)]
+        struct Handler {
+            compression: CompressionHandler,
+            responder: Responder,
+        }
+
+        let mut app = DefaultApp::<Handler>::from_conf(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                    compression_level_gzip: 6
+                    streaming_paths: [/events, /stream/*]
+                "#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        for (uri, compressed) in [
+            ("/?text/plain", true),
+            ("/events?text/plain", false),
+            ("/events/other?text/plain", true),
+            ("/stream/1?text/plain", false),
+            ("/?text/event-stream", false),
+            ("/?text/event-stream;%20charset=utf-8", false),
+        ] {
+            let mut header = RequestHeader::build("GET", uri.as_bytes(), None).unwrap();
+            header.insert_header("Accept-Encoding", "gzip").unwrap();
+            let session = create_test_session(header).await;
+            let mut result = app.handle_request(session).await;
+            assert!(result.err().is_none(), "{uri}");
+
+            let content_encoding = result
+                .session()
+                .response_written()
+                .unwrap()
+                .headers
+                .get("Content-Encoding")
+                .cloned();
+            assert_eq!(content_encoding.is_some(), compressed, "{uri}");
+
+            if !compressed {
+                // Each chunk is passed on as soon as it is written
+                assert_eq!(
+                    result.body_chunks(),
+                    [("data: 1\n\n".into(), false), ("data: 2\n\n".into(), true)],
+                    "{uri}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn merge_with_opt() {
+        let mut conf = CompressionConf {
+            compression_level_gzip: Some(3),
+            compression_level_brotli: Some(4),
+            compression_level_zstd: None,
+            decompress_upstream: Some(true),
+            compression_statuses: None,
+            streaming_paths: Default::default(),
+        };
+        let orig_conf = conf.clone();
+
+        conf.merge_with_opt(CompressionOpt::parse_from(["test"]));
+        assert_eq!(conf, orig_conf);
+
+        conf.merge_with_opt(CompressionOpt::parse_from([
+            "test",
+            "--compression-level-gzip",
+            "6",
+            "--compression-level-zstd",
+            "9",
+            "--compression-statuses",
+            "2xx",
+            "--compression-statuses",
+            "404",
+            "--streaming-paths",
+            "/events/*",
+        ]));
+        assert_eq!(
+            conf,
+            CompressionConf {
+                compression_level_gzip: Some(6),
+                compression_level_brotli: Some(4),
+                compression_level_zstd: Some(9),
+                decompress_upstream: Some(true),
+                compression_statuses: Some(
+                    vec![StatusMatch(200..=299), StatusMatch(404..=404)].into()
+                ),
+                streaming_paths: vec!["/events/*".into()].into(),
+            }
+        );
+    }
 }
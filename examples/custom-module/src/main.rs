@@ -77,6 +77,19 @@ fn main() {
     conf.handler.web_app.merge_with_opt(opt.web_app);
 
     let server = match DefaultApp::<Handler>::from_conf(conf.handler)
+        .map(|app| {
+            app.with_max_uri_length(conf.startup.max_uri_length)
+                .with_strict_path(conf.startup.strict_path)
+                .with_server_timing(
+                    conf.startup.server_timing,
+                    &conf.startup.server_timing_clients,
+                )
+                .with_metrics(
+                    conf.startup.metrics_path.as_deref(),
+                    &conf.startup.metrics_clients,
+                )
+        })
+        .and_then(|app| app.with_inline_files(&conf.startup.inline_files))
         .and_then(|app| conf.startup.into_server(app, Some(opt.startup)))
     {
         Ok(server) => server,
@@ -19,15 +19,18 @@
 
 use http::{
     header,
-    header::{HeaderName, HeaderValue},
+    header::{HeaderName, HeaderValue, InvalidHeaderValue},
 };
+use log::warn;
 use pandora_module_utils::merger::{HostPathMatcher, PathMatch, PathMatchResult};
 use pandora_module_utils::router::{Path, EMPTY_PATH};
 use pandora_module_utils::{DeserializeMap, OneOrMany};
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
 
+use crate::deserialize::deserialize_header_names;
+
 /// Include and exclude rules applying to a configuration entry
 ///
 /// When deciding which rule applies, the “closest” rule to the host/path combination is selected:
@@ -163,6 +166,10 @@ pub struct WithMatchRules<C: Default + Clone + PartialEq + Eq> {
     #[pandora(flatten)]
     pub match_rules: MatchRules,
 
+    /// Optional rule name, a counter with this name will be incremented whenever the rule applies
+    /// to a request
+    pub name: Option<String>,
+
     /// The actual configuration
     #[pandora(flatten)]
     pub conf: C,
@@ -276,6 +283,34 @@ macro_rules! impl_conf {
             HeaderValue::from_str(&$list.join("; ")).unwrap(),
         )]
     };
+
+    // Strict-Transport-Security types
+    (doc($header_name:literal, hsts Option<usize>)) => {
+        concat!("If set, ", $header_name, " directive will be sent")
+    };
+    (doc($header_name:literal, hsts Option<bool>)) => {
+        concat!(
+            "If `true`, ",
+            $header_name,
+            " directive will be sent, `false` overrides less specific rules"
+        )
+    };
+    (push($list:expr, $header_name:literal, $value:expr, hsts Option<usize>)) => {
+        if let Some(value) = $value {
+            $list.push(format!(concat!($header_name, "={}"), value).into());
+        }
+    };
+    (push($list:expr, $header_name:literal, $value:expr, hsts Option<bool>)) => {
+        if $value == Some(true) {
+            $list.push($header_name.into());
+        }
+    };
+    (finalize($list:expr, hsts)) => {
+        vec![(
+            header::STRICT_TRANSPORT_SECURITY,
+            HeaderValue::from_str(&$list.join("; ")).unwrap(),
+        )]
+    };
 }
 
 impl_conf! {cache_control:
@@ -329,6 +364,141 @@ impl_conf! {csp:
     }
 }
 
+impl_conf! {hsts:
+    /// Configuration for the Strict-Transport-Security header
+    pub struct HstsConf {
+        max_age("max-age", Option<usize>),
+        include_sub_domains("includeSubDomains", Option<bool>),
+        preload("preload", Option<bool>),
+    }
+}
+
+/// Minimal `max-age` value accepted for HSTS preload lists, one year
+const HSTS_PRELOAD_MIN_AGE: usize = 31536000;
+
+impl HstsConf {
+    /// Checks whether the directives form a valid `Strict-Transport-Security` header. This is
+    /// meant for the result of merging all rules applying to a host/path combination.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        let include_sub_domains = self.include_sub_domains == Some(true);
+        let preload = self.preload == Some(true);
+        let Some(max_age) = self.max_age else {
+            if include_sub_domains || preload {
+                return Err("max-age is required".to_owned());
+            }
+            return Ok(());
+        };
+
+        if preload {
+            if !include_sub_domains {
+                return Err("preload requires includeSubDomains".to_owned());
+            }
+            if max_age < HSTS_PRELOAD_MIN_AGE {
+                return Err(format!(
+                    "preload requires max-age to be at least {HSTS_PRELOAD_MIN_AGE}"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Configuration for the Permissions-Policy header
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct PermissionsPolicyConf {
+    /// Features like `geolocation` mapped to the origins allowed to use them. Besides origins
+    /// like `https://example.com`, the list can contain `self` (same origin) or `*` (any origin).
+    /// An empty list disables the feature.
+    pub features: BTreeMap<String, OneOrMany<String>>,
+}
+
+impl PermissionsPolicyConf {
+    /// Checks whether the features and origins can be expressed in a `Permissions-Policy`
+    /// header, returning a description of the problem if not.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        for (feature, allowlist) in &self.features {
+            // Structured field dictionary keys, see RFC 8941 section 3.2
+            let valid_name = feature
+                .bytes()
+                .next()
+                .is_some_and(|c| c.is_ascii_lowercase() || c == b'*')
+                && feature
+                    .bytes()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || b"_-.*".contains(&c));
+            if !valid_name {
+                return Err(format!("invalid feature name `{feature}`"));
+            }
+
+            for origin in allowlist.iter() {
+                match origin.as_str() {
+                    "self" => {}
+                    "*" if allowlist.len() > 1 => {
+                        return Err(format!(
+                            "`*` cannot be combined with other origins for feature {feature}"
+                        ));
+                    }
+                    "*" => {}
+                    origin => {
+                        if !origin.contains("://")
+                            || !origin
+                                .bytes()
+                                .all(|c| c.is_ascii_graphic() && c != b'"' && c != b'\\')
+                        {
+                            return Err(format!(
+                                "invalid origin `{origin}` for feature {feature}, expected a value like `https://example.com`, `self` or `*`"
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl IntoHeaders for PermissionsPolicyConf {
+    fn merge_with(&mut self, other: &Self) {
+        self.features.extend(
+            other
+                .features
+                .iter()
+                .map(|(feature, allowlist)| (feature.clone(), allowlist.clone())),
+        );
+    }
+
+    fn into_headers(self) -> Vec<Header> {
+        if self.features.is_empty() {
+            return Vec::new();
+        }
+
+        let entries = self
+            .features
+            .iter()
+            .map(|(feature, allowlist)| {
+                if allowlist.iter().any(|origin| origin == "*") {
+                    format!("{feature}=*")
+                } else {
+                    let origins = allowlist
+                        .iter()
+                        .map(|origin| {
+                            if origin == "self" {
+                                origin.clone()
+                            } else {
+                                format!("\"{origin}\"")
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    format!("{feature}=({})", origins.join(" "))
+                }
+            })
+            .collect::<Vec<_>>();
+        vec![(
+            HeaderName::from_static("permissions-policy"),
+            HeaderValue::from_str(&entries.join(", ")).unwrap(),
+        )]
+    }
+}
+
 /// Custom headers configuration
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct CustomHeadersConf {
@@ -336,6 +506,96 @@ pub struct CustomHeadersConf {
     pub headers: HashMap<HeaderName, HeaderValue>,
 }
 
+impl CustomHeadersConf {
+    /// Resolves variable references like `${name}` in header values.
+    pub(crate) fn resolve_variables(
+        &mut self,
+        variables: &HashMap<String, String>,
+    ) -> Result<(), InvalidHeaderValue> {
+        for value in self.headers.values_mut() {
+            if value.as_bytes().windows(2).any(|w| w == b"${") {
+                *value =
+                    HeaderValue::from_bytes(&substitute_variables(value.as_bytes(), variables))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Merges two configurations like [`IntoHeaders::merge_with`] but combines the values of the
+    /// headers listed in `append` instead of replacing them.
+    pub(crate) fn merge_appending(&mut self, other: &Self, append: &[HeaderName]) {
+        for (name, value) in &other.headers {
+            let value = match self.headers.get(name) {
+                Some(existing) if append.contains(name) => append_value(existing, value),
+                _ => value.clone(),
+            };
+            self.headers.insert(name.clone(), value);
+        }
+    }
+}
+
+/// Replaces variable references like `${name}` in a header value by the values from the
+/// `variables` setting. Undefined variables are replaced by an empty string.
+fn substitute_variables(mut value: &[u8], variables: &HashMap<String, String>) -> Vec<u8> {
+    let mut result = Vec::with_capacity(value.len());
+    while let Some(start) = value.windows(2).position(|w| w == b"${") {
+        let name_start = start + 2;
+        let name_len = value[name_start..]
+            .iter()
+            .position(|c| !c.is_ascii_alphanumeric() && *c != b'_');
+        match name_len {
+            Some(len) if len > 0 && value[name_start + len] == b'}' => {
+                result.extend_from_slice(&value[..start]);
+
+                // Only ASCII characters here, conversion cannot fail
+                let name = std::str::from_utf8(&value[name_start..name_start + len]).unwrap();
+                if let Some(variable) = variables.get(name) {
+                    result.extend_from_slice(variable.as_bytes());
+                } else {
+                    warn!("undefined variable ${{{name}}} in header value, using empty value");
+                }
+                value = &value[name_start + len + 1..];
+            }
+            _ => {
+                // Not a variable reference, keep it as is
+                result.extend_from_slice(&value[..name_start]);
+                value = &value[name_start..];
+            }
+        }
+    }
+    result.extend_from_slice(value);
+    result
+}
+
+/// Appends a header value to an existing one, leaving out any list entries already present
+/// (case-insensitive).
+pub(crate) fn append_value(existing: &HeaderValue, value: &HeaderValue) -> HeaderValue {
+    fn entries(value: &[u8]) -> impl Iterator<Item = &[u8]> {
+        value
+            .split(|c| *c == b',')
+            .map(|entry| {
+                let start = entry.iter().position(|c| !c.is_ascii_whitespace());
+                let end = entry.iter().rposition(|c| !c.is_ascii_whitespace());
+                match (start, end) {
+                    (Some(start), Some(end)) => &entry[start..=end],
+                    _ => &[],
+                }
+            })
+            .filter(|entry| !entry.is_empty())
+    }
+
+    let mut result = existing.as_bytes().to_vec();
+    for entry in entries(value.as_bytes()) {
+        if !entries(&result).any(|e| e.eq_ignore_ascii_case(entry)) {
+            if !result.is_empty() {
+                result.extend_from_slice(b", ");
+            }
+            result.extend_from_slice(entry);
+        }
+    }
+    HeaderValue::from_bytes(&result).unwrap()
+}
+
 impl IntoHeaders for CustomHeadersConf {
     fn merge_with(&mut self, other: &Self) {
         self.headers.extend(
@@ -351,6 +611,307 @@ impl IntoHeaders for CustomHeadersConf {
     }
 }
 
+/// Destination types allowed in the `as` attribute of preload links
+const PRELOAD_DESTINATIONS: &[&str] = &[
+    "audio", "document", "embed", "fetch", "font", "image", "object", "script", "style", "track",
+    "video", "worker",
+];
+
+/// A resource to be preloaded
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct PreloadLink {
+    /// Resource URL, e.g. `/style.css`
+    pub href: String,
+
+    /// Resource type, e.g. `style` or `script`
+    #[pandora(rename = "as")]
+    pub destination: String,
+
+    /// Optional MIME type of the resource
+    #[pandora(rename = "type")]
+    pub mime_type: Option<String>,
+
+    /// If `true`, the resource will be requested in CORS mode
+    pub crossorigin: bool,
+}
+
+impl PreloadLink {
+    /// Checks whether the link can be sent in a `Link` header, returning a description of the
+    /// problem if not.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.href.is_empty() {
+            return Err("empty href".into());
+        }
+        if !self
+            .href
+            .bytes()
+            .all(|c| c.is_ascii_graphic() && c != b'<' && c != b'>')
+        {
+            return Err("href contains characters not allowed in a URL".into());
+        }
+        if !PRELOAD_DESTINATIONS.contains(&self.destination.as_str()) {
+            return Err(format!(
+                "unsupported destination type `{}`, expected one of {}",
+                self.destination,
+                PRELOAD_DESTINATIONS.join(", ")
+            ));
+        }
+        if let Some(mime_type) = &self.mime_type {
+            if mime_type.is_empty()
+                || !mime_type
+                    .bytes()
+                    .all(|c| c.is_ascii_graphic() && c != b'"' && c != b'\\')
+            {
+                return Err(format!("invalid MIME type `{mime_type}`"));
+            }
+        }
+        Ok(())
+    }
+
+    fn to_link(&self) -> String {
+        let mut link = format!("<{}>; rel=preload; as={}", self.href, self.destination);
+        if let Some(mime_type) = &self.mime_type {
+            link.push_str(&format!("; type=\"{mime_type}\""));
+        }
+        if self.crossorigin {
+            link.push_str("; crossorigin");
+        }
+        link
+    }
+}
+
+/// Configuration for preload `Link` headers
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct PreloadConf {
+    /// Resources to be preloaded
+    pub links: OneOrMany<PreloadLink>,
+}
+
+impl IntoHeaders for PreloadConf {
+    fn merge_with(&mut self, other: &Self) {
+        for link in other.links.iter() {
+            if !self.links.contains(link) {
+                self.links.push(link.clone());
+            }
+        }
+    }
+
+    fn into_headers(self) -> Vec<Header> {
+        if self.links.is_empty() {
+            Vec::new()
+        } else {
+            let links = self
+                .links
+                .iter()
+                .map(PreloadLink::to_link)
+                .collect::<Vec<_>>();
+            vec![(
+                header::LINK,
+                HeaderValue::from_str(&links.join(", ")).unwrap(),
+            )]
+        }
+    }
+}
+
+/// An alternative service, see [RFC 7838](https://datatracker.ietf.org/doc/html/rfc7838)
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct AltService {
+    /// ALPN protocol identifier, e.g. `h3`
+    pub protocol: String,
+
+    /// Host and port of the alternative service, e.g. `alt.example.com:8443`. The host can be
+    /// omitted if it is the same as the origin’s, e.g. `:443`.
+    pub authority: String,
+
+    /// If set, the number of seconds the alternative service is considered fresh
+    #[pandora(rename = "ma")]
+    pub max_age: Option<usize>,
+
+    /// If `true`, the alternative service is kept when the client’s network changes
+    pub persist: bool,
+}
+
+impl AltService {
+    /// Checks whether the entry can be sent in an `Alt-Svc` header, returning a description of
+    /// the problem if not.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        // ALPN identifiers are tokens, other characters would need to be percent-encoded
+        if self.protocol.is_empty()
+            || !self
+                .protocol
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c))
+        {
+            return Err(format!("invalid protocol identifier `{}`", self.protocol));
+        }
+
+        let valid_authority = self.authority.rsplit_once(':').is_some_and(|(host, port)| {
+            host.bytes()
+                .all(|c| c.is_ascii_graphic() && c != b'"' && c != b'\\' && c != b'/')
+                && port.parse::<u16>().is_ok_and(|port| port > 0)
+        });
+        if !valid_authority {
+            return Err(format!(
+                "invalid authority `{}`, expected a value like `alt.example.com:443` or `:443`",
+                self.authority
+            ));
+        }
+        Ok(())
+    }
+
+    fn to_entry(&self) -> String {
+        let mut entry = format!("{}=\"{}\"", self.protocol, self.authority);
+        if let Some(max_age) = self.max_age {
+            entry.push_str(&format!("; ma={max_age}"));
+        }
+        if self.persist {
+            entry.push_str("; persist=1");
+        }
+        entry
+    }
+}
+
+/// Configuration for the `Alt-Svc` header
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct AltSvcConf {
+    /// Alternative services to be advertised
+    pub services: OneOrMany<AltService>,
+
+    /// If `true`, clients are asked to forget any previously advertised alternative services.
+    /// This cannot be combined with `services`.
+    pub clear: bool,
+}
+
+impl AltSvcConf {
+    /// Checks whether the configuration forms a valid `Alt-Svc` header, returning a description
+    /// of the problem if not.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.clear && !self.services.is_empty() {
+            return Err("clear cannot be combined with services".to_owned());
+        }
+        for service in self.services.iter() {
+            service.validate()?;
+        }
+        Ok(())
+    }
+}
+
+impl IntoHeaders for AltSvcConf {
+    fn merge_with(&mut self, other: &Self) {
+        if other.clear {
+            self.services = Default::default();
+            self.clear = true;
+        } else if !other.services.is_empty() {
+            self.clear = false;
+            for service in other.services.iter() {
+                if !self.services.contains(service) {
+                    self.services.push(service.clone());
+                }
+            }
+        }
+    }
+
+    fn into_headers(self) -> Vec<Header> {
+        let value = if self.clear {
+            "clear".to_owned()
+        } else if self.services.is_empty() {
+            return Vec::new();
+        } else {
+            let entries = self
+                .services
+                .iter()
+                .map(AltService::to_entry)
+                .collect::<Vec<_>>();
+            entries.join(", ")
+        };
+        vec![(header::ALT_SVC, HeaderValue::from_str(&value).unwrap())]
+    }
+}
+
+/// Configuration for client hints, see
+/// [RFC 8942](https://datatracker.ietf.org/doc/html/rfc8942)
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct ClientHintsConf {
+    /// Client hints the client is asked to send, e.g. `Sec-CH-DPR`
+    #[pandora(deserialize_with = "deserialize_header_names")]
+    pub accept: Vec<HeaderName>,
+
+    /// Client hints the response depends on so much that the client should retry the request if
+    /// it didn’t send them. These are implicitly added to `accept`.
+    #[pandora(deserialize_with = "deserialize_header_names")]
+    pub critical: Vec<HeaderName>,
+}
+
+impl IntoHeaders for ClientHintsConf {
+    fn merge_with(&mut self, other: &Self) {
+        for name in &other.accept {
+            if !self.accept.contains(name) {
+                self.accept.push(name.clone());
+            }
+        }
+        for name in &other.critical {
+            if !self.critical.contains(name) {
+                self.critical.push(name.clone());
+            }
+        }
+    }
+
+    fn into_headers(mut self) -> Vec<Header> {
+        for name in &self.critical {
+            if !self.accept.contains(name) {
+                self.accept.push(name.clone());
+            }
+        }
+        if self.accept.is_empty() {
+            return Vec::new();
+        }
+
+        let join = |names: &[HeaderName]| {
+            let names = names.iter().map(HeaderName::as_str).collect::<Vec<_>>();
+            HeaderValue::from_str(&names.join(", ")).unwrap()
+        };
+
+        // Responses depend on the hints, caches have to take them into account
+        let mut headers = vec![
+            (HeaderName::from_static("accept-ch"), join(&self.accept)),
+            (header::VARY, join(&self.accept)),
+        ];
+        if !self.critical.is_empty() {
+            headers.push((HeaderName::from_static("critical-ch"), join(&self.critical)));
+        }
+        headers
+    }
+}
+
+/// Request headers configuration
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct RequestHeadersConf {
+    /// Headers to be added to the request as name => value map, replacing any existing headers
+    /// with the same name
+    pub set: CustomHeadersConf,
+
+    /// Names of headers to be removed from the request
+    #[pandora(deserialize_with = "deserialize_header_names")]
+    pub remove: Vec<HeaderName>,
+}
+
+impl RequestHeadersConf {
+    /// Merges two configurations, with conflicting settings from `other` being prioritized.
+    pub(crate) fn merge_with(&mut self, other: &Self) {
+        for name in other.set.headers.keys() {
+            self.remove.retain(|n| n != name);
+        }
+        self.set.merge_with(&other.set);
+
+        for name in &other.remove {
+            self.set.headers.remove(name);
+            if !self.remove.contains(name) {
+                self.remove.push(name.clone());
+            }
+        }
+    }
+}
+
 /// Various settings to configure HTTP response headers
 #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct HeadersInnerConf {
@@ -360,13 +921,68 @@ pub struct HeadersInnerConf {
     /// Content-Security-Policy header
     pub content_security_policy: OneOrMany<WithMatchRules<ContentSecurityPolicyConf>>,
 
+    /// Strict-Transport-Security header, only sent with HTTPS responses
+    pub hsts: OneOrMany<WithMatchRules<HstsConf>>,
+
+    /// Permissions-Policy header
+    pub permissions_policy: OneOrMany<WithMatchRules<PermissionsPolicyConf>>,
+
     /// Custom headers, headers configures as name => value map here
     pub custom: OneOrMany<WithMatchRules<CustomHeadersConf>>,
+
+    /// Preload `Link` headers, only sent with HTML responses
+    pub preload: OneOrMany<WithMatchRules<PreloadConf>>,
+
+    /// Client hints to be advertised via `Accept-CH` and `Critical-CH` headers
+    pub client_hints: OneOrMany<WithMatchRules<ClientHintsConf>>,
+
+    /// Alternative services to be advertised via the `Alt-Svc` header
+    pub alt_svc: OneOrMany<WithMatchRules<AltSvcConf>>,
+
+    /// Names of headers with values to be combined if multiple rules apply instead of the more
+    /// specific rule replacing the value
+    #[pandora(deserialize_with = "deserialize_header_names")]
+    pub append: Vec<HeaderName>,
 }
 
 /// Configuration file settings of the headers module
-#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct HeadersConf {
+    /// Modifications to HTTP request headers, applied before any other handlers run
+    pub request_headers: OneOrMany<WithMatchRules<RequestHeadersConf>>,
+
     /// Various settings to configure HTTP response headers
     pub response_headers: HeadersInnerConf,
+
+    /// Maximal number of request and response header rules that can be configured
+    pub max_header_rules: usize,
+
+    /// Named values that can be used in custom header values like `${name}`
+    pub variables: HashMap<String, String>,
+}
+
+impl HeadersConf {
+    /// Total number of request and response header rules
+    pub(crate) fn rule_count(&self) -> usize {
+        self.request_headers.len()
+            + self.response_headers.cache_control.len()
+            + self.response_headers.content_security_policy.len()
+            + self.response_headers.hsts.len()
+            + self.response_headers.permissions_policy.len()
+            + self.response_headers.custom.len()
+            + self.response_headers.preload.len()
+            + self.response_headers.client_hints.len()
+            + self.response_headers.alt_svc.len()
+    }
+}
+
+impl Default for HeadersConf {
+    fn default() -> Self {
+        Self {
+            request_headers: Default::default(),
+            response_headers: Default::default(),
+            max_header_rules: 10000,
+            variables: HashMap::new(),
+        }
+    }
 }
@@ -15,7 +15,7 @@
 //! Custom deserialization code for the configuration
 
 use http::header::{HeaderName, HeaderValue};
-use pandora_module_utils::{DeserializeMap, MapVisitor};
+use pandora_module_utils::{DeserializeMap, MapVisitor, OneOrMany};
 use serde::de::{
     Deserialize, DeserializeSeed, Deserializer, Error as _, MapAccess, Unexpected, Visitor,
 };
@@ -132,6 +132,20 @@ impl<'de> MapVisitor<'de> for CustomHeadersVisitor {
     }
 }
 
+pub(crate) fn deserialize_header_names<'de, D>(deserializer: D) -> Result<Vec<HeaderName>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let names = <OneOrMany<String> as Deserialize>::deserialize(deserializer)?;
+    names
+        .iter()
+        .map(|name| {
+            HeaderName::try_from(name)
+                .map_err(|_| D::Error::invalid_value(Unexpected::Str(name), &"header name"))
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use crate::configuration::{MatchRules, WithMatchRules};
@@ -159,6 +173,7 @@ mod tests {
             DummyConf {
                 inner: vec![WithMatchRules {
                     match_rules: Default::default(),
+                    name: None,
                     conf: CustomHeadersConf {
                         headers: HashMap::from([
                             ("x-a".try_into().unwrap(), "a".try_into().unwrap()),
@@ -186,6 +201,7 @@ mod tests {
                         include: vec![HostPathMatcher::from("/*")].into(),
                         ..Default::default()
                     },
+                    name: None,
                     conf: CustomHeadersConf {
                         headers: HashMap::from([
                             ("x-a".try_into().unwrap(), "a".try_into().unwrap()),
@@ -214,6 +230,7 @@ mod tests {
                         include: vec![HostPathMatcher::from("/*")].into(),
                         ..Default::default()
                     },
+                    name: None,
                     conf: CustomHeadersConf {
                         headers: HashMap::from([
                             ("x-a".try_into().unwrap(), "a".try_into().unwrap()),
@@ -243,6 +260,7 @@ mod tests {
                 inner: vec![
                     WithMatchRules {
                         match_rules: Default::default(),
+                        name: None,
                         conf: CustomHeadersConf {
                             headers: HashMap::from([
                                 ("x-a".try_into().unwrap(), "a".try_into().unwrap()),
@@ -255,6 +273,7 @@ mod tests {
                             include: vec![HostPathMatcher::from("/*")].into(),
                             ..Default::default()
                         },
+                        name: None,
                         conf: CustomHeadersConf {
                             headers: HashMap::from([(
                                 "include".try_into().unwrap(),
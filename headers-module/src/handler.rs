@@ -13,33 +13,173 @@
 // limitations under the License.
 
 use async_trait::async_trait;
-use http::{HeaderName, HeaderValue};
-use log::trace;
+use http::{header, HeaderName, HeaderValue};
+use log::{error, trace};
 use pandora_module_utils::merger::{Merger, StrictHostPathMatcher};
+use pandora_module_utils::metrics::Counter;
 use pandora_module_utils::pingora::{
-    Error, HttpModule, HttpModuleBuilder, HttpModules, ResponseHeader, SessionWrapper,
+    Error, ErrorType, HttpModule, HttpModuleBuilder, HttpModules, ResponseHeader, SessionWrapper,
 };
 use pandora_module_utils::router::Router;
 use pandora_module_utils::{OneOrMany, RequestFilter, RequestFilterResult};
 use std::any::Any;
+use std::cell::RefCell;
+use std::convert::Infallible;
 
-use crate::configuration::{Header, HeadersConf, IntoHeaders, WithMatchRules};
+use crate::configuration::{
+    append_value, AltSvcConf, CacheControlConf, ClientHintsConf, ContentSecurityPolicyConf, Header,
+    HeadersConf, HstsConf, IntoHeaders, PermissionsPolicyConf, PreloadConf, RequestHeadersConf,
+    WithMatchRules,
+};
+
+/// Headers and rule counters applying to a particular host/path combination
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct RuleResult {
+    headers: Vec<Header>,
+    counters: Vec<Counter>,
+}
+
+/// Request header modifications and rule counters applying to a particular host/path combination
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct RequestRuleResult {
+    set: Vec<Header>,
+    remove: Vec<HeaderName>,
+    counters: Vec<Counter>,
+}
+
+fn merge_request_rules(
+    rules: OneOrMany<WithMatchRules<RequestHeadersConf>>,
+) -> Router<RequestRuleResult> {
+    let mut merger = Merger::new();
+    for rule in rules {
+        let counter = rule
+            .name
+            .map(|name| Counter::register("headers_module", &name));
+        merger.push(rule.match_rules, (rule.conf, counter));
+    }
+    merger.merge(|values| {
+        let mut result = RequestHeadersConf::default();
+        let mut counters = Vec::new();
+        for (conf, counter) in values {
+            result.merge_with(conf);
+            counters.extend(counter.iter().cloned());
+        }
+        RequestRuleResult {
+            set: result.set.into_headers(),
+            remove: result.remove,
+            counters,
+        }
+    })
+}
+
+fn merge_rules<C>(
+    rules: OneOrMany<WithMatchRules<C>>,
+    merge: impl Fn(&mut C, &C),
+) -> Merger<StrictHostPathMatcher, RuleResult>
+where
+    C: Default + Clone + Eq + IntoHeaders,
+{
+    merge_validated_rules(rules, merge, |_| Ok::<_, Infallible>(()))
+        .unwrap_or_else(|never| match never {})
+}
 
-fn merge_rules<C>(rules: OneOrMany<WithMatchRules<C>>) -> Merger<StrictHostPathMatcher, Vec<Header>>
+/// Merges the rules like [`merge_rules`], validating the merged configuration for each host/path
+/// combination. The first validation error encountered is returned.
+fn merge_validated_rules<C, E>(
+    rules: OneOrMany<WithMatchRules<C>>,
+    merge: impl Fn(&mut C, &C),
+    validate: impl Fn(&C) -> Result<(), E>,
+) -> Result<Merger<StrictHostPathMatcher, RuleResult>, E>
 where
     C: Default + Clone + Eq + IntoHeaders,
 {
     let mut merger = Merger::new();
     for rule in rules {
-        merger.push(rule.match_rules, rule.conf);
+        let counter = rule
+            .name
+            .map(|name| Counter::register("headers_module", &name));
+        merger.push(rule.match_rules, (rule.conf, counter));
     }
-    merger.merge_into_merger(|values| {
+
+    let error = RefCell::new(None);
+    let merger = merger.merge_into_merger(|values| {
         let mut result = C::default();
-        for conf in values {
-            result.merge_with(conf);
+        let mut counters = Vec::new();
+        for (conf, counter) in values {
+            merge(&mut result, conf);
+            counters.extend(counter.iter().cloned());
         }
-        result.into_headers()
-    })
+        if let Err(err) = validate(&result) {
+            error.borrow_mut().get_or_insert(err);
+        }
+        RuleResult {
+            headers: result.into_headers(),
+            counters,
+        }
+    });
+
+    match error.into_inner() {
+        Some(err) => Err(err),
+        None => Ok(merger),
+    }
+}
+
+/// Combines the results of all rules applying to a host/path combination.
+fn combine_results<'a>(
+    values: Box<dyn Iterator<Item = &'a RuleResult> + 'a>,
+    append: &[HeaderName],
+) -> RuleResult {
+    let mut result = Vec::<(HeaderName, HeaderValue)>::new();
+    let mut counters = Vec::new();
+    for rule_result in values {
+        counters.extend_from_slice(&rule_result.counters);
+        for (name, value) in &rule_result.headers {
+            if let Some(existing) = result.iter().position(|(n, _)| n == name) {
+                // Combine duplicate headers
+                // https://datatracker.ietf.org/doc/html/rfc7230#section-3.2.2
+                result[existing].1 = if append.contains(name) {
+                    append_value(&result[existing].1, value)
+                } else {
+                    let mut new_value = result[existing].1.as_bytes().to_vec();
+                    new_value.extend_from_slice(b", ");
+                    new_value.extend_from_slice(value.as_bytes());
+                    HeaderValue::from_bytes(&new_value).unwrap()
+                };
+            } else {
+                result.push((name.clone(), value.clone()))
+            }
+        }
+    }
+    RuleResult {
+        headers: result,
+        counters,
+    }
+}
+
+/// Checks whether the response has an HTML content type.
+fn is_html(resp: &ResponseHeader) -> bool {
+    resp.headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/html"))
+}
+
+/// Adds headers to the response, keeping existing values and only adding list entries not
+/// present yet.
+fn merge_into_response(resp: &mut ResponseHeader, list: &[Header]) -> Result<(), Box<Error>> {
+    for (name, value) in list.iter() {
+        let existing = resp
+            .headers
+            .get_all(name)
+            .iter()
+            .map(|v| v.as_bytes())
+            .collect::<Vec<_>>()
+            .join(b", ".as_slice());
+        let value = append_value(&HeaderValue::from_bytes(&existing).unwrap(), value);
+        resp.insert_header(name, value)?;
+    }
+    Ok(())
 }
 
 struct HeadersHttpModuleBuilder {}
@@ -52,11 +192,19 @@ impl HttpModuleBuilder for HeadersHttpModuleBuilder {
 
 struct HeadersHttpModule {
     headers: Option<Vec<Header>>,
+    preload: Option<Vec<Header>>,
+    client_hints: Option<Vec<Header>>,
+    hsts: Option<Vec<Header>>,
 }
 
 impl HeadersHttpModule {
     fn new() -> Self {
-        Self { headers: None }
+        Self {
+            headers: None,
+            preload: None,
+            client_hints: None,
+            hsts: None,
+        }
     }
 }
 
@@ -81,6 +229,26 @@ impl HttpModule for HeadersHttpModule {
             }
             trace!("Added headers to response: {list:?}");
         }
+
+        if let Some(list) = &self.preload {
+            if is_html(resp) {
+                merge_into_response(resp, list)?;
+                trace!("Added preload headers to response: {list:?}");
+            }
+        }
+
+        if let Some(list) = &self.client_hints {
+            // Existing `Vary` values have to be kept, e.g. `Accept-Encoding`
+            merge_into_response(resp, list)?;
+            trace!("Added client hints headers to response: {list:?}");
+        }
+
+        if let Some(list) = &self.hsts {
+            for (name, value) in list.iter() {
+                resp.insert_header(name, value)?;
+            }
+            trace!("Added HSTS headers to response: {list:?}");
+        }
         Ok(())
     }
 }
@@ -88,41 +256,124 @@ impl HttpModule for HeadersHttpModule {
 /// Headers module handler
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct HeadersHandler {
-    router: Router<Vec<Header>>,
+    request_router: Router<RequestRuleResult>,
+    router: Router<RuleResult>,
+    preload_router: Router<RuleResult>,
+    client_hints_router: Router<RuleResult>,
+    hsts_router: Router<RuleResult>,
 }
 
 impl TryFrom<HeadersConf> for HeadersHandler {
     type Error = Box<Error>;
 
-    fn try_from(value: HeadersConf) -> Result<Self, Self::Error> {
-        let cache_control = merge_rules(value.response_headers.cache_control);
-        let content_security_policy = merge_rules(value.response_headers.content_security_policy);
-        let custom = merge_rules(value.response_headers.custom);
+    fn try_from(mut value: HeadersConf) -> Result<Self, Self::Error> {
+        let rule_count = value.rule_count();
+        if rule_count > value.max_header_rules {
+            error!(
+                "{rule_count} header rules configured, exceeding the limit of {} set via max_header_rules",
+                value.max_header_rules
+            );
+            return Err(Error::new(ErrorType::InternalError));
+        }
 
-        let mut merged = cache_control;
-        merged.extend([content_security_policy, custom]);
-
-        let router = merged.merge(|values| {
-            let mut result = Vec::<(HeaderName, HeaderValue)>::new();
-            for headers in values {
-                for (name, value) in headers {
-                    if let Some(existing) = result.iter().position(|(n, _)| n == name) {
-                        // Combine duplicate headers
-                        // https://datatracker.ietf.org/doc/html/rfc7230#section-3.2.2
-                        let mut new_value = result[existing].1.as_bytes().to_vec();
-                        new_value.extend_from_slice(b", ");
-                        new_value.extend_from_slice(value.as_bytes());
-                        result[existing].1 = HeaderValue::from_bytes(&new_value).unwrap();
-                    } else {
-                        result.push((name.clone(), value.clone()))
-                    }
+        for rule in value.response_headers.preload.iter() {
+            for link in rule.conf.links.iter() {
+                if let Err(err) = link.validate() {
+                    error!("invalid preload link {}: {err}", link.href);
+                    return Err(Error::new(ErrorType::InternalError));
                 }
             }
-            result
+        }
+
+        for rule in value.response_headers.permissions_policy.iter() {
+            if let Err(err) = rule.conf.validate() {
+                error!("invalid Permissions-Policy configuration: {err}");
+                return Err(Error::new(ErrorType::InternalError));
+            }
+        }
+
+        for rule in value.response_headers.alt_svc.iter() {
+            if let Err(err) = rule.conf.validate() {
+                error!("invalid Alt-Svc configuration: {err}");
+                return Err(Error::new(ErrorType::InternalError));
+            }
+        }
+
+        let custom_headers = value
+            .request_headers
+            .iter_mut()
+            .map(|rule| &mut rule.conf.set)
+            .chain(
+                value
+                    .response_headers
+                    .custom
+                    .iter_mut()
+                    .map(|rule| &mut rule.conf),
+            );
+        for conf in custom_headers {
+            if let Err(err) = conf.resolve_variables(&value.variables) {
+                error!("invalid header value after resolving variables: {err}");
+                return Err(Error::new(ErrorType::InternalError));
+            }
+        }
+
+        let request_router = merge_request_rules(value.request_headers);
+        trace!("Merged request headers configuration into: {request_router:#?}");
+
+        let append = value.response_headers.append;
+        let cache_control = merge_rules(
+            value.response_headers.cache_control,
+            CacheControlConf::merge_with,
+        );
+        let content_security_policy = merge_rules(
+            value.response_headers.content_security_policy,
+            ContentSecurityPolicyConf::merge_with,
+        );
+        let permissions_policy = merge_rules(
+            value.response_headers.permissions_policy,
+            PermissionsPolicyConf::merge_with,
+        );
+        let alt_svc = merge_rules(value.response_headers.alt_svc, AltSvcConf::merge_with);
+        let custom = merge_rules(value.response_headers.custom, |conf, other| {
+            conf.merge_appending(other, &append)
         });
+
+        let mut merged = cache_control;
+        merged.extend([content_security_policy, permissions_policy, alt_svc, custom]);
+
+        let router = merged.merge(|values| combine_results(values, &append));
         trace!("Merged headers configuration into: {router:#?}");
 
-        Ok(Self { router })
+        let preload_router = merge_rules(value.response_headers.preload, PreloadConf::merge_with)
+            .merge(|values| combine_results(values, &[]));
+        trace!("Merged preload configuration into: {preload_router:#?}");
+
+        let client_hints_router = merge_rules(
+            value.response_headers.client_hints,
+            ClientHintsConf::merge_with,
+        )
+        .merge(|values| combine_results(values, &[]));
+        trace!("Merged client hints configuration into: {client_hints_router:#?}");
+
+        let hsts_router = merge_validated_rules(
+            value.response_headers.hsts,
+            HstsConf::merge_with,
+            HstsConf::validate,
+        )
+        .map_err(|err| {
+            error!("invalid HSTS configuration: {err}");
+            Error::new(ErrorType::InternalError)
+        })?
+        .merge(|values| combine_results(values, &[]));
+        trace!("Merged HSTS configuration into: {hsts_router:#?}");
+
+        Ok(Self {
+            request_router,
+            router,
+            preload_router,
+            client_hints_router,
+            hsts_router,
+        })
     }
 }
 
@@ -140,6 +391,35 @@ impl RequestFilter for HeadersHandler {
         modules.add_module(Box::new(HeadersHttpModuleBuilder {}));
     }
 
+    async fn early_request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        _ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        let path = session.uri().path();
+        let host = session.host().unwrap_or_default();
+        if let Some(result) = self.request_router.lookup(host.as_ref(), path) {
+            for counter in &result.counters {
+                counter.increment();
+            }
+
+            let header = session.req_header_mut();
+            for name in &result.remove {
+                header.remove_header(name);
+            }
+            for (name, value) in &result.set {
+                header.insert_header(name.clone(), value)?;
+            }
+            trace!(
+                "Modified request headers, removed: {:?}, set: {:?}",
+                result.remove,
+                result.set
+            );
+        }
+
+        Ok(())
+    }
+
     async fn request_filter(
         &self,
         session: &mut impl SessionWrapper,
@@ -152,13 +432,81 @@ impl RequestFilter for HeadersHandler {
         );
 
         let host = session.host().unwrap_or_default();
-        if let Some(list) = self.router.lookup(host.as_ref(), path) {
+        let preload = self
+            .preload_router
+            .lookup(host.as_ref(), path)
+            .map(|result| {
+                for counter in &result.counters {
+                    counter.increment();
+                }
+                result.headers.clone()
+            });
+
+        let client_hints = self
+            .client_hints_router
+            .lookup(host.as_ref(), path)
+            .map(|result| {
+                for counter in &result.counters {
+                    counter.increment();
+                }
+                result.headers.clone()
+            });
+
+        // Browsers ignore Strict-Transport-Security on plain HTTP responses, RFC 6797 section 7.2
+        let is_tls = session
+            .digest()
+            .and_then(|digest| digest.ssl_digest.as_ref())
+            .is_some();
+        let hsts = if is_tls {
+            self.hsts_router.lookup(host.as_ref(), path).map(|result| {
+                for counter in &result.counters {
+                    counter.increment();
+                }
+                result.headers.clone()
+            })
+        } else {
+            None
+        };
+
+        if let Some(result) = self.router.lookup(host.as_ref(), path) {
+            for counter in &result.counters {
+                counter.increment();
+            }
+
+            let list = result.headers.clone();
+            trace!("Prepared headers for response: {list:?}");
             session
                 .downstream_modules_ctx
                 .get_mut::<HeadersHttpModule>()
                 .unwrap()
-                .headers = Some(list.as_value().clone());
-            trace!("Prepared headers for response: {list:?}");
+                .headers = Some(list);
+        }
+
+        if preload.is_some() {
+            trace!("Prepared preload headers for response: {preload:?}");
+            session
+                .downstream_modules_ctx
+                .get_mut::<HeadersHttpModule>()
+                .unwrap()
+                .preload = preload;
+        }
+
+        if client_hints.is_some() {
+            trace!("Prepared client hints headers for response: {client_hints:?}");
+            session
+                .downstream_modules_ctx
+                .get_mut::<HeadersHttpModule>()
+                .unwrap()
+                .client_hints = client_hints;
+        }
+
+        if hsts.is_some() {
+            trace!("Prepared HSTS headers for response: {hsts:?}");
+            session
+                .downstream_modules_ctx
+                .get_mut::<HeadersHttpModule>()
+                .unwrap()
+                .hsts = hsts;
         }
 
         Ok(RequestFilterResult::Unhandled)
@@ -170,9 +518,12 @@ mod tests {
     use super::*;
 
     use http::header;
-    use pandora_module_utils::pingora::{create_test_session, HttpPeer, RequestHeader, Session};
+    use pandora_module_utils::pingora::{
+        create_test_session, HttpPeer, RequestHeader, Session, SslDigest,
+    };
     use pandora_module_utils::{DeserializeMap, FromYaml};
     use startup_module::DefaultApp;
+    use std::sync::Arc;
     use test_log::test;
     use upstream_module::{UpstreamConf, UpstreamHandler};
 
@@ -196,6 +547,7 @@ mod tests {
                 Some(
                     UpstreamConf {
                         upstream: Some("http://127.0.0.1".try_into().unwrap()),
+                        ..Default::default()
                     }
                     .try_into()
                     .unwrap(),
@@ -221,7 +573,19 @@ mod tests {
             if let Some(inner) = &self.inner {
                 inner.request_filter(session, ctx).await
             } else {
-                let header = make_response_header()?;
+                let mut header = make_response_header()?;
+                if let Some(value) = session.req_header().headers.get("X-Request") {
+                    header.insert_header("X-Seen-Request", value.clone())?;
+                }
+                if let Some(value) = session.req_header().headers.get("X-Response-Type") {
+                    header.insert_header(header::CONTENT_TYPE, value.clone())?;
+                }
+                if let Some(value) = session.req_header().headers.get("X-Response-Link") {
+                    header.insert_header(header::LINK, value.clone())?;
+                }
+                if let Some(value) = session.req_header().headers.get("X-Response-Vary") {
+                    header.insert_header(header::VARY, value.clone())?;
+                }
                 session
                     .write_response_header(Box::new(header), true)
                     .await?;
@@ -674,4 +1038,778 @@ mod tests {
             ],
         );
     }
+
+    #[test(tokio::test)]
+    async fn rule_counters() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    cache_control:
+                        max-age: 300
+                        include: example.com/counted/*
+                        name: rule_counters_test
+                    custom:
+                        X-Test: value
+            "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        let counter = Counter::register("headers_module", "rule_counters_test");
+        assert_eq!(counter.get(), 0);
+
+        let session = make_session("https://example.com/counted/file.txt").await;
+        let result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(counter.get(), 1);
+
+        let session = make_session("https://example.com/file.txt").await;
+        let result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(counter.get(), 1);
+
+        let session = make_session("https://example.com/counted/").await;
+        let result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(counter.get(), 2);
+    }
+
+    #[test(tokio::test)]
+    async fn request_headers() {
+        fn make_app(send_response: bool) -> DefaultApp<Handler> {
+            DefaultApp::new(
+                <Handler as RequestFilter>::Conf::from_yaml(format!(
+                    r#"
+                    send_response: {send_response}
+                    request_headers:
+                    -
+                        set:
+                            X-Request: injected
+                            Accept-Encoding: identity
+                        remove: X-Remove
+                    -
+                        include: example.com/subdir/*
+                        remove: [X-Request, X-Other]
+                "#,
+                ))
+                .unwrap()
+                .try_into()
+                .unwrap(),
+            )
+        }
+
+        async fn make_session_with_headers(path: &str) -> Session {
+            let mut session = make_session(path).await;
+            let header = session.req_header_mut();
+            header.insert_header("X-Request", "original").unwrap();
+            header.insert_header("X-Remove", "value").unwrap();
+            header.insert_header("X-Other", "value").unwrap();
+            header.insert_header("Accept-Encoding", "gzip").unwrap();
+            session
+        }
+
+        fn request_headers(session: &Session) -> Vec<(String, String)> {
+            let mut headers: Vec<_> = session
+                .req_header()
+                .headers
+                .iter()
+                .filter(|(name, _)| *name != header::CONTENT_LENGTH && *name != header::HOST)
+                .map(|(name, value)| (name.as_str().to_owned(), value.to_str().unwrap().to_owned()))
+                .collect();
+            headers.sort();
+            headers
+        }
+
+        // Downstream handlers see the modified request
+        let mut app = make_app(true);
+        let session = make_session_with_headers("https://example.com/").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                ("X-Seen-Request", "injected"),
+            ],
+        );
+
+        let session = make_session_with_headers("https://example.com/subdir/file.txt").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![("X-Me", "none"), ("X-Test", "unchanged")],
+        );
+
+        // The modified request is forwarded upstream
+        let mut app = make_app(false);
+        let session = make_session_with_headers("https://example.com/").await;
+        let result = app
+            .handle_request_with_upstream(session, |session, _| {
+                assert_eq!(
+                    request_headers(session),
+                    vec![
+                        ("accept-encoding".to_owned(), "identity".to_owned()),
+                        ("x-other".to_owned(), "value".to_owned()),
+                        ("x-request".to_owned(), "injected".to_owned()),
+                    ]
+                );
+                make_response_header()
+            })
+            .await;
+        assert!(result.err().is_none());
+
+        let session = make_session_with_headers("https://example.com/subdir/").await;
+        let result = app
+            .handle_request_with_upstream(session, |session, _| {
+                assert_eq!(
+                    request_headers(session),
+                    vec![("accept-encoding".to_owned(), "identity".to_owned())]
+                );
+                make_response_header()
+            })
+            .await;
+        assert!(result.err().is_none());
+    }
+
+    #[test(tokio::test)]
+    async fn append_headers() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    append: [Cache-Control, Vary]
+                    cache_control:
+                        public: true
+                        include: example.com/api/*
+                    custom:
+                    -
+                        Cache-Control: public
+                        Vary: Accept-Encoding
+                        X-Other: broad
+                    -
+                        include: example.com/api/*
+                        Cache-Control: max-age=60
+                        Vary: accept-encoding, Origin
+                        X-Other: narrow
+            "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        let session = make_session("https://example.com/").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                ("Cache-Control", "public"),
+                ("Vary", "Accept-Encoding"),
+                ("X-Other", "broad"),
+            ],
+        );
+
+        let session = make_session("https://example.com/api/endpoint").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                ("Cache-Control", "public, max-age=60"),
+                ("Vary", "Accept-Encoding, Origin"),
+                ("X-Other", "narrow"),
+            ],
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn preload() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    preload:
+                    -
+                        include: example.com
+                        links:
+                        -
+                            href: /style.css
+                            as: style
+                        -
+                            href: /font.woff2
+                            as: font
+                            type: font/woff2
+                            crossorigin: true
+                    -
+                        include: example.com/app/*
+                        links:
+                            href: /app.js
+                            as: script
+            "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        async fn make_session_with_type(
+            path: &str,
+            content_type: &str,
+            link: Option<&str>,
+        ) -> Session {
+            let mut session = make_session(path).await;
+            let header = session.req_header_mut();
+            header
+                .insert_header("X-Response-Type", content_type)
+                .unwrap();
+            if let Some(link) = link {
+                header.insert_header("X-Response-Link", link).unwrap();
+            }
+            session
+        }
+
+        let session = make_session_with_type("https://example.com/", "text/html", None).await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                ("Content-Type", "text/html"),
+                ("Link", "</style.css>; rel=preload; as=style, </font.woff2>; rel=preload; as=font; type=\"font/woff2\"; crossorigin"),
+            ],
+        );
+
+        // Links already present aren't duplicated
+        let session = make_session_with_type(
+            "https://example.com/app/",
+            "text/html; charset=utf-8",
+            Some("</app.js>; rel=preload; as=script"),
+        )
+        .await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                ("Content-Type", "text/html; charset=utf-8"),
+                ("Link", "</app.js>; rel=preload; as=script, </style.css>; rel=preload; as=style, </font.woff2>; rel=preload; as=font; type=\"font/woff2\"; crossorigin"),
+            ],
+        );
+
+        // Only HTML responses get preload headers
+        let session = make_session_with_type("https://example.com/", "text/css", None).await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                ("Content-Type", "text/css"),
+            ],
+        );
+
+        // Invalid links are rejected
+        let conf = |link: &str| {
+            HeadersConf::from_yaml(format!(
+                r#"
+                    response_headers:
+                        preload:
+                            links: {link}
+                "#
+            ))
+            .unwrap()
+        };
+        assert!(HeadersHandler::try_from(conf("{href: /app.js, as: script}")).is_ok());
+        assert!(HeadersHandler::try_from(conf("{href: /app.js, as: program}")).is_err());
+        assert!(HeadersHandler::try_from(conf("{href: '', as: script}")).is_err());
+        assert!(HeadersHandler::try_from(conf("{href: '/app.js>', as: script}")).is_err());
+        assert!(HeadersHandler::try_from(conf("{href: /app.js, as: script, type: '\"'}")).is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn client_hints() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    client_hints:
+                    -
+                        include: example.com
+                        accept: [Sec-CH-Viewport-Width]
+                    -
+                        include: example.com/images/*
+                        accept: Sec-CH-Viewport-Width
+                        critical: Sec-CH-DPR
+            "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        let session = make_session("https://example.com/").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                ("Accept-CH", "sec-ch-viewport-width"),
+                ("Vary", "sec-ch-viewport-width"),
+            ],
+        );
+
+        // Existing Vary values are kept
+        let mut session = make_session("https://example.com/images/logo.png").await;
+        session
+            .req_header_mut()
+            .insert_header("X-Response-Vary", "Accept-Encoding")
+            .unwrap();
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                ("Vary", "Accept-Encoding, sec-ch-viewport-width, sec-ch-dpr"),
+                ("Accept-CH", "sec-ch-viewport-width, sec-ch-dpr"),
+                ("Critical-CH", "sec-ch-dpr"),
+            ],
+        );
+
+        let session = make_session("https://example.net/").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![("X-Me", "none"), ("X-Test", "unchanged")],
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn hsts() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    hsts:
+                    -
+                        max-age: 63072000
+                        includeSubDomains: true
+                        preload: true
+                        include: example.com
+                    -
+                        max-age: 0
+                        includeSubDomains: false
+                        preload: false
+                        include: example.com/legacy/*
+            "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        async fn make_tls_session(path: &str) -> Session {
+            let mut session = make_session(path).await;
+            session.digest_mut().unwrap().ssl_digest = Some(Arc::new(SslDigest {
+                cipher: "TLS_AES_128_GCM_SHA256",
+                version: "TLSv1.3",
+                organization: None,
+                serial_number: None,
+                cert_digest: Vec::new(),
+            }));
+            session
+        }
+
+        let session = make_tls_session("https://example.com/").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                (
+                    "Strict-Transport-Security",
+                    "max-age=63072000; includeSubDomains; preload",
+                ),
+            ],
+        );
+
+        let session = make_tls_session("https://example.com/legacy/").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                ("Strict-Transport-Security", "max-age=0"),
+            ],
+        );
+
+        let session = make_tls_session("https://example.net/").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![("X-Me", "none"), ("X-Test", "unchanged")],
+        );
+
+        // No header on plain HTTP responses
+        let session = make_session("http://example.com/").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![("X-Me", "none"), ("X-Test", "unchanged")],
+        );
+
+        // Invalid configurations are rejected
+        let conf = |hsts: &str| {
+            HeadersConf::from_yaml(format!(
+                r#"
+                    response_headers:
+                        hsts: {hsts}
+                "#
+            ))
+            .unwrap()
+        };
+        assert!(HeadersHandler::try_from(conf("{max-age: 3600}")).is_ok());
+        assert!(HeadersHandler::try_from(conf(
+            "{max-age: 31536000, includeSubDomains: true, preload: true}"
+        ))
+        .is_ok());
+        assert!(HeadersHandler::try_from(conf("{includeSubDomains: true}")).is_err());
+        assert!(HeadersHandler::try_from(conf("{max-age: 31536000, preload: true}")).is_err());
+        assert!(HeadersHandler::try_from(conf(
+            "{max-age: 86400, includeSubDomains: true, preload: true}"
+        ))
+        .is_err());
+
+        // Validation applies to the merged configuration
+        assert!(HeadersHandler::try_from(conf(
+            "[{max-age: 31536000, includeSubDomains: true}, {preload: true, include: /sub/*}]"
+        ))
+        .is_ok());
+        assert!(HeadersHandler::try_from(conf(
+            "[{max-age: 31536000, includeSubDomains: true, preload: true}, {max-age: 0, include: /legacy/*}]"
+        ))
+        .is_err());
+        assert!(HeadersHandler::try_from(conf(
+            "[{max-age: 31536000, includeSubDomains: true, preload: true}, {includeSubDomains: false, include: /sub/*}]"
+        ))
+        .is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn permissions_policy() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    permissions_policy:
+                    -
+                        include: example.com
+                        features:
+                            geolocation: [self, "https://maps.example.com"]
+                            camera: []
+                            fullscreen: "*"
+                    -
+                        include: example.com/video/*
+                        features:
+                            camera: self
+            "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        let session = make_session("https://example.com/").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                (
+                    "Permissions-Policy",
+                    "camera=(), fullscreen=*, geolocation=(self \"https://maps.example.com\")",
+                ),
+            ],
+        );
+
+        let session = make_session("https://example.com/video/").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                (
+                    "Permissions-Policy",
+                    "camera=(self), fullscreen=*, geolocation=(self \"https://maps.example.com\")",
+                ),
+            ],
+        );
+
+        let session = make_session("https://example.net/").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![("X-Me", "none"), ("X-Test", "unchanged")],
+        );
+
+        // Invalid configurations are rejected
+        let conf = |features: &str| {
+            HeadersConf::from_yaml(format!(
+                r#"
+                    response_headers:
+                        permissions_policy:
+                            features: {features}
+                "#
+            ))
+            .unwrap()
+        };
+        assert!(
+            HeadersHandler::try_from(conf("{autoplay: [self, \"https://a.example\"]}")).is_ok()
+        );
+        assert!(HeadersHandler::try_from(conf("{Autoplay: self}")).is_err());
+        assert!(HeadersHandler::try_from(conf("{autoplay: \"'self'\"}")).is_err());
+        assert!(HeadersHandler::try_from(conf("{autoplay: example.com}")).is_err());
+        assert!(HeadersHandler::try_from(conf("{autoplay: [self, \"*\"]}")).is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn alt_svc() {
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                send_response: true
+                response_headers:
+                    alt_svc:
+                    -
+                        include: example.com
+                        services:
+                            protocol: h3
+                            authority: ":443"
+                            ma: 86400
+                    -
+                        include: example.com/dir/*
+                        services:
+                        -
+                            protocol: h2
+                            authority: alt.example.com:8443
+                            persist: true
+                    -
+                        include: example.com/old/*
+                        clear: true
+            "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        let session = make_session("https://example.com/").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                ("Alt-Svc", "h3=\":443\"; ma=86400"),
+            ],
+        );
+
+        let session = make_session("https://example.com/dir/file").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                (
+                    "Alt-Svc",
+                    "h3=\":443\"; ma=86400, h2=\"alt.example.com:8443\"; persist=1",
+                ),
+            ],
+        );
+
+        let session = make_session("https://example.com/old/file").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                ("Alt-Svc", "clear"),
+            ],
+        );
+
+        let session = make_session("https://example.net/").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![("X-Me", "none"), ("X-Test", "unchanged")],
+        );
+
+        // Invalid configurations are rejected
+        let conf = |alt_svc: &str| {
+            HeadersConf::from_yaml(format!(
+                r#"
+                    response_headers:
+                        alt_svc: {alt_svc}
+                "#
+            ))
+            .unwrap()
+        };
+        assert!(HeadersHandler::try_from(conf(
+            "{services: {protocol: h3, authority: \"alt.example.com:443\"}}"
+        ))
+        .is_ok());
+        assert!(
+            HeadersHandler::try_from(conf("{services: {protocol: h3, authority: \":0\"}}"))
+                .is_err()
+        );
+        assert!(HeadersHandler::try_from(conf(
+            "{services: {protocol: h3, authority: alt.example.com}}"
+        ))
+        .is_err());
+        assert!(HeadersHandler::try_from(conf(
+            "{services: {protocol: h3, authority: \"a\\\"b:443\"}}"
+        ))
+        .is_err());
+        assert!(HeadersHandler::try_from(conf(
+            "{services: {protocol: \"h 3\", authority: \":443\"}}"
+        ))
+        .is_err());
+        assert!(HeadersHandler::try_from(conf(
+            "{clear: true, services: {protocol: h3, authority: \":443\"}}"
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn rule_limit() {
+        let conf = |max: usize| {
+            HeadersConf::from_yaml(format!(
+                r#"
+                    max_header_rules: {max}
+                    request_headers:
+                        remove: X-Request
+                    response_headers:
+                        cache_control:
+                            max-age: 60
+                        custom:
+                        - X-Test: value
+                        - include: example.com
+                          X-Other: value
+                "#
+            ))
+            .unwrap()
+        };
+
+        assert!(HeadersHandler::try_from(conf(4)).is_ok());
+        assert!(HeadersHandler::try_from(conf(3)).is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn variables() {
+        #[derive(Debug, RequestFilter)]
+        struct Handler {
+            headers: HeadersHandler,
+            rewrite: rewrite_module::RewriteHandler,
+            test: TestHandler,
+        }
+
+        let mut app: DefaultApp<Handler> = DefaultApp::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                    send_response: true
+                    variables:
+                        cdn: cdn.example.com
+                        tail: ignored
+                    rewrite_rules:
+                        from: /images/*
+                        to: https://${cdn}/images${tail}
+                        type: redirect
+                    request_headers:
+                        set:
+                            X-Request: ${cdn}
+                    response_headers:
+                        custom:
+                            X-CDN: https://${cdn}/
+                            X-Undefined: a${undefined}b
+                            X-Literal: ${not-a-variable}
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        let session = make_session("/file.txt").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_headers(
+            result.session().response_written().unwrap(),
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                ("X-Seen-Request", "cdn.example.com"),
+                ("X-CDN", "https://cdn.example.com/"),
+                ("X-Undefined", "ab"),
+                ("X-Literal", "${not-a-variable}"),
+            ],
+        );
+
+        // Request-derived variables take precedence
+        let session = make_session("/images/logo.png").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        let session = result.session();
+        let response = session.response_written().unwrap();
+        assert_eq!(response.status, 307);
+        assert_eq!(
+            response.headers.get(header::LOCATION).unwrap(),
+            "https://cdn.example.com/images/logo.png"
+        );
+        assert_eq!(
+            response.headers.get("X-CDN").unwrap(),
+            "https://cdn.example.com/"
+        );
+    }
 }
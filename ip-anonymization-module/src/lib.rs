@@ -272,4 +272,20 @@ mod tests {
             ))
         );
     }
+
+    #[test]
+    fn merge_with_opt() {
+        let mut conf = IPAnonymizationConf {
+            anonymization_enabled: true,
+        };
+        conf.merge_with_opt(IPAnonymizationOpt::parse_from(["test"]));
+        assert!(conf.anonymization_enabled);
+
+        let mut conf = IPAnonymizationConf::default();
+        conf.merge_with_opt(IPAnonymizationOpt::parse_from([
+            "test",
+            "--anonymization-enabled",
+        ]));
+        assert!(conf.anonymization_enabled);
+    }
 }
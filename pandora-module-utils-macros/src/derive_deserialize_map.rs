@@ -24,6 +24,7 @@ use crate::utils::{generics_with_de, get_fields, type_name_short, where_clause};
 struct ContainerAttributes {
     rename_all: RenameRule,
     crate_path: Path,
+    share_fields: bool,
 }
 
 impl TryFrom<&DeriveInput> for ContainerAttributes {
@@ -32,6 +33,7 @@ impl TryFrom<&DeriveInput> for ContainerAttributes {
     fn try_from(value: &DeriveInput) -> Result<Self, Self::Error> {
         let mut rename_all = RenameRule::None;
         let mut crate_path = None;
+        let mut share_fields = false;
 
         for attr in &value.attrs {
             if !attr.path().is_ident("pandora") {
@@ -66,6 +68,9 @@ impl TryFrom<&DeriveInput> for ContainerAttributes {
                     let lit: LitStr = meta.value()?.parse()?;
                     crate_path = Some(lit.parse()?);
                     Ok(())
+                } else if meta.path.is_ident("share_fields") {
+                    share_fields = true;
+                    Ok(())
                 } else {
                     Err(Error::new_spanned(meta.path, "unexpected parameter"))
                 }
@@ -81,6 +86,7 @@ impl TryFrom<&DeriveInput> for ContainerAttributes {
         Ok(Self {
             rename_all,
             crate_path,
+            share_fields,
         })
     }
 }
@@ -296,7 +302,8 @@ fn generate_deserialize_map_impl(
     let flattened_name = field_attrs
         .iter()
         .filter(|attr| attr.flatten)
-        .map(|attr| &attr.name);
+        .map(|attr| &attr.name)
+        .collect::<Vec<_>>();
     let flattened_type = field_attrs
         .iter()
         .zip(inner_type.iter())
@@ -315,6 +322,33 @@ fn generate_deserialize_map_impl(
     let regular_deserialize = regular_fields.iter().map(|attr| &attr.deserialize);
     let deserialize_name = collect_deserialize_names(&regular_fields)?;
 
+    let shared_fields = if container_attrs.share_fields {
+        quote! {
+            let mut __accepting = 0;
+            #(
+                if #flattened_type::accepts_field(field) {
+                    __accepting += 1;
+                }
+            )*
+
+            if __accepting > 1 {
+                // The field is shared by multiple flattened structures, e.g. a setting consulted
+                // by several modules. Each needs to see the value, so it has to be buffered.
+                let value = <#crate_path::serde_yaml::Value as #crate_path::serde::Deserialize>::deserialize(deserializer)?;
+                #(
+                    if #flattened_type::accepts_field(field) {
+                        self.#flattened_name = self.#flattened_name
+                            .visit_field(field, value.clone())
+                            .map_err(<D::Error as #crate_path::serde::de::Error>::custom)?;
+                    }
+                )*
+                return ::std::result::Result::Ok(self);
+            }
+        }
+    } else {
+        quote! {}
+    };
+
     Ok(quote! {
         const _: () = {
             const __FIELDS: &[&::std::primitive::str] = &[
@@ -367,6 +401,8 @@ fn generate_deserialize_map_impl(
                             }
                         )*
                         other => {
+                            #shared_fields
+
                             #(
                                 if #flattened_type::accepts_field(field) {
                                     self.#flattened_name = self.#flattened_name.visit_field(field, deserializer)?;
@@ -13,11 +13,78 @@
 // limitations under the License.
 
 use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
 use quote::quote;
-use syn::{DeriveInput, Error, FieldsNamed, Ident};
+use syn::{DeriveInput, Error, Field, FieldsNamed, Ident, LitStr};
 
 use crate::utils::{generics, get_fields, get_fields_mut, type_name_short, where_clause};
 
+#[derive(Debug, Default)]
+struct FieldAttributes {
+    handler_order: bool,
+    before: Vec<LitStr>,
+}
+
+impl TryFrom<&Field> for FieldAttributes {
+    type Error = Error;
+
+    fn try_from(field: &Field) -> Result<Self, Self::Error> {
+        let mut result = Self::default();
+        for attr in &field.attrs {
+            if !attr.path().is_ident("pandora") {
+                continue;
+            }
+
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("handler_order") {
+                    result.handler_order = true;
+                    Ok(())
+                } else if meta.path.is_ident("before") {
+                    result.before.push(meta.value()?.parse()?);
+                    Ok(())
+                } else {
+                    Err(Error::new_spanned(meta.path, "unexpected parameter"))
+                }
+            })?;
+        }
+        Ok(result)
+    }
+}
+
+/// Removes the attributes processed here, so that these don’t get applied to the configuration
+/// and context structs.
+fn strip_attributes(input: &mut DeriveInput) {
+    if let Some(fields) = get_fields_mut(input) {
+        for field in fields.named.iter_mut() {
+            field.attrs.retain(|attr| !attr.path().is_ident("pandora"));
+        }
+    }
+}
+
+/// Produces code calling the handlers in the chain.
+///
+/// If there is no handler order field, the code for each handler is simply run in sequence.
+/// Otherwise it is run in a loop, in the order determined by the handler order field.
+fn dispatch(order_field: Option<&Ident>, code: Vec<TokenStream2>) -> TokenStream2 {
+    if let Some(order_field) = order_field {
+        let index = 0..code.len();
+        quote! {
+            for __index in self.#order_field.order() {
+                match *__index {
+                    #(
+                        #index => { #code }
+                    )*
+                    _ => {}
+                }
+            }
+        }
+    } else {
+        quote! {
+            #( #code )*
+        }
+    }
+}
+
 fn generate_request_filter_impl(
     input: &DeriveInput,
     fields: &FieldsNamed,
@@ -28,6 +95,7 @@ fn generate_request_filter_impl(
 
     // Produce merged handler configuration
     let mut conf = input.clone();
+    strip_attributes(&mut conf);
     conf.ident = Ident::new("__Conf", input.ident.span());
     if let Some(fields) = get_fields_mut(&mut conf) {
         for field in fields.named.iter_mut() {
@@ -39,6 +107,7 @@ fn generate_request_filter_impl(
 
     // Produce merged context
     let mut ctx = input.clone();
+    strip_attributes(&mut ctx);
     ctx.ident = Ident::new("__CTX", input.ident.span());
     if let Some(fields) = get_fields_mut(&mut ctx) {
         for field in fields.named.iter_mut() {
@@ -60,6 +129,158 @@ fn generate_request_filter_impl(
         .map(|field| &field.ty)
         .collect::<Vec<_>>();
 
+    // Determine the handler order field and the handlers making up the chain
+    let mut order_field = None;
+    let mut chain = Vec::new();
+    let mut constraints = Vec::new();
+    for field in &fields.named {
+        let attrs = FieldAttributes::try_from(field)?;
+        let name = field.ident.as_ref();
+        if attrs.handler_order {
+            if order_field.is_some() {
+                return Err(Error::new_spanned(
+                    field,
+                    "only one field can be marked as handler_order",
+                ));
+            }
+            order_field = name;
+        } else {
+            chain.push(name);
+            let name = name.map(|name| name.to_string()).unwrap_or_default();
+            for before in attrs.before {
+                constraints.push(quote! {(#name, #before)});
+            }
+        }
+    }
+    let chain_name = chain
+        .iter()
+        .map(|name| name.map(|name| name.to_string()).unwrap_or_default())
+        .collect::<Vec<_>>();
+    let resolve_order = order_field.map(|order_field| {
+        quote! {
+            let mut #order_field = #order_field;
+            #order_field.resolve(&[#( #chain_name ),*], &[#( #constraints ),*])?;
+        }
+    });
+
+    let early_request_filter = dispatch(
+        order_field,
+        chain
+            .iter()
+            .map(|field_name| {
+                quote! {
+                    self.#field_name.early_request_filter(_session, &mut _ctx.#field_name).await?;
+                }
+            })
+            .collect(),
+    );
+    let request_filter = dispatch(
+        order_field,
+        chain
+            .iter()
+            .map(|field_name| {
+                quote! {
+                    let result = ::pandora_module_utils::request_filter_head_as_get(
+                        &self.#field_name,
+                        _session,
+                        &mut _ctx.#field_name,
+                    )
+                    .await?;
+                    if result != ::pandora_module_utils::RequestFilterResult::Unhandled {
+                        return ::std::result::Result::Ok(result);
+                    }
+                }
+            })
+            .collect(),
+    );
+    let upstream_peer = dispatch(
+        order_field,
+        chain
+            .iter()
+            .map(|field_name| {
+                quote! {
+                    if let ::std::option::Option::Some(peer) =
+                        self.#field_name.upstream_peer(_session, &mut _ctx.#field_name).await?
+                    {
+                        return ::std::result::Result::Ok(::std::option::Option::Some(peer));
+                    }
+                }
+            })
+            .collect(),
+    );
+    let upstream_response_trailer_filter = dispatch(
+        order_field,
+        chain
+            .iter()
+            .map(|field_name| {
+                quote! {
+                    self.#field_name.upstream_response_trailer_filter(
+                        _session,
+                        _trailers,
+                        &mut _ctx.#field_name,
+                    )?;
+                }
+            })
+            .collect(),
+    );
+    let fail_to_connect = dispatch(
+        order_field,
+        chain
+            .iter()
+            .map(|field_name| {
+                quote! {
+                    _e = self.#field_name.fail_to_connect(
+                        _session,
+                        _peer,
+                        &mut _ctx.#field_name,
+                        _e,
+                    );
+                }
+            })
+            .collect(),
+    );
+    let fail_to_proxy = dispatch(
+        order_field,
+        chain
+            .iter()
+            .map(|field_name| {
+                quote! {
+                    if let ::std::option::Option::Some(status) =
+                        self.#field_name.fail_to_proxy(_session, _e, &mut _ctx.#field_name).await
+                    {
+                        return ::std::option::Option::Some(status);
+                    }
+                }
+            })
+            .collect(),
+    );
+    let allowed_methods = dispatch(
+        order_field,
+        chain
+            .iter()
+            .map(|field_name| {
+                quote! {
+                    for method in self.#field_name.allowed_methods() {
+                        if !methods.contains(&method) {
+                            methods.push(method);
+                        }
+                    }
+                }
+            })
+            .collect(),
+    );
+    let logging = dispatch(
+        order_field,
+        chain
+            .iter()
+            .map(|field_name| {
+                quote! {
+                    self.#field_name.logging(_session, _e, &mut _ctx.#field_name).await;
+                }
+            })
+            .collect(),
+    );
+
     Ok(quote! {
         const _: () = {
             #[::pandora_module_utils::merge_conf]
@@ -78,6 +299,10 @@ fn generate_request_filter_impl(
                     #(
                         let #field_name = <#field_type>::try_from(conf.#field_name)?;
                     )*
+                    #resolve_order
+                    ::pandora_module_utils::PathCaseUsage::check(&[
+                        #( ::pandora_module_utils::RequestFilter::path_case_usage(&#chain), )*
+                    ])?;
                     ::std::result::Result::Ok(Self {
                         #( #field_name, )*
                     })
@@ -108,6 +333,23 @@ fn generate_request_filter_impl(
                     )*
                 }
 
+                fn handles_head() -> bool {
+                    // `HEAD` requests are presented as `GET` to individual handlers as necessary
+                    true
+                }
+
+                fn allowed_methods(&self) -> ::std::vec::Vec<::pandora_module_utils::pingora::Method> {
+                    let mut methods = ::std::vec::Vec::new();
+                    #allowed_methods
+                    methods
+                }
+
+                fn path_case_usage(&self) -> ::pandora_module_utils::PathCaseUsage {
+                    ::pandora_module_utils::PathCaseUsage::merge(&[
+                        #( self.#chain.path_case_usage(), )*
+                    ])
+                }
+
                 async fn early_request_filter(
                     &self,
                     _session: &mut impl ::pandora_module_utils::pingora::SessionWrapper,
@@ -117,9 +359,7 @@ fn generate_request_filter_impl(
                     ::std::boxed::Box<::pandora_module_utils::pingora::Error>
                 >
                 {
-                    #(
-                        self.#field_name.early_request_filter(_session, &mut _ctx.#field_name).await?;
-                    )*
+                    #early_request_filter
                     ::std::result::Result::Ok(())
                 }
 
@@ -132,12 +372,7 @@ fn generate_request_filter_impl(
                     ::std::boxed::Box<::pandora_module_utils::pingora::Error>
                 >
                 {
-                    #(
-                        let result = self.#field_name.request_filter(_session, &mut _ctx.#field_name).await?;
-                        if result != ::pandora_module_utils::RequestFilterResult::Unhandled {
-                            return ::std::result::Result::Ok(result);
-                        }
-                    )*
+                    #request_filter
                     ::std::result::Result::Ok(pandora_module_utils::RequestFilterResult::Unhandled)
                 }
 
@@ -150,25 +385,54 @@ fn generate_request_filter_impl(
                     ::std::boxed::Box<::pandora_module_utils::pingora::Error>
                 >
                 {
-                    #(
-                        if let ::std::option::Option::Some(peer) =
-                            self.#field_name.upstream_peer(_session, &mut _ctx.#field_name).await?
-                        {
-                            return ::std::result::Result::Ok(::std::option::Option::Some(peer));
-                        }
-                    )*
+                    #upstream_peer
                     ::std::result::Result::Ok(::std::option::Option::None)
                 }
 
+                fn upstream_response_trailer_filter(
+                    &self,
+                    _session: &mut impl ::pandora_module_utils::pingora::SessionWrapper,
+                    _trailers: &mut ::pandora_module_utils::pingora::HeaderMap,
+                    _ctx: &mut Self::CTX,
+                ) -> ::std::result::Result<
+                    (),
+                    ::std::boxed::Box<::pandora_module_utils::pingora::Error>
+                >
+                {
+                    #upstream_response_trailer_filter
+                    ::std::result::Result::Ok(())
+                }
+
+                fn fail_to_connect(
+                    &self,
+                    _session: &mut impl ::pandora_module_utils::pingora::SessionWrapper,
+                    _peer: &::pandora_module_utils::pingora::HttpPeer,
+                    _ctx: &mut Self::CTX,
+                    mut _e: ::std::boxed::Box<::pandora_module_utils::pingora::Error>,
+                ) -> ::std::boxed::Box<::pandora_module_utils::pingora::Error>
+                {
+                    #fail_to_connect
+                    _e
+                }
+
+                async fn fail_to_proxy(
+                    &self,
+                    _session: &mut impl ::pandora_module_utils::pingora::SessionWrapper,
+                    _e: &::pandora_module_utils::pingora::Error,
+                    _ctx: &mut Self::CTX,
+                ) -> ::std::option::Option<u16>
+                {
+                    #fail_to_proxy
+                    ::std::option::Option::None
+                }
+
                 async fn logging(
                     &self,
                     _session: &mut impl ::pandora_module_utils::pingora::SessionWrapper,
                     _e: ::std::option::Option<&::pandora_module_utils::pingora::Error>,
                     _ctx: &mut Self::CTX,
                 ) {
-                    #(
-                        self.#field_name.logging(_session, _e, &mut _ctx.#field_name).await;
-                    )*
+                    #logging
                 }
             }
         };
@@ -61,8 +61,9 @@ pub fn merge_opt(_args: TokenStream, input: TokenStream) -> TokenStream {
 
 /// This attribute macro merges the configuration settings from all structs identified as field of
 /// the current struct. It’s essentially a shortcut for deriving `Debug`, `Default` and
-/// `DeserializeMap` traits, the latter with all fields flattened. All field types are required to
-/// implement `Debug`, `Default` and `DeserializeMap`.
+/// `DeserializeMap` traits, the latter with all fields flattened. A setting accepted by multiple
+/// fields is passed to each of them. All field types are required to implement `Debug`, `Default`
+/// and `DeserializeMap`.
 ///
 /// ```rust
 /// use pandora_module_utils::{merge_conf, DeserializeMap, FromYaml};
@@ -172,7 +173,69 @@ pub fn merge_conf(_attr: TokenStream, input: TokenStream) -> TokenStream {
 ///     unknown_field: flagged
 /// "#).is_err());
 /// ```
-#[proc_macro_derive(RequestFilter)]
+///
+/// The order of the handlers can be made configurable by adding a
+/// [`HandlerOrder`](../pandora_module_utils/handler_order/struct.HandlerOrder.html) field marked
+/// with the `#[pandora(handler_order)]` attribute. The `handler_order` configuration setting then
+/// lists handler names in the order they should be called, any handlers not listed are called
+/// afterwards. A `#[pandora(before = "…")]` attribute on a handler field requires it to be called
+/// before the named handler, configurations violating this requirement are rejected:
+///
+/// ```rust
+/// use pandora_module_utils::handler_order::HandlerOrder;
+/// use pandora_module_utils::{FromYaml, RequestFilter};
+/// use compression_module::CompressionHandler;
+/// use static_files_module::StaticFilesHandler;
+///
+/// #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
+/// struct Handler {
+///     #[pandora(handler_order)]
+///     order: HandlerOrder,
+///     compression: CompressionHandler,
+///     static_files: StaticFilesHandler,
+/// }
+///
+/// type Conf = <Handler as RequestFilter>::Conf;
+///
+/// let conf = Conf::from_yaml(r#"
+///     handler_order: [static_files, compression]
+/// "#).unwrap();
+/// let handler: Handler = conf.try_into().unwrap();
+/// assert_eq!(handler.order.order(), &[1, 0]);
+///
+/// assert!(Conf::from_yaml("handler_order: [unknown]")
+///     .map(Handler::try_from)
+///     .unwrap()
+///     .is_err());
+/// ```
+///
+/// The `enabled` configuration setting of a `HandlerOrder` field allows disabling handlers by
+/// name. Disabled handlers aren’t called in any phase:
+///
+/// ```rust
+/// # use pandora_module_utils::handler_order::HandlerOrder;
+/// # use pandora_module_utils::{FromYaml, RequestFilter};
+/// # use compression_module::CompressionHandler;
+/// # use static_files_module::StaticFilesHandler;
+/// #
+/// # #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
+/// # struct Handler {
+/// #     #[pandora(handler_order)]
+/// #     order: HandlerOrder,
+/// #     compression: CompressionHandler,
+/// #     static_files: StaticFilesHandler,
+/// # }
+/// #
+/// # type Conf = <Handler as RequestFilter>::Conf;
+/// #
+/// let conf = Conf::from_yaml(r#"
+///     enabled:
+///         compression: false
+/// "#).unwrap();
+/// let handler: Handler = conf.try_into().unwrap();
+/// assert_eq!(handler.order.order(), &[1]);
+/// ```
+#[proc_macro_derive(RequestFilter, attributes(pandora))]
 pub fn derive_request_filter(input: TokenStream) -> TokenStream {
     derive_request_filter::derive_request_filter(input)
         .unwrap_or_else(|err| err.into_compile_error().into())
@@ -240,6 +303,12 @@ pub fn derive_request_filter(input: TokenStream) -> TokenStream {
 ///   Specify a path to the `pandora_module_utils` crate instance to use when referring to APIs
 ///   from generated code. This is normally only applicable when `pandora_module_utils` isn’t
 ///   accessible under its usual name but only as a re-exported name from a different crate.
+/// * `#[pandora(share_fields)]`
+///
+///   Pass settings accepted by multiple flattened fields to all of them rather than only the
+///   first one. This requires intermediate storage, so such values are deserialized into
+///   `serde_yaml::Value` first. The `merge_conf` macro sets this attribute, allowing modules to
+///   consult common settings.
 ///
 /// Unknown fields will cause a deserialization error, missing fields will be left at their initial
 /// value. This is similar to the behavior of
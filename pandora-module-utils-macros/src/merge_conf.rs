@@ -22,13 +22,15 @@ use crate::utils::get_fields_mut;
 pub(crate) fn merge_conf(input: TokenStream) -> Result<TokenStream, Error> {
     let mut input: DeriveInput = syn::parse(input)?;
 
-    // Derive Debug, Default and DeserializeMap
+    // Derive Debug, Default and DeserializeMap, settings accepted by multiple fields are passed
+    // to all of them
     let attributes = quote! {
         #[derive(
             ::std::fmt::Debug,
             ::std::default::Default,
             ::pandora_module_utils::DeserializeMap
         )]
+        #[pandora(share_fields)]
     };
     let attributes = Attribute::parse_outer.parse2(attributes)?;
     input.attrs.extend(attributes);
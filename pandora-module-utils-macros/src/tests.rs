@@ -13,8 +13,9 @@
 // limitations under the License.
 
 use async_trait::async_trait;
+use pandora_module_utils::handler_order::HandlerOrder;
 use pandora_module_utils::pingora::{
-    create_test_session, Error, ErrorType, RequestHeader, SessionWrapper,
+    create_test_session, Error, ErrorType, Method, RequestHeader, SessionWrapper,
 };
 use pandora_module_utils::serde::{Deserialize, Deserializer};
 use pandora_module_utils::{
@@ -25,6 +26,10 @@ use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
 use test_log::test;
 
+/// Request methods seen by the handlers, stored in session extensions
+#[derive(Debug, Default, Clone)]
+struct SeenMethods(Vec<Method>);
+
 #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
 struct Handler1Conf {
     handle_request: bool,
@@ -52,11 +57,21 @@ impl RequestFilter for Handler1 {
 
     fn new_ctx() -> Self::CTX {}
 
+    fn allowed_methods(&self) -> Vec<Method> {
+        vec![Method::GET, Method::POST]
+    }
+
     async fn request_filter(
         &self,
-        _session: &mut (impl SessionWrapper),
+        session: &mut (impl SessionWrapper),
         _ctx: &mut Self::CTX,
     ) -> Result<RequestFilterResult, Box<Error>> {
+        let method = session.req_header().method.clone();
+        session
+            .extensions_mut()
+            .get_or_insert_default::<SeenMethods>()
+            .0
+            .push(method);
         Ok(if self.handle_request {
             RequestFilterResult::ResponseSent
         } else {
@@ -115,11 +130,25 @@ impl<T: Default + Sync, U: Default + Sync> RequestFilter for Handler2<T, U> {
         }
     }
 
+    fn handles_head() -> bool {
+        true
+    }
+
+    fn allowed_methods(&self) -> Vec<Method> {
+        vec![Method::PUT, Method::POST]
+    }
+
     async fn request_filter(
         &self,
-        _session: &mut (impl SessionWrapper),
+        session: &mut (impl SessionWrapper),
         ctx: &mut Self::CTX,
     ) -> Result<RequestFilterResult, Box<Error>> {
+        let method = session.req_header().method.clone();
+        session
+            .extensions_mut()
+            .get_or_insert_default::<SeenMethods>()
+            .0
+            .push(method);
         ctx.value1 = self.conf.value3;
         Ok(RequestFilterResult::Unhandled)
     }
@@ -142,6 +171,9 @@ fn request_filter() {
     assert_eq!(conf.handler2.value2, u32::default());
     assert_eq!(conf.handler2.value3, 1234u32);
 
+    assert!(!Handler1::handles_head());
+    assert!(<Handler<String, u32> as RequestFilter>::handles_head());
+
     let ctx = <Handler<String, u32> as RequestFilter>::new_ctx();
     assert_eq!(ctx.handler2.value1, 4321u32);
     assert_eq!(ctx.handler2.value2, String::from("Hi!"));
@@ -175,6 +207,10 @@ async fn handler() -> Result<(), Box<Error>> {
 
     let conf = <Handler<String, u32> as RequestFilter>::Conf::default();
     let handler = Handler::<String, u32>::try_from(conf).unwrap();
+    assert_eq!(
+        handler.allowed_methods(),
+        vec![Method::PUT, Method::POST, Method::GET]
+    );
     let mut app = DefaultApp::new(handler);
 
     let result = app.handle_request(session).await;
@@ -194,6 +230,18 @@ async fn handler() -> Result<(), Box<Error>> {
     let result = app.handle_request(session).await;
     assert!(result.err().is_none());
 
+    // Only the handler not processing HEAD requests itself sees a GET request
+    let header = RequestHeader::build("HEAD", "/".as_bytes(), None)?;
+    let session = create_test_session(header).await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    let session = result.session();
+    assert_eq!(session.req_header().method, Method::HEAD);
+    assert_eq!(
+        session.extensions().get::<SeenMethods>().unwrap().0,
+        vec![Method::HEAD, Method::GET]
+    );
+
     Ok(())
 }
 
@@ -336,6 +384,46 @@ fn field_attributes() {
     assert_eq!(conf.value6.value, String::new());
 }
 
+#[test]
+fn shared_fields() {
+    #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+    struct Conf1 {
+        shared: HashMap<String, String>,
+        value1: u32,
+    }
+
+    #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+    struct Conf2 {
+        shared: HashMap<String, String>,
+        value2: u32,
+    }
+
+    #[merge_conf]
+    struct Conf {
+        conf1: Conf1,
+        conf2: Conf2,
+    }
+
+    // Fields accepted by multiple structures are passed to each of them
+    let conf = Conf::from_yaml(
+        r#"
+            value1: 1
+            shared:
+                a: b
+            value2: 2
+        "#,
+    )
+    .unwrap();
+    let expected = HashMap::from([("a".to_owned(), "b".to_owned())]);
+    assert_eq!(conf.conf1.shared, expected);
+    assert_eq!(conf.conf1.value1, 1);
+    assert_eq!(conf.conf2.shared, expected);
+    assert_eq!(conf.conf2.value2, 2);
+
+    // Errors are still reported
+    Conf::from_yaml("shared: [a, b]").unwrap_err();
+}
+
 #[test]
 fn from_yaml_seed() {
     fn assert_hash_eq<V: Debug + Eq>(left: &HashMap<String, V>, right: Vec<(&str, V)>) {
@@ -743,3 +831,193 @@ fn deserialize_merge_across_options() {
         }
     );
 }
+
+macro_rules! recording_handler {
+    ($handler:ident, $conf:ident, $respond:ident, $name:literal, $method:ident) => {
+        #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+        struct $conf {
+            $respond: bool,
+        }
+
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        struct $handler {
+            respond: bool,
+        }
+
+        impl TryFrom<$conf> for $handler {
+            type Error = Box<Error>;
+
+            fn try_from(conf: $conf) -> Result<Self, Self::Error> {
+                Ok(Self {
+                    respond: conf.$respond,
+                })
+            }
+        }
+
+        #[async_trait]
+        impl RequestFilter for $handler {
+            type Conf = $conf;
+            type CTX = ();
+
+            fn new_ctx() -> Self::CTX {}
+
+            fn allowed_methods(&self) -> Vec<Method> {
+                vec![Method::$method]
+            }
+
+            async fn request_filter(
+                &self,
+                session: &mut (impl SessionWrapper),
+                _ctx: &mut Self::CTX,
+            ) -> Result<RequestFilterResult, Box<Error>> {
+                let extensions = session.extensions_mut();
+                if let Some(calls) = extensions.get_mut::<Vec<&'static str>>() {
+                    calls.push($name);
+                } else {
+                    extensions.insert(vec![$name]);
+                }
+
+                Ok(if self.respond {
+                    session.respond_error(200).await?;
+                    RequestFilterResult::ResponseSent
+                } else {
+                    RequestFilterResult::Unhandled
+                })
+            }
+        }
+    };
+}
+
+recording_handler!(FirstHandler, FirstConf, first_respond, "first", PUT);
+recording_handler!(SecondHandler, SecondConf, second_respond, "second", POST);
+recording_handler!(ThirdHandler, ThirdConf, third_respond, "third", DELETE);
+
+#[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
+struct OrderedHandler {
+    #[pandora(handler_order)]
+    order: HandlerOrder,
+    #[pandora(before = "third")]
+    first: FirstHandler,
+    second: SecondHandler,
+    third: ThirdHandler,
+}
+
+#[test(tokio::test)]
+async fn handler_order() -> Result<(), Box<Error>> {
+    async fn calls(conf: &str) -> Vec<&'static str> {
+        let conf = <OrderedHandler as RequestFilter>::Conf::from_yaml(conf).unwrap();
+        let handler = OrderedHandler::try_from(conf).unwrap();
+        let mut app = DefaultApp::new(handler);
+
+        let header = RequestHeader::build("GET", "/".as_bytes(), None).unwrap();
+        let session = create_test_session(header).await;
+        let mut result = app.handle_request(session).await;
+        let calls = result
+            .session()
+            .extensions()
+            .get::<Vec<&'static str>>()
+            .cloned();
+        calls.unwrap_or_default()
+    }
+
+    assert_eq!(calls("{}").await, vec!["first", "second", "third"]);
+    assert_eq!(
+        calls("handler_order: second").await,
+        vec!["second", "first", "third"]
+    );
+    assert_eq!(
+        calls("handler_order: [first, third, second]").await,
+        vec!["first", "third", "second"]
+    );
+    assert_eq!(
+        calls(
+            r#"
+                handler_order: [first, third, second]
+                third_respond: true
+            "#
+        )
+        .await,
+        vec!["first", "third"]
+    );
+
+    assert_eq!(
+        calls(
+            r#"
+                enabled: {first: false, third: true}
+                first_respond: true
+            "#
+        )
+        .await,
+        vec!["second", "third"]
+    );
+    assert_eq!(
+        calls(
+            r#"
+                handler_order: [third, second]
+                enabled: {first: false}
+            "#
+        )
+        .await,
+        vec!["third", "second"]
+    );
+
+    let handler = |conf: &str| {
+        OrderedHandler::try_from(<OrderedHandler as RequestFilter>::Conf::from_yaml(conf).unwrap())
+    };
+    assert!(handler("handler_order: [third, first]").is_err());
+    assert!(handler("handler_order: [first, first]").is_err());
+    assert!(handler("handler_order: unknown").is_err());
+    assert!(handler("handler_order: order").is_err());
+    assert!(handler("enabled: {unknown: false}").is_err());
+
+    // Disabled handlers don’t advertise their methods
+    assert_eq!(
+        handler("{}").unwrap().allowed_methods(),
+        vec![Method::PUT, Method::POST, Method::DELETE]
+    );
+    assert_eq!(
+        handler("handler_order: [third, second]\nenabled: {first: false}")
+            .unwrap()
+            .allowed_methods(),
+        vec![Method::DELETE, Method::POST]
+    );
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn handler_order_nested() -> Result<(), Box<Error>> {
+    #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
+    struct InnerHandler {
+        second: SecondHandler,
+        third: ThirdHandler,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
+    struct OuterHandler {
+        #[pandora(handler_order)]
+        order: HandlerOrder,
+        #[pandora(before = "inner")]
+        first: FirstHandler,
+        inner: InnerHandler,
+    }
+
+    let handler = |conf: &str| {
+        OuterHandler::try_from(<OuterHandler as RequestFilter>::Conf::from_yaml(conf).unwrap())
+    };
+
+    let mut app = DefaultApp::new(handler("first_respond: true\nthird_respond: true")?);
+    let header = RequestHeader::build("GET", "/".as_bytes(), None).unwrap();
+    let session = create_test_session(header).await;
+    let mut result = app.handle_request(session).await;
+    assert_eq!(
+        result.session().extensions().get::<Vec<&'static str>>(),
+        Some(&vec!["first"])
+    );
+
+    // Handlers of the nested chain cannot be moved before the handler preceding it
+    assert!(handler("handler_order: [inner, first]").is_err());
+    assert!(handler("handler_order: inner").is_err());
+
+    Ok(())
+}
@@ -0,0 +1,63 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compares the time needed to build a large router with the time needed to load it from its
+//! serialized representation. Run via `cargo bench -p pandora-module-utils`.
+
+use pandora_module_utils::router::Router;
+use std::time::{Duration, Instant};
+
+const TENANTS: usize = 200;
+const RULES_PER_TENANT: usize = 100;
+const ITERATIONS: u32 = 5;
+
+fn build() -> Router<String> {
+    let mut builder = Router::builder();
+    for tenant in 0..TENANTS {
+        let host = format!("tenant{tenant}.example.com");
+        for rule in 0..RULES_PER_TENANT {
+            builder.push(
+                &host,
+                format!("/section{}/page{rule}/", rule % 10),
+                format!("X-Rule: {tenant}/{rule}"),
+                Some(format!("X-Prefix-Rule: {tenant}/{rule}")),
+            );
+        }
+    }
+    builder.build()
+}
+
+fn measure<T>(f: impl Fn() -> T) -> (Duration, T) {
+    let start = Instant::now();
+    let mut result = f();
+    for _ in 1..ITERATIONS {
+        result = f();
+    }
+    (start.elapsed() / ITERATIONS, result)
+}
+
+fn main() {
+    let (build_time, router) = measure(build);
+    let data = router.to_bytes().unwrap();
+    let (load_time, loaded) = measure(|| Router::<String>::from_bytes(&data).unwrap());
+    assert_eq!(loaded, router);
+
+    println!(
+        "{} rules, {} bytes serialized",
+        TENANTS * RULES_PER_TENANT,
+        data.len()
+    );
+    println!("build: {build_time:?}");
+    println!("load:  {load_time:?}");
+}
@@ -0,0 +1,175 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compression of response bodies produced by handlers
+//!
+//! Pingora’s dynamic compression doesn’t apply to all responses written by handlers. Handlers
+//! producing the same response repeatedly can compress the body once via [`PrecompressedBody`]
+//! instead and select the variant matching the request’s `Accept-Encoding` header.
+
+use bytes::Bytes;
+use http::{header, HeaderValue};
+use log::error;
+use std::io::Write;
+use std::str::FromStr;
+
+use crate::pingora::{CompressionAlgorithm, Error, ErrorType, ResponseHeader, SessionWrapper};
+
+/// Determines the quality value the `Accept-Encoding` header assigns to the given algorithm,
+/// ranging from 0 (not acceptable) to 1000.
+fn quality(accept_encoding: &str, algorithm: CompressionAlgorithm) -> u16 {
+    let mut wildcard = 0;
+    for entry in accept_encoding.split(',') {
+        let mut params = entry.split(';');
+        let name = params.next().unwrap_or("").trim();
+        let quality = params
+            .filter_map(|param| param.split_once('='))
+            .find(|(name, _)| name.trim() == "q")
+            .and_then(|(_, value)| f64::from_str(value.trim()).ok())
+            .map(|value| (value.clamp(0.0, 1.0) * 1000.0) as u16)
+            .unwrap_or(1000);
+
+        if name == "*" {
+            wildcard = quality;
+        } else if !name.is_empty() && CompressionAlgorithm::from(name) == algorithm {
+            return quality;
+        }
+    }
+    wildcard
+}
+
+/// Compresses data with the given algorithm and compression level.
+fn compress(data: &[u8], algorithm: CompressionAlgorithm, level: u32) -> Result<Bytes, Box<Error>> {
+    if algorithm == CompressionAlgorithm::Brotli {
+        // Pingora’s Brotli compressor only flushes the stream but never terminates it
+        let mut compressor = brotli::CompressorWriter::new(Vec::new(), 4096, level, 22);
+        compressor.write_all(data).map_err(|err| {
+            Error::because(ErrorType::InternalError, "failed compressing data", err)
+        })?;
+        return Ok(compressor.into_inner().into());
+    }
+
+    let Some(mut compressor) = algorithm.compressor(level) else {
+        error!(
+            "compression algorithm {} with level {level} isn’t supported",
+            algorithm.as_str()
+        );
+        return Err(Error::new(ErrorType::InternalError));
+    };
+    compressor.encode(data, true)
+}
+
+/// A response body along with its compressed variants
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrecompressedBody {
+    identity: Bytes,
+    compressed: Vec<(CompressionAlgorithm, Bytes)>,
+}
+
+impl PrecompressedBody {
+    /// Compresses the body with each of the given algorithms and compression levels. The order
+    /// of the algorithms determines the preference if the client accepts several of them equally.
+    ///
+    /// Compressed variants that aren’t smaller than the original body are discarded.
+    pub fn new(
+        body: impl Into<Bytes>,
+        algorithms: &[(CompressionAlgorithm, u32)],
+    ) -> Result<Self, Box<Error>> {
+        let identity = body.into();
+        let mut compressed = Vec::new();
+        for (algorithm, level) in algorithms {
+            let data = compress(&identity, *algorithm, *level)?;
+            if data.len() < identity.len() {
+                compressed.push((*algorithm, data));
+            }
+        }
+
+        Ok(Self {
+            identity,
+            compressed,
+        })
+    }
+
+    /// Returns `true` if there are compressed variants of the body, meaning that the response
+    /// depends on the `Accept-Encoding` request header.
+    pub fn is_compressed(&self) -> bool {
+        !self.compressed.is_empty()
+    }
+
+    /// Selects the body variant to be sent in response to the current request. Returns the
+    /// compression algorithm used if any along with the body.
+    pub fn select(&self, session: &impl SessionWrapper) -> (Option<CompressionAlgorithm>, &Bytes) {
+        let accept_encoding = session
+            .req_header()
+            .headers
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("");
+
+        let mut best = (0, None, &self.identity);
+        for (algorithm, data) in &self.compressed {
+            let quality = quality(accept_encoding, *algorithm);
+            if quality > best.0 {
+                best = (quality, Some(*algorithm), data);
+            }
+        }
+        (best.1, best.2)
+    }
+
+    /// Selects the body variant to be sent in response to the current request and adjusts the
+    /// response header accordingly: sets `Content-Length`, `Content-Encoding` and `Vary` headers.
+    pub fn apply(
+        &self,
+        session: &impl SessionWrapper,
+        header: &mut ResponseHeader,
+    ) -> Result<Bytes, Box<Error>> {
+        let (algorithm, body) = self.select(session);
+        header.insert_header(header::CONTENT_LENGTH, body.len())?;
+        if let Some(algorithm) = algorithm {
+            header.insert_header(header::CONTENT_ENCODING, algorithm.as_str())?;
+        }
+        if self.is_compressed() {
+            header.append_header(header::VARY, HeaderValue::from_static("Accept-Encoding"))?;
+        }
+        Ok(body.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_encoding() {
+        assert_eq!(quality("", CompressionAlgorithm::Brotli), 0);
+        assert_eq!(quality("br", CompressionAlgorithm::Brotli), 1000);
+        assert_eq!(quality("gzip, BR", CompressionAlgorithm::Brotli), 1000);
+        assert_eq!(quality("gzip", CompressionAlgorithm::Brotli), 0);
+        assert_eq!(
+            quality("gzip;q=0.5, br;q=0.8", CompressionAlgorithm::Gzip),
+            500
+        );
+        assert_eq!(
+            quality("gzip;q=0.5, br;q=0.8", CompressionAlgorithm::Brotli),
+            800
+        );
+        assert_eq!(quality("br;q=0", CompressionAlgorithm::Brotli), 0);
+        assert_eq!(quality("*", CompressionAlgorithm::Zstd), 1000);
+        assert_eq!(quality("*;q=0.1, zstd;q=0", CompressionAlgorithm::Zstd), 0);
+        assert_eq!(
+            quality("*;q=0.1, zstd;q=0", CompressionAlgorithm::Gzip),
+            100
+        );
+    }
+}
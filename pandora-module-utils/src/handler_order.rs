@@ -0,0 +1,149 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Configurable order of the handlers in a chain
+//!
+//! A handler chain produced by deriving [`RequestFilter`] calls its handlers in the order of the
+//! struct fields. Adding a [`HandlerOrder`] field marked with `#[pandora(handler_order)]` allows
+//! changing that order via the `handler_order` configuration setting, see
+//! [`RequestFilter`](derive@crate::RequestFilter) derive macro for an example.
+//!
+//! Handlers not listed in the configuration run after the listed ones, in their default order.
+//! A field marked with `#[pandora(before = "other_field")]` has to run before the named handler,
+//! configurations violating this constraint are rejected.
+//!
+//! The `enabled` configuration setting maps handler names to a boolean value. Handlers mapped to
+//! `false` are skipped entirely, their configuration is still loaded and validated however.
+
+use async_trait::async_trait;
+use log::error;
+use std::collections::HashMap;
+
+use crate::pingora::{Error, ErrorType};
+use crate::{DeserializeMap, OneOrMany, RequestFilter};
+
+/// Configuration settings of the handler order
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+#[pandora(crate = "crate")]
+pub struct HandlerOrderConf {
+    /// Names of the handlers in the order they should be called
+    pub handler_order: OneOrMany<String>,
+    /// Handlers mapped to `false` are disabled and won’t be called
+    pub enabled: HashMap<String, bool>,
+}
+
+/// Handler order of a chain, determined from configuration
+///
+/// This handler doesn’t process any requests itself. See [module documentation](self) for usage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandlerOrder {
+    names: Vec<String>,
+    enabled: Vec<(String, bool)>,
+    order: Vec<usize>,
+}
+
+impl HandlerOrder {
+    /// Resolves the configured handler names into handler indices.
+    ///
+    /// `handlers` lists the names of all handlers in the chain in their default order. Disabled
+    /// handlers are left out of the resulting order.
+    /// `constraints` lists pairs of handler names, with the first handler required to run before
+    /// the second. Constraints referring to handlers not present in the chain are ignored.
+    ///
+    /// This is called by the `RequestFilter` derive macro, it shouldn’t usually be called
+    /// directly.
+    pub fn resolve(
+        &mut self,
+        handlers: &[&str],
+        constraints: &[(&str, &str)],
+    ) -> Result<(), Box<Error>> {
+        let mut order = Vec::with_capacity(handlers.len());
+        for name in &self.names {
+            let Some(index) = handlers.iter().position(|handler| handler == name) else {
+                error!(
+                    "unknown handler {name} in handler_order setting, known handlers are: {}",
+                    handlers.join(", ")
+                );
+                return Err(Error::new(ErrorType::InternalError));
+            };
+            if order.contains(&index) {
+                error!("handler {name} listed multiple times in handler_order setting");
+                return Err(Error::new(ErrorType::InternalError));
+            }
+            order.push(index);
+        }
+
+        for index in 0..handlers.len() {
+            if !order.contains(&index) {
+                order.push(index);
+            }
+        }
+
+        for (name, enabled) in &self.enabled {
+            let Some(index) = handlers.iter().position(|handler| handler == name) else {
+                error!(
+                    "unknown handler {name} in enabled setting, known handlers are: {}",
+                    handlers.join(", ")
+                );
+                return Err(Error::new(ErrorType::InternalError));
+            };
+            if !enabled {
+                order.retain(|i| *i != index);
+            }
+        }
+
+        let position = |name: &str| {
+            let index = handlers.iter().position(|handler| *handler == name)?;
+            order.iter().position(|i| *i == index)
+        };
+        for (first, second) in constraints {
+            if let (Some(first_pos), Some(second_pos)) = (position(first), position(second)) {
+                if first_pos > second_pos {
+                    error!("handler_order setting is invalid, handler {first} has to run before {second}");
+                    return Err(Error::new(ErrorType::InternalError));
+                }
+            }
+        }
+
+        self.order = order;
+        Ok(())
+    }
+
+    /// Returns the indices of the handlers in the order they should be called.
+    pub fn order(&self) -> &[usize] {
+        &self.order
+    }
+}
+
+impl TryFrom<HandlerOrderConf> for HandlerOrder {
+    type Error = Box<Error>;
+
+    fn try_from(conf: HandlerOrderConf) -> Result<Self, Self::Error> {
+        let mut enabled = conf.enabled.into_iter().collect::<Vec<_>>();
+        enabled.sort();
+
+        Ok(Self {
+            names: conf.handler_order.into(),
+            enabled,
+            order: Vec::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl RequestFilter for HandlerOrder {
+    type Conf = HandlerOrderConf;
+    type CTX = ();
+    fn new_ctx() -> Self::CTX {}
+}
@@ -0,0 +1,389 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Matching of client IP addresses against configured address ranges
+//!
+//! Address ranges are given in CIDR notation like `192.168.0.0/16` or `fd00::/8`, a plain
+//! address like `127.0.0.1` is a range containing only this address. [`IpMatchRules`] combine
+//! `include` and `exclude` ranges, the most specific range containing an address decides:
+//!
+//! ```rust
+//! use pandora_module_utils::ip_matcher::{IpMatchRules, IpMatcher};
+//! use pandora_module_utils::FromYaml;
+//!
+//! let rules = IpMatchRules::from_yaml(
+//!     r#"
+//!         include: [10.0.0.0/8, "::1"]
+//!         exclude: 10.1.0.0/16
+//!     "#,
+//! )
+//! .unwrap();
+//! let matcher = IpMatcher::from(&rules);
+//! assert!(matcher.matches(&"10.2.3.4".parse().unwrap()));
+//! assert!(!matcher.matches(&"10.1.3.4".parse().unwrap()));
+//! assert!(matcher.matches(&"::1".parse().unwrap()));
+//! assert!(!matcher.matches(&"192.168.1.1".parse().unwrap()));
+//! ```
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::{DeserializeMap, OneOrMany};
+
+/// Error type produced for invalid address ranges
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidIpRange(String);
+
+impl Display for InvalidIpRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid IP address range {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidIpRange {}
+
+/// An IP address range in CIDR notation, e.g. `192.168.0.0/16`
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    /// Network address of the range, with any bits beyond the prefix set to zero
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Number of leading bits an address has to share with the network address
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Checks whether the range contains the given address
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, normalize(addr)) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                mask_v4(addr.into(), self.prefix_len) == u32::from(network)
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                mask_v6(addr.into(), self.prefix_len) == u128::from(network)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Debug for IpRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+impl Display for IpRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = InvalidIpRange;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidIpRange(value.to_owned());
+
+        let (addr, prefix_len) = match value.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (value, None),
+        };
+        let addr = IpAddr::from_str(addr.trim()).map_err(|_| invalid())?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => u8::from_str(prefix_len.trim()).map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+
+        let (addr, prefix_len) = match addr {
+            IpAddr::V4(addr) => (
+                IpAddr::V4(mask_v4(addr.into(), prefix_len).into()),
+                prefix_len,
+            ),
+            IpAddr::V6(addr) => {
+                if let Some(addr) = addr.to_ipv4_mapped() {
+                    // IPv4-mapped IPv6 range, e.g. ::ffff:10.0.0.0/104
+                    let prefix_len = prefix_len.checked_sub(96).ok_or_else(invalid)?;
+                    (
+                        IpAddr::V4(mask_v4(addr.into(), prefix_len).into()),
+                        prefix_len,
+                    )
+                } else {
+                    (
+                        IpAddr::V6(mask_v6(addr.into(), prefix_len).into()),
+                        prefix_len,
+                    )
+                }
+            }
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl TryFrom<&str> for IpRange {
+    type Error = InvalidIpRange;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = InvalidIpRange;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Treats IPv4-mapped IPv6 addresses like `::ffff:127.0.0.1` as the respective IPv4 addresses,
+/// these are commonly seen on dual-stack sockets.
+fn normalize(addr: &IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(*addr),
+        IpAddr::V4(_) => *addr,
+    }
+}
+
+fn mask_v4(addr: u32, prefix_len: u8) -> u32 {
+    addr.checked_shr(32 - prefix_len as u32)
+        .and_then(|addr| addr.checked_shl(32 - prefix_len as u32))
+        .unwrap_or(0)
+}
+
+fn mask_v6(addr: u128, prefix_len: u8) -> u128 {
+    addr.checked_shr(128 - prefix_len as u32)
+        .and_then(|addr| addr.checked_shl(128 - prefix_len as u32))
+        .unwrap_or(0)
+}
+
+/// Include and exclude rules for client IP addresses
+///
+/// If no rule contains an address, it is considered a match only if there are no include rules.
+/// Otherwise the most specific rule containing the address decides, exclude rules win over
+/// include rules for identical ranges.
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+#[pandora(crate = "crate")]
+pub struct IpMatchRules {
+    /// Address ranges to be matched
+    pub include: OneOrMany<IpRange>,
+    /// Address ranges to be excluded from matching
+    pub exclude: OneOrMany<IpRange>,
+}
+
+impl IpMatchRules {
+    /// Checks whether any rules are configured
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+}
+
+/// Rules of a single address family, grouped by prefix length in descending order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct FamilyRules<T: Eq + Hash> {
+    by_prefix_len: Vec<(u8, HashMap<T, bool>)>,
+}
+
+impl<T: Copy + Eq + Hash> FamilyRules<T> {
+    fn insert(&mut self, network: T, prefix_len: u8, include: bool) {
+        let index = match self
+            .by_prefix_len
+            .binary_search_by(|(len, _)| prefix_len.cmp(len))
+        {
+            Ok(index) => index,
+            Err(index) => {
+                self.by_prefix_len
+                    .insert(index, (prefix_len, HashMap::new()));
+                index
+            }
+        };
+        let networks = &mut self.by_prefix_len[index].1;
+        if include {
+            networks.entry(network).or_insert(true);
+        } else {
+            networks.insert(network, false);
+        }
+    }
+
+    fn lookup(&self, addr: T, mask: impl Fn(T, u8) -> T) -> Option<bool> {
+        self.by_prefix_len
+            .iter()
+            .find_map(|(prefix_len, networks)| networks.get(&mask(addr, *prefix_len)).copied())
+    }
+}
+
+/// Matcher classifying IP addresses according to [`IpMatchRules`]
+///
+/// The lookup cost depends on the number of distinct prefix lengths configured rather than the
+/// number of rules.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpMatcher {
+    v4: FamilyRules<u32>,
+    v6: FamilyRules<u128>,
+    default: bool,
+}
+
+impl IpMatcher {
+    /// Checks whether the address matches the rules
+    pub fn matches(&self, addr: &IpAddr) -> bool {
+        let result = match normalize(addr) {
+            IpAddr::V4(addr) => self.v4.lookup(addr.into(), mask_v4),
+            IpAddr::V6(addr) => self.v6.lookup(addr.into(), mask_v6),
+        };
+        result.unwrap_or(self.default)
+    }
+}
+
+impl From<&IpMatchRules> for IpMatcher {
+    fn from(rules: &IpMatchRules) -> Self {
+        let mut matcher = Self {
+            default: rules.include.is_empty(),
+            ..Default::default()
+        };
+        for (ranges, include) in [(&rules.include, true), (&rules.exclude, false)] {
+            for range in ranges.iter() {
+                match range.addr {
+                    IpAddr::V4(addr) => matcher.v4.insert(addr.into(), range.prefix_len, include),
+                    IpAddr::V6(addr) => matcher.v6.insert(addr.into(), range.prefix_len, include),
+                }
+            }
+        }
+        matcher
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(include: &[&str], exclude: &[&str]) -> IpMatcher {
+        let parse = |ranges: &[&str]| {
+            ranges
+                .iter()
+                .map(|range| range.parse().unwrap())
+                .collect::<Vec<_>>()
+                .into()
+        };
+        IpMatcher::from(&IpMatchRules {
+            include: parse(include),
+            exclude: parse(exclude),
+        })
+    }
+
+    fn matches(matcher: &IpMatcher, addr: &str) -> bool {
+        matcher.matches(&addr.parse().unwrap())
+    }
+
+    #[test]
+    fn parsing() {
+        let range = IpRange::from_str("192.168.1.1/16").unwrap();
+        assert_eq!(range.to_string(), "192.168.0.0/16");
+        assert_eq!(
+            IpRange::from_str("10.0.0.1").unwrap().to_string(),
+            "10.0.0.1/32"
+        );
+        assert_eq!(
+            IpRange::from_str("0.0.0.0/0").unwrap().to_string(),
+            "0.0.0.0/0"
+        );
+        assert_eq!(
+            IpRange::from_str("fd00::1/8").unwrap().to_string(),
+            "fd00::/8"
+        );
+        assert_eq!(IpRange::from_str("::1").unwrap().to_string(), "::1/128");
+        assert_eq!(
+            IpRange::from_str("::ffff:10.1.2.3/104")
+                .unwrap()
+                .to_string(),
+            "10.0.0.0/8"
+        );
+
+        for invalid in [
+            "",
+            "10.0.0.0/",
+            "10.0.0.0/33",
+            "10.0.0/8",
+            "fd00::/129",
+            "::ffff:10.0.0.0/64",
+            "example.com",
+        ] {
+            assert!(IpRange::from_str(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn contains() {
+        let range = IpRange::from_str("10.1.0.0/16").unwrap();
+        assert!(range.contains(&"10.1.255.3".parse().unwrap()));
+        assert!(range.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!range.contains(&"10.2.0.1".parse().unwrap()));
+        assert!(!range.contains(&"::1".parse().unwrap()));
+
+        let range = IpRange::from_str("::/0").unwrap();
+        assert!(range.contains(&"2001:db8::1".parse().unwrap()));
+        assert!(!range.contains(&"10.2.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn include_exclude() {
+        let m = matcher(&[], &[]);
+        assert!(matches(&m, "10.0.0.1"));
+        assert!(matches(&m, "::1"));
+
+        let m = matcher(
+            &["10.0.0.0/8", "10.1.2.0/24", "2001:db8::/32"],
+            &["10.1.0.0/16"],
+        );
+        assert!(matches(&m, "10.0.0.1"));
+        assert!(!matches(&m, "10.1.0.1"));
+        assert!(matches(&m, "10.1.2.3"));
+        assert!(matches(&m, "::ffff:10.1.2.3"));
+        assert!(!matches(&m, "11.0.0.1"));
+        assert!(matches(&m, "2001:db8:1::1"));
+        assert!(!matches(&m, "2001:db9::1"));
+
+        // Only exclude rules, everything else matches
+        let m = matcher(&[], &["192.168.0.0/16", "fe80::/10"]);
+        assert!(matches(&m, "10.0.0.1"));
+        assert!(!matches(&m, "192.168.1.1"));
+        assert!(!matches(&m, "fe80::1"));
+        assert!(matches(&m, "2001:db8::1"));
+
+        // Exclude wins for identical ranges
+        let m = matcher(&["10.0.0.0/8"], &["10.0.0.0/8"]);
+        assert!(!matches(&m, "10.0.0.1"));
+
+        // Catch-all ranges
+        let m = matcher(&["0.0.0.0/0"], &["127.0.0.1"]);
+        assert!(matches(&m, "1.2.3.4"));
+        assert!(!matches(&m, "127.0.0.1"));
+        assert!(!matches(&m, "::1"));
+    }
+}
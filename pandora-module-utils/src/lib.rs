@@ -15,24 +15,28 @@
 #![doc = include_str!("../README.md")]
 #![allow(non_ascii_idents)]
 
+pub mod compression;
 mod deserialize;
+pub mod handler_order;
+pub mod ip_matcher;
 #[doc(hidden)]
 pub mod jar;
 pub mod merger;
+pub mod metrics;
 pub mod pingora;
 pub mod router;
 pub mod standard_response;
 mod trie;
 
 use log::{error, info, trace};
-use pingora::{Error, ErrorType, HttpModules, HttpPeer, SessionWrapper};
+use pingora::{Error, ErrorType, HeaderMap, HttpModules, HttpPeer, Method, SessionWrapper};
 use serde::{de::DeserializeSeed, Deserialize};
 use std::fmt::Debug;
 use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
 
-pub use deserialize::{DeserializeMap, MapVisitor, OneOrMany, _private};
+pub use deserialize::{_private, DeserializeMap, MapVisitor, OneOrMany};
 pub use pandora_module_utils_macros::{merge_conf, merge_opt, DeserializeMap, RequestFilter};
 
 // Required for macros
@@ -61,6 +65,48 @@ pub enum RequestFilterResult {
     Unhandled,
 }
 
+/// Describes how a handler treats letter case in request paths, see
+/// [`RequestFilter::path_case_usage`].
+///
+/// A handler ignoring letter case when looking up resources mustn’t be combined with handlers
+/// restricting access by case-sensitive path matching: a rule for `/admin/*` wouldn’t protect a
+/// file served for `/Admin/`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct PathCaseUsage {
+    /// The handler ignores ASCII letter case when mapping request paths to resources
+    pub case_insensitive: bool,
+    /// The handler restricts access via rules matching paths case-sensitively
+    pub case_sensitive_rules: bool,
+    /// The handler restricts access to all requests it processes
+    pub restricts_access: bool,
+}
+
+impl PathCaseUsage {
+    /// Combines the usages of multiple handlers.
+    pub fn merge(usages: &[Self]) -> Self {
+        usages.iter().fold(Self::default(), |result, usage| Self {
+            case_insensitive: result.case_insensitive || usage.case_insensitive,
+            case_sensitive_rules: result.case_sensitive_rules || usage.case_sensitive_rules,
+            restricts_access: result.restricts_access || usage.restricts_access,
+        })
+    }
+
+    /// Makes sure that none of the handlers ignores letter case while another one has
+    /// case-sensitive access rules. Each handler is expected to have validated its own
+    /// configuration already.
+    pub fn check(usages: &[Self]) -> Result<(), Box<Error>> {
+        for (i, first) in usages.iter().enumerate() {
+            for (j, second) in usages.iter().enumerate() {
+                if i != j && first.case_insensitive && second.case_sensitive_rules {
+                    error!("case_insensitive_paths setting cannot be used along with path-specific access rules like auth_rules");
+                    return Err(Error::new(ErrorType::InternalError));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 /// Trait to be implemented by request filters.
 #[async_trait::async_trait]
 pub trait RequestFilter: Sized {
@@ -94,6 +140,33 @@ pub trait RequestFilter: Sized {
     /// possible host-specific handlers will run.
     fn init_downstream_modules(_modules: &mut HttpModules) {}
 
+    /// Indicates whether this handler processes `HEAD` requests itself.
+    ///
+    /// By default, the `request_filter` phase runs for `HEAD` requests as if these were `GET`
+    /// requests and any response body written is suppressed, see [`request_filter_head_as_get`].
+    /// A handler returning `true` here opts out of this behavior, it will see the original request
+    /// method and has to avoid sending a response body on its own. Handler chains apply this
+    /// logic to each handler individually.
+    fn handles_head() -> bool {
+        false
+    }
+
+    /// Lists the request methods this handler responds to, to be advertised in the `Allow` header
+    /// of `405 Method Not Allowed` responses. See [`SessionWrapper::allowed_methods`].
+    ///
+    /// By default, a handler contributes no methods. For handler chains, the lists of all
+    /// handlers are combined.
+    fn allowed_methods(&self) -> Vec<Method> {
+        Vec::new()
+    }
+
+    /// Describes how this handler treats letter case in request paths. Handler chains combine the
+    /// values of their handlers and reject configurations where case-insensitive lookups would
+    /// bypass case-sensitive access rules.
+    fn path_case_usage(&self) -> PathCaseUsage {
+        PathCaseUsage::default()
+    }
+
     /// Handler to run during Pingora’s `early_request_filter` phase, see
     /// [`pingora::ProxyHttp::early_request_filter`].
     async fn early_request_filter(
@@ -127,6 +200,45 @@ pub trait RequestFilter: Sized {
         Ok(None)
     }
 
+    /// Handler to run during Pingora’s `upstream_response_trailer_filter` phase, see
+    /// [`pingora::ProxyHttp::upstream_response_trailer_filter`]. All handlers in the chain are
+    /// called and can modify the response trailers received from the upstream server.
+    fn upstream_response_trailer_filter(
+        &self,
+        _session: &mut impl SessionWrapper,
+        _trailers: &mut HeaderMap,
+        _ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        Ok(())
+    }
+
+    /// Handler to run during Pingora’s `fail_to_connect` phase, see
+    /// [`pingora::ProxyHttp::fail_to_connect`]. All handlers in the chain are called and can mark
+    /// the error as retryable via [`Error::set_retry`], the `upstream_peer` phase will run again
+    /// then.
+    fn fail_to_connect(
+        &self,
+        _session: &mut impl SessionWrapper,
+        _peer: &HttpPeer,
+        _ctx: &mut Self::CTX,
+        e: Box<Error>,
+    ) -> Box<Error> {
+        e
+    }
+
+    /// Handler to run during Pingora’s `fail_to_proxy` phase, see
+    /// [`pingora::ProxyHttp::fail_to_proxy`]. A handler can send an error response here and return
+    /// the status code of it. If `None` is returned, other handlers in the chain will be called. If
+    /// all of them return `None`, Pingora’s default error response will be sent.
+    async fn fail_to_proxy(
+        &self,
+        _session: &mut impl SessionWrapper,
+        _e: &Error,
+        _ctx: &mut Self::CTX,
+    ) -> Option<u16> {
+        None
+    }
+
     /// Handler to run during Pingora’s `logging` phase, see [`pingora::ProxyHttp::logging`].
     async fn logging(
         &self,
@@ -137,6 +249,37 @@ pub trait RequestFilter: Sized {
     }
 }
 
+/// Marker in [`SessionWrapper::extensions`] indicating that a `HEAD` request is currently being
+/// presented to a handler as `GET` request, see [`request_filter_head_as_get`]. The session has to
+/// suppress any response body while it is present.
+#[derive(Debug, Clone)]
+pub struct HeadAsGet;
+
+/// Runs the `request_filter` phase of a handler. Unless the handler processes `HEAD` requests
+/// itself (see [`RequestFilter::handles_head`]), `HEAD` requests are presented to it as `GET`
+/// requests and the original request method is restored afterwards.
+pub async fn request_filter_head_as_get<H: RequestFilter + Sync>(
+    handler: &H,
+    session: &mut impl SessionWrapper,
+    ctx: &mut H::CTX,
+) -> Result<RequestFilterResult, Box<Error>> {
+    let head_as_get = !H::handles_head()
+        && session.req_header().method == Method::HEAD
+        && session.extensions().get::<HeadAsGet>().is_none();
+    if head_as_get {
+        session.req_header_mut().set_method(Method::GET);
+        session.extensions_mut().insert(HeadAsGet);
+    }
+
+    let result = handler.request_filter(session, ctx).await;
+
+    if head_as_get {
+        session.extensions_mut().remove::<HeadAsGet>();
+        session.req_header_mut().set_method(Method::HEAD);
+    }
+    result
+}
+
 /// Trait for configuration structures that can be loaded from YAML files. This trait has a blanket
 /// implementation for any structure implementing [`serde::Deserialize`].
 pub trait FromYaml {
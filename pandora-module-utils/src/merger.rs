@@ -438,6 +438,7 @@ where
     Matcher: PathMatch,
 {
     hosts: HashMap<Vec<u8>, MergerEntries<Matcher, Conf>>,
+    case_insensitive: bool,
 }
 
 impl<Matcher, Conf> Merger<Matcher, Conf>
@@ -449,9 +450,16 @@ where
     pub fn new() -> Self {
         Self {
             hosts: HashMap::new(),
+            case_insensitive: false,
         }
     }
 
+    /// Makes the resulting router ignore ASCII letter case when matching paths, see
+    /// [`RouterBuilder::set_case_insensitive`](crate::router::RouterBuilder::set_case_insensitive).
+    pub fn set_case_insensitive(&mut self, case_insensitive: bool) {
+        self.case_insensitive = case_insensitive;
+    }
+
     fn ensure_host(&mut self, host: &[u8]) -> &mut MergerEntries<Matcher, Conf> {
         if !self.hosts.contains_key(host) {
             // Copy fallback host if it exists
@@ -570,6 +578,7 @@ where
         M: Clone + Eq,
     {
         let mut builder = Router::builder();
+        builder.set_case_insensitive(self.case_insensitive);
         for (host, entries) in self.hosts {
             let mut values = Vec::new();
             for (path, list) in entries.inner {
@@ -648,11 +657,16 @@ where
             new_hosts.insert(host, new_entries);
         }
 
-        Merger { hosts: new_hosts }
+        Merger {
+            hosts: new_hosts,
+            case_insensitive: self.case_insensitive,
+        }
     }
 
     /// Combines the data in the two mergers.
     fn push_merger(&mut self, mut other: Self) {
+        self.case_insensitive |= other.case_insensitive;
+
         // Ensure `other` has all entries present in `self`
         for (host, entries) in &self.hosts {
             let other_entries = other.ensure_host(host);
@@ -702,6 +716,8 @@ mod tests {
 
     use super::*;
 
+    use proptest::prelude::*;
+
     fn lookup(router: &Router<String>, host: &str, path: &str) -> Option<String> {
         router.lookup(host, path).as_deref().cloned()
     }
@@ -1043,4 +1059,82 @@ mod tests {
 
         assert_eq!(lookup(&router, "", ""), Some("bfdeagc".to_owned()));
     }
+
+    /// Naive reference implementation: collects all rules matching the host/path combination,
+    /// orders them by specificity and joins their values.
+    fn reference_lookup(rules: &[(HostPathMatcher, String)], host: &str, path: &str) -> String {
+        let path = Path::new(path);
+        let mut matching = rules
+            .iter()
+            .filter(|(matcher, _)| matcher.matches(host.as_bytes(), &path, false).any())
+            .collect::<Vec<_>>();
+        matching.sort_by(|(a, _), (b, _)| a.cmp(b));
+        matching
+            .into_iter()
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    fn host_strategy() -> impl Strategy<Value = String> {
+        prop::sample::select(vec!["", "", "h1", "h2"]).prop_map(str::to_owned)
+    }
+
+    fn path_strategy(max_depth: usize) -> impl Strategy<Value = String> {
+        prop::collection::vec(
+            prop::sample::select(vec!["a", "b", "ab", "c"]),
+            0..=max_depth,
+        )
+        .prop_map(|segments| segments.join("/"))
+    }
+
+    fn rule_strategy() -> impl Strategy<Value = HostPathMatcher> {
+        (host_strategy(), path_strategy(3), any::<bool>()).prop_map(|(host, path, exact)| {
+            HostPathMatcher {
+                host: host.into_bytes(),
+                path: Path::new(path),
+                exact,
+            }
+        })
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(2000))]
+
+        #[test]
+        fn merge_matches_reference(
+            rules in prop::collection::vec(rule_strategy(), 0..8),
+            lookups in prop::collection::vec(
+                (host_strategy(), path_strategy(4), any::<bool>()),
+                1..16,
+            ),
+        ) {
+            let rules = rules
+                .into_iter()
+                .enumerate()
+                .map(|(index, matcher)| (matcher, char::from(b'a' + index as u8).to_string()))
+                .collect::<Vec<_>>();
+
+            let mut merger = Merger::new();
+            for (matcher, value) in &rules {
+                merger.push(matcher.clone(), value.clone());
+            }
+            let router = merger.merge(|values| values.map(String::as_str).collect::<String>());
+
+            for (host, path, trailing_slash) in lookups {
+                let mut path = format!("/{path}");
+                if trailing_slash {
+                    path.push('/');
+                }
+
+                prop_assert_eq!(
+                    lookup(&router, &host, &path).unwrap_or_default(),
+                    reference_lookup(&rules, &host, &path),
+                    "lookup of {}{} with rules {:?}",
+                    host,
+                    path,
+                    rules
+                );
+            }
+        }
+    }
 }
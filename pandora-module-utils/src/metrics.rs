@@ -0,0 +1,158 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Process-wide registry of named counters
+//!
+//! Modules register counters under a module name and a counter name, typically a rule name
+//! explicitly given in the configuration. Registering the same combination twice produces the
+//! same underlying counter, so that multiple handler instances (e.g. for different virtual hosts)
+//! contribute to a single value.
+//!
+//! ```rust
+//! use pandora_module_utils::metrics::{counters, Counter};
+//!
+//! let counter = Counter::register("example", "my_rule");
+//! counter.increment();
+//! counter.increment();
+//! assert_eq!(counter.get(), 2);
+//! assert!(counters().contains(&("example".to_owned(), "my_rule".to_owned(), 2)));
+//! ```
+//!
+//! [`prometheus_text`] renders all counters in the Prometheus text exposition format, the Startup
+//! module uses it to serve the counters under the configured metrics path.
+
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+type Registry = BTreeMap<(String, String), Arc<AtomicU64>>;
+
+static REGISTRY: Lazy<Mutex<Registry>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// A named counter registered in the process-wide registry
+#[derive(Clone)]
+pub struct Counter {
+    module: Arc<str>,
+    name: Arc<str>,
+    value: Arc<AtomicU64>,
+}
+
+impl Counter {
+    /// Retrieves the counter with the given module and counter name from the registry, creating
+    /// it if necessary.
+    pub fn register(module: &str, name: &str) -> Self {
+        let mut registry = REGISTRY.lock().unwrap_or_else(|err| err.into_inner());
+        let value = registry
+            .entry((module.to_owned(), name.to_owned()))
+            .or_default()
+            .clone();
+        Self {
+            module: module.into(),
+            name: name.into(),
+            value,
+        }
+    }
+
+    /// Name of the module that registered this counter
+    pub fn module(&self) -> &str {
+        &self.module
+    }
+
+    /// Name of this counter
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Increases the counter value by one.
+    pub fn increment(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the current counter value.
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+impl Debug for Counter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Counter")
+            .field("module", &self.module)
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl PartialEq for Counter {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.value, &other.value)
+    }
+}
+
+impl Eq for Counter {}
+
+/// Returns a snapshot of all registered counters as `(module, name, value)` tuples, sorted by
+/// module and counter name.
+pub fn counters() -> Vec<(String, String, u64)> {
+    let registry = REGISTRY.lock().unwrap_or_else(|err| err.into_inner());
+    registry
+        .iter()
+        .map(|((module, name), value)| {
+            (module.clone(), name.clone(), value.load(Ordering::Relaxed))
+        })
+        .collect()
+}
+
+/// Renders all registered counters in the Prometheus text exposition format as a
+/// `pandora_rule_applied_total` metric with `module` and `rule` labels.
+pub fn prometheus_text() -> String {
+    fn escape(value: &str) -> String {
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    }
+
+    let mut result = String::from(
+        "# HELP pandora_rule_applied_total Number of times a named rule has been applied.\n\
+         # TYPE pandora_rule_applied_total counter\n",
+    );
+    for (module, name, value) in counters() {
+        result.push_str(&format!(
+            "pandora_rule_applied_total{{module=\"{}\",rule=\"{}\"}} {value}\n",
+            escape(&module),
+            escape(&name)
+        ));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prometheus() {
+        let counter = Counter::register("metrics_test", "quoted \"rule\"\n");
+        counter.increment();
+        let text = prometheus_text();
+        assert!(text.starts_with("# HELP pandora_rule_applied_total "));
+        assert!(text.contains("\n# TYPE pandora_rule_applied_total counter\n"));
+        assert!(text.contains(
+            "\npandora_rule_applied_total{module=\"metrics_test\",rule=\"quoted \\\"rule\\\"\\n\"} 1\n"
+        ));
+    }
+}
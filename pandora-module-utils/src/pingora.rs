@@ -17,21 +17,28 @@
 
 use async_trait::async_trait;
 use bytes::Bytes;
-use http::{header, Extensions, Uri};
+use http::{header, Extensions, HeaderName, Uri};
 use once_cell::sync::OnceCell;
+
+use crate::RequestFilter;
+pub use http::{HeaderMap, Method};
 pub use pingora::http::{IntoCaseHeaderName, RequestHeader, ResponseHeader};
 pub use pingora::modules::http::compression::{ResponseCompression, ResponseCompressionBuilder};
-pub use pingora::modules::http::{HttpModule, HttpModuleBuilder, HttpModules};
+pub use pingora::modules::http::{HttpModule, HttpModuleBuilder, HttpModules, ModuleBuilder};
 pub use pingora::protocols::http::compression::Algorithm as CompressionAlgorithm;
 pub use pingora::protocols::l4::socket::SocketAddr;
+pub use pingora::protocols::ssl::SslDigest;
 pub use pingora::proxy::{http_proxy_service, ProxyHttp, Session};
 pub use pingora::server::configuration::{Opt as ServerOpt, ServerConf};
 pub use pingora::server::Server;
 pub use pingora::upstreams::peer::HttpPeer;
-pub use pingora::{Error, ErrorType};
+pub use pingora::{Error, ErrorSource, ErrorType};
+use std::any::TypeId;
 use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::io::{Cursor, Seek, SeekFrom, Write};
-use std::ops::{Deref, DerefMut};
+use std::ops::{Deref, DerefMut, RangeInclusive};
 use std::sync::Arc;
 
 /// A trait implemented by wrappers around Pingora’s session
@@ -130,11 +137,83 @@ pub trait SessionWrapper: Send + Deref<Target = Session> + DerefMut {
         self.extensions_mut().insert(RemoteUser(remote_user));
     }
 
+    /// Returns the authenticated user along with their roles if any
+    fn authenticated_user(&self) -> Option<&AuthenticatedUser> {
+        self.extensions().get()
+    }
+
+    /// Records the authenticated user, making their roles available to subsequent handlers
+    fn set_authenticated_user(&mut self, user: AuthenticatedUser) {
+        self.extensions_mut().insert(user);
+    }
+
+    /// Returns the request credentials used to authorize the request if any
+    fn auth_credentials(&self) -> Option<&AuthCredentials> {
+        self.extensions().get()
+    }
+
+    /// Records the request credentials used to authorize the request, so that these aren’t
+    /// passed on to other servers
+    fn set_auth_credentials(&mut self, credentials: AuthCredentials) {
+        self.extensions_mut().insert(credentials);
+    }
+
+    /// Returns the request methods supported by the handlers processing this request, as
+    /// reported by [`RequestFilter::allowed_methods`].
+    fn allowed_methods(&self) -> &[Method] {
+        if let Some(AllowedMethods(methods)) = self.extensions().get() {
+            methods
+        } else {
+            &[]
+        }
+    }
+
+    /// Adds request methods supported by the handlers processing this request, skipping the ones
+    /// already present
+    fn add_allowed_methods(&mut self, methods: Vec<Method>) {
+        if let Some(AllowedMethods(existing)) = self.extensions_mut().get_mut() {
+            for method in methods {
+                if !existing.contains(&method) {
+                    existing.push(method);
+                }
+            }
+        } else {
+            self.extensions_mut().insert(AllowedMethods(Vec::new()));
+            self.add_allowed_methods(methods);
+        }
+    }
+
+    /// Returns the response status codes dynamic compression is restricted to, `None` if all
+    /// responses can be compressed.
+    fn compression_statuses(&self) -> Option<&[RangeInclusive<u16>]> {
+        if let Some(CompressionStatuses(statuses)) = self.extensions().get() {
+            Some(statuses)
+        } else {
+            None
+        }
+    }
+
+    /// Restricts dynamic compression to responses with the given status codes
+    fn set_compression_statuses(&mut self, statuses: Vec<RangeInclusive<u16>>) {
+        self.extensions_mut().insert(CompressionStatuses(statuses));
+    }
+
     /// See [`Session::response_written`](pingora::protocols::http::server::Session::response_written)
     fn response_written(&self) -> Option<&ResponseHeader> {
         self.deref().response_written()
     }
 
+    /// See [`Session::write_response_header`](pingora::protocols::http::server::Session::write_response_header)
+    async fn write_response_header(
+        &mut self,
+        resp: Box<ResponseHeader>,
+        end_of_stream: bool,
+    ) -> Result<(), Box<Error>> {
+        self.deref_mut()
+            .write_response_header(resp, end_of_stream)
+            .await
+    }
+
     /// See [`Session::write_response_body`](pingora::protocols::http::server::Session::write_response_body)
     async fn write_response_body(
         &mut self,
@@ -145,12 +224,88 @@ pub trait SessionWrapper: Send + Deref<Target = Session> + DerefMut {
             .write_response_body(data, end_of_stream)
             .await
     }
+
+    /// Writes response trailers, to be sent after the response body. This ends the response.
+    ///
+    /// Trailers are only supported for HTTP/2 connections, with HTTP/1.1 these are silently
+    /// dropped. See
+    /// [`Session::write_response_trailers`](pingora::protocols::http::server::Session::write_response_trailers)
+    async fn write_response_trailers(&mut self, trailers: HeaderMap) -> Result<(), Box<Error>> {
+        self.deref_mut()
+            .downstream_session
+            .write_response_trailers(trailers)
+            .await
+    }
+}
+
+thread_local! {
+    /// Types of the modules added via [`add_module_once`] during the current
+    /// [`init_handler_modules`] call
+    static ADDED_MODULES: RefCell<Option<HashSet<TypeId>>> = const { RefCell::new(None) };
+}
+
+/// Runs the `init_downstream_modules` phase of a handler.
+///
+/// Unlike calling [`RequestFilter::init_downstream_modules`] directly, this makes sure that
+/// modules added via [`add_module_once`] are only added once. This matters when the same module
+/// is present both at the top level and in per-host configurations.
+pub fn init_handler_modules<H: RequestFilter>(modules: &mut HttpModules) {
+    let previous = ADDED_MODULES.replace(Some(HashSet::new()));
+    H::init_downstream_modules(modules);
+    ADDED_MODULES.set(previous);
+}
+
+/// Adds a module unless a module of the same type has been added already during the current
+/// [`init_handler_modules`] call.
+///
+/// Pingora doesn’t allow multiple modules of the same type, so handlers which could be present
+/// multiple times in a handler chain should add their modules via this function.
+pub fn add_module_once(modules: &mut HttpModules, builder: ModuleBuilder) {
+    let type_id = builder.init().as_any().type_id();
+    let is_new = ADDED_MODULES
+        .with_borrow_mut(|added| added.as_mut().map_or(true, |added| added.insert(type_id)));
+    if is_new {
+        modules.add_module(builder);
+    }
+}
+
+/// Request credentials used by an authentication handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthCredentials {
+    /// Credentials sent in a request header like `Authorization`
+    Header(HeaderName),
+    /// Credentials sent in the cookie with the given name
+    Cookie(String),
+}
+
+/// User authenticated by an authentication handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthenticatedUser {
+    /// User name
+    pub name: String,
+    /// Roles configured for the user
+    pub roles: Vec<String>,
+}
+
+impl AuthenticatedUser {
+    /// Checks whether the user has been assigned the given role
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
 }
 
 /// Type used to store remote user’s name in `SessionWrapper::extensions`
 #[derive(Debug, Clone)]
 struct RemoteUser(String);
 
+/// Type used to store the allowed request methods in `SessionWrapper::extensions`
+#[derive(Debug, Clone)]
+struct AllowedMethods(Vec<Method>);
+
+/// Type used to store the status codes eligible for compression in `SessionWrapper::extensions`
+#[derive(Debug, Clone)]
+struct CompressionStatuses(Vec<RangeInclusive<u16>>);
+
 /// Type used to store original request URI in `SessionWrapper::extensions`
 #[derive(Debug, Clone)]
 struct OriginalUri(Uri);
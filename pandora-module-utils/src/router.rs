@@ -26,16 +26,31 @@
 //! Empty host name is considered the fallback host, its values apply to all hosts but with a lower
 //! priority than values designated to the host.
 //!
+//! Optionally, the router can ignore ASCII letter case in paths, so that `/Dir/File` will match a
+//! rule defined for `/dir/file`. Note that rules only differing in case become ambiguous then,
+//! only one of them will apply.
+//!
 //! Only the best match is returned. If rules exist for `/`, `/dir/` and `/dir/subdir/` for
 //! example, the path `/dir/subdir/file` will match `/dir/subdir/`.
 
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::ops::Deref;
+use std::path::Path as FilePath;
 
+use crate::pingora::{Error, ErrorType};
 pub use crate::trie::LookupResult;
 use crate::trie::{common_prefix_length, Trie, SEPARATOR};
 
+/// Marker at the start of a serialized router
+const SERIALIZED_MAGIC: &[u8] = b"PDRT";
+
+/// Version of the serialized router format, has to be increased whenever the data layout of
+/// `Router` or `Trie` changes
+const SERIALIZED_VERSION: u16 = 2;
+
 /// Empty path
 pub const EMPTY_PATH: &Path = &Path { path: Vec::new() };
 
@@ -90,19 +105,36 @@ impl Path {
     /// If this path is a non-empty prefix of the given path, removes the prefix. Otherwise returns
     /// `None`.
     pub fn remove_prefix_from<'a>(&self, path: &'a impl AsRef<[u8]>) -> Option<&'a [u8]> {
+        self.strip_prefix(path.as_ref(), false)
+    }
+
+    /// Removes the prefix like [`Path::remove_prefix_from`] but ignores ASCII letter case when
+    /// comparing path segments.
+    pub fn remove_prefix_from_ignore_case<'a>(
+        &self,
+        path: &'a impl AsRef<[u8]>,
+    ) -> Option<&'a [u8]> {
+        self.strip_prefix(path.as_ref(), true)
+    }
+
+    fn strip_prefix<'a>(&self, mut path: &'a [u8], ignore_case: bool) -> Option<&'a [u8]> {
         if self.path.is_empty() {
             return None;
         }
 
-        let mut path = path.as_ref();
         for segment in self.path.split(|b| *b == SEPARATOR) {
             while let [SEPARATOR, rest @ ..] = path {
                 path = rest;
             }
 
-            if !path.starts_with(segment)
-                || path.get(segment.len()).is_some_and(|b| *b != SEPARATOR)
-            {
+            let matches = path.get(..segment.len()).is_some_and(|start| {
+                if ignore_case {
+                    start.eq_ignore_ascii_case(segment)
+                } else {
+                    start == segment
+                }
+            });
+            if !matches || path.get(segment.len()).is_some_and(|b| *b != SEPARATOR) {
                 return None;
             }
 
@@ -168,6 +200,7 @@ impl<Value> Router<Value> {
         RouterBuilder {
             entries: Default::default(),
             fallbacks: Default::default(),
+            case_insensitive: false,
         }
     }
 
@@ -189,6 +222,103 @@ impl<Value> Router<Value> {
     pub fn retrieve(&self, index: usize) -> Option<&Value> {
         self.trie.retrieve(index)
     }
+
+    /// Serializes the router into a binary representation. This allows building a large router
+    /// once and loading it via [`Router::from_bytes`] later, which is considerably faster than
+    /// building it again.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Box<Error>>
+    where
+        Value: Serialize,
+    {
+        let mut data = SERIALIZED_MAGIC.to_vec();
+        data.extend_from_slice(&SERIALIZED_VERSION.to_be_bytes());
+        rmp_serde::encode::write(&mut data, &(&self.trie, &self.fallback)).map_err(|err| {
+            Error::because(ErrorType::InternalError, "failed serializing router", err)
+        })?;
+        Ok(data)
+    }
+
+    /// Loads a router serialized via [`Router::to_bytes`]. This will produce an error if the data
+    /// has been produced by an incompatible version of this crate or is corrupted.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Box<Error>>
+    where
+        Value: DeserializeOwned,
+    {
+        let data = data.strip_prefix(SERIALIZED_MAGIC).ok_or_else(|| {
+            Error::explain(ErrorType::InternalError, "data isn't a serialized router")
+        })?;
+        let (version, data) = data.split_at(std::cmp::min(data.len(), 2));
+        if version != SERIALIZED_VERSION.to_be_bytes() {
+            return Err(Error::explain(
+                ErrorType::InternalError,
+                format!(
+                    "serialized router has an unsupported format version, expected {SERIALIZED_VERSION}"
+                ),
+            ));
+        }
+
+        let (trie, fallback): (Trie<Value>, Trie<Value>) =
+            rmp_serde::from_slice(data).map_err(|err| {
+                Error::because(ErrorType::InternalError, "failed loading router", err)
+            })?;
+        if !trie.is_consistent() || !fallback.is_consistent() {
+            return Err(Error::explain(
+                ErrorType::InternalError,
+                "serialized router data is corrupted",
+            ));
+        }
+        Ok(Self { trie, fallback })
+    }
+
+    /// Loads a router from the cache file if it has been saved for the same key, otherwise builds
+    /// it and saves it to the cache file. The key should uniquely identify the rules the router
+    /// is built from, so that configuration changes invalidate the cache.
+    ///
+    /// Cache errors are not fatal, the router will be built then.
+    pub fn load_or_build(cache: &FilePath, key: &[u8], build: impl FnOnce() -> Self) -> Self
+    where
+        Value: Serialize + DeserializeOwned,
+    {
+        match std::fs::read(cache) {
+            Ok(data) => {
+                let cached_key = data
+                    .get(..4)
+                    .map(|len| u32::from_be_bytes(len.try_into().unwrap()) as usize)
+                    .and_then(|len| data.get(4..4 + len));
+                if cached_key == Some(key) {
+                    match Self::from_bytes(&data[4 + key.len()..]) {
+                        Ok(router) => return router,
+                        Err(err) => {
+                            log::warn!("failed loading router from {}: {err}", cache.display())
+                        }
+                    }
+                } else {
+                    log::info!("router cache {} is outdated, rebuilding", cache.display());
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => log::warn!("failed reading router cache {}: {err}", cache.display()),
+        }
+
+        let router = build();
+        let data = u32::try_from(key.len())
+            .map_err(|_| Error::explain(ErrorType::InternalError, "router cache key too long"))
+            .and_then(|len| {
+                let mut data = len.to_be_bytes().to_vec();
+                data.extend_from_slice(key);
+                data.extend(router.to_bytes()?);
+                Ok(data)
+            })
+            .and_then(|data| {
+                std::fs::write(cache, data).map_err(|err| {
+                    Error::because(ErrorType::InternalError, "failed writing file", err)
+                })
+            });
+        if let Err(err) = data {
+            log::warn!("failed saving router cache {}: {err}", cache.display());
+        }
+        router
+    }
 }
 
 fn make_key<'a>(
@@ -221,6 +351,7 @@ struct RouterEntry<Value> {
 pub struct RouterBuilder<Value> {
     entries: HashMap<Vec<u8>, Vec<RouterEntry<Value>>>,
     fallbacks: Vec<RouterEntry<Value>>,
+    case_insensitive: bool,
 }
 
 impl<Value: Clone + Eq> RouterBuilder<Value> {
@@ -228,7 +359,7 @@ impl<Value: Clone + Eq> RouterBuilder<Value> {
         existing: &mut Vec<RouterEntry<Value>>,
         path: Path,
         value_exact: Value,
-        mut value_prefix: Option<Value>,
+        value_prefix: Option<Value>,
     ) -> bool {
         match existing.binary_search_by_key(&path.as_slice(), |entry| entry.path.as_slice()) {
             Ok(index) => {
@@ -239,17 +370,9 @@ impl<Value: Clone + Eq> RouterBuilder<Value> {
                 true
             }
             Err(index) => {
-                // Adding a new entry.
-                if value_prefix.is_none() {
-                    // Copy `value_prefix` from closest parent.
-                    for parent in existing[0..index].iter_mut().rev() {
-                        if parent.path.is_prefix_of(&path) && parent.value_prefix.is_some() {
-                            value_prefix.clone_from(&parent.value_prefix);
-                            break;
-                        }
-                    }
-                }
-
+                // Adding a new entry. If `value_prefix` is missing, the trie lookup will fall back
+                // to the prefix value of the closest parent. Copying the parent value here would
+                // produce stale results if the parent entry is overwritten later.
                 existing.insert(
                     index,
                     RouterEntry {
@@ -287,10 +410,16 @@ impl<Value: Clone + Eq> RouterBuilder<Value> {
         Self::merge_value(existing, path, value_exact, value_prefix)
     }
 
+    /// Makes the router ignore ASCII letter case when matching paths.
+    pub fn set_case_insensitive(&mut self, case_insensitive: bool) {
+        self.case_insensitive = case_insensitive;
+    }
+
     /// Translates all rules into a router instance while also merging values if multiple apply to
     /// the same location.
     pub fn build(self) -> Router<Value> {
         let mut builder = Trie::builder();
+        builder.set_case_insensitive(self.case_insensitive);
         for (host, entries) in self.entries {
             for entry in entries {
                 let mut key = host.clone();
@@ -303,6 +432,7 @@ impl<Value: Clone + Eq> RouterBuilder<Value> {
         }
 
         let mut fallback_builder = Trie::builder();
+        fallback_builder.set_case_insensitive(self.case_insensitive);
         for entry in self.fallbacks {
             fallback_builder.push(entry.path.path, entry.value_exact, entry.value_prefix);
         }
@@ -320,6 +450,8 @@ mod tests {
 
     use super::*;
 
+    use proptest::prelude::*;
+
     #[test]
     fn path_normalization() {
         assert_eq!(&Path::new("").path, b"");
@@ -359,6 +491,15 @@ mod tests {
             Path::new("/abc/def/").remove_prefix_from(b"/abc//def/xyz"),
             Some("/xyz".as_bytes())
         );
+        assert_eq!(Path::new("/abc/").remove_prefix_from(b"/ABC/Xyz"), None);
+        assert_eq!(
+            Path::new("/abc/").remove_prefix_from_ignore_case(b"/ABC/Xyz"),
+            Some("/Xyz".as_bytes())
+        );
+        assert_eq!(
+            Path::new("/abc/").remove_prefix_from_ignore_case(b"/ABCD/xyz"),
+            None
+        );
     }
 
     #[test]
@@ -495,4 +636,210 @@ mod tests {
         // is not an issue but it might become one as the implementation changes.
         assert_eq!(lookup(&router, "localhost/def", "/abc"), Some(2));
     }
+
+    #[test]
+    fn routing_case_insensitive() {
+        fn lookup(router: &Router<u8>, host: &str, path: &str) -> Option<u8> {
+            router.lookup(host, path).as_deref().copied()
+        }
+
+        let mut builder = Router::builder();
+        builder.set_case_insensitive(true);
+        builder.push("localhost", "/Abc", 1u8, Some(2));
+        builder.push("", "/xyz/", 3, Some(4));
+        let router = builder.build();
+
+        assert_eq!(lookup(&router, "localhost", "/abc"), Some(1));
+        assert_eq!(lookup(&router, "localhost", "/ABC/def"), Some(2));
+        assert_eq!(lookup(&router, "localhost", "/XyZ"), Some(3));
+        assert_eq!(lookup(&router, "example.com", "/xyz/File"), Some(4));
+
+        let data = router.to_bytes().unwrap();
+        let loaded = Router::<u8>::from_bytes(&data).unwrap();
+        assert_eq!(lookup(&loaded, "localhost", "/ABC"), Some(1));
+
+        let mut builder = Router::builder();
+        builder.push("localhost", "/Abc", 1u8, Some(2));
+        let router = builder.build();
+
+        assert_eq!(lookup(&router, "localhost", "/Abc"), Some(1));
+        assert_eq!(lookup(&router, "localhost", "/abc"), None);
+        assert_eq!(lookup(&router, "localhost", "/ABC/def"), None);
+    }
+
+    #[test]
+    fn serialization() {
+        let mut builder = Router::builder();
+        builder.push("localhost", "/", "root".to_owned(), Some("dir".to_owned()));
+        builder.push("localhost", "/abc", "abc".to_owned(), None);
+        builder.push("", "/xyz/", "xyz".to_owned(), Some("dir".to_owned()));
+        let router = builder.build();
+
+        let data = router.to_bytes().unwrap();
+        let loaded = Router::<String>::from_bytes(&data).unwrap();
+        assert_eq!(loaded, router);
+        assert_eq!(
+            loaded.lookup("localhost", "/abc").as_deref(),
+            Some(&"abc".to_owned())
+        );
+        assert_eq!(
+            loaded.lookup("example.com", "/xyz/file").as_deref(),
+            Some(&"dir".to_owned())
+        );
+
+        // Values have to deserialize into the same type
+        assert!(Router::<u8>::from_bytes(&data).is_err());
+
+        // Incompatible format version
+        let mut modified = data.clone();
+        modified[SERIALIZED_MAGIC.len() + 1] += 1;
+        assert!(Router::<String>::from_bytes(&modified).is_err());
+
+        // Not a serialized router
+        assert!(Router::<String>::from_bytes(&data[1..]).is_err());
+        assert!(Router::<String>::from_bytes(SERIALIZED_MAGIC).is_err());
+        assert!(Router::<String>::from_bytes(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn cache() {
+        let build = |value: u8| {
+            let mut builder = Router::builder();
+            builder.push("localhost", "/abc", value, None);
+            builder.build()
+        };
+        let cache = std::env::temp_dir().join(format!("router-cache-{}", std::process::id()));
+        let _ = std::fs::remove_file(&cache);
+
+        // Missing cache file is created
+        let router = Router::load_or_build(&cache, b"key1", || build(1));
+        assert_eq!(router.lookup("localhost", "/abc").as_deref(), Some(&1));
+        assert!(cache.exists());
+
+        // Router is loaded if the key matches
+        let router = Router::load_or_build(&cache, b"key1", || panic!("unexpected build"));
+        assert_eq!(router.lookup("localhost", "/abc").as_deref(), Some(&1));
+
+        // Different key means rebuilding and updating the cache
+        let router = Router::load_or_build(&cache, b"key2", || build(2));
+        assert_eq!(router.lookup("localhost", "/abc").as_deref(), Some(&2));
+        let router = Router::load_or_build(&cache, b"key2", || panic!("unexpected build"));
+        assert_eq!(router.lookup("localhost", "/abc").as_deref(), Some(&2));
+
+        // Corrupted cache file is ignored
+        std::fs::write(&cache, b"garbage").unwrap();
+        let router = Router::load_or_build(&cache, b"key2", || build(3));
+        assert_eq!(router.lookup("localhost", "/abc").as_deref(), Some(&3));
+
+        std::fs::remove_file(&cache).unwrap();
+    }
+
+    /// Naive reference implementation of the routing table: the entry matching the path exactly
+    /// wins, otherwise the prefix value of the closest parent entry is used. Host-specific entries
+    /// take precedence over fallback entries.
+    fn reference_lookup(
+        entries: &[(String, String, u8, Option<u8>)],
+        host: &str,
+        path: &str,
+    ) -> Option<u8> {
+        fn lookup_host(
+            entries: &[(String, String, u8, Option<u8>)],
+            host: &str,
+            path: &Path,
+        ) -> Option<u8> {
+            let mut exact = None;
+            let mut prefix: Option<(usize, u8)> = None;
+            for (entry_host, entry_path, value_exact, value_prefix) in entries {
+                if entry_host != host {
+                    continue;
+                }
+
+                let entry_path = Path::new(entry_path);
+                if &entry_path == path {
+                    // Later entries for the same path overwrite earlier ones
+                    exact = Some(*value_exact);
+                }
+                if let Some(value_prefix) = value_prefix {
+                    if entry_path.is_prefix_of(path)
+                        && prefix.map_or(true, |(length, _)| entry_path.len() >= length)
+                    {
+                        prefix = Some((entry_path.len(), *value_prefix));
+                    }
+                }
+            }
+            exact.or(prefix.map(|(_, value)| value))
+        }
+
+        let path = Path::new(path);
+        if !host.is_empty() {
+            if let Some(value) = lookup_host(entries, host, &path) {
+                return Some(value);
+            }
+        }
+        lookup_host(entries, "", &path)
+    }
+
+    fn entry_strategy() -> impl Strategy<Value = (String, String, u8, Option<u8>)> {
+        (
+            prop::sample::select(vec!["", "", "h1", "h2"]),
+            prop::collection::vec(prop::sample::select(vec!["a", "b", "ab"]), 0..=3),
+            any::<u8>(),
+            any::<Option<u8>>(),
+        )
+            .prop_map(|(host, segments, value_exact, value_prefix)| {
+                (
+                    host.to_owned(),
+                    segments.join("/"),
+                    value_exact,
+                    value_prefix,
+                )
+            })
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(2000))]
+
+        #[test]
+        fn routing_matches_reference(
+            entries in prop::collection::vec(entry_strategy(), 0..10),
+            lookups in prop::collection::vec(
+                (
+                    prop::sample::select(vec!["", "h1", "h2", "h3"]),
+                    prop::collection::vec(prop::sample::select(vec!["a", "b", "ab", "c"]), 0..=4),
+                    prop::sample::select(vec!["", "/", "//"]),
+                ),
+                1..16,
+            ),
+        ) {
+            let mut builder = Router::builder();
+            for (host, path, value_exact, value_prefix) in &entries {
+                builder.push(host, path, *value_exact, *value_prefix);
+            }
+            let router = builder.build();
+
+            for (host, segments, suffix) in lookups {
+                let path = format!("/{}{suffix}", segments.join("//"));
+                prop_assert_eq!(
+                    router.lookup(host, &path).as_deref().copied(),
+                    reference_lookup(&entries, host, &path),
+                    "lookup of {}{} with entries {:?}",
+                    host,
+                    path,
+                    entries
+                );
+            }
+        }
+
+        #[test]
+        fn serialization_roundtrip(entries in prop::collection::vec(entry_strategy(), 0..10)) {
+            let mut builder = Router::builder();
+            for (host, path, value_exact, value_prefix) in &entries {
+                builder.push(host, path, *value_exact, *value_prefix);
+            }
+            let router = builder.build();
+
+            let loaded = Router::from_bytes(&router.to_bytes().unwrap()).unwrap();
+            prop_assert_eq!(loaded, router);
+        }
+    }
 }
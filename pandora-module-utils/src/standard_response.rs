@@ -14,7 +14,7 @@
 
 //! Standard responses for various conditions
 
-use http::{header, method::Method, status::StatusCode};
+use http::{header, header::HeaderName, method::Method, status::StatusCode};
 use maud::{html, DOCTYPE};
 
 use crate::pingora::{Error, ResponseHeader, SessionWrapper};
@@ -47,19 +47,15 @@ pub fn response_text(status: StatusCode) -> String {
 async fn response(
     session: &mut impl SessionWrapper,
     status: StatusCode,
-    location: Option<&str>,
-    cookie: Option<&str>,
+    headers: &[(HeaderName, &str)],
 ) -> Result<(), Box<Error>> {
     let text = response_text(status);
 
-    let mut header = ResponseHeader::build(status, Some(4))?;
+    let mut header = ResponseHeader::build(status, Some(2 + headers.len()))?;
     header.append_header(header::CONTENT_LENGTH, text.len().to_string())?;
     header.append_header(header::CONTENT_TYPE, "text/html;charset=utf-8")?;
-    if let Some(location) = location {
-        header.append_header(header::LOCATION, location)?;
-    }
-    if let Some(cookie) = cookie {
-        header.append_header(header::SET_COOKIE, cookie)?;
+    for (name, value) in headers {
+        header.append_header(name.clone(), *value)?;
     }
 
     let send_body = session.req_header().method != Method::HEAD;
@@ -79,7 +75,7 @@ pub async fn error_response(
     session: &mut impl SessionWrapper,
     status: StatusCode,
 ) -> Result<(), Box<Error>> {
-    response(session, status, None, None).await
+    response(session, status, &[]).await
 }
 
 /// Responds with a redirect to the given location.
@@ -88,7 +84,7 @@ pub async fn redirect_response(
     status: StatusCode,
     location: &str,
 ) -> Result<(), Box<Error>> {
-    response(session, status, Some(location), None).await
+    response(session, status, &[(header::LOCATION, location)]).await
 }
 
 /// Responds with a redirect to the given location and setting a cookie.
@@ -98,5 +94,38 @@ pub async fn redirect_response_with_cookie(
     location: &str,
     cookie: &str,
 ) -> Result<(), Box<Error>> {
-    response(session, status, Some(location), Some(cookie)).await
+    response(
+        session,
+        status,
+        &[(header::LOCATION, location), (header::SET_COOKIE, cookie)],
+    )
+    .await
+}
+
+/// Responds with `405 Method Not Allowed`, listing the request methods supported by the handlers
+/// in the `Allow` header.
+pub async fn method_not_allowed_response(
+    session: &mut impl SessionWrapper,
+) -> Result<(), Box<Error>> {
+    let allow = allow_header(session);
+    if allow.is_empty() {
+        response(session, StatusCode::METHOD_NOT_ALLOWED, &[]).await
+    } else {
+        response(
+            session,
+            StatusCode::METHOD_NOT_ALLOWED,
+            &[(header::ALLOW, &allow)],
+        )
+        .await
+    }
+}
+
+/// Produces the value of the `Allow` header from the request methods supported by the handlers.
+pub fn allow_header(session: &impl SessionWrapper) -> String {
+    session
+        .allowed_methods()
+        .iter()
+        .map(Method::as_str)
+        .collect::<Vec<_>>()
+        .join(", ")
 }
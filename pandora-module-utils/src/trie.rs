@@ -20,8 +20,10 @@
 //! * The labels are segmented with a separator character (forward slash) and only full segment
 //!   matches are accepted.
 //! * Different value returned for exact and prefix matches
+//! * Optional ASCII case-insensitive matching
 //! * When the same value is used multiple times, only one copy is stored
 
+use serde::{Deserialize, Serialize};
 use std::{
     fmt::Debug,
     ops::{Deref, Range},
@@ -33,9 +35,15 @@ pub(crate) const SEPARATOR: u8 = b'/';
 /// Calculates the length of the longest common prefix of two labels. A common prefix is identical
 /// and ends at a boundary in both labels (either end of the label or a separator character).
 pub(crate) fn common_prefix_length(a: &[u8], b: &[u8]) -> usize {
+    common_prefix_length_by(a, b, |a, b| a == b)
+}
+
+/// Calculates the length of the longest common prefix of two labels like `common_prefix_length`
+/// but uses the given function to compare characters.
+fn common_prefix_length_by(a: &[u8], b: &[u8], eq: impl Fn(u8, u8) -> bool) -> usize {
     let mut length = 0;
     for i in 0..std::cmp::min(a.len(), b.len()) {
-        if a[i] != b[i] {
+        if !eq(a[i], b[i]) {
             return length;
         }
 
@@ -67,11 +75,15 @@ pub(crate) fn common_prefix_length(a: &[u8], b: &[u8]) -> usize {
 /// Finally, the third vector stores the labels of the nodes, so that nodes don’t need separate
 /// allocations for their labels. Each nodes refers to its label within this vector via an index
 /// range.
-#[derive(Clone, PartialEq, Eq)]
+///
+/// In a case-insensitive trie all labels are stored in lower case, lookup converts the segments
+/// to lower case while comparing.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) struct Trie<Value> {
     nodes: Vec<Node>,
     values: Vec<Value>,
     labels: Vec<u8>,
+    case_insensitive: bool,
 }
 
 /// Trie lookup result, will dereference into the value
@@ -119,7 +131,7 @@ impl<Value> Deref for LookupResult<'_, Value> {
 /// Each child node represents a unique path further from this node. Multiple child node labels
 /// never start with the same segment: in such scenarios the builder inserts an intermediate node
 /// that serves as the common parent for all nodes reachable via that segment.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 struct Node {
     label: Range<usize>,
     value_exact: Option<usize>,
@@ -146,6 +158,15 @@ impl<Value> Trie<Value> {
             .map(|(value, index)| (LookupResult::new(value, index)))
     }
 
+    /// Calculates the length of the common prefix of a lookup segment and a node label.
+    fn matching_length(&self, segment: &[u8], label: &[u8]) -> usize {
+        if self.case_insensitive {
+            common_prefix_length_by(segment, label, |a, b| a.to_ascii_lowercase() == b)
+        } else {
+            common_prefix_length(segment, label)
+        }
+    }
+
     /// Looks up a particular label in the trie.
     ///
     /// The label is identified by an iterator producing segments. The segments are expected to be
@@ -178,7 +199,7 @@ impl<Value> Trie<Value> {
                 let child = self.nodes.get(child)?;
                 let mut label_start = child.label.start;
                 let label_end = child.label.end;
-                let length = common_prefix_length(segment, &self.labels[label_start..label_end]);
+                let length = self.matching_length(segment, &self.labels[label_start..label_end]);
                 if length > 0 {
                     label_start += length;
 
@@ -195,7 +216,7 @@ impl<Value> Trie<Value> {
                         };
 
                         let length =
-                            common_prefix_length(segment, &self.labels[label_start..label_end]);
+                            self.matching_length(segment, &self.labels[label_start..label_end]);
                         if length > 0 {
                             label_start += length;
                         } else {
@@ -216,6 +237,24 @@ impl<Value> Trie<Value> {
         }
     }
 
+    /// Checks whether all indices stored in the nodes are valid. This is always the case for a
+    /// trie produced by the builder but not necessarily for one that has been deserialized.
+    pub(crate) fn is_consistent(&self) -> bool {
+        let valid_value = |value: Option<usize>| value.map_or(true, |v| v < self.values.len());
+
+        !self.nodes.is_empty()
+            && self.nodes.iter().enumerate().all(|(index, node)| {
+                node.label.start <= node.label.end
+                    && node.label.end <= self.labels.len()
+                    && valid_value(node.value_exact)
+                    && valid_value(node.value_prefix)
+                    // Children are always stored after their parent
+                    && (node.children.is_empty() || node.children.start > index)
+                    && node.children.start <= node.children.end
+                    && node.children.end <= self.nodes.len()
+            })
+    }
+
     /// Retrieves the value from a previous lookup by its index
     pub(crate) fn retrieve(&self, index: usize) -> Option<&Value> {
         self.values.get(index)
@@ -279,6 +318,7 @@ pub(crate) struct TrieBuilder<Value> {
     nodes: usize,
     labels: usize,
     root: BuilderNode<Value>,
+    case_insensitive: bool,
 }
 
 /// A builder node
@@ -304,9 +344,16 @@ impl<Value: Eq> TrieBuilder<Value> {
                 value_exact: None,
                 value_prefix: None,
             },
+            case_insensitive: false,
         }
     }
 
+    /// Makes the trie match labels regardless of ASCII letter case. This has to be called before
+    /// any labels are added.
+    pub(crate) fn set_case_insensitive(&mut self, case_insensitive: bool) {
+        self.case_insensitive = case_insensitive;
+    }
+
     /// Recursively finds the node that a particular label should be added to.
     ///
     /// If the label shares a common prefix with a child node of the current node, this will
@@ -374,6 +421,10 @@ impl<Value: Eq> TrieBuilder<Value> {
         value_exact: Value,
         value_prefix: Option<Value>,
     ) -> bool {
+        if self.case_insensitive {
+            label.make_ascii_lowercase();
+        }
+
         let node = Self::find_insertion_point(
             &mut self.root,
             &mut self.nodes,
@@ -479,6 +530,7 @@ impl<Value: Eq> TrieBuilder<Value> {
             nodes,
             labels,
             values,
+            case_insensitive: self.case_insensitive,
         }
     }
 }
@@ -576,6 +628,29 @@ mod tests {
         assert_eq!(trie.lookup(make_key("a/bc/de/h")).as_deref(), Some(&16));
     }
 
+    #[test]
+    fn lookup_case_insensitive() {
+        let mut builder = Trie::builder();
+        builder.set_case_insensitive(true);
+        for (label, value_exact, value_prefix) in [("a", 1, 11), ("Dir/File", 2, 12)] {
+            builder.push(label.as_bytes().to_vec(), value_exact, Some(value_prefix));
+        }
+        let trie = builder.build();
+        assert_eq!(trie.lookup(make_key("A")).as_deref(), Some(&1));
+        assert_eq!(trie.lookup(make_key("dir/file")).as_deref(), Some(&2));
+        assert_eq!(trie.lookup(make_key("DIR/FILE/x")).as_deref(), Some(&12));
+        assert_eq!(trie.lookup(make_key("dir")).as_deref(), None);
+
+        let mut builder = Trie::builder();
+        for (label, value_exact, value_prefix) in [("a", 1, 11), ("Dir/File", 2, 12)] {
+            builder.push(label.as_bytes().to_vec(), value_exact, Some(value_prefix));
+        }
+        let trie = builder.build();
+        assert_eq!(trie.lookup(make_key("A")).as_deref(), None);
+        assert_eq!(trie.lookup(make_key("dir/file")).as_deref(), None);
+        assert_eq!(trie.lookup(make_key("Dir/File")).as_deref(), Some(&2));
+    }
+
     #[test]
     fn value_compacting() {
         let mut builder = Trie::builder();
@@ -591,4 +666,30 @@ mod tests {
         let trie = builder.build();
         assert_eq!(trie.values.len(), 2);
     }
+
+    #[test]
+    fn consistency() {
+        let mut builder = Trie::builder();
+        for (label, value_exact, value_prefix) in [("a", 1, 11), ("a/bc", 2, 12), ("d", 3, 13)] {
+            builder.push(label.as_bytes().to_vec(), value_exact, Some(value_prefix));
+        }
+        let trie = builder.build();
+        assert!(trie.is_consistent());
+
+        let mut broken = trie.clone();
+        broken.labels.pop();
+        assert!(!broken.is_consistent());
+
+        let mut broken = trie.clone();
+        broken.values.pop();
+        assert!(!broken.is_consistent());
+
+        let mut broken = trie.clone();
+        broken.nodes[1].children = 0..1;
+        assert!(!broken.is_consistent());
+
+        let mut broken = trie.clone();
+        broken.nodes.clear();
+        assert!(!broken.is_consistent());
+    }
 }
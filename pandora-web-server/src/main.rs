@@ -16,11 +16,14 @@
 
 use clap::Parser;
 use log::error;
+use pandora_module_utils::handler_order::HandlerOrder;
 use pandora_module_utils::{merge_conf, merge_opt, FromYaml, RequestFilter};
 use startup_module::{DefaultApp, StartupConf, StartupOpt};
 
 #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
 struct Handler {
+    #[pandora(handler_order)]
+    order: HandlerOrder,
     #[cfg(feature = "ip-anonymization-top-level")]
     anonymization: ip_anonymization_module::IPAnonymizationHandler,
     #[cfg(feature = "common-log-top-level")]
@@ -30,9 +33,18 @@ struct Handler {
     #[cfg(feature = "headers-top-level")]
     headers: headers_module::HeadersHandler,
     #[cfg(feature = "auth-top-level")]
+    #[pandora(
+        before = "well_known",
+        before = "upstream",
+        before = "static_files",
+        before = "response",
+        before = "virtual_hosts"
+    )]
     auth: auth_module::AuthHandler,
     #[cfg(feature = "rewrite-top-level")]
     rewrite: rewrite_module::RewriteHandler,
+    #[cfg(feature = "response-top-level")]
+    well_known: response_module::WellKnownHandler,
     #[cfg(feature = "upstream-top-level")]
     upstream: upstream_module::UpstreamHandler,
     #[cfg(feature = "static-files-top-level")]
@@ -55,6 +67,8 @@ struct Handler {
 
 #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
 struct HostHandler {
+    #[pandora(handler_order)]
+    order: HandlerOrder,
     #[cfg(feature = "ip-anonymization-per-host")]
     anonymization: ip_anonymization_module::IPAnonymizationHandler,
     #[cfg(feature = "common-log-per-host")]
@@ -64,9 +78,17 @@ struct HostHandler {
     #[cfg(feature = "headers-per-host")]
     headers: headers_module::HeadersHandler,
     #[cfg(feature = "auth-per-host")]
+    #[pandora(
+        before = "well_known",
+        before = "upstream",
+        before = "static_files",
+        before = "response"
+    )]
     auth: auth_module::AuthHandler,
     #[cfg(feature = "rewrite-per-host")]
     rewrite: rewrite_module::RewriteHandler,
+    #[cfg(feature = "response-per-host")]
+    well_known: response_module::WellKnownHandler,
     #[cfg(feature = "upstream-per-host")]
     upstream: upstream_module::UpstreamHandler,
     #[cfg(feature = "static-files-per-host")]
@@ -83,11 +105,11 @@ struct Opt {
     anonymization: ip_anonymization_module::IPAnonymizationOpt,
     #[cfg(feature = "common-log-top-level")]
     log: common_log_module::CommonLogOpt,
-    #[cfg(feature = "auth-top-level")]
+    #[cfg(feature = "compression-top-level")]
     compression: compression_module::CompressionOpt,
-    #[cfg(feature = "static-files-top-level")]
+    #[cfg(feature = "auth-top-level")]
     auth: auth_module::AuthOpt,
-    #[cfg(feature = "compression-top-level")]
+    #[cfg(feature = "static-files-top-level")]
     static_files: static_files_module::StaticFilesOpt,
 }
 
@@ -124,6 +146,19 @@ fn main() {
     conf.handler.static_files.merge_with_opt(opt.static_files);
 
     let server = match DefaultApp::<Handler>::from_conf(conf.handler)
+        .map(|app| {
+            app.with_max_uri_length(conf.startup.max_uri_length)
+                .with_strict_path(conf.startup.strict_path)
+                .with_server_timing(
+                    conf.startup.server_timing,
+                    &conf.startup.server_timing_clients,
+                )
+                .with_metrics(
+                    conf.startup.metrics_path.as_deref(),
+                    &conf.startup.metrics_clients,
+                )
+        })
+        .and_then(|app| app.with_inline_files(&conf.startup.inline_files))
         .and_then(|app| conf.startup.into_server(app, Some(opt.startup)))
     {
         Ok(server) => server,
@@ -135,3 +170,82 @@ fn main() {
 
     server.run_forever();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(
+        all(feature = "auth-top-level", feature = "static-files-top-level"),
+        all(feature = "auth-per-host", feature = "static-files-per-host")
+    ))]
+    use pandora_module_utils::pingora::Error;
+
+    #[cfg(any(
+        all(feature = "auth-top-level", feature = "static-files-top-level"),
+        all(feature = "auth-per-host", feature = "static-files-per-host")
+    ))]
+    fn check(conf: &str) -> Result<Handler, Box<Error>> {
+        Handler::try_from(<Handler as RequestFilter>::Conf::from_yaml(conf).unwrap())
+    }
+
+    #[cfg(all(feature = "auth-top-level", feature = "static-files-top-level"))]
+    #[test]
+    fn case_insensitive_auth_rules() {
+        let conf = |case_insensitive: bool| {
+            format!(
+                r#"
+                    root: .
+                    case_insensitive_paths: {case_insensitive}
+                    auth_rules:
+                        - path: /admin/*
+                          auth_mode: http
+                          auth_credentials:
+                              me: test
+                "#
+            )
+        };
+
+        assert!(check(&conf(true)).is_err());
+        assert!(check(&conf(false)).is_ok());
+    }
+
+    #[cfg(all(feature = "auth-per-host", feature = "static-files-per-host"))]
+    #[test]
+    fn case_insensitive_subpath_auth() {
+        let conf = |vhosts_case_insensitive: bool| {
+            format!(
+                r#"
+                    case_insensitive_paths: {vhosts_case_insensitive}
+                    vhosts:
+                        localhost:
+                            root: .
+                            case_insensitive_paths: true
+                            subpaths:
+                                /admin/*:
+                                    auth_mode: http
+                                    auth_credentials:
+                                        me: test
+                "#
+            )
+        };
+
+        // /ADMIN/secret would be served by the host’s static files without auth
+        assert!(check(&conf(false)).is_err());
+        assert!(check(&conf(true)).is_ok());
+
+        assert!(check(
+            r#"
+                vhosts:
+                    localhost:
+                        root: .
+                        subpaths:
+                            /admin/*:
+                                auth_mode: http
+                                auth_credentials:
+                                    me: test
+            "#
+        )
+        .is_ok());
+    }
+}
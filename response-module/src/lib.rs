@@ -14,14 +14,19 @@
 
 #![doc = include_str!("../README.md")]
 
+mod well_known;
+
 use async_trait::async_trait;
 use headers_module::configuration::CustomHeadersConf;
-use http::{header, HeaderName, HeaderValue, StatusCode};
-use pandora_module_utils::pingora::{ResponseHeader, SessionWrapper};
+use http::{HeaderName, HeaderValue, StatusCode};
+use pandora_module_utils::compression::PrecompressedBody;
+use pandora_module_utils::pingora::{CompressionAlgorithm, ResponseHeader, SessionWrapper};
 use pandora_module_utils::{pingora::Error, RequestFilterResult};
 use pandora_module_utils::{DeserializeMap, RequestFilter};
 use serde::de::{Deserialize, Deserializer, Unexpected};
 
+pub use well_known::{WellKnownConf, WellKnownHandler, WellKnownResourceConf};
+
 fn deserialize_status_code<'de, D>(deserializer: D) -> Result<StatusCode, D::Error>
 where
     D: Deserializer<'de>,
@@ -44,12 +49,18 @@ pub struct ResponseConf {
     pub response_status: StatusCode,
     /// HTTP headers to add to the response if any
     pub response_headers: CustomHeadersConf,
+    /// Compression level for the Brotli-compressed variant of the response (omit to disable)
+    pub response_compression_level_brotli: Option<u32>,
+    /// Compression level for the Zstandard-compressed variant of the response (omit to disable)
+    pub response_compression_level_zstd: Option<u32>,
+    /// Compression level for the gzip-compressed variant of the response (omit to disable)
+    pub response_compression_level_gzip: Option<u32>,
 }
 
 /// Response module handler
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ResponseHandler {
-    response: Option<String>,
+    response: Option<PrecompressedBody>,
     response_status: StatusCode,
     response_headers: Vec<(HeaderName, HeaderValue)>,
 }
@@ -58,8 +69,29 @@ impl TryFrom<ResponseConf> for ResponseHandler {
     type Error = Box<Error>;
 
     fn try_from(conf: ResponseConf) -> Result<Self, Self::Error> {
+        let algorithms = [
+            (
+                CompressionAlgorithm::Brotli,
+                conf.response_compression_level_brotli,
+            ),
+            (
+                CompressionAlgorithm::Zstd,
+                conf.response_compression_level_zstd,
+            ),
+            (
+                CompressionAlgorithm::Gzip,
+                conf.response_compression_level_gzip,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(algorithm, level)| Some((algorithm, level?)))
+        .collect::<Vec<_>>();
+
         Ok(Self {
-            response: conf.response,
+            response: conf
+                .response
+                .map(|response| PrecompressedBody::new(response, &algorithms))
+                .transpose()?,
             response_status: conf.response_status,
             response_headers: conf.response_headers.headers.into_iter().collect(),
         })
@@ -81,17 +113,15 @@ impl RequestFilter for ResponseHandler {
     ) -> Result<RequestFilterResult, Box<Error>> {
         if let Some(response) = &self.response {
             let mut response_header =
-                ResponseHeader::build(self.response_status, Some(self.response_headers.len() + 1))?;
+                ResponseHeader::build(self.response_status, Some(self.response_headers.len() + 3))?;
             for (name, value) in &self.response_headers {
                 response_header.insert_header(name, value)?;
             }
-            response_header.insert_header(header::CONTENT_LENGTH, response.len())?;
+            let body = response.apply(session, &mut response_header)?;
             session
                 .write_response_header(Box::new(response_header), false)
                 .await?;
-            session
-                .write_response_body(Some(response.clone().into()), true)
-                .await?;
+            session.write_response_body(Some(body), true).await?;
             Ok(RequestFilterResult::ResponseSent)
         } else {
             Ok(RequestFilterResult::Unhandled)
@@ -103,6 +133,7 @@ impl RequestFilter for ResponseHandler {
 mod tests {
     use super::*;
 
+    use http::header;
     use pandora_module_utils::{
         pingora::{create_test_session, ErrorType, RequestHeader, Session},
         FromYaml,
@@ -172,6 +203,22 @@ mod tests {
         assert_headers(response, vec![("Content-Length", "2")]);
     }
 
+    #[test(tokio::test)]
+    async fn head_request() {
+        let mut app = make_app("response: hi");
+        let header = RequestHeader::build("HEAD", b"/", None).unwrap();
+        let session = create_test_session(header).await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(result.body_str(), "");
+
+        let session = result.session();
+        assert_eq!(session.req_header().method, "HEAD");
+        let response = session.response_written().unwrap();
+        assert_eq!(response.status, 200);
+        assert_headers(response, vec![("Content-Length", "2")]);
+    }
+
     #[test(tokio::test)]
     async fn custom_status() {
         let mut app = make_app(
@@ -218,4 +265,70 @@ mod tests {
             ],
         );
     }
+
+    #[test(tokio::test)]
+    async fn compressed() {
+        let text = "Hello, world! ".repeat(1000);
+        let mut app = make_app(&format!(
+            r#"
+                response: "{text}"
+                response_compression_level_brotli: 5
+            "#
+        ));
+
+        let mut session = make_session().await;
+        session
+            .req_header_mut()
+            .insert_header(header::ACCEPT_ENCODING, "gzip, br")
+            .unwrap();
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+
+        let compressed = result.body().to_vec();
+        assert!(compressed.len() < text.len());
+        let decompressed = CompressionAlgorithm::Brotli
+            .decompressor(true)
+            .unwrap()
+            .encode(&compressed, true)
+            .unwrap();
+        assert_eq!(decompressed, text.as_bytes());
+
+        let session = result.session();
+        let response = session.response_written().unwrap();
+        assert_eq!(response.status, 200);
+        assert_headers(
+            response,
+            vec![
+                ("Content-Length", &compressed.len().to_string()),
+                ("Content-Encoding", "br"),
+                ("Vary", "Accept-Encoding"),
+            ],
+        );
+
+        // No compression if the client doesn’t accept Brotli
+        let mut session = make_session().await;
+        session
+            .req_header_mut()
+            .insert_header(header::ACCEPT_ENCODING, "gzip, br;q=0")
+            .unwrap();
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(result.body_str(), text);
+
+        let session = result.session();
+        let response = session.response_written().unwrap();
+        assert_eq!(response.status, 200);
+        assert_headers(
+            response,
+            vec![
+                ("Content-Length", &text.len().to_string()),
+                ("Vary", "Accept-Encoding"),
+            ],
+        );
+
+        let session = make_session().await;
+        let result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(result.body_str(), text);
+    }
 }
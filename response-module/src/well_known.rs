@@ -0,0 +1,270 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Handler serving resources below `/.well-known/` from configuration
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{header, HeaderValue, Method, StatusCode};
+use log::error;
+use pandora_module_utils::pingora::{Error, ErrorType, ResponseHeader, SessionWrapper};
+use pandora_module_utils::{DeserializeMap, RequestFilter, RequestFilterResult};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Path prefix of the well-known resources
+const WELL_KNOWN_PREFIX: &str = "/.well-known/";
+
+/// Default MIME type of well-known resources, `security.txt` requires this one
+const DEFAULT_CONTENT_TYPE: &str = "text/plain;charset=utf-8";
+
+/// Configuration of a single well-known resource
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct WellKnownResourceConf {
+    /// Inline contents of the resource
+    pub content: Option<String>,
+    /// File to read the contents of the resource from
+    pub file: Option<PathBuf>,
+    /// MIME type of the resource, `text/plain;charset=utf-8` if omitted
+    pub content_type: Option<String>,
+}
+
+/// Configuration file settings of the well-known resources handler
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct WellKnownConf {
+    /// Resources mapped by their path below `/.well-known/`, e.g. `security.txt`
+    pub well_known: HashMap<String, WellKnownResourceConf>,
+}
+
+/// A well-known resource ready to be served
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Resource {
+    content: Bytes,
+    content_type: HeaderValue,
+}
+
+impl TryFrom<WellKnownResourceConf> for Resource {
+    type Error = Box<Error>;
+
+    fn try_from(conf: WellKnownResourceConf) -> Result<Self, Self::Error> {
+        let content = match (conf.content, conf.file) {
+            (Some(content), None) => content.into_bytes(),
+            (None, Some(file)) => std::fs::read(&file).map_err(|err| {
+                error!("failed reading well-known resource from {file:?}: {err}");
+                Error::new(ErrorType::InternalError)
+            })?,
+            _ => {
+                error!("exactly one of `content` and `file` settings is required for well-known resources");
+                return Err(Error::new(ErrorType::InternalError));
+            }
+        };
+
+        let content_type = conf.content_type.as_deref().unwrap_or(DEFAULT_CONTENT_TYPE);
+        let content_type = HeaderValue::from_str(content_type).map_err(|err| {
+            error!("invalid content type {content_type} for well-known resource: {err}");
+            Error::new(ErrorType::InternalError)
+        })?;
+
+        Ok(Self {
+            content: content.into(),
+            content_type,
+        })
+    }
+}
+
+/// Well-known resources handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WellKnownHandler {
+    resources: HashMap<String, Resource>,
+}
+
+impl TryFrom<WellKnownConf> for WellKnownHandler {
+    type Error = Box<Error>;
+
+    fn try_from(conf: WellKnownConf) -> Result<Self, Self::Error> {
+        let mut resources = HashMap::with_capacity(conf.well_known.len());
+        for (name, resource) in conf.well_known {
+            let name = name.trim_matches('/').to_owned();
+            let resource = resource.try_into().map_err(|err| {
+                Error::because(
+                    ErrorType::InternalError,
+                    format!("failed setting up well-known resource {name}"),
+                    err,
+                )
+            })?;
+            resources.insert(name, resource);
+        }
+        Ok(Self { resources })
+    }
+}
+
+#[async_trait]
+impl RequestFilter for WellKnownHandler {
+    type Conf = WellKnownConf;
+
+    type CTX = ();
+
+    fn new_ctx() -> Self::CTX {}
+
+    async fn request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        _ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        let method = &session.req_header().method;
+        if method != Method::GET && method != Method::HEAD {
+            return Ok(RequestFilterResult::Unhandled);
+        }
+
+        let resource = session
+            .uri()
+            .path()
+            .strip_prefix(WELL_KNOWN_PREFIX)
+            .and_then(|name| self.resources.get(name));
+        let Some(resource) = resource else {
+            return Ok(RequestFilterResult::Unhandled);
+        };
+
+        let send_body = session.req_header().method != Method::HEAD;
+        let mut header = ResponseHeader::build(StatusCode::OK, Some(2))?;
+        header.insert_header(header::CONTENT_LENGTH, resource.content.len())?;
+        header.insert_header(header::CONTENT_TYPE, &resource.content_type)?;
+        session
+            .write_response_header(Box::new(header), !send_body)
+            .await?;
+        if send_body {
+            session
+                .write_response_body(Some(resource.content.clone()), true)
+                .await?;
+        }
+        Ok(RequestFilterResult::ResponseSent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{create_test_session, RequestHeader, Session};
+    use pandora_module_utils::FromYaml;
+    use startup_module::DefaultApp;
+    use test_log::test;
+
+    fn make_app(conf: &str) -> DefaultApp<WellKnownHandler> {
+        DefaultApp::new(
+            <WellKnownHandler as RequestFilter>::Conf::from_yaml(conf)
+                .unwrap()
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    async fn make_session(method: &str, path: &str) -> Session {
+        let header = RequestHeader::build(method, path.as_bytes(), None).unwrap();
+        create_test_session(header).await
+    }
+
+    #[test(tokio::test)]
+    async fn security_txt() {
+        let mut app = make_app(
+            r#"
+                well_known:
+                    security.txt:
+                        content: |
+                            Contact: mailto:security@example.com
+                            Expires: 2030-01-01T00:00:00.000Z
+            "#,
+        );
+
+        let session = make_session("GET", "/.well-known/security.txt").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(
+            result.body_str(),
+            "Contact: mailto:security@example.com\nExpires: 2030-01-01T00:00:00.000Z\n"
+        );
+        let session = result.session();
+        let response = session.response_written().unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.headers.get("Content-Type").unwrap(),
+            "text/plain;charset=utf-8"
+        );
+        assert_eq!(response.headers.get("Content-Length").unwrap(), "71");
+
+        let session = make_session("HEAD", "/.well-known/security.txt").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(result.body_str(), "");
+        let session = result.session();
+        let response = session.response_written().unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.get("Content-Length").unwrap(), "71");
+
+        // Other paths and methods are left to other handlers
+        for (method, path) in [
+            ("GET", "/.well-known/other.txt"),
+            ("GET", "/security.txt"),
+            ("POST", "/.well-known/security.txt"),
+        ] {
+            let session = make_session(method, path).await;
+            let result = app.handle_request(session).await;
+            assert_eq!(
+                result.err().as_ref().map(|err| &err.etype),
+                Some(&ErrorType::HTTPStatus(404)),
+                "{method} {path}"
+            );
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn from_file() {
+        let mut file = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        file.push("testdata");
+        file.push("apple-app-site-association");
+
+        let mut app = make_app(&format!(
+            r#"
+                well_known:
+                    /apple-app-site-association:
+                        file: {}
+                        content_type: application/json
+            "#,
+            file.display()
+        ));
+
+        let session = make_session("GET", "/.well-known/apple-app-site-association").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(result.body_str(), r#"{"applinks": {}}"#);
+        let session = result.session();
+        let response = session.response_written().unwrap();
+        assert_eq!(
+            response.headers.get("Content-Type").unwrap(),
+            "application/json"
+        );
+    }
+
+    #[test]
+    fn invalid_conf() {
+        for conf in [
+            "well_known: {security.txt: {}}",
+            "well_known: {security.txt: {content: x, file: /dev/null}}",
+            "well_known: {security.txt: {file: /nonexistent/security.txt}}",
+        ] {
+            let parsed = WellKnownConf::from_yaml(conf).unwrap();
+            assert!(WellKnownHandler::try_from(parsed).is_err(), "{conf}");
+        }
+    }
+}
@@ -15,18 +15,22 @@
 //! Structures required to deserialize Rewrite Module configuration from YAML configuration files.
 
 use http::HeaderName;
+use log::warn;
 use pandora_module_utils::merger::PathMatcher;
 use pandora_module_utils::{DeserializeMap, OneOrMany};
 use regex::Regex;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::default::Default;
 use std::fmt::Debug;
+use std::path::PathBuf;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) enum Variable {
     Tail,
     Query,
     Header(HeaderName),
+    Custom(String),
 }
 
 #[derive(Clone, PartialEq, Eq)]
@@ -90,6 +94,10 @@ impl From<&str> for VariableInterpolation {
                             search_start = start + Self::VARIABLE_PREFIX.len();
                             continue;
                         }
+                    } else if !name.is_empty()
+                        && name.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'_')
+                    {
+                        Variable::Custom(name.to_owned())
                     } else {
                         // Not a variable name, look for another variable start further ahead
                         search_start = start + Self::VARIABLE_PREFIX.len();
@@ -127,6 +135,20 @@ impl VariableInterpolation {
     const VARIABLE_PREFIX: &'static str = "${";
     const VARIABLE_SUFFIX: &'static str = "}";
 
+    /// Replaces custom variables by their values from the `variables` setting. Undefined variables
+    /// are replaced by an empty string.
+    pub(crate) fn resolve_custom(&mut self, variables: &HashMap<String, String>) {
+        for part in &mut self.parts {
+            if let VariableInterpolationPart::Variable(Variable::Custom(name)) = part {
+                let value = variables.get(name).cloned().unwrap_or_else(|| {
+                    warn!("undefined variable ${{{name}}} in rewrite target, using empty value");
+                    String::new()
+                });
+                *part = VariableInterpolationPart::Literal(value.into_bytes());
+            }
+        }
+    }
+
     pub(crate) fn interpolate<L>(&self, lookup: L) -> Vec<u8>
     where
         L: Fn(&Variable, &mut Vec<u8>),
@@ -156,6 +178,21 @@ pub enum RewriteType {
     Permanent,
 }
 
+/// Handling of query parameters present multiple times, e.g. `?a=1&a=2`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateQueryParams {
+    /// Query string is left unchanged
+    #[default]
+    Keep,
+    /// Only the first occurrence of a parameter is kept
+    First,
+    /// Only the last occurrence of a parameter is kept
+    Last,
+    /// Requests with duplicate parameters are rejected with `400 Bad Request`
+    Reject,
+}
+
 /// A parsed representation of a field like `from_regex` of the rewrite rule
 #[derive(Debug, Clone, Deserialize)]
 #[serde(try_from = "String")]
@@ -237,6 +274,20 @@ pub struct RewriteRule {
     /// but those containing a `file` parameter.
     pub query_regex: Option<RegexMatch>,
 
+    /// If `true`, `query_regex` is matched against the decoded query string: `+` characters are
+    /// replaced by spaces and percent-encoded sequences are decoded, as for HTML form data. By
+    /// default the query string is matched as sent by the client.
+    pub query_decode: bool,
+
+    /// Additional regular expression to restrict matches to particular `User-Agent` header
+    /// values, e.g. `(?i)googlebot|bingbot` to serve prerendered content to search engine
+    /// crawlers. Prefixing the regular expression with `!` will negate its effect. Requests
+    /// without a `User-Agent` header are matched against an empty string.
+    ///
+    /// Responses to requests this rule was considered for will get a `Vary: User-Agent` header,
+    /// regardless of whether the rule was applied.
+    pub user_agent_regex: Option<RegexMatch>,
+
     /// New URI to be set on match
     ///
     /// The following variables will be resolved:
@@ -250,10 +301,17 @@ pub struct RewriteRule {
     ///   `/file.txt?a=b` will be rewritten into `/file.html?a=b`.
     /// * `${http_<header>}`: This allows inserting arbitrary HTTP headers into the redirect
     ///   target.
+    /// * `${<name>}`: Any other name refers to a value defined in the `variables` setting.
     pub to: VariableInterpolation,
 
     /// Rewriting type, one of `internal` (default), `redirect` or `permanent`
     pub r#type: RewriteType,
+
+    /// Optional rule name
+    ///
+    /// If set, a counter with this name will be registered and incremented each time the rule is
+    /// applied. Counters are only maintained for named rules.
+    pub name: Option<String>,
 }
 
 impl Default for RewriteRule {
@@ -262,17 +320,51 @@ impl Default for RewriteRule {
             from: "/*".into(),
             from_regex: None,
             query_regex: None,
+            query_decode: false,
+            user_agent_regex: None,
             to: "/".into(),
             r#type: RewriteType::Internal,
+            name: None,
         }
     }
 }
 
 /// Configuration file settings of the rewrite module
-#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct RewriteConf {
     /// A list of rewrite rules
     pub rewrite_rules: OneOrMany<RewriteRule>,
+    /// Maximal number of rewrite rules that can be configured
+    pub max_rewrite_rules: usize,
+    /// File to store the compiled rewrite rules in. It is loaded on startup if it matches the
+    /// configured rules, otherwise the rules are compiled and the file is updated.
+    pub rewrite_router_cache: Option<PathBuf>,
+    /// Named values that can be used in rewrite targets like `${name}`
+    pub variables: HashMap<String, String>,
+    /// Handling of query parameters present multiple times, one of `keep` (default), `first`,
+    /// `last` or `reject`. Parameter names are compared after decoding.
+    pub duplicate_query_params: DuplicateQueryParams,
+    /// Requests with a query string matching any of these regular expressions are rejected with
+    /// `400 Bad Request`, e.g. `\.\./` to reject directory traversal attempts. The query string
+    /// is decoded before matching.
+    pub reject_query_regex: OneOrMany<RegexMatch>,
+    /// Request headers mapped to regular expressions, requests where any of the header’s values
+    /// matches are rejected with `400 Bad Request`.
+    pub reject_header_regex: HashMap<String, OneOrMany<RegexMatch>>,
+}
+
+impl Default for RewriteConf {
+    fn default() -> Self {
+        Self {
+            rewrite_rules: Default::default(),
+            max_rewrite_rules: 10000,
+            rewrite_router_cache: None,
+            variables: HashMap::new(),
+            duplicate_query_params: DuplicateQueryParams::Keep,
+            reject_query_regex: Default::default(),
+            reject_header_regex: HashMap::new(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -290,9 +382,16 @@ mod tests {
         );
 
         assert_eq!(
-            VariableInterpolation::from("ab${xyz}cd")
+            VariableInterpolation::from("ab${x-z}cd")
                 .interpolate(|_, _| panic!("Unexpected lookup call")),
-            b"ab${xyz}cd".to_vec()
+            b"ab${x-z}cd".to_vec()
+        );
+
+        let mut interpolation = VariableInterpolation::from("ab${xyz}cd${undefined}");
+        interpolation.resolve_custom(&HashMap::from([("xyz".to_owned(), "value".to_owned())]));
+        assert_eq!(
+            interpolation.interpolate(|_, _| panic!("Unexpected lookup call")),
+            b"abvaluecd".to_vec()
         );
 
         assert_eq!(
@@ -307,7 +406,7 @@ mod tests {
         );
 
         assert_eq!(
-            VariableInterpolation::from("a${query}${tail}bc${http_abc}d${unknown}e").interpolate(
+            VariableInterpolation::from("a${query}${tail}bc${http_abc}d${unknown-}e").interpolate(
                 |variable, result| {
                     result.extend_from_slice(if variable == &Variable::Query {
                         b"query resolved"
@@ -320,7 +419,7 @@ mod tests {
                     })
                 }
             ),
-            b"aquery resolvedtail resolvedbcheader resolvedd${unknown}e".to_vec()
+            b"aquery resolvedtail resolvedbcheader resolvedd${unknown-}e".to_vec()
         );
 
         assert_eq!(
@@ -15,35 +15,202 @@
 //! Handler for the `request_filter` phase.
 
 use async_trait::async_trait;
-use http::StatusCode;
+use http::uri::{PathAndQuery, Uri};
+use http::{header, HeaderName, HeaderValue, StatusCode};
 use log::{error, trace};
 use pandora_module_utils::merger::Merger;
-use pandora_module_utils::pingora::{Error, SessionWrapper};
+use pandora_module_utils::metrics::Counter;
+use pandora_module_utils::pingora::{
+    add_module_once, Error, ErrorType, HttpModule, HttpModuleBuilder, HttpModules, ResponseHeader,
+    SessionWrapper,
+};
 use pandora_module_utils::router::{Path, Router};
-use pandora_module_utils::standard_response::redirect_response;
+use pandora_module_utils::standard_response::{error_response, redirect_response};
 use pandora_module_utils::{RequestFilter, RequestFilterResult};
+use percent_encoding::percent_decode_str;
+use std::any::Any;
+use std::borrow::Cow;
 
-use crate::configuration::{RegexMatch, RewriteConf, RewriteType, Variable, VariableInterpolation};
+use crate::configuration::{
+    DuplicateQueryParams, RegexMatch, RewriteConf, RewriteType, Variable, VariableInterpolation,
+};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct Rule {
     from_regex: Option<RegexMatch>,
     query_regex: Option<RegexMatch>,
+    query_decode: bool,
+    user_agent_regex: Option<RegexMatch>,
     to: VariableInterpolation,
     r#type: RewriteType,
+    counter: Option<Counter>,
+}
+
+struct VaryHttpModuleBuilder {}
+
+impl HttpModuleBuilder for VaryHttpModuleBuilder {
+    fn init(&self) -> Box<dyn HttpModule + Sync + Send> {
+        Box::new(VaryHttpModule { user_agent: false })
+    }
+}
+
+/// Adds `Vary: User-Agent` to responses that depended on a `user_agent_regex` condition.
+struct VaryHttpModule {
+    user_agent: bool,
+}
+
+#[async_trait]
+impl HttpModule for VaryHttpModule {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    async fn response_header_filter(
+        &mut self,
+        resp: &mut ResponseHeader,
+        _end_of_stream: bool,
+    ) -> Result<(), Box<Error>> {
+        if !self.user_agent {
+            return Ok(());
+        }
+
+        let present = resp
+            .headers
+            .get_all(header::VARY)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|name| {
+                let name = name.trim();
+                name == "*" || name.eq_ignore_ascii_case("user-agent")
+            });
+        if !present {
+            resp.append_header(header::VARY, HeaderValue::from_static("User-Agent"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes a query string like HTML form data: `+` is a space, percent-encoded sequences are
+/// decoded. Unlike in the path, `+` only has this meaning in the query.
+fn decode_query(query: &str) -> Cow<'_, str> {
+    if !query.contains(['+', '%']) {
+        return Cow::Borrowed(query);
+    }
+
+    let query = query.replace('+', " ");
+    Cow::Owned(percent_decode_str(&query).decode_utf8_lossy().into_owned())
+}
+
+/// Removes duplicate parameters from a query string according to `mode`, keeping the order of the
+/// remaining parameters. Returns `Ok(None)` if the query string doesn’t need to change and
+/// `Err(())` if duplicate parameters should be rejected.
+fn normalize_query(query: &str, mode: DuplicateQueryParams) -> Result<Option<String>, ()> {
+    let params = query.split('&').collect::<Vec<_>>();
+    let names = params
+        .iter()
+        .map(|param| decode_query(param.split('=').next().unwrap_or_default()))
+        .collect::<Vec<_>>();
+
+    let is_duplicate = |index: usize| {
+        let name = &names[index];
+        !name.is_empty()
+            && match mode {
+                DuplicateQueryParams::Last => names[index + 1..].contains(name),
+                _ => names[..index].contains(name),
+            }
+    };
+
+    let mut result = Vec::with_capacity(params.len());
+    for (index, param) in params.iter().enumerate() {
+        if is_duplicate(index) {
+            if mode == DuplicateQueryParams::Reject {
+                return Err(());
+            }
+        } else {
+            result.push(*param);
+        }
+    }
+
+    if result.len() == params.len() {
+        Ok(None)
+    } else {
+        Ok(Some(result.join("&")))
+    }
+}
+
+/// Makes sure the response will get a `Vary: User-Agent` header.
+fn set_vary_user_agent(session: &mut impl SessionWrapper) {
+    if let Some(module) = session.downstream_modules_ctx.get_mut::<VaryHttpModule>() {
+        module.user_agent = true;
+    }
 }
 
 /// Rewrite module handler
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RewriteHandler {
-    router: Router<Vec<(Path, Rule)>>,
+    rules: Vec<(Path, Rule)>,
+    router: Router<Vec<usize>>,
+    duplicate_query_params: DuplicateQueryParams,
+    reject_query_regex: Vec<RegexMatch>,
+    reject_header_regex: Vec<(HeaderName, Vec<RegexMatch>)>,
+}
+
+impl RewriteHandler {
+    /// Checks whether the request’s query string or headers match any of the patterns configured
+    /// via `reject_query_regex` and `reject_header_regex`.
+    fn is_rejected(&self, session: &impl SessionWrapper) -> bool {
+        if !self.reject_query_regex.is_empty() {
+            if let Some(query) = session.uri().query() {
+                let query = decode_query(query);
+                if self.reject_query_regex.iter().any(|r| r.matches(&query)) {
+                    trace!("Query string matches a rejected pattern: {query}");
+                    return true;
+                }
+            }
+        }
+
+        for (name, regexes) in &self.reject_header_regex {
+            for value in session.req_header().headers.get_all(name) {
+                let value = String::from_utf8_lossy(value.as_bytes());
+                if regexes.iter().any(|r| r.matches(&value)) {
+                    trace!("Header {name} matches a rejected pattern: {value}");
+                    return true;
+                }
+            }
+        }
+        false
+    }
 }
 
 impl TryFrom<RewriteConf> for RewriteHandler {
     type Error = Box<Error>;
 
     fn try_from(mut conf: RewriteConf) -> Result<Self, Self::Error> {
-        let mut merger = Merger::new();
+        if conf.rewrite_rules.len() > conf.max_rewrite_rules {
+            error!(
+                "{} rewrite rules configured, exceeding the limit of {} set via max_rewrite_rules",
+                conf.rewrite_rules.len(),
+                conf.max_rewrite_rules
+            );
+            return Err(Error::new(ErrorType::InternalError));
+        }
+
+        let reject_header_regex = conf
+            .reject_header_regex
+            .into_iter()
+            .map(|(name, regexes)| {
+                let name = HeaderName::try_from(&name).map_err(|err| {
+                    error!("invalid header name {name} in reject_header_regex: {err}");
+                    Error::new(ErrorType::InternalError)
+                })?;
+                Ok((name, regexes.into()))
+            })
+            .collect::<Result<_, Box<Error>>>()?;
 
         // Add in reverse order, so that the first rule listed in configuration takes precedence.
         conf.rewrite_rules.reverse();
@@ -51,21 +218,53 @@ impl TryFrom<RewriteConf> for RewriteHandler {
         // Sort by prefix so that exact rules get priority.
         conf.rewrite_rules.sort_by(|a, b| a.from.cmp(&b.from));
 
+        // The router only refers to rules by their index, so that it doesn’t depend on anything
+        // but the rules’ paths and can be cached.
+        let mut matchers = Vec::with_capacity(conf.rewrite_rules.len());
+        let mut rules = Vec::with_capacity(conf.rewrite_rules.len());
         for rule in conf.rewrite_rules {
             let path = rule.from.path.clone();
-            let from = rule.from;
+            matchers.push(rule.from);
+            let mut to = rule.to;
+            to.resolve_custom(&conf.variables);
             let rule = Rule {
                 from_regex: rule.from_regex,
                 query_regex: rule.query_regex,
-                to: rule.to,
+                query_decode: rule.query_decode,
+                user_agent_regex: rule.user_agent_regex,
+                to,
                 r#type: rule.r#type,
+                counter: rule
+                    .name
+                    .map(|name| Counter::register("rewrite_module", &name)),
             };
 
-            merger.push(from, (path, rule));
+            rules.push((path, rule));
         }
 
+        let build = || {
+            let mut merger = Merger::new();
+            for (index, from) in matchers.iter().enumerate() {
+                merger.push(from.clone(), index);
+            }
+            merger.merge(|indices| indices.copied().collect::<Vec<_>>())
+        };
+        let router = if let Some(cache) = &conf.rewrite_router_cache {
+            let key = matchers
+                .iter()
+                .map(|from| format!("{from:?}\n"))
+                .collect::<String>();
+            Router::load_or_build(cache, key.as_bytes(), build)
+        } else {
+            build()
+        };
+
         Ok(Self {
-            router: merger.merge(|rules| rules.cloned().collect::<Vec<_>>()),
+            rules,
+            router,
+            duplicate_query_params: conf.duplicate_query_params,
+            reject_query_regex: conf.reject_query_regex.into(),
+            reject_header_regex,
         })
     }
 }
@@ -78,11 +277,44 @@ impl RequestFilter for RewriteHandler {
 
     fn new_ctx() -> Self::CTX {}
 
+    fn init_downstream_modules(modules: &mut HttpModules) {
+        // Rewrite rules can be configured both globally and per host
+        add_module_once(modules, Box::new(VaryHttpModuleBuilder {}));
+    }
+
     async fn request_filter(
         &self,
         session: &mut impl SessionWrapper,
         _ctx: &mut Self::CTX,
     ) -> Result<RequestFilterResult, Box<Error>> {
+        if self.is_rejected(session) {
+            error_response(session, StatusCode::BAD_REQUEST).await?;
+            return Ok(RequestFilterResult::ResponseSent);
+        }
+
+        if self.duplicate_query_params != DuplicateQueryParams::Keep {
+            if let Some(query) = session.uri().query() {
+                match normalize_query(query, self.duplicate_query_params) {
+                    Ok(Some(query)) => {
+                        trace!("Removed duplicate query parameters, new query: {query}");
+                        let mut parts = session.uri().clone().into_parts();
+                        let path = format!("{}?{query}", session.uri().path());
+                        parts.path_and_query = PathAndQuery::try_from(path).ok();
+                        match Uri::from_parts(parts) {
+                            Ok(uri) => session.set_uri(uri),
+                            Err(err) => error!("Could not build normalized URI: {err}"),
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(()) => {
+                        trace!("Rejecting request with duplicate query parameters: {query}");
+                        error_response(session, StatusCode::BAD_REQUEST).await?;
+                        return Ok(RequestFilterResult::ResponseSent);
+                    }
+                }
+            }
+        }
+
         let path = session.uri().path();
         trace!("Determining rewrite rules for path {path}");
 
@@ -95,8 +327,10 @@ impl RequestFilter for RewriteHandler {
 
         trace!("Applying rewrite rules: {list:?}");
 
+        let mut vary_user_agent = false;
+
         // Iterate in reverse order, merging puts rules in reverse order of precedence.
-        for (rule_path, rule) in list.iter().rev() {
+        for (rule_path, rule) in list.iter().rev().map(|index| &self.rules[*index]) {
             if let Some(from_regex) = &rule.from_regex {
                 if !from_regex.matches(session.uri().path()) {
                     continue;
@@ -104,7 +338,28 @@ impl RequestFilter for RewriteHandler {
             }
 
             if let Some(query_regex) = &rule.query_regex {
-                if !query_regex.matches(session.uri().query().unwrap_or("")) {
+                let query = session.uri().query().unwrap_or("");
+                let matches = if rule.query_decode {
+                    query_regex.matches(&decode_query(query))
+                } else {
+                    query_regex.matches(query)
+                };
+                if !matches {
+                    continue;
+                }
+            }
+
+            if let Some(user_agent_regex) = &rule.user_agent_regex {
+                // The response depends on the user agent now, whether the rule applies or not
+                vary_user_agent = true;
+
+                let user_agent = session
+                    .req_header()
+                    .headers
+                    .get(header::USER_AGENT)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or("");
+                if !user_agent_regex.matches(user_agent) {
                     continue;
                 }
             }
@@ -114,6 +369,10 @@ impl RequestFilter for RewriteHandler {
                 String::from_utf8_lossy(rule_path)
             );
 
+            if let Some(counter) = &rule.counter {
+                counter.increment();
+            }
+
             let target = rule.to.interpolate(|variable, result| match variable {
                 Variable::Tail => {
                     result.extend_from_slice(
@@ -133,8 +392,14 @@ impl RequestFilter for RewriteHandler {
                         result.extend_from_slice(value.as_bytes())
                     }
                 }
+                // Custom variables are resolved when the handler is created
+                Variable::Custom(_) => {}
             });
 
+            if vary_user_agent {
+                set_vary_user_agent(session);
+            }
+
             match rule.r#type {
                 RewriteType::Internal => {
                     let uri = match target.as_slice().try_into() {
@@ -166,6 +431,10 @@ impl RequestFilter for RewriteHandler {
             }
         }
 
+        if vary_user_agent {
+            set_vary_user_agent(session);
+        }
+
         Ok(RequestFilterResult::Unhandled)
     }
 }
@@ -176,6 +445,7 @@ mod tests {
 
     use pandora_module_utils::pingora::{create_test_session, ErrorType, RequestHeader, Session};
     use pandora_module_utils::FromYaml;
+    use response_module::ResponseHandler;
     use startup_module::DefaultApp;
     use test_log::test;
 
@@ -241,6 +511,46 @@ mod tests {
         assert_eq!(result.session().original_uri(), "/path/file.txt");
     }
 
+    #[test(tokio::test)]
+    async fn router_cache() {
+        async fn rewritten(app: &mut DefaultApp<RewriteHandler>, path: &str) -> String {
+            let session = make_session(path).await;
+            let mut result = app.handle_request(session).await;
+            let uri = result.session().uri().to_string();
+            uri
+        }
+
+        let cache = std::env::temp_dir().join(format!("rewrite-cache-{}", std::process::id()));
+        let _ = std::fs::remove_file(&cache);
+        let conf = |from: &str| {
+            format!(
+                "rewrite_router_cache: {}\nrewrite_rules:\n- from: {from}\n  to: /another\n- from: /exact\n  to: /other",
+                cache.display()
+            )
+        };
+
+        let mut app = make_app(&conf("/path/*"));
+        assert!(cache.exists());
+        assert_eq!(rewritten(&mut app, "/path/file").await, "/another");
+
+        // Same rules, router is loaded from the cache file
+        let data = std::fs::read(&cache).unwrap();
+        let mut app = make_app(&conf("/path/*"));
+        assert_eq!(std::fs::read(&cache).unwrap(), data);
+        assert_eq!(rewritten(&mut app, "/path/file").await, "/another");
+        assert_eq!(rewritten(&mut app, "/exact").await, "/other");
+        assert_eq!(rewritten(&mut app, "/file").await, "/file");
+
+        // Changed rules, cache is updated
+        let mut app = make_app(&conf("/file"));
+        assert_ne!(std::fs::read(&cache).unwrap(), data);
+        assert_eq!(rewritten(&mut app, "/path/file").await, "/path/file");
+        assert_eq!(rewritten(&mut app, "/exact").await, "/other");
+        assert_eq!(rewritten(&mut app, "/file").await, "/another");
+
+        std::fs::remove_file(&cache).unwrap();
+    }
+
     #[test(tokio::test)]
     async fn conditions() {
         let mut app = make_app(
@@ -315,6 +625,40 @@ mod tests {
         assert_eq!(result.session().uri(), "/file.txt?no_redirect");
     }
 
+    #[test(tokio::test)]
+    async fn query_decode() {
+        let mut app = make_app(
+            r#"
+                rewrite_rules:
+                -
+                    from: /search
+                    query_regex: "^q=hello world$"
+                    query_decode: true
+                    to: /decoded
+                -
+                    from: /search
+                    query_regex: "^q=a\\+b$"
+                    to: /raw
+                -
+                    from: /a+b.txt
+                    to: /plus.txt
+            "#,
+        );
+
+        for (path, expected) in [
+            ("/search?q=hello+world", "/decoded"),
+            ("/search?q=hello%20world", "/decoded"),
+            ("/search?q=a+b", "/raw"),
+            ("/search?q=a%2Bb", "/search?q=a%2Bb"),
+            ("/a+b.txt", "/plus.txt"),
+            ("/a%20b.txt", "/a%20b.txt"),
+        ] {
+            let session = make_session(path).await;
+            let mut result = app.handle_request(session).await;
+            assert_eq!(result.session().uri(), expected, "{path}");
+        }
+    }
+
     #[test(tokio::test)]
     async fn interpolation() {
         let mut app = make_app(
@@ -484,4 +828,214 @@ mod tests {
         );
         assert_eq!(result.session().uri(), "/1");
     }
+
+    #[test(tokio::test)]
+    async fn rule_counters() {
+        let mut app = make_app(
+            r#"
+                rewrite_rules:
+                -
+                    from: /path/*
+                    query_regex: "counted"
+                    to: /counted
+                    name: rule_counters_test
+                -
+                    from: /path/*
+                    to: /uncounted
+            "#,
+        );
+
+        let counter = Counter::register("rewrite_module", "rule_counters_test");
+        assert_eq!(counter.get(), 0);
+
+        let session = make_session("/path/file.txt?counted").await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(result.session().uri(), "/counted");
+        assert_eq!(counter.get(), 1);
+
+        let session = make_session("/path/file.txt").await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(result.session().uri(), "/uncounted");
+        assert_eq!(counter.get(), 1);
+
+        let session = make_session("/path/?counted").await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(result.session().uri(), "/counted");
+        assert_eq!(counter.get(), 2);
+    }
+
+    #[test(tokio::test)]
+    async fn user_agent() {
+        #[derive(Debug, RequestFilter)]
+        struct Handler {
+            rewrite: RewriteHandler,
+            response: ResponseHandler,
+        }
+
+        let mut app = DefaultApp::<Handler>::new(
+            <Handler as RequestFilter>::Conf::from_yaml(
+                r#"
+                    rewrite_rules:
+                        from: /*
+                        user_agent_regex: "(?i)googlebot|bingbot"
+                        to: /prerender${tail}
+                    response: "ok"
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        let make_session = |user_agent: &'static str| async move {
+            let mut session = make_session("/page.html").await;
+            session
+                .req_header_mut()
+                .insert_header("User-Agent", user_agent)
+                .unwrap();
+            session
+        };
+
+        let session = make_session("Mozilla/5.0 (compatible; Googlebot/2.1)").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        let session = result.session();
+        assert_eq!(session.uri(), "/prerender/page.html");
+        assert_eq!(
+            session.response_written().unwrap().headers.get("Vary"),
+            Some(&HeaderValue::from_static("User-Agent"))
+        );
+
+        let session = make_session("Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Firefox/128.0").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        let session = result.session();
+        assert_eq!(session.uri(), "/page.html");
+        assert_eq!(
+            session.response_written().unwrap().headers.get("Vary"),
+            Some(&HeaderValue::from_static("User-Agent"))
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn duplicate_query_params() {
+        for (mode, expected) in [
+            ("keep", Some("/other.txt?a=1&b=x&a=2&%61=3")),
+            ("first", Some("/other.txt?a=1&b=x")),
+            ("last", Some("/file.txt?b=x&%61=3")),
+            ("reject", None),
+        ] {
+            let mut app = make_app(&format!(
+                r#"
+                    duplicate_query_params: {mode}
+                    rewrite_rules:
+                        from: /file.txt
+                        query_regex: "a=1"
+                        to: /other.txt${{query}}
+                "#
+            ));
+
+            let session = make_session("/file.txt?a=1&b=x&a=2&%61=3").await;
+            let mut result = app.handle_request(session).await;
+            if let Some(expected) = expected {
+                assert_eq!(
+                    result.err().as_ref().map(|err| &err.etype),
+                    Some(&ErrorType::HTTPStatus(404)),
+                    "{mode}"
+                );
+                // Rewrite rules see the normalized query
+                assert_eq!(result.session().uri(), expected, "{mode}");
+            } else {
+                assert!(result.err().is_none(), "{mode}");
+                assert_eq!(
+                    result.session().response_written().map(|r| r.status),
+                    Some(StatusCode::BAD_REQUEST),
+                    "{mode}"
+                );
+            }
+        }
+
+        // Parameters without duplicates are left alone
+        let mut app = make_app("duplicate_query_params: reject");
+        let session = make_session("/file.txt?a=1&b=2&&c").await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(
+            result.err().as_ref().map(|err| &err.etype),
+            Some(&ErrorType::HTTPStatus(404))
+        );
+        assert_eq!(result.session().uri(), "/file.txt?a=1&b=2&&c");
+    }
+
+    #[test(tokio::test)]
+    async fn reject_patterns() {
+        let mut app = make_app(
+            r#"
+                reject_query_regex: "\\.\\./"
+                reject_header_regex:
+                    X-File: ["\\.\\./", "^/etc/"]
+            "#,
+        );
+
+        async fn status(app: &mut DefaultApp<RewriteHandler>, session: Session) -> Option<u16> {
+            let mut result = app.handle_request(session).await;
+            let status = result
+                .session()
+                .response_written()
+                .map(|r| r.status.as_u16());
+            status
+        }
+
+        let session = make_session("/file.txt?file=../secret").await;
+        assert_eq!(status(&mut app, session).await, Some(400));
+
+        // Query is decoded before matching
+        let session = make_session("/file.txt?file=%2e%2e%2fsecret").await;
+        assert_eq!(status(&mut app, session).await, Some(400));
+
+        // Only the query string is checked, not the path
+        let session = make_session("/../file.txt?file=secret").await;
+        assert_eq!(status(&mut app, session).await, None);
+
+        let mut session = make_session("/file.txt").await;
+        session
+            .req_header_mut()
+            .insert_header("X-File", "/etc/passwd")
+            .unwrap();
+        assert_eq!(status(&mut app, session).await, Some(400));
+
+        // Other headers aren't checked
+        let mut session = make_session("/file.txt").await;
+        session
+            .req_header_mut()
+            .insert_header("X-Other", "../secret")
+            .unwrap();
+        assert_eq!(status(&mut app, session).await, None);
+
+        assert!(RewriteHandler::try_from(
+            RewriteConf::from_yaml("reject_header_regex: {\"in valid\": x}").unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn rule_limit() {
+        let conf = |max: usize| {
+            RewriteConf::from_yaml(format!(
+                r#"
+                    max_rewrite_rules: {max}
+                    rewrite_rules:
+                    - from: /a
+                      to: /b
+                    - from: /c
+                      to: /d
+                    - from: /e
+                      to: /f
+                "#
+            ))
+            .unwrap()
+        };
+
+        assert!(RewriteHandler::try_from(conf(3)).is_ok());
+        assert!(RewriteHandler::try_from(conf(2)).is_err());
+    }
 }
@@ -13,7 +13,11 @@
 // limitations under the License.
 
 use async_trait::async_trait;
+use bytes::Bytes;
 use clap::Parser;
+use openssl::asn1::{Asn1GeneralizedTimeRef, Asn1Time};
+use openssl::ocsp::{OcspCertId, OcspCertStatus, OcspResponse, OcspResponseStatus};
+use pandora_module_utils::ip_matcher::IpMatchRules;
 use pandora_module_utils::pingora::{
     http_proxy_service, Error, ErrorType, ProxyHttp, Server, ServerConf, ServerOpt,
 };
@@ -23,8 +27,9 @@ use pingora::services::Service;
 use pingora::tls::ext::ssl_add_chain_cert;
 use pingora::tls::{
     ext::{ssl_use_certificate, ssl_use_private_key},
+    hash::MessageDigest,
     pkey::PKey,
-    ssl::{NameType, SslRef},
+    ssl::{NameType, SslOptions, SslRef},
     x509::X509,
 };
 use pingora::utils::CertKey;
@@ -32,19 +37,38 @@ use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
 use std::collections::HashMap;
 use std::fs::read;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
+#[cfg(unix)]
+use crate::embedded::EmbeddedServer;
 use crate::redirector::create_redirector;
 
 pub(crate) const TLS_CONF_ERR: ErrorType = ErrorType::Custom("TLSConfigError");
 
+/// Clock skew in seconds tolerated when checking OCSP response validity
+const OCSP_CLOCK_SKEW: u32 = 300;
+
+/// Minimal interval between attempts to reload an OCSP response file
+const OCSP_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Default value of the `max_uri_length` setting
+pub const DEFAULT_MAX_URI_LENGTH: usize = 8192;
+
+/// Server services along with the addresses they need bound
+type ServicesWithAddrs = (Vec<Box<dyn Service>>, Vec<ListenAddr>);
+
 /// Run a web server
 #[derive(Debug, Default, Parser)]
 pub struct StartupOpt {
     /// Address and port to listen on, e.g. "127.0.0.1:8080". This command line flag can be
     /// specified multiple times.
-    #[clap(short, long, value_parser = clap::value_parser!(String))]
+    #[clap(short, long)]
     pub listen: Option<Vec<ListenAddr>>,
+    /// File descriptor of an already bound listening socket inherited from the parent process,
+    /// e.g. 3. This command line flag can be specified multiple times.
+    #[clap(long)]
+    pub listen_fd: Option<Vec<i32>>,
     /// Use this flag to make the server run in the background.
     #[clap(short, long)]
     pub daemon: bool,
@@ -204,6 +228,9 @@ pub struct CertKeyConf {
 
     /// Path to the private key file
     pub key_path: Option<PathBuf>,
+
+    /// Path to a DER-encoded OCSP response for the certificate, to be stapled to TLS handshakes
+    pub ocsp_response_path: Option<PathBuf>,
 }
 
 impl CertKeyConf {
@@ -218,7 +245,55 @@ impl CertKeyConf {
         })
     }
 
-    fn into_certificate(self) -> Result<CertKey, Box<Error>> {
+    /// Loads an OCSP response and makes sure that it confirms the validity of the certificate.
+    fn read_ocsp_response(path: &Path, cert: &CertKey) -> Result<OcspResponseData, Box<Error>> {
+        let data = Self::read_file(path)?;
+        let response = OcspResponse::from_der(&data)
+            .map_err(|err| Error::because(TLS_CONF_ERR, "failed parsing OCSP response", err))?;
+        if response.status() != OcspResponseStatus::SUCCESSFUL {
+            return Err(Error::explain(
+                TLS_CONF_ERR,
+                format!(
+                    "OCSP response has unsuccessful status {}",
+                    response.status().as_raw()
+                ),
+            ));
+        }
+        let response = response
+            .basic()
+            .map_err(|err| Error::because(TLS_CONF_ERR, "failed parsing OCSP response", err))?;
+
+        // Self-signed certificates are their own issuers
+        let issuer = cert.intermediates().first().unwrap_or(cert.leaf());
+        let id = OcspCertId::from_cert(MessageDigest::sha1(), cert.leaf(), issuer)
+            .map_err(|err| Error::because(TLS_CONF_ERR, "failed hashing certificate", err))?;
+        let status = response.find_status(&id).ok_or_else(|| {
+            Error::explain(
+                TLS_CONF_ERR,
+                "OCSP response doesn't apply to the certificate",
+            )
+        })?;
+        if status.status != OcspCertStatus::GOOD {
+            return Err(Error::explain(
+                TLS_CONF_ERR,
+                format!(
+                    "OCSP response reports certificate status {}",
+                    status.status.as_raw()
+                ),
+            ));
+        }
+        status
+            .check_validity(OCSP_CLOCK_SKEW, None)
+            .map_err(|err| Error::because(TLS_CONF_ERR, "OCSP response isn't current", err))?;
+
+        Ok(OcspResponseData {
+            this_update: to_system_time(status.this_update)?,
+            next_update: to_system_time(status.next_update)?,
+            data,
+        })
+    }
+
+    fn into_certificate(self) -> Result<Certificate, Box<Error>> {
         if let (Some(cert_path), Some(key_path)) = (self.cert_path, self.key_path) {
             const END_MARKER: &[u8] = b"-----END CERTIFICATE-----";
             let mut certs = Vec::new();
@@ -250,7 +325,13 @@ impl CertKeyConf {
             let key = PKey::private_key_from_pem(&Self::read_file(key_path)?)
                 .map_err(|err| Error::because(TLS_CONF_ERR, "failed parsing private key", err))?;
 
-            Ok(CertKey::new(certs, key))
+            let cert_key = CertKey::new(certs, key);
+            let ocsp = self
+                .ocsp_response_path
+                .map(|path| OcspStaple::new(path, cert_key.clone()).map(Arc::new))
+                .transpose()?;
+
+            Ok(Certificate { cert_key, ocsp })
         } else {
             Err(Error::explain(
                 TLS_CONF_ERR,
@@ -260,6 +341,145 @@ impl CertKeyConf {
     }
 }
 
+/// Converts an ASN.1 time value into system time.
+fn to_system_time(time: &Asn1GeneralizedTimeRef) -> Result<SystemTime, Box<Error>> {
+    // The only way to get at the value is the textual representation like
+    // `Jan  2 03:04:05.678 2024 GMT`, parse it and let OpenSSL do the calendar calculations.
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let text = time.to_string();
+    let invalid = || Error::explain(TLS_CONF_ERR, format!("unexpected time value {text}"));
+
+    let parts = text.split_whitespace().collect::<Vec<_>>();
+    let [month, day, time_of_day, year, "GMT"] = parts[..] else {
+        return Err(invalid());
+    };
+    let month = MONTHS
+        .iter()
+        .position(|name| *name == month)
+        .ok_or_else(invalid)?
+        + 1;
+    let day: u32 = day.parse().map_err(|_| invalid())?;
+    let year: u32 = year.parse().map_err(|_| invalid())?;
+    let time_of_day = time_of_day.split('.').next().unwrap_or_default();
+    let [hours, minutes, seconds] = time_of_day
+        .split(':')
+        .map(|value| value.parse::<u32>().map_err(|_| invalid()))
+        .collect::<Result<Vec<_>, _>>()?[..]
+    else {
+        return Err(invalid());
+    };
+
+    let time = Asn1Time::from_str(&format!(
+        "{year:04}{month:02}{day:02}{hours:02}{minutes:02}{seconds:02}Z"
+    ))
+    .map_err(|err| Error::because(TLS_CONF_ERR, format!("invalid time value {text}"), err))?;
+    let diff = Asn1Time::from_unix(0)
+        .and_then(|epoch| epoch.diff(&time))
+        .map_err(|err| Error::because(TLS_CONF_ERR, format!("invalid time value {text}"), err))?;
+    let offset = i64::from(diff.days) * 86400 + i64::from(diff.secs);
+    Ok(if offset >= 0 {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(offset.unsigned_abs())
+    } else {
+        SystemTime::UNIX_EPOCH - Duration::from_secs(offset.unsigned_abs())
+    })
+}
+
+/// OCSP response data along with its validity period
+#[derive(Debug, Clone)]
+struct OcspResponseData {
+    data: Vec<u8>,
+    this_update: SystemTime,
+    next_update: SystemTime,
+}
+
+impl OcspResponseData {
+    /// Checks whether a newer response should be available. Responders typically produce new
+    /// responses halfway through the validity period of the old ones.
+    fn needs_refresh(&self, now: SystemTime) -> bool {
+        let validity = self
+            .next_update
+            .duration_since(self.this_update)
+            .unwrap_or_default();
+        now >= self.this_update + validity / 2
+    }
+}
+
+/// OCSP response to be stapled, reloaded from its file when due for a refresh
+#[derive(Debug)]
+struct OcspStaple {
+    path: PathBuf,
+    cert_key: CertKey,
+    state: Mutex<OcspStapleState>,
+}
+
+#[derive(Debug)]
+struct OcspStapleState {
+    response: OcspResponseData,
+    last_reload: Option<Instant>,
+}
+
+impl OcspStaple {
+    fn new(path: PathBuf, cert_key: CertKey) -> Result<Self, Box<Error>> {
+        let response = CertKeyConf::read_ocsp_response(&path, &cert_key)?;
+        Ok(Self {
+            path,
+            cert_key,
+            state: Mutex::new(OcspStapleState {
+                response,
+                last_reload: None,
+            }),
+        })
+    }
+
+    /// Retrieves the response to be stapled, `None` if the response expired. Once the response
+    /// is due for a refresh the file is reloaded, at most once per `OCSP_RELOAD_INTERVAL`.
+    fn current(&self) -> Option<Vec<u8>> {
+        let now = SystemTime::now();
+        let reload = {
+            let mut state = self.state.lock().unwrap();
+            let reload = state.response.needs_refresh(now)
+                && state
+                    .last_reload
+                    .map_or(true, |time| time.elapsed() >= OCSP_RELOAD_INTERVAL);
+            if reload {
+                state.last_reload = Some(Instant::now());
+            }
+            reload
+        };
+
+        if reload {
+            match CertKeyConf::read_ocsp_response(&self.path, &self.cert_key) {
+                Ok(response) => {
+                    let mut state = self.state.lock().unwrap();
+                    if response.next_update > state.response.next_update {
+                        state.response = response;
+                    }
+                }
+                Err(err) => log::warn!(
+                    "failed reloading OCSP response from {}: {err}",
+                    self.path.display()
+                ),
+            }
+        }
+
+        let state = self.state.lock().unwrap();
+        if now < state.response.next_update {
+            Some(state.response.data.clone())
+        } else {
+            None
+        }
+    }
+}
+
+/// Loaded certificate/key combination along with the OCSP response to be stapled
+#[derive(Debug, Clone)]
+struct Certificate {
+    cert_key: CertKey,
+    ocsp: Option<Arc<OcspStaple>>,
+}
+
 /// Certificate/key combination for a single server name
 #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct TlsRedirectorConf {
@@ -295,7 +515,7 @@ impl TlsRedirectorConf {
 }
 
 /// TLS configuration for the server
-#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct TlsConf {
     /// Default certificate/key combination
     #[pandora(flatten)]
@@ -306,6 +526,20 @@ pub struct TlsConf {
 
     /// HTTP to HTTPS redirector settings
     pub redirector: TlsRedirectorConf,
+
+    /// If `false`, TLS session tickets won’t be issued, preventing session resumption via tickets
+    pub session_tickets: bool,
+}
+
+impl Default for TlsConf {
+    fn default() -> Self {
+        Self {
+            default: Default::default(),
+            server_names: Default::default(),
+            redirector: Default::default(),
+            session_tickets: true,
+        }
+    }
 }
 
 impl TlsConf {
@@ -334,13 +568,45 @@ impl TlsConf {
             )
         })?;
         certificates.insert(String::new(), cert);
-        Ok(TlsAcceptCallbacks { certificates })
+
+        Ok(TlsAcceptCallbacks {
+            certificates,
+            session_tickets: self.session_tickets,
+        })
     }
 }
 
 #[derive(Debug, Clone)]
 struct TlsAcceptCallbacks {
-    certificates: HashMap<String, CertKey>,
+    certificates: HashMap<String, Certificate>,
+    session_tickets: bool,
+}
+
+impl TlsAcceptCallbacks {
+    /// Produces TLS settings for a listening address, with certificates selected by these
+    /// callbacks.
+    fn to_settings(&self) -> Result<TlsSettings, Box<Error>> {
+        let mut settings = TlsSettings::with_callbacks(Box::new(self.clone()))?;
+
+        if !self.session_tickets {
+            settings.set_options(SslOptions::NO_TICKET);
+            settings.set_num_tickets(0).map_err(|err| {
+                Error::because(TLS_CONF_ERR, "failed disabling session tickets", err)
+            })?;
+        }
+
+        if self.certificates.values().any(|cert| cert.ocsp.is_some()) {
+            // The response has been set already when the certificate was selected, all that’s
+            // left is indicating whether there is one.
+            settings
+                .set_status_callback(|ssl| Ok(ssl.ocsp_status().is_some()))
+                .map_err(|err| {
+                    Error::because(TLS_CONF_ERR, "failed enabling OCSP stapling", err)
+                })?;
+        }
+
+        Ok(settings)
+    }
 }
 
 #[async_trait]
@@ -353,58 +619,195 @@ impl TlsAccept for TlsAcceptCallbacks {
         if let Some(cert) = cert {
             // Errors are unexpected here, these should only occur if a certificate has been set
             // already or private key and certificate don’t match. Ok to panic then.
-            ssl_use_certificate(ssl, cert.leaf()).unwrap();
-            for intermediate in cert.intermediates() {
+            let cert_key = &cert.cert_key;
+            ssl_use_certificate(ssl, cert_key.leaf()).unwrap();
+            for intermediate in cert_key.intermediates() {
                 ssl_add_chain_cert(ssl, intermediate).unwrap();
             }
-            ssl_use_private_key(ssl, cert.key()).unwrap();
+            ssl_use_private_key(ssl, cert_key.key()).unwrap();
+
+            if let Some(response) = cert.ocsp.as_ref().and_then(|ocsp| ocsp.current()) {
+                if let Err(err) = ssl.set_ocsp_status(&response) {
+                    log::warn!("failed stapling OCSP response: {err}");
+                }
+            }
+        }
+    }
+}
+
+/// Content served directly for a path like `/robots.txt`, without running any handlers
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct InlineFileConf {
+    /// Text to be sent as response
+    pub content: Option<String>,
+
+    /// File to be sent as response, read at startup
+    pub file: Option<PathBuf>,
+
+    /// MIME type of the response
+    pub content_type: String,
+
+    /// If `false`, requests for this path won’t be passed on to the handlers’ `logging` phase
+    pub log: bool,
+}
+
+impl Default for InlineFileConf {
+    fn default() -> Self {
+        Self {
+            content: None,
+            file: None,
+            content_type: "text/plain;charset=utf-8".into(),
+            log: true,
         }
     }
 }
 
+/// Response content for a path configured via [`InlineFileConf`]
+#[derive(Debug, Clone)]
+pub(crate) struct InlineFile {
+    pub(crate) content: Bytes,
+    pub(crate) content_type: String,
+    pub(crate) log: bool,
+}
+
+impl InlineFileConf {
+    pub(crate) fn load(&self, path: &str) -> Result<InlineFile, Box<Error>> {
+        let content = match (&self.content, &self.file) {
+            (Some(content), None) => Bytes::from(content.clone()),
+            (None, Some(file)) => read(file)
+                .map_err(|err| {
+                    Error::because(
+                        ErrorType::InternalError,
+                        format!("failed reading file {}", file.display()),
+                        err,
+                    )
+                })?
+                .into(),
+            _ => {
+                return Err(Error::explain(
+                    ErrorType::InternalError,
+                    format!("exactly one of content and file has to be set for inline file {path}"),
+                ))
+            }
+        };
+        Ok(InlineFile {
+            content,
+            content_type: self.content_type.clone(),
+            log: self.log,
+        })
+    }
+}
+
 /// Configuration settings of the startup module
-#[derive(Debug, Default, PartialEq, Eq, DeserializeMap)]
+#[derive(Debug, PartialEq, Eq, DeserializeMap)]
 pub struct StartupConf {
     /// List of address/port combinations to listen on, e.g. "127.0.0.1:8080"
     pub listen: OneOrMany<ListenAddr>,
 
+    /// List of inherited listening socket file descriptors to use (Unix only)
+    pub listen_fd: OneOrMany<i32>,
+
     /// TLS configuration for the server
     pub tls: TlsConf,
 
+    /// Maximal length in bytes of request URIs, longer URIs result in `414 URI Too Long`
+    /// responses. `0` means no limit.
+    pub max_uri_length: usize,
+
+    /// If `true`, request paths containing empty segments like `//` or dot segments like `/./`
+    /// and `/../` are rejected with `400 Bad Request` rather than normalized.
+    pub strict_path: bool,
+
+    /// Request paths like `/robots.txt` mapped to the content to be served for them directly
+    pub inline_files: HashMap<String, InlineFileConf>,
+
+    /// If `true`, responses carry a `Server-Timing` header with the total request processing
+    /// time and, for proxied requests, the time until the upstream response arrived
+    pub server_timing: bool,
+
+    /// Client addresses to send the `Server-Timing` header to, all clients by default
+    pub server_timing_clients: IpMatchRules,
+
+    /// Request path like `/metrics` to serve rule counters under, in the Prometheus text format
+    pub metrics_path: Option<String>,
+
+    /// Client addresses allowed to retrieve the metrics, only loopback addresses by default
+    pub metrics_clients: IpMatchRules,
+
     /// Pingora’s default server configuration options
     #[pandora(flatten)]
     pub server: ServerConf,
 }
 
+impl Default for StartupConf {
+    fn default() -> Self {
+        Self {
+            listen: Default::default(),
+            listen_fd: Default::default(),
+            tls: Default::default(),
+            max_uri_length: DEFAULT_MAX_URI_LENGTH,
+            strict_path: false,
+            inline_files: Default::default(),
+            server_timing: false,
+            server_timing_clients: Default::default(),
+            metrics_path: None,
+            metrics_clients: Default::default(),
+            server: Default::default(),
+        }
+    }
+}
+
 impl StartupConf {
-    /// Sets up a server with the given configuration and command line options
-    pub fn into_server<SV>(self, app: SV, opt: Option<StartupOpt>) -> Result<Server, Box<Error>>
+    /// Sets up the services handling the listening addresses. Returns the services along with the
+    /// addresses that still need to be bound, meaning all addresses not using inherited sockets.
+    ///
+    /// If `bind` is `true`, the listening addresses are bound right away rather than when the
+    /// services start up. No unbound addresses are returned then.
+    fn into_services<SV>(
+        self,
+        app: SV,
+        opt: StartupOpt,
+        server_conf: &Arc<ServerConf>,
+        bind: bool,
+    ) -> Result<ServicesWithAddrs, Box<Error>>
     where
         SV: ProxyHttp + Send + Sync + 'static,
         <SV as ProxyHttp>::CTX: Send + Sync,
     {
-        let opt = opt.unwrap_or_default();
-
         let mut listen = opt.listen.map(|l| l.into()).unwrap_or(self.listen);
+        let listen_fd = opt.listen_fd.map(|l| l.into()).unwrap_or(self.listen_fd);
+
+        #[cfg(unix)]
+        let mut inherited_fds = crate::listen_fds::assign(
+            listen_fd
+                .into_iter()
+                .chain(crate::listen_fds::systemd_fds()),
+            &mut listen,
+            &self.tls.redirector.listen,
+        )?;
+
+        #[cfg(not(unix))]
+        if !listen_fd.is_empty() {
+            log::error!("inheriting listening sockets is only supported on Unix");
+            return Err(Error::new(ErrorType::InternalError));
+        }
+
+        #[cfg(not(unix))]
+        if bind {
+            log::error!("binding listening sockets upfront is only supported on Unix");
+            return Err(Error::new(ErrorType::InternalError));
+        }
+
         if listen.is_empty() {
             // Make certain we have a listening address
             listen.push("127.0.0.1:8080".into());
             listen.push("[::1]:8080".into());
         }
 
-        let mut server = Server::new_with_opt_and_conf(
-            ServerOpt {
-                daemon: opt.daemon,
-                test: opt.test,
-                upgrade: false,
-                nocapture: false,
-                conf: None,
-            },
-            self.server,
-        );
-        server.bootstrap();
+        let mut services: Vec<Box<dyn Service>> = Vec::new();
+        let mut unbound = listen.to_vec();
 
-        let mut service = http_proxy_service(&server.configuration, app);
+        let mut service = http_proxy_service(server_conf, app);
         for addr in &listen {
             if addr.tls {
                 continue;
@@ -417,9 +820,11 @@ impl StartupConf {
             }
         }
 
+        let mut redirector = None;
         if listen.iter().any(|addr| addr.tls) {
-            if let Some(redirector) = self.tls.redirector.to_redirector(&server.configuration)? {
-                server.add_service(redirector);
+            redirector = self.tls.redirector.to_redirector(server_conf)?;
+            if redirector.is_some() {
+                unbound.extend(self.tls.redirector.listen.iter().cloned());
             }
 
             let tls_callbacks = self.tls.into_callbacks()?;
@@ -431,12 +836,275 @@ impl StartupConf {
                 service.add_tls_with_settings(
                     &addr.addr,
                     addr.to_socket_options(),
-                    TlsSettings::with_callbacks(Box::new(tls_callbacks.clone()))?,
+                    tls_callbacks.to_settings()?,
                 );
             }
         }
-        server.add_service(service);
+
+        #[cfg(unix)]
+        {
+            unbound.retain(|addr| {
+                !inherited_fds
+                    .iter()
+                    .any(|(inherited, _)| inherited == &addr.addr)
+            });
+
+            if bind {
+                inherited_fds.extend(crate::listen_fds::bind_addrs(&unbound)?);
+                unbound.clear();
+            }
+        }
+
+        if let Some(redirector) = redirector {
+            #[cfg(unix)]
+            let redirector =
+                crate::listen_fds::InheritedFdsService::new(redirector, inherited_fds.clone());
+            services.push(Box::new(redirector));
+        }
+
+        #[cfg(unix)]
+        let service = crate::listen_fds::InheritedFdsService::new(service, inherited_fds);
+        services.push(Box::new(service));
+
+        Ok((services, unbound))
+    }
+
+    /// Sets up a server with the given configuration and command line options
+    pub fn into_server<SV>(mut self, app: SV, opt: Option<StartupOpt>) -> Result<Server, Box<Error>>
+    where
+        SV: ProxyHttp + Send + Sync + 'static,
+        <SV as ProxyHttp>::CTX: Send + Sync,
+    {
+        let opt = opt.unwrap_or_default();
+
+        // Pingora would only switch users when daemonizing, before the listening sockets are bound.
+        // Instead, the sockets are bound upfront here and privileges dropped afterwards.
+        let mut server_conf = std::mem::take(&mut self.server);
+        let user = server_conf.user.take();
+        let group = server_conf.group.take();
+
+        let mut server = Server::new_with_opt_and_conf(
+            ServerOpt {
+                daemon: opt.daemon,
+                test: opt.test,
+                upgrade: false,
+                nocapture: false,
+                conf: None,
+            },
+            server_conf,
+        );
+        server.bootstrap();
+
+        if user.is_none() && group.is_some() {
+            log::error!("group setting requires user to be set as well");
+            return Err(Error::new(ErrorType::InternalError));
+        }
+
+        #[cfg(not(unix))]
+        if let Some(user) = &user {
+            log::error!("switching to user {user} is only supported on Unix");
+            return Err(Error::new(ErrorType::InternalError));
+        }
+
+        let (services, _) = self.into_services(app, opt, &server.configuration, user.is_some())?;
+        server.add_services(services);
+
+        #[cfg(unix)]
+        if let Some(user) = user {
+            crate::privileges::drop_privileges(&user, group.as_deref())?;
+        }
 
         Ok(server)
     }
+
+    /// Sets up a server to run within an existing Tokio runtime, for applications embedding
+    /// Pandora Web Server (Unix only).
+    ///
+    /// Unlike with [`StartupConf::into_server`], there is no signal handling, daemonization or
+    /// switching users. The returned server is started via [`EmbeddedServer::run`] and keeps
+    /// running until [`ServerHandle::shutdown`](crate::ServerHandle::shutdown) is called.
+    #[cfg(unix)]
+    pub fn into_embedded_server<SV>(
+        mut self,
+        app: SV,
+        opt: Option<StartupOpt>,
+    ) -> Result<EmbeddedServer, Box<Error>>
+    where
+        SV: ProxyHttp + Send + Sync + 'static,
+        <SV as ProxyHttp>::CTX: Send + Sync,
+    {
+        let opt = opt.unwrap_or_default();
+        if self.server.user.is_some() || self.server.group.is_some() {
+            log::error!("user and group settings are not supported for embedded servers");
+            return Err(Error::new(ErrorType::InternalError));
+        }
+
+        let server_conf = Arc::new(std::mem::take(&mut self.server));
+        let (services, unbound) = self.into_services(app, opt, &server_conf, false)?;
+        Ok(EmbeddedServer::new(services, unbound))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::FromYaml;
+    use test_log::test;
+
+    fn testdata(name: &str) -> String {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("testdata");
+        path.push(name);
+        path.to_string_lossy().into_owned()
+    }
+
+    fn tls_conf(extra: &str) -> TlsConf {
+        TlsConf::from_yaml(format!(
+            "cert_path: {}\nkey_path: {}\n{extra}",
+            testdata("cert_example.com.pem"),
+            testdata("key_example.com.pem"),
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn session_tickets() {
+        let settings = tls_conf("")
+            .into_callbacks()
+            .unwrap()
+            .to_settings()
+            .unwrap();
+        assert!(!settings.options().contains(SslOptions::NO_TICKET));
+
+        let settings = tls_conf("session_tickets: false")
+            .into_callbacks()
+            .unwrap()
+            .to_settings()
+            .unwrap();
+        assert!(settings.options().contains(SslOptions::NO_TICKET));
+    }
+
+    #[test]
+    fn ocsp_response() {
+        let callbacks = tls_conf("").into_callbacks().unwrap();
+        assert!(callbacks.certificates[""].ocsp.is_none());
+
+        let callbacks = tls_conf(&format!(
+            "ocsp_response_path: {}",
+            testdata("ocsp_example.com.der")
+        ))
+        .into_callbacks()
+        .unwrap();
+        let ocsp = callbacks.certificates[""].ocsp.as_ref().unwrap();
+        assert_eq!(
+            ocsp.current(),
+            Some(read(testdata("ocsp_example.com.der")).unwrap())
+        );
+        let response = &ocsp.state.lock().unwrap().response;
+        assert!(response.this_update < SystemTime::now());
+        assert!(response.next_update > SystemTime::now() + Duration::from_secs(86400 * 365));
+
+        // Response for a different certificate
+        assert!(tls_conf(&format!(
+            "ocsp_response_path: {}",
+            testdata("ocsp_localhost.der")
+        ))
+        .into_callbacks()
+        .is_err());
+
+        // Not an OCSP response
+        assert!(tls_conf(&format!(
+            "ocsp_response_path: {}",
+            testdata("cert_example.com.pem")
+        ))
+        .into_callbacks()
+        .is_err());
+    }
+
+    #[test]
+    fn ocsp_response_per_server_name() {
+        let callbacks = tls_conf(&format!(
+            "server_names:\n  localhost:\n    cert_path: {}\n    key_path: {}\n    ocsp_response_path: {}",
+            testdata("cert_localhost.pem"),
+            testdata("key_localhost.pem"),
+            testdata("ocsp_localhost.der"),
+        ))
+        .into_callbacks()
+        .unwrap();
+        assert!(callbacks.certificates[""].ocsp.is_none());
+        assert!(callbacks.certificates["localhost"].ocsp.is_some());
+    }
+
+    #[test]
+    fn ocsp_refresh() {
+        fn expire(ocsp: &OcspStaple) {
+            let mut state = ocsp.state.lock().unwrap();
+            state.response.this_update = SystemTime::now() - Duration::from_secs(7200);
+            state.response.next_update = SystemTime::now() - Duration::from_secs(1);
+        }
+
+        let callbacks = tls_conf(&format!(
+            "ocsp_response_path: {}",
+            testdata("ocsp_example.com.der")
+        ))
+        .into_callbacks()
+        .unwrap();
+        let ocsp = callbacks.certificates[""].ocsp.as_ref().unwrap();
+
+        // An expired response is replaced by the current one from the file
+        expire(ocsp);
+        assert!(ocsp.current().is_some());
+        assert!(ocsp.state.lock().unwrap().response.next_update > SystemTime::now());
+
+        // Expired response isn’t stapled if the file doesn’t contain a valid response
+        let ocsp = OcspStaple {
+            path: testdata("cert_example.com.pem").into(),
+            cert_key: ocsp.cert_key.clone(),
+            state: Mutex::new(OcspStapleState {
+                response: ocsp.state.lock().unwrap().response.clone(),
+                last_reload: None,
+            }),
+        };
+        assert!(ocsp.current().is_some());
+        expire(&ocsp);
+        assert!(ocsp.current().is_none());
+        assert!(ocsp.state.lock().unwrap().last_reload.is_some());
+    }
+
+    #[cfg(unix)]
+    #[derive(Debug)]
+    struct Handler;
+
+    #[cfg(unix)]
+    #[async_trait]
+    impl pandora_module_utils::RequestFilter for Handler {
+        type Conf = ();
+        type CTX = ();
+        fn new_ctx() -> Self::CTX {}
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bind_before_switching_user() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        // Switching to the current user is a no-op, but the sockets are still bound upfront.
+        let user = nix::unistd::User::from_uid(nix::unistd::geteuid())
+            .unwrap()
+            .unwrap()
+            .name;
+        let conf =
+            StartupConf::from_yaml(format!("listen: 127.0.0.1:{port}\nuser: {user}")).unwrap();
+        let _server = conf
+            .into_server(crate::DefaultApp::new(Handler), None)
+            .unwrap();
+
+        let err = std::net::TcpListener::bind(("127.0.0.1", port)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+    }
 }
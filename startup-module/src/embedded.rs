@@ -0,0 +1,266 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Server running within an existing Tokio runtime (Unix only)
+//!
+//! Pingora’s `Server::run_forever` creates its own runtimes, handles signals and terminates the
+//! process when done. An embedded server binds the listening sockets itself instead and passes
+//! them to the services via Pingora’s file descriptor table, so that it is known when the server
+//! is ready to accept connections.
+
+use log::info;
+use pandora_module_utils::pingora::{Error, ErrorType};
+use pingora::server::Fds;
+use pingora::services::Service;
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{watch, Mutex};
+
+use crate::configuration::ListenAddr;
+use crate::listen_fds::{bind_addr, socket_addr};
+
+/// Handle to control an embedded server and observe its state
+#[derive(Debug, Clone)]
+pub struct ServerHandle {
+    shutdown: Arc<watch::Sender<bool>>,
+    ready: watch::Receiver<Option<Vec<SocketAddr>>>,
+}
+
+impl ServerHandle {
+    /// Waits until the server is listening on all its addresses and returns the bound addresses.
+    /// These can differ from the configured ones if port `0` has been configured.
+    ///
+    /// An error is returned if the server stopped without becoming ready, e.g. because binding
+    /// one of the addresses failed.
+    pub async fn ready(&self) -> Result<Vec<SocketAddr>, Box<Error>> {
+        let mut ready = self.ready.clone();
+        let addrs = ready.wait_for(Option::is_some).await.map_err(|_| {
+            Error::explain(
+                ErrorType::InternalError,
+                "server stopped before becoming ready",
+            )
+        })?;
+        Ok(addrs.clone().unwrap_or_default())
+    }
+
+    /// Makes the server stop accepting new connections. [`EmbeddedServer::run`] returns once all
+    /// services have shut down.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+}
+
+/// A server running within an existing Tokio runtime, see
+/// [`StartupConf::into_embedded_server`](crate::StartupConf::into_embedded_server)
+pub struct EmbeddedServer {
+    services: Vec<Box<dyn Service>>,
+    listen: Vec<ListenAddr>,
+    shutdown: Arc<watch::Sender<bool>>,
+    ready: watch::Sender<Option<Vec<SocketAddr>>>,
+}
+
+impl Debug for EmbeddedServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EmbeddedServer")
+            .field("listen", &self.listen)
+            .finish()
+    }
+}
+
+impl EmbeddedServer {
+    pub(crate) fn new(services: Vec<Box<dyn Service>>, listen: Vec<ListenAddr>) -> Self {
+        Self {
+            services,
+            listen,
+            shutdown: Arc::new(watch::channel(false).0),
+            ready: watch::channel(None).0,
+        }
+    }
+
+    /// Returns a handle that can be used to wait for the server to become ready and to shut it
+    /// down.
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            shutdown: self.shutdown.clone(),
+            ready: self.ready.subscribe(),
+        }
+    }
+
+    /// Binds the listening addresses and runs the services until [`ServerHandle::shutdown`] is
+    /// called.
+    pub async fn run(self) -> Result<(), Box<Error>> {
+        let mut fds = Fds::new();
+        let mut addrs = Vec::with_capacity(self.listen.len());
+        for addr in &self.listen {
+            let fd = bind_addr(addr)?;
+            fds.add(addr.addr.clone(), fd);
+            addrs.push(socket_addr(fd)?);
+        }
+        let fds = Arc::new(Mutex::new(fds));
+
+        let tasks = self
+            .services
+            .into_iter()
+            .map(|mut service| {
+                let fds = fds.clone();
+                let shutdown = self.shutdown.subscribe();
+                tokio::spawn(async move { service.start_service(Some(fds), shutdown).await })
+            })
+            .collect::<Vec<_>>();
+
+        info!("embedded server listening on {addrs:?}");
+        self.ready.send_replace(Some(addrs));
+
+        for task in tasks {
+            task.await
+                .map_err(|err| Error::because(ErrorType::InternalError, "service failed", err))?;
+        }
+        info!("embedded server shut down");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_trait::async_trait;
+    use pandora_module_utils::pingora::SessionWrapper;
+    use pandora_module_utils::{FromYaml, RequestFilter, RequestFilterResult};
+    use test_log::test;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use crate::{DefaultApp, StartupConf};
+
+    #[derive(Debug)]
+    struct Handler;
+
+    #[async_trait]
+    impl RequestFilter for Handler {
+        type Conf = ();
+        type CTX = ();
+        fn new_ctx() -> Self::CTX {}
+
+        async fn request_filter(
+            &self,
+            session: &mut impl SessionWrapper,
+            _ctx: &mut Self::CTX,
+        ) -> Result<RequestFilterResult, Box<Error>> {
+            session.respond_error(204).await?;
+            Ok(RequestFilterResult::ResponseSent)
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn embedded() {
+        let conf = StartupConf::from_yaml("listen: 127.0.0.1:0").unwrap();
+        let server = conf
+            .into_embedded_server(DefaultApp::new(Handler), None)
+            .unwrap();
+        let handle = server.handle();
+        let task = tokio::spawn(server.run());
+
+        let addrs = handle.ready().await.unwrap();
+        assert_eq!(addrs.len(), 1);
+        assert!(addrs[0].ip().is_loopback());
+        assert_ne!(addrs[0].port(), 0);
+
+        let mut stream = TcpStream::connect(addrs[0]).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            stream.read_to_string(&mut response),
+        )
+        .await
+        .expect("no response received")
+        .unwrap();
+        assert!(response.starts_with("HTTP/1.1 204"), "{response}");
+
+        handle.shutdown();
+        tokio::time::timeout(std::time::Duration::from_secs(5), task)
+            .await
+            .expect("server didn't shut down")
+            .unwrap()
+            .unwrap();
+
+        // The address is no longer accepting connections
+        assert!(TcpStream::connect(addrs[0]).await.is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn ocsp_stapling() {
+        use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode, StatusType};
+        use std::path::PathBuf;
+
+        let testdata = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        let conf = StartupConf::from_yaml(format!(
+            "listen: {{addr: 127.0.0.1:0, tls: true}}\n\
+             tls:\n  cert_path: {}\n  key_path: {}\n  ocsp_response_path: {}",
+            testdata.join("cert_example.com.pem").display(),
+            testdata.join("key_example.com.pem").display(),
+            testdata.join("ocsp_example.com.der").display(),
+        ))
+        .unwrap();
+        let server = conf
+            .into_embedded_server(DefaultApp::new(Handler), None)
+            .unwrap();
+        let handle = server.handle();
+        let task = tokio::spawn(server.run());
+        let addr = handle.ready().await.unwrap()[0];
+
+        let stapled = tokio::task::spawn_blocking(move || {
+            let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+            connector.set_verify(SslVerifyMode::NONE);
+            let mut ssl = connector
+                .build()
+                .configure()
+                .unwrap()
+                .into_ssl("example.com")
+                .unwrap();
+            ssl.set_status_type(StatusType::OCSP).unwrap();
+            let stream = std::net::TcpStream::connect(addr).unwrap();
+            let stream = ssl.connect(stream).unwrap();
+            stream.ssl().ocsp_status().map(<[u8]>::to_vec)
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            stapled,
+            Some(std::fs::read(testdata.join("ocsp_example.com.der")).unwrap())
+        );
+
+        handle.shutdown();
+        task.await.unwrap().unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn bind_failure() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let conf = StartupConf::from_yaml(format!("listen: {addr}")).unwrap();
+        let server = conf
+            .into_embedded_server(DefaultApp::new(Handler), None)
+            .unwrap();
+        let handle = server.handle();
+        assert!(server.run().await.is_err());
+        assert!(handle.ready().await.is_err());
+    }
+}
@@ -15,23 +15,41 @@
 #![doc = include_str!("../README.md")]
 
 mod configuration;
+#[cfg(unix)]
+mod embedded;
+#[cfg(unix)]
+mod listen_fds;
+#[cfg(unix)]
+mod privileges;
 mod redirector;
 
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
+use configuration::InlineFile;
 pub use configuration::{
-    CertKeyConf, ListenAddr, StartupConf, StartupOpt, TlsConf, TlsRedirectorConf,
+    CertKeyConf, InlineFileConf, ListenAddr, StartupConf, StartupOpt, TlsConf, TlsRedirectorConf,
+    DEFAULT_MAX_URI_LENGTH,
 };
-use http::Extensions;
+#[cfg(unix)]
+pub use embedded::{EmbeddedServer, ServerHandle};
+use http::{header, Extensions, HeaderMap, Method, StatusCode};
+use log::warn;
+use pandora_module_utils::ip_matcher::{IpMatchRules, IpMatcher};
 use pandora_module_utils::pingora::{
-    Error, HttpPeer, ProxyHttp, ResponseHeader, Session, SessionWrapper,
+    init_handler_modules, Error, HttpPeer, ProxyHttp, ResponseCompression, ResponseHeader, Session,
+    SessionWrapper,
+};
+use pandora_module_utils::{
+    metrics, request_filter_head_as_get, HeadAsGet, RequestFilter, RequestFilterResult,
 };
-use pandora_module_utils::{RequestFilter, RequestFilterResult};
 use pingora::modules::http::HttpModules;
-use pingora::ErrorType;
+use pingora::{ErrorSource, ErrorType};
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::fmt::Debug;
-use std::ops::{Deref, DerefMut};
+use std::net::IpAddr;
+use std::ops::{Deref, DerefMut, RangeInclusive};
+use std::time::{Duration, Instant};
 
 struct NoDebug<T> {
     inner: T,
@@ -106,16 +124,244 @@ impl AppResult {
     pub fn body_str(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(&self.body)
     }
+
+    /// Retrieves the individual `write_response_body` calls along with their `end_of_stream`
+    /// flags, in the order these were made. Calls without data are recorded as empty chunks.
+    pub fn body_chunks(&self) -> &[(Bytes, bool)] {
+        self.extensions
+            .get::<ResponseChunks>()
+            .map_or(&[], |ResponseChunks(chunks)| chunks.as_slice())
+    }
+
+    /// Retrieves the informational (1xx) response headers written before the final response
+    pub fn informational_responses(&self) -> &[ResponseHeader] {
+        self.extensions
+            .get::<InformationalResponses>()
+            .map_or(&[], |InformationalResponses(responses)| {
+                responses.as_slice()
+            })
+    }
+
+    /// Retrieves the response trailers if any were written
+    pub fn trailers(&self) -> Option<&HeaderMap> {
+        self.extensions
+            .get::<ResponseTrailers>()
+            .map(|ResponseTrailers(trailers)| trailers)
+    }
+}
+
+/// Type used to capture response body chunks in `AppResult::extensions`
+#[derive(Debug, Clone, Default)]
+struct ResponseChunks(Vec<(Bytes, bool)>);
+
+/// Type used to capture informational response headers in `AppResult::extensions`
+#[derive(Debug, Clone, Default)]
+struct InformationalResponses(Vec<ResponseHeader>);
+
+/// Type used to capture response trailers in `AppResult::extensions`
+#[derive(Debug, Clone)]
+struct ResponseTrailers(HeaderMap);
+
+/// Type used to mark requests that shouldn’t be logged in `DefaultCtx::extensions`
+#[derive(Debug, Clone)]
+struct SkipLogging;
+
+/// Type used to record request processing times in `DefaultCtx::extensions`, only present if
+/// a `Server-Timing` header should be sent
+#[derive(Debug, Clone)]
+struct RequestTiming {
+    start: Instant,
+    upstream_start: Option<Instant>,
+    upstream_end: Option<Instant>,
+}
+
+impl RequestTiming {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            upstream_start: None,
+            upstream_end: None,
+        }
+    }
+
+    /// Produces the `Server-Timing` header value, durations are given in milliseconds.
+    fn header_value(&self) -> String {
+        fn format(name: &str, duration: Duration) -> String {
+            format!("{name};dur={:.1}", duration.as_secs_f64() * 1000.0)
+        }
+
+        let mut value = format("total", self.start.elapsed());
+        if let (Some(start), Some(end)) = (self.upstream_start, self.upstream_end) {
+            value.push_str(", ");
+            value.push_str(&format("upstream", end.duration_since(start)));
+        }
+        value
+    }
+}
+
+/// Checks whether the client of this session matches the rules, `None` meaning no restrictions.
+fn client_matches(session: &Session, clients: Option<&IpMatcher>) -> bool {
+    let Some(clients) = clients else {
+        return true;
+    };
+    session
+        .client_addr()
+        .and_then(|addr| addr.as_inet())
+        .is_some_and(|addr| clients.matches(&addr.ip()))
+}
+
+/// Checks whether the client of this session connected via a loopback address.
+fn is_loopback_client(session: &Session) -> bool {
+    session
+        .client_addr()
+        .and_then(|addr| addr.as_inet())
+        .is_some_and(|addr| match addr.ip() {
+            IpAddr::V4(ip) => ip.is_loopback(),
+            IpAddr::V6(ip) => {
+                ip.is_loopback() || ip.to_ipv4_mapped().is_some_and(|ip| ip.is_loopback())
+            }
+        })
+}
+
+/// Disables dynamic compression for responses with status codes outside of the configured
+/// compression statuses, see [`SessionWrapper::set_compression_statuses`].
+fn honor_compression_statuses(
+    session: &mut Session,
+    statuses: Option<&[RangeInclusive<u16>]>,
+    response: &ResponseHeader,
+) {
+    let status = response.status.as_u16();
+    if response.status.is_informational()
+        || statuses.map_or(true, |statuses| {
+            statuses.iter().any(|range| range.contains(&status))
+        })
+    {
+        return;
+    }
+
+    if let Some(compression) = session
+        .downstream_modules_ctx
+        .get_mut::<ResponseCompression>()
+        .filter(|compression| compression.is_enabled())
+    {
+        compression.adjust_level(0);
+    }
+}
+
+/// Disables dynamic compression for responses declaring `Cache-Control: no-transform`, these
+/// must not be modified by intermediaries.
+fn honor_no_transform(session: &mut Session, response: &ResponseHeader) {
+    if response.status.is_informational() {
+        return;
+    }
+
+    let no_transform = response
+        .headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+    if !no_transform {
+        return;
+    }
+
+    if let Some(compression) = session
+        .downstream_modules_ctx
+        .get_mut::<ResponseCompression>()
+        .filter(|compression| compression.is_enabled())
+    {
+        compression.adjust_level(0);
+        compression.adjust_decompression(false);
+    }
+}
+
+/// Disables dynamic compression for `text/event-stream` responses, compression would hold back
+/// events until the compressor’s buffer fills up.
+fn honor_event_stream(session: &mut Session, response: &ResponseHeader) {
+    let event_stream = response
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/event-stream"));
+    if !event_stream {
+        return;
+    }
+
+    if let Some(compression) = session
+        .downstream_modules_ctx
+        .get_mut::<ResponseCompression>()
+        .filter(|compression| compression.is_enabled())
+    {
+        compression.adjust_level(0);
+    }
+}
+
+/// Checks whether a request path is free of empty segments like `//` and dot segments like `/./`
+/// or `/../`, also in their percent-encoded form.
+fn is_strict_path(path: &str) -> bool {
+    let Some(path) = path.strip_prefix('/') else {
+        // Paths like `*` in `OPTIONS *` requests
+        return true;
+    };
+
+    let mut segments = path.split('/').peekable();
+    while let Some(segment) = segments.next() {
+        if segment.is_empty() && segments.peek().is_some() {
+            // Empty segments are only allowed at the end, as in `/dir/`
+            return false;
+        }
+
+        let segment = segment.to_ascii_lowercase().replace("%2e", ".");
+        if segment == "." || segment == ".." {
+            return false;
+        }
+    }
+    true
 }
 
 /// A basic Pingora app implementation, to be passed to [`StartupConf::into_server`]
 ///
-/// This app will only handle the `request_filter`, `upstream_peer`, `upstream_response_filter` and
-/// `logging` phases. All processing will be delegated to the respective `RequestFilter` methods.
+/// This app will only handle the `request_filter`, `upstream_peer`, `upstream_response_filter`,
+/// `upstream_response_trailer_filter`, `fail_to_connect`, `fail_to_proxy` and `logging` phases.
+/// All processing will be delegated to the respective `RequestFilter` methods.
+///
+/// Unless the handler opts out via [`RequestFilter::handles_head`], `HEAD` requests are presented
+/// to the `request_filter` phase as `GET` requests. The response headers produced are sent
+/// unchanged, any response body is suppressed however. For handler chains, this happens for each
+/// handler individually.
+///
+/// Responses declaring `Cache-Control: no-transform` are never compressed or decompressed
+/// dynamically.
+///
+/// Responses with status codes outside of [`SessionWrapper::compression_statuses`] are never
+/// compressed dynamically. Neither are `text/event-stream` responses, so that events are passed
+/// on immediately.
+///
+/// Requests with a URI longer than [`DefaultApp::with_max_uri_length`] allows are rejected with
+/// `414 URI Too Long` before any handler sees them. With [`DefaultApp::with_strict_path`] enabled,
+/// requests with paths containing `//` or dot segments are rejected with `400 Bad Request`.
+///
+/// `GET` and `HEAD` requests for paths configured via [`DefaultApp::with_inline_files`] are
+/// answered directly, without involving the handler’s `request_filter` phase.
+///
+/// With [`DefaultApp::with_server_timing`] enabled, responses carry a `Server-Timing` header
+/// listing the total processing time and, for proxied requests, the upstream response time.
+///
+/// `GET` and `HEAD` requests for the path configured via [`DefaultApp::with_metrics`] are
+/// answered with the counters registered in [`pandora_module_utils::metrics`].
 #[derive(Debug)]
 pub struct DefaultApp<H> {
     handler: H,
     capture_body: bool,
+    max_uri_length: usize,
+    strict_path: bool,
+    inline_files: HashMap<String, InlineFile>,
+    server_timing: bool,
+    server_timing_clients: Option<IpMatcher>,
+    metrics_path: Option<String>,
+    metrics_clients: Option<IpMatcher>,
 }
 
 impl<H> DefaultApp<H> {
@@ -124,6 +370,85 @@ impl<H> DefaultApp<H> {
         Self {
             handler,
             capture_body: false,
+            max_uri_length: DEFAULT_MAX_URI_LENGTH,
+            strict_path: false,
+            inline_files: HashMap::new(),
+            server_timing: false,
+            server_timing_clients: None,
+            metrics_path: None,
+            metrics_clients: None,
+        }
+    }
+
+    /// Sets the maximal length in bytes of request URIs, `0` means no limit. This is usually the
+    /// value of the [`StartupConf::max_uri_length`] setting.
+    pub fn with_max_uri_length(mut self, max_uri_length: usize) -> Self {
+        self.max_uri_length = max_uri_length;
+        self
+    }
+
+    /// Enables rejecting request paths with empty or dot segments. This is usually the value of
+    /// the [`StartupConf::strict_path`] setting.
+    pub fn with_strict_path(mut self, strict_path: bool) -> Self {
+        self.strict_path = strict_path;
+        self
+    }
+
+    /// Sets up content to be served directly for particular request paths. This is usually the
+    /// value of the [`StartupConf::inline_files`] setting.
+    ///
+    /// Will error out if the content of an inline file cannot be loaded.
+    pub fn with_inline_files(
+        mut self,
+        inline_files: &HashMap<String, InlineFileConf>,
+    ) -> Result<Self, Box<Error>> {
+        for (path, conf) in inline_files {
+            self.inline_files.insert(path.clone(), conf.load(path)?);
+        }
+        Ok(self)
+    }
+
+    /// Enables the `Server-Timing` response header for clients matching the rules. This is
+    /// usually the value of the [`StartupConf::server_timing`] and
+    /// [`StartupConf::server_timing_clients`] settings.
+    ///
+    /// With client rules configured, the header is never sent if the client address is unknown.
+    pub fn with_server_timing(mut self, server_timing: bool, clients: &IpMatchRules) -> Self {
+        self.server_timing = server_timing;
+        self.server_timing_clients = if clients.is_empty() {
+            None
+        } else {
+            Some(clients.into())
+        };
+        self
+    }
+
+    /// Makes rule counters available under the given path, to the clients matching the rules.
+    /// This is usually the value of the [`StartupConf::metrics_path`] and
+    /// [`StartupConf::metrics_clients`] settings.
+    ///
+    /// Without client rules, only clients connecting via a loopback address can retrieve the
+    /// counters. Requests from other clients are passed on to the handler.
+    pub fn with_metrics(mut self, path: Option<&str>, clients: &IpMatchRules) -> Self {
+        self.metrics_path = path.map(|path| path.to_owned());
+        self.metrics_clients = if clients.is_empty() {
+            None
+        } else {
+            Some(clients.into())
+        };
+        self
+    }
+
+    /// Checks whether the `Server-Timing` header should be sent to the client of this session.
+    fn wants_server_timing(&self, session: &Session) -> bool {
+        self.server_timing && client_matches(session, self.server_timing_clients.as_ref())
+    }
+
+    /// Checks whether rule counters can be served to the client of this session.
+    fn wants_metrics(&self, session: &Session) -> bool {
+        match &self.metrics_clients {
+            Some(clients) => client_matches(session, Some(clients)),
+            None => is_loopback_client(session),
         }
     }
 
@@ -159,17 +484,61 @@ impl<H> DefaultApp<H> {
     /// upstream response.
     ///
     /// This method is meant for testing. Will call `upstream_response` callback to produce a fake
-    /// upstream response if necessary. Upon successful completion, `evaluate_result` callback is
-    /// called to validate the session.
+    /// upstream response if necessary. Errors returned by the callback are treated like connection
+    /// errors: if the `fail_to_connect` phase marks them as retryable, the `upstream_peer` phase
+    /// runs again and the callback is called with the new peer. Upon successful completion,
+    /// `evaluate_result` callback is called to validate the session.
     pub async fn handle_request_with_upstream<C>(
         &mut self,
-        mut session: Session,
+        session: Session,
         upstream_response: C,
     ) -> AppResult
     where
         C: Fn(&mut Session, Box<HttpPeer>) -> Result<ResponseHeader, Box<Error>>,
         H: RequestFilter + Sync,
         H::CTX: Send + Sync,
+    {
+        self.handle_request_impl(session, |session, peer| {
+            Ok((upstream_response(session, peer)?, None))
+        })
+        .await
+    }
+
+    /// Handles all request phases for a request like Pingora would do it while also faking
+    /// upstream response including response trailers.
+    ///
+    /// This method is meant for testing. Will call `upstream_response` callback to produce a fake
+    /// upstream response header and trailers if necessary. The trailers will be forwarded to the
+    /// client the way Pingora does it for HTTP/2 connections.
+    pub async fn handle_request_with_upstream_trailers<C>(
+        &mut self,
+        session: Session,
+        upstream_response: C,
+    ) -> AppResult
+    where
+        C: Fn(&mut Session, Box<HttpPeer>) -> Result<(ResponseHeader, HeaderMap), Box<Error>>,
+        H: RequestFilter + Sync,
+        H::CTX: Send + Sync,
+    {
+        self.handle_request_impl(session, |session, peer| {
+            let (header, trailers) = upstream_response(session, peer)?;
+            Ok((header, Some(trailers)))
+        })
+        .await
+    }
+
+    async fn handle_request_impl<C>(
+        &mut self,
+        mut session: Session,
+        upstream_response: C,
+    ) -> AppResult
+    where
+        C: Fn(
+            &mut Session,
+            Box<HttpPeer>,
+        ) -> Result<(ResponseHeader, Option<HeaderMap>), Box<Error>>,
+        H: RequestFilter + Sync,
+        H::CTX: Send + Sync,
     {
         let mut modules = HttpModules::new();
         self.init_downstream_modules(&mut modules);
@@ -190,13 +559,25 @@ impl<H> DefaultApp<H> {
 
             match self.request_filter(&mut session, &mut ctx).await {
                 Ok(false) => {
-                    let upstream_peer = self.upstream_peer(&mut session, &mut ctx).await?;
-                    let mut response_header = upstream_response(&mut session, upstream_peer)?;
+                    let (mut response_header, trailers) = loop {
+                        let upstream_peer = self.upstream_peer(&mut session, &mut ctx).await?;
+                        match upstream_response(&mut session, upstream_peer.clone()) {
+                            Ok(response) => break response,
+                            Err(err) => {
+                                let err = self.fail_to_connect(
+                                    &mut session,
+                                    &upstream_peer,
+                                    &mut ctx,
+                                    err,
+                                );
+                                if !err.retry() {
+                                    return Err(err);
+                                }
+                            }
+                        }
+                    };
                     self.upstream_response_filter(&mut session, &mut response_header, &mut ctx);
-                    session
-                        .downstream_modules_ctx
-                        .response_header_filter(&mut response_header, false)
-                        .await?;
+                    // This runs the downstream modules’ response header filters
                     session
                         .write_response_header(Box::new(response_header), false)
                         .await?;
@@ -204,7 +585,19 @@ impl<H> DefaultApp<H> {
                     let mut body = ctx.extensions.remove::<BytesMut>().map(|body| body.into());
                     session
                         .downstream_modules_ctx
-                        .response_body_filter(&mut body, true)
+                        .response_body_filter(&mut body, true)?;
+
+                    if let Some(mut trailers) = trailers {
+                        self.upstream_response_trailer_filter(
+                            &mut session,
+                            &mut trailers,
+                            &mut ctx,
+                        )?;
+                        SessionWrapperImpl::new(&mut session, &mut ctx.extensions, true)
+                            .write_response_trailers(trailers)
+                            .await?;
+                    }
+                    Ok(())
                 }
                 Ok(true) => Ok(()),
                 Err(err) => Err(err),
@@ -212,6 +605,15 @@ impl<H> DefaultApp<H> {
         }
         .await;
 
+        if let Err(err) = &result {
+            // Only give handlers a chance to respond, Pingora’s default error response would
+            // obscure the result otherwise.
+            let mut wrapper = SessionWrapperImpl::new(&mut session, &mut ctx.extensions, true);
+            self.handler
+                .fail_to_proxy(&mut wrapper, err, &mut ctx.handler)
+                .await;
+        }
+
         self.logging(
             &mut session,
             result.as_ref().err().map(|err| err.as_ref()),
@@ -250,7 +652,7 @@ where
     }
 
     fn init_downstream_modules(&self, modules: &mut HttpModules) {
-        H::init_downstream_modules(modules);
+        init_handler_modules::<H>(modules);
     }
 
     async fn early_request_filter(
@@ -258,6 +660,31 @@ where
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> Result<(), Box<Error>> {
+        // Check the actual client address before handlers get a chance to change it
+        if self.wants_server_timing(session) {
+            ctx.extensions.insert(RequestTiming::new());
+        }
+
+        // raw_path() panics for URIs without a path like `example.com:443`
+        let header = session.req_header();
+        let uri_length = if header.uri.path_and_query().is_some() {
+            header.raw_path().len()
+        } else {
+            0
+        };
+        if self.max_uri_length > 0 && uri_length > self.max_uri_length {
+            warn!("rejecting request with URI length {uri_length} exceeding the limit");
+            return Err(Error::new(ErrorType::HTTPStatus(414)));
+        }
+
+        if self.strict_path && !is_strict_path(header.uri.path()) {
+            warn!(
+                "rejecting request with non-normalized path {}",
+                header.uri.path()
+            );
+            return Err(Error::new(ErrorType::HTTPStatus(400)));
+        }
+
         let mut session = SessionWrapperImpl::new(session, &mut ctx.extensions, self.capture_body);
         self.handler
             .early_request_filter(&mut session, &mut ctx.handler)
@@ -270,11 +697,45 @@ where
         ctx: &mut Self::CTX,
     ) -> Result<bool, Box<Error>> {
         let mut session = SessionWrapperImpl::new(session, &mut ctx.extensions, self.capture_body);
-        Ok(self
-            .handler
-            .request_filter(&mut session, &mut ctx.handler)
-            .await?
-            == RequestFilterResult::ResponseSent)
+
+        let method = &session.req_header().method;
+        if method == Method::GET || method == Method::HEAD {
+            let path = session.req_header().uri.path();
+            let content = if let Some(file) = self.inline_files.get(path) {
+                if !file.log {
+                    session.extensions_mut().insert(SkipLogging);
+                }
+                Some((file.content.clone(), file.content_type.as_str()))
+            } else if self.metrics_path.as_deref() == Some(path) && self.wants_metrics(&session) {
+                Some((
+                    Bytes::from(metrics::prometheus_text()),
+                    "text/plain; version=0.0.4; charset=utf-8",
+                ))
+            } else {
+                None
+            };
+
+            if let Some((content, content_type)) = content {
+                let mut header = ResponseHeader::build(StatusCode::OK, Some(2))?;
+                header.append_header(header::CONTENT_LENGTH, content.len().to_string())?;
+                header.append_header(header::CONTENT_TYPE, content_type)?;
+                let send_body = session.req_header().method != Method::HEAD;
+                session
+                    .write_response_header(Box::new(header), !send_body)
+                    .await?;
+                if send_body {
+                    session.write_response_body(Some(content), true).await?;
+                }
+
+                return Ok(true);
+            }
+        }
+
+        session.add_allowed_methods(self.handler.allowed_methods());
+        Ok(
+            request_filter_head_as_get(&self.handler, &mut session, &mut ctx.handler).await?
+                == RequestFilterResult::ResponseSent,
+        )
     }
 
     async fn upstream_peer(
@@ -282,6 +743,10 @@ where
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>, Box<Error>> {
+        if let Some(timing) = ctx.extensions.get_mut::<RequestTiming>() {
+            timing.upstream_start = Some(Instant::now());
+        }
+
         let mut session = SessionWrapperImpl::new(session, &mut ctx.extensions, self.capture_body);
         let result = self
             .handler
@@ -294,7 +759,93 @@ where
         }
     }
 
+    fn upstream_response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) {
+        let statuses = SessionWrapperImpl::new(session, &mut ctx.extensions, self.capture_body)
+            .compression_statuses()
+            .map(|statuses| statuses.to_vec());
+        honor_compression_statuses(session, statuses.as_deref(), upstream_response);
+        honor_no_transform(session, upstream_response);
+        honor_event_stream(session, upstream_response);
+
+        if let Some(timing) = ctx.extensions.get_mut::<RequestTiming>() {
+            if !upstream_response.status.is_informational() {
+                timing.upstream_end = Some(Instant::now());
+                if let Err(err) =
+                    upstream_response.insert_header("Server-Timing", timing.header_value())
+                {
+                    warn!("failed adding Server-Timing header: {err}");
+                }
+            }
+        }
+    }
+
+    fn upstream_response_trailer_filter(
+        &self,
+        session: &mut Session,
+        upstream_trailers: &mut HeaderMap,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        let mut session = SessionWrapperImpl::new(session, &mut ctx.extensions, self.capture_body);
+        self.handler.upstream_response_trailer_filter(
+            &mut session,
+            upstream_trailers,
+            &mut ctx.handler,
+        )
+    }
+
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
+        peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        e: Box<Error>,
+    ) -> Box<Error> {
+        let mut session = SessionWrapperImpl::new(session, &mut ctx.extensions, self.capture_body);
+        self.handler
+            .fail_to_connect(&mut session, peer, &mut ctx.handler, e)
+    }
+
+    async fn fail_to_proxy(&self, session: &mut Session, e: &Error, ctx: &mut Self::CTX) -> u16 {
+        {
+            let mut session =
+                SessionWrapperImpl::new(session, &mut ctx.extensions, self.capture_body);
+            if let Some(status) = self
+                .handler
+                .fail_to_proxy(&mut session, e, &mut ctx.handler)
+                .await
+            {
+                return status;
+            }
+        }
+
+        // Same as Pingora’s default implementation
+        let status = match e.etype() {
+            ErrorType::HTTPStatus(status) => *status,
+            _ => match e.esource() {
+                ErrorSource::Upstream => 502,
+                ErrorSource::Downstream => match e.etype() {
+                    ErrorType::WriteError | ErrorType::ReadError | ErrorType::ConnectionClosed => 0,
+                    _ => 400,
+                },
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        };
+        if status > 0 {
+            session.as_mut().respond_error(status).await
+        }
+        status
+    }
+
     async fn logging(&self, session: &mut Session, e: Option<&Error>, ctx: &mut Self::CTX) {
+        if ctx.extensions.get::<SkipLogging>().is_some() {
+            return;
+        }
+
         let mut session = SessionWrapperImpl::new(session, &mut ctx.extensions, self.capture_body);
         self.handler
             .logging(&mut session, e, &mut ctx.handler)
@@ -329,12 +880,53 @@ impl SessionWrapper for SessionWrapperImpl<'_> {
         self.extensions
     }
 
+    async fn write_response_header(
+        &mut self,
+        mut resp: Box<ResponseHeader>,
+        end_of_stream: bool,
+    ) -> Result<(), Box<Error>> {
+        if let Some(timing) = self.extensions.get::<RequestTiming>() {
+            if !resp.status.is_informational() {
+                resp.insert_header("Server-Timing", timing.header_value())?;
+            }
+        }
+        if self.capture_body && resp.status.is_informational() {
+            self.extensions_mut()
+                .get_or_insert_default::<InformationalResponses>()
+                .0
+                .push(resp.as_ref().clone());
+        }
+        if !resp.status.is_informational() && self.extensions.get::<HeadAsGet>().is_some() {
+            // Restore request method, so that Pingora knows not to expect a response body
+            self.deref_mut().req_header_mut().set_method(Method::HEAD);
+        }
+        let statuses = self
+            .compression_statuses()
+            .map(|statuses| statuses.to_vec());
+        honor_compression_statuses(self.deref_mut(), statuses.as_deref(), &resp);
+        honor_no_transform(self.deref_mut(), &resp);
+        honor_event_stream(self.deref_mut(), &resp);
+        self.deref_mut()
+            .write_response_header(resp, end_of_stream)
+            .await
+    }
+
     async fn write_response_body(
         &mut self,
         data: Option<Bytes>,
         end_of_stream: bool,
     ) -> Result<(), Box<Error>> {
+        let data = if self.extensions.get::<HeadAsGet>().is_some() {
+            None
+        } else {
+            data
+        };
+
         if self.capture_body {
+            self.extensions_mut()
+                .get_or_insert_default::<ResponseChunks>()
+                .0
+                .push((data.clone().unwrap_or_default(), end_of_stream));
             if let Some(data) = data {
                 self.extensions_mut()
                     .get_or_insert_default::<BytesMut>()
@@ -347,6 +939,18 @@ impl SessionWrapper for SessionWrapperImpl<'_> {
                 .await
         }
     }
+
+    async fn write_response_trailers(&mut self, trailers: HeaderMap) -> Result<(), Box<Error>> {
+        if self.capture_body {
+            self.extensions_mut().insert(ResponseTrailers(trailers));
+            Ok(())
+        } else {
+            self.deref_mut()
+                .downstream_session
+                .write_response_trailers(trailers)
+                .await
+        }
+    }
 }
 
 impl Deref for SessionWrapperImpl<'_> {
@@ -362,3 +966,454 @@ impl DerefMut for SessionWrapperImpl<'_> {
         self.inner
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{create_test_session, RequestHeader, SocketAddr};
+    use std::path::PathBuf;
+    use test_log::test;
+
+    #[derive(Debug)]
+    struct Handler;
+
+    #[async_trait]
+    impl RequestFilter for Handler {
+        type Conf = ();
+        type CTX = ();
+        fn new_ctx() -> Self::CTX {}
+
+        async fn early_request_filter(
+            &self,
+            _session: &mut impl SessionWrapper,
+            _ctx: &mut Self::CTX,
+        ) -> Result<(), Box<Error>> {
+            // Marks requests that reached the handler
+            Err(Error::new(ErrorType::HTTPStatus(403)))
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn max_uri_length() {
+        async fn status(app: &mut DefaultApp<Handler>, path: &str) -> Option<ErrorType> {
+            let header = RequestHeader::build("GET", path.as_bytes(), None).unwrap();
+            let session = create_test_session(header).await;
+            let result = app.handle_request(session).await;
+            result.err().as_ref().map(|err| err.etype.clone())
+        }
+
+        let path = format!("/{}?{}", "a".repeat(10), "b".repeat(10));
+
+        let mut app = DefaultApp::new(Handler).with_max_uri_length(22);
+        assert_eq!(
+            status(&mut app, &path).await,
+            Some(ErrorType::HTTPStatus(403))
+        );
+
+        let mut app = DefaultApp::new(Handler).with_max_uri_length(21);
+        assert_eq!(
+            status(&mut app, &path).await,
+            Some(ErrorType::HTTPStatus(414))
+        );
+
+        let mut app = DefaultApp::new(Handler).with_max_uri_length(0);
+        let path = format!("/{}", "a".repeat(DEFAULT_MAX_URI_LENGTH));
+        assert_eq!(
+            status(&mut app, &path).await,
+            Some(ErrorType::HTTPStatus(403))
+        );
+
+        let mut app = DefaultApp::new(Handler);
+        assert_eq!(
+            status(&mut app, &path).await,
+            Some(ErrorType::HTTPStatus(414))
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn strict_path() {
+        async fn status(app: &mut DefaultApp<Handler>, path: &str) -> Option<ErrorType> {
+            let header = RequestHeader::build("GET", path.as_bytes(), None).unwrap();
+            let session = create_test_session(header).await;
+            let result = app.handle_request(session).await;
+            result.err().as_ref().map(|err| err.etype.clone())
+        }
+
+        let mut app = DefaultApp::new(Handler).with_strict_path(true);
+        for path in [
+            "/",
+            "/file.txt",
+            "/dir/",
+            "/dir/file.txt?a//b",
+            "/.well-known/x",
+        ] {
+            assert_eq!(
+                status(&mut app, path).await,
+                Some(ErrorType::HTTPStatus(403)),
+                "{path}"
+            );
+        }
+        for path in [
+            "//",
+            "/dir//file.txt",
+            "/./file.txt",
+            "/dir/../file.txt",
+            "/dir/%2E%2e/file.txt",
+            "/dir/..",
+        ] {
+            assert_eq!(
+                status(&mut app, path).await,
+                Some(ErrorType::HTTPStatus(400)),
+                "{path}"
+            );
+        }
+
+        // Without strict mode such paths are left to the handlers
+        let mut app = DefaultApp::new(Handler);
+        assert_eq!(
+            status(&mut app, "/dir//file.txt").await,
+            Some(ErrorType::HTTPStatus(403))
+        );
+    }
+
+    #[derive(Debug)]
+    struct StreamingHandler;
+
+    #[async_trait]
+    impl RequestFilter for StreamingHandler {
+        type Conf = ();
+        type CTX = ();
+        fn new_ctx() -> Self::CTX {}
+
+        async fn request_filter(
+            &self,
+            session: &mut impl SessionWrapper,
+            _ctx: &mut Self::CTX,
+        ) -> Result<RequestFilterResult, Box<Error>> {
+            let header = ResponseHeader::build(200, None)?;
+            session
+                .write_response_header(Box::new(header), false)
+                .await?;
+            session.write_response_body(Some("a".into()), false).await?;
+            session
+                .write_response_body(Some("bc".into()), false)
+                .await?;
+            session.write_response_body(None, true).await?;
+            Ok(RequestFilterResult::ResponseSent)
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn body_chunks() {
+        let mut app = DefaultApp::new(StreamingHandler);
+
+        let header = RequestHeader::build("GET", b"/", None).unwrap();
+        let session = create_test_session(header).await;
+        let result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(result.body_str(), "abc");
+        assert_eq!(
+            result.body_chunks(),
+            [
+                (Bytes::from("a"), false),
+                (Bytes::from("bc"), false),
+                (Bytes::new(), true),
+            ]
+        );
+
+        // Suppressed HEAD response bodies still show the calls made
+        let header = RequestHeader::build("HEAD", b"/", None).unwrap();
+        let session = create_test_session(header).await;
+        let result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(result.body_str(), "");
+        assert_eq!(
+            result.body_chunks(),
+            [
+                (Bytes::new(), false),
+                (Bytes::new(), false),
+                (Bytes::new(), true),
+            ]
+        );
+    }
+
+    #[derive(Debug)]
+    struct DenyingHandler;
+
+    #[derive(Debug, Clone)]
+    struct Logged;
+
+    #[async_trait]
+    impl RequestFilter for DenyingHandler {
+        type Conf = ();
+        type CTX = ();
+        fn new_ctx() -> Self::CTX {}
+
+        async fn request_filter(
+            &self,
+            _session: &mut impl SessionWrapper,
+            _ctx: &mut Self::CTX,
+        ) -> Result<RequestFilterResult, Box<Error>> {
+            // Stands in for an authentication handler
+            Err(Error::new(ErrorType::HTTPStatus(401)))
+        }
+
+        async fn logging(
+            &self,
+            session: &mut impl SessionWrapper,
+            _e: Option<&Error>,
+            _ctx: &mut Self::CTX,
+        ) {
+            session.extensions_mut().insert(Logged);
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn inline_files() {
+        let mut inline_files = HashMap::new();
+        inline_files.insert(
+            "/robots.txt".to_owned(),
+            InlineFileConf {
+                content: Some("User-agent: *\nDisallow:\n".into()),
+                ..Default::default()
+            },
+        );
+        inline_files.insert(
+            "/favicon.ico".to_owned(),
+            InlineFileConf {
+                file: Some(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml")),
+                content_type: "image/x-icon".into(),
+                log: false,
+                ..Default::default()
+            },
+        );
+        let mut app = DefaultApp::new(DenyingHandler)
+            .with_inline_files(&inline_files)
+            .unwrap();
+
+        let header = RequestHeader::build("GET", b"/robots.txt", None).unwrap();
+        let session = create_test_session(header).await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(result.body_str(), "User-agent: *\nDisallow:\n");
+        let session = result.session();
+        let response = session.response_written().unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.headers.get("Content-Type").unwrap(),
+            "text/plain;charset=utf-8"
+        );
+        assert_eq!(response.headers.get("Content-Length").unwrap(), "24");
+        assert!(session.extensions().get::<Logged>().is_some());
+
+        let header = RequestHeader::build("HEAD", b"/favicon.ico", None).unwrap();
+        let session = create_test_session(header).await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(result.body_str(), "");
+        let session = result.session();
+        let response = session.response_written().unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.headers.get("Content-Type").unwrap(),
+            "image/x-icon"
+        );
+        assert!(session.extensions().get::<Logged>().is_none());
+
+        // Other paths and methods are left to the handler
+        for (method, path) in [("GET", "/robots.txt/"), ("POST", "/robots.txt")] {
+            let header = RequestHeader::build(method, path.as_bytes(), None).unwrap();
+            let session = create_test_session(header).await;
+            let result = app.handle_request(session).await;
+            assert_eq!(
+                result.err().as_ref().map(|err| err.etype.clone()),
+                Some(ErrorType::HTTPStatus(401))
+            );
+        }
+
+        inline_files.insert("/invalid".to_owned(), InlineFileConf::default());
+        assert!(DefaultApp::new(DenyingHandler)
+            .with_inline_files(&inline_files)
+            .is_err());
+    }
+
+    #[derive(Debug)]
+    struct ProxyingHandler;
+
+    #[async_trait]
+    impl RequestFilter for ProxyingHandler {
+        type Conf = ();
+        type CTX = ();
+        fn new_ctx() -> Self::CTX {}
+
+        async fn request_filter(
+            &self,
+            session: &mut impl SessionWrapper,
+            _ctx: &mut Self::CTX,
+        ) -> Result<RequestFilterResult, Box<Error>> {
+            if session.uri().path() == "/local" {
+                let header = ResponseHeader::build(200, None)?;
+                session
+                    .write_response_header(Box::new(header), true)
+                    .await?;
+                Ok(RequestFilterResult::ResponseSent)
+            } else {
+                Ok(RequestFilterResult::Unhandled)
+            }
+        }
+
+        async fn upstream_peer(
+            &self,
+            _session: &mut impl SessionWrapper,
+            _ctx: &mut Self::CTX,
+        ) -> Result<Option<Box<HttpPeer>>, Box<Error>> {
+            Ok(Some(Box::new(HttpPeer::new(
+                "127.0.0.1:8080",
+                false,
+                String::new(),
+            ))))
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn server_timing() {
+        async fn server_timing(
+            app: &mut DefaultApp<ProxyingHandler>,
+            path: &str,
+            client_addr: Option<&str>,
+        ) -> Option<String> {
+            let header = RequestHeader::build("GET", path.as_bytes(), None).unwrap();
+            let mut session = create_test_session(header).await;
+            if let Some(addr) = client_addr {
+                let mut extensions = Extensions::new();
+                SessionWrapperImpl::new(&mut session, &mut extensions, false)
+                    .set_client_addr(SocketAddr::Inet(addr.parse().unwrap()));
+            }
+            let mut result = app
+                .handle_request_with_upstream(session, |_, _| ResponseHeader::build(200, None))
+                .await;
+            assert!(result.err().is_none());
+            let session = result.session();
+            let response = session.response_written().unwrap();
+            response
+                .headers
+                .get("Server-Timing")
+                .map(|value| value.to_str().unwrap().to_owned())
+        }
+
+        fn is_duration(value: &str, name: &str) -> bool {
+            value
+                .strip_prefix(name)
+                .and_then(|value| value.strip_prefix(";dur="))
+                .and_then(|value| value.parse::<f64>().ok())
+                .is_some()
+        }
+
+        let mut app = DefaultApp::new(ProxyingHandler);
+        assert_eq!(server_timing(&mut app, "/local", None).await, None);
+        assert_eq!(server_timing(&mut app, "/", None).await, None);
+
+        let mut app =
+            DefaultApp::new(ProxyingHandler).with_server_timing(true, &Default::default());
+        let value = server_timing(&mut app, "/local", None).await.unwrap();
+        assert!(is_duration(&value, "total"), "{value}");
+
+        let value = server_timing(&mut app, "/", None).await.unwrap();
+        let (total, upstream) = value.split_once(", ").unwrap();
+        assert!(is_duration(total, "total"), "{value}");
+        assert!(is_duration(upstream, "upstream"), "{value}");
+
+        // Restricted to particular clients
+        let clients = IpMatchRules {
+            include: vec!["127.0.0.0/8".parse().unwrap()].into(),
+            exclude: vec!["127.0.0.2".parse().unwrap()].into(),
+        };
+        let mut app = DefaultApp::new(ProxyingHandler).with_server_timing(true, &clients);
+        assert!(server_timing(&mut app, "/local", Some("127.0.0.1:1234"))
+            .await
+            .is_some());
+        assert_eq!(
+            server_timing(&mut app, "/local", Some("127.0.0.2:1234")).await,
+            None
+        );
+        assert_eq!(
+            server_timing(&mut app, "/local", Some("[::1]:1234")).await,
+            None
+        );
+        assert_eq!(server_timing(&mut app, "/local", None).await, None);
+    }
+
+    #[test(tokio::test)]
+    async fn metrics() {
+        async fn get_metrics(
+            app: &mut DefaultApp<DenyingHandler>,
+            client_addr: Option<&str>,
+        ) -> Option<String> {
+            let header = RequestHeader::build("GET", b"/metrics", None).unwrap();
+            let mut session = create_test_session(header).await;
+            if let Some(addr) = client_addr {
+                let mut extensions = Extensions::new();
+                SessionWrapperImpl::new(&mut session, &mut extensions, false)
+                    .set_client_addr(SocketAddr::Inet(addr.parse().unwrap()));
+            }
+            let mut result = app.handle_request(session).await;
+            if result.err().is_some() {
+                return None;
+            }
+            let body = result.body_str().into_owned();
+            let session = result.session();
+            let response = session.response_written().unwrap();
+            assert_eq!(response.status, 200);
+            assert_eq!(
+                response.headers.get("Content-Type").unwrap(),
+                "text/plain; version=0.0.4; charset=utf-8"
+            );
+            Some(body)
+        }
+
+        let counter = metrics::Counter::register("startup_test", "metrics_rule");
+        counter.increment();
+        counter.increment();
+
+        let mut app = DefaultApp::new(DenyingHandler);
+        assert_eq!(get_metrics(&mut app, None).await, None);
+
+        // Only loopback clients by default
+        let mut app =
+            DefaultApp::new(DenyingHandler).with_metrics(Some("/metrics"), &Default::default());
+        assert_eq!(get_metrics(&mut app, None).await, None);
+        assert_eq!(get_metrics(&mut app, Some("192.0.2.1:1234")).await, None);
+        assert!(get_metrics(&mut app, Some("[::ffff:127.0.0.1]:1234"))
+            .await
+            .is_some());
+        let body = get_metrics(&mut app, Some("[::1]:1234")).await.unwrap();
+        assert!(
+            body.contains(
+                "\npandora_rule_applied_total{module=\"startup_test\",rule=\"metrics_rule\"} 2\n"
+            ),
+            "{body}"
+        );
+
+        counter.increment();
+        let body = get_metrics(&mut app, Some("127.0.0.1:1234")).await.unwrap();
+        assert!(
+            body.contains(
+                "\npandora_rule_applied_total{module=\"startup_test\",rule=\"metrics_rule\"} 3\n"
+            ),
+            "{body}"
+        );
+
+        // Restricted to particular clients
+        let clients = IpMatchRules {
+            include: vec!["127.0.0.0/8".parse().unwrap()].into(),
+            exclude: Default::default(),
+        };
+        let mut app = DefaultApp::new(DenyingHandler).with_metrics(Some("/metrics"), &clients);
+        assert!(get_metrics(&mut app, Some("127.0.0.1:1234"))
+            .await
+            .is_some());
+        assert_eq!(get_metrics(&mut app, Some("[::1]:1234")).await, None);
+        assert_eq!(get_metrics(&mut app, None).await, None);
+    }
+}
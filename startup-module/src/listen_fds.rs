@@ -0,0 +1,328 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Listening sockets inherited from the parent process (Unix only)
+//!
+//! Pingora looks up listening sockets by their address in a file descriptor table before binding
+//! new ones. This table is normally only filled during a graceful upgrade. Here inherited sockets
+//! are added to it before the services start up, so that Pingora uses them instead of binding.
+//! The same mechanism is used for sockets bound upfront, e.g. before dropping privileges.
+
+use async_trait::async_trait;
+use log::{error, info};
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::sys::socket::{
+    bind, getsockname, getsockopt, listen, setsockopt, socket, sockopt, sockopt::AcceptConn,
+    AddressFamily, SockFlag, SockType, SockaddrStorage,
+};
+use nix::unistd::close;
+use pandora_module_utils::pingora::{Error, ErrorType};
+use pingora::server::{Fds, ListenFds, ShutdownWatch};
+use pingora::services::Service;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::RawFd;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::configuration::ListenAddr;
+
+/// First file descriptor passed in by systemd socket activation, see `sd_listen_fds(3)`
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Maximal number of pending connections for the listening sockets, same as Pingora’s
+const LISTENER_BACKLOG: usize = 65535;
+
+/// Returns the file descriptors passed in via systemd socket activation if any.
+pub(crate) fn systemd_fds() -> Vec<RawFd> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let count = std::env::var("LISTEN_FDS").ok();
+
+    // Make sure that child processes won't consider these file descriptors their own
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    if pid.and_then(|pid| pid.parse().ok()) != Some(std::process::id()) {
+        return Vec::new();
+    }
+
+    let count = count.and_then(|count| count.parse().ok()).unwrap_or(0);
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count).collect()
+}
+
+/// Determines the local address of an inherited socket, making sure that it is a listening TCP
+/// socket.
+pub(crate) fn socket_addr(fd: RawFd) -> Result<SocketAddr, Box<Error>> {
+    if !getsockopt(fd, AcceptConn).unwrap_or(false) {
+        error!("file descriptor {fd} is not a listening socket");
+        return Err(Error::new(ErrorType::InternalError));
+    }
+
+    let addr = getsockname::<SockaddrStorage>(fd).map_err(|err| {
+        error!("failed determining address of file descriptor {fd}: {err}");
+        Error::new(ErrorType::InternalError)
+    })?;
+
+    if let Some(addr) = addr.as_sockaddr_in() {
+        Ok(SocketAddrV4::new(addr.ip().into(), addr.port()).into())
+    } else if let Some(addr) = addr.as_sockaddr_in6() {
+        Ok(SocketAddrV6::new(addr.ip(), addr.port(), addr.flowinfo(), addr.scope_id()).into())
+    } else {
+        error!("file descriptor {fd} is not a TCP socket");
+        Err(Error::new(ErrorType::InternalError))
+    }
+}
+
+/// Puts an inherited socket into non-blocking mode as expected by the async runtime.
+fn set_nonblocking(fd: RawFd) -> Result<(), Box<Error>> {
+    fcntl(fd, FcntlArg::F_GETFL)
+        .and_then(|flags| {
+            let flags = OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK;
+            fcntl(fd, FcntlArg::F_SETFL(flags))
+        })
+        .map_err(|err| {
+            error!("failed making file descriptor {fd} non-blocking: {err}");
+            Error::new(ErrorType::InternalError)
+        })?;
+    Ok(())
+}
+
+/// Associates inherited sockets with listening addresses, producing the entries to be added to
+/// Pingora’s file descriptor table.
+///
+/// Sockets matching an address in `listen` or `redirector_listen` will be used for that address.
+/// For any other sockets a plain HTTP address is added to `listen`.
+pub(crate) fn assign(
+    fds: impl IntoIterator<Item = RawFd>,
+    listen: &mut Vec<ListenAddr>,
+    redirector_listen: &[ListenAddr],
+) -> Result<Vec<(String, RawFd)>, Box<Error>> {
+    let mut result = Vec::new();
+    for fd in fds {
+        let socket_addr = socket_addr(fd)?;
+        set_nonblocking(fd)?;
+        let existing = listen
+            .iter()
+            .chain(redirector_listen)
+            .find(|addr| addr.addr.parse() == Ok(socket_addr));
+        let addr = if let Some(existing) = existing {
+            existing.addr.clone()
+        } else {
+            let addr = socket_addr.to_string();
+            listen.push(addr.clone().into());
+            addr
+        };
+
+        info!("using inherited file descriptor {fd} for address {addr}");
+        result.push((addr, fd));
+    }
+    Ok(result)
+}
+
+/// Binds a non-blocking listening socket for the given address.
+pub(crate) fn bind_addr(addr: &ListenAddr) -> Result<RawFd, Box<Error>> {
+    let parsed: SocketAddr = addr.addr.parse().map_err(|err| {
+        error!("failed parsing listening address {}: {err}", addr.addr);
+        Error::new(ErrorType::InternalError)
+    })?;
+
+    let family = if parsed.is_ipv4() {
+        AddressFamily::Inet
+    } else {
+        AddressFamily::Inet6
+    };
+    let fd = socket(
+        family,
+        SockType::Stream,
+        SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+        None,
+    )
+    .map_err(|err| {
+        error!("failed creating socket for address {}: {err}", addr.addr);
+        Error::new(ErrorType::BindError)
+    })?;
+
+    let result = setsockopt(fd, sockopt::ReuseAddr, &true)
+        .and_then(|_| match addr.ipv6_only {
+            Some(ipv6_only) if parsed.is_ipv6() => setsockopt(fd, sockopt::Ipv6V6Only, &ipv6_only),
+            _ => Ok(()),
+        })
+        .and_then(|_| bind(fd, &SockaddrStorage::from(parsed)))
+        .and_then(|_| listen(fd, LISTENER_BACKLOG));
+    if let Err(err) = result {
+        error!("failed binding address {}: {err}", addr.addr);
+        let _ = close(fd);
+        return Err(Error::new(ErrorType::BindError));
+    }
+    Ok(fd)
+}
+
+/// Binds listening sockets for all given addresses, producing the entries to be added to
+/// Pingora’s file descriptor table. If binding any address fails, the sockets bound already are
+/// closed again.
+pub(crate) fn bind_addrs(addrs: &[ListenAddr]) -> Result<Vec<(String, RawFd)>, Box<Error>> {
+    let mut result = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        match bind_addr(addr) {
+            Ok(fd) => result.push((addr.addr.clone(), fd)),
+            Err(err) => {
+                for (_, fd) in result {
+                    let _ = close(fd);
+                }
+                return Err(err);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Service wrapper adding inherited sockets to the file descriptor table before starting the
+/// service
+pub(crate) struct InheritedFdsService<S> {
+    inner: S,
+    fds: Vec<(String, RawFd)>,
+}
+
+impl<S> InheritedFdsService<S> {
+    pub(crate) fn new(inner: S, fds: Vec<(String, RawFd)>) -> Self {
+        Self { inner, fds }
+    }
+}
+
+#[async_trait]
+impl<S: Service> Service for InheritedFdsService<S> {
+    async fn start_service(&mut self, fds: Option<ListenFds>, shutdown: ShutdownWatch) {
+        let fds = fds.unwrap_or_else(|| Arc::new(Mutex::new(Fds::new())));
+        {
+            let mut table = fds.lock().await;
+            for (addr, fd) in &self.fds {
+                // Sockets passed on by the previous process during a graceful upgrade take
+                // precedence.
+                if table.get(addr).is_none() {
+                    table.add(addr.clone(), *fd);
+                }
+            }
+        }
+        self.inner.start_service(Some(fds), shutdown).await
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn threads(&self) -> Option<usize> {
+        self.inner.threads()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{http_proxy_service, ServerConf, SessionWrapper};
+    use pandora_module_utils::{RequestFilter, RequestFilterResult};
+    use std::net::{TcpListener, UdpSocket};
+    use std::os::fd::{AsRawFd, IntoRawFd};
+    use test_log::test;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use crate::DefaultApp;
+
+    #[derive(Debug)]
+    struct Handler;
+
+    #[async_trait]
+    impl RequestFilter for Handler {
+        type Conf = ();
+        type CTX = ();
+        fn new_ctx() -> Self::CTX {}
+
+        async fn request_filter(
+            &self,
+            session: &mut impl SessionWrapper,
+            _ctx: &mut Self::CTX,
+        ) -> Result<RequestFilterResult, Box<Error>> {
+            session.respond_error(204).await?;
+            Ok(RequestFilterResult::ResponseSent)
+        }
+    }
+
+    #[test]
+    fn addresses() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert_eq!(socket_addr(listener.as_raw_fd()).unwrap(), addr);
+
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(socket_addr(udp.as_raw_fd()).is_err());
+
+        let other = TcpListener::bind("127.0.0.1:0").unwrap();
+        let configured = ListenAddr {
+            addr: addr.to_string(),
+            tls: true,
+            ipv6_only: None,
+        };
+        let mut listen = vec![configured.clone()];
+        let fds = assign([listener.as_raw_fd(), other.as_raw_fd()], &mut listen, &[]).unwrap();
+        assert_eq!(
+            fds,
+            vec![
+                (addr.to_string(), listener.as_raw_fd()),
+                (other.local_addr().unwrap().to_string(), other.as_raw_fd()),
+            ]
+        );
+        assert_eq!(
+            listen,
+            vec![configured, other.local_addr().unwrap().to_string().into()]
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn inherited_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        // Pingora takes ownership of the file descriptor
+        let fd = listener.into_raw_fd();
+        let fds = assign([fd], &mut Vec::new(), &[]).unwrap();
+
+        let mut service =
+            http_proxy_service(&Arc::new(ServerConf::default()), DefaultApp::new(Handler));
+        service.add_tcp(&addr);
+        let mut service = InheritedFdsService::new(service, fds);
+
+        let (shutdown_sender, shutdown) = tokio::sync::watch::channel(false);
+        let task = tokio::spawn(async move { service.start_service(None, shutdown).await });
+
+        // Binding the address again would fail, so a response means the inherited socket is used
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            stream.read_to_string(&mut response),
+        )
+        .await
+        .expect("no response received")
+        .unwrap();
+        assert!(response.starts_with("HTTP/1.1 204"), "{response}");
+
+        shutdown_sender.send(true).unwrap();
+        task.await.unwrap();
+    }
+}
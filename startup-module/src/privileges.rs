@@ -0,0 +1,171 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Switching to an unprivileged user after startup (Unix only)
+//!
+//! Pingora binds the listening sockets only once the services start up. So the sockets are bound
+//! upfront instead and passed on to Pingora via its file descriptor table, allowing privileges to
+//! be dropped completely before the server starts.
+
+use log::{error, info};
+use nix::unistd::{getegid, geteuid, getuid, setgid, setgroups, setuid, Gid, Group, Uid, User};
+use pandora_module_utils::pingora::{Error, ErrorType};
+
+/// Resolves user and group names into numerical IDs. If no group name is given, the user’s
+/// primary group is used.
+pub(crate) fn resolve_user(user: &str, group: Option<&str>) -> Result<(Uid, Gid), Box<Error>> {
+    let entry = User::from_name(user)
+        .map_err(|err| {
+            error!("failed looking up user {user}: {err}");
+            Error::new(ErrorType::InternalError)
+        })?
+        .ok_or_else(|| {
+            error!("user {user} does not exist");
+            Error::new(ErrorType::InternalError)
+        })?;
+
+    let gid = if let Some(group) = group {
+        Group::from_name(group)
+            .map_err(|err| {
+                error!("failed looking up group {group}: {err}");
+                Error::new(ErrorType::InternalError)
+            })?
+            .ok_or_else(|| {
+                error!("group {group} does not exist");
+                Error::new(ErrorType::InternalError)
+            })?
+            .gid
+    } else {
+        entry.gid
+    };
+
+    Ok((entry.uid, gid))
+}
+
+#[cfg(target_os = "linux")]
+mod capabilities {
+    use caps::errors::CapsError;
+    use caps::CapSet;
+
+    /// Drops all capabilities of the process.
+    pub(super) fn clear() -> Result<(), CapsError> {
+        caps::clear(None, CapSet::Ambient)?;
+        caps::clear(None, CapSet::Inheritable)?;
+        caps::clear(None, CapSet::Effective)?;
+        caps::clear(None, CapSet::Permitted)
+    }
+}
+
+/// Switches the process to the given user and group, returning the name of the failed operation
+/// along with the error message on failure.
+pub(crate) fn switch_user(uid: Uid, gid: Gid) -> Result<(), (&'static str, String)> {
+    setgroups(&[gid]).map_err(|err| ("setgroups", err.to_string()))?;
+    setgid(gid).map_err(|err| ("setgid", err.to_string()))?;
+    setuid(uid).map_err(|err| ("setuid", err.to_string()))?;
+
+    #[cfg(target_os = "linux")]
+    capabilities::clear().map_err(|err| ("clearing capabilities", err.to_string()))?;
+
+    // Make certain that there is no way back
+    if getuid() != uid || geteuid() != uid || getegid() != gid {
+        return Err(("verifying user", "user or group not switched".to_owned()));
+    }
+    if !uid.is_root() && setuid(Uid::from_raw(0)).is_ok() {
+        return Err((
+            "verifying user",
+            "switching back to root succeeded".to_owned(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Drops privileges by switching to the given user and group.
+pub(crate) fn drop_privileges(user: &str, group: Option<&str>) -> Result<(), Box<Error>> {
+    let (uid, gid) = resolve_user(user, group)?;
+
+    if uid == geteuid() {
+        info!("already running as user {user}, not switching");
+        return Ok(());
+    }
+
+    switch_user(uid, gid).map_err(|(operation, err)| {
+        error!("failed switching to user {user}, {operation} failed: {err}");
+        Error::new(ErrorType::InternalError)
+    })?;
+
+    info!(
+        "switched to user {user} ({uid}) and group {} ({gid})",
+        group.unwrap_or("primary group")
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::process::Command;
+
+    const CHILD_ENV: &str = "PANDORA_TEST_SWITCH_USER";
+
+    #[test]
+    fn resolve() {
+        assert_eq!(
+            resolve_user("root", None).unwrap(),
+            (Uid::from_raw(0), Gid::from_raw(0))
+        );
+        assert!(resolve_user("nonexistent-user", None).is_err());
+        assert!(resolve_user("root", Some("nonexistent-group")).is_err());
+    }
+
+    #[test]
+    fn switch() {
+        if !geteuid().is_root() {
+            // Cannot test switching users without root privileges
+            return;
+        }
+
+        // Switching users would affect all other tests, so this runs the switch_child test in a
+        // separate process.
+        let status = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "privileges::tests::switch_child"])
+            .env(CHILD_ENV, "1")
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn switch_child() {
+        if std::env::var_os(CHILD_ENV).is_none() {
+            return;
+        }
+
+        drop_privileges("nobody", None).unwrap();
+        assert!(!getuid().is_root());
+        assert!(!geteuid().is_root());
+        assert!(setuid(Uid::from_raw(0)).is_err());
+
+        #[cfg(target_os = "linux")]
+        for set in [
+            caps::CapSet::Ambient,
+            caps::CapSet::Inheritable,
+            caps::CapSet::Effective,
+            caps::CapSet::Permitted,
+        ] {
+            assert!(caps::read(None, set).unwrap().is_empty(), "{set:?}");
+        }
+    }
+}
@@ -0,0 +1,67 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compares the throughput of conditional requests resulting in `304 Not Modified` responses with
+//! and without the metadata cache. Run via `cargo bench -p static-files-module`.
+
+use pandora_module_utils::pingora::{create_test_session, RequestHeader};
+use pandora_module_utils::FromYaml;
+use startup_module::DefaultApp;
+use static_files_module::metadata::Metadata;
+use static_files_module::{StaticFilesConf, StaticFilesHandler};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const REQUESTS: u32 = 20000;
+
+fn root_path() -> PathBuf {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("testdata");
+    path.push("root");
+    path
+}
+
+async fn measure(metadata_cache_size: usize, etag: &str) -> Duration {
+    let conf = StaticFilesConf::from_yaml(format!(
+        "root: {}\nmetadata_cache_size: {metadata_cache_size}",
+        root_path().display()
+    ))
+    .unwrap();
+    let mut app = DefaultApp::<StaticFilesHandler>::from_conf(conf).unwrap();
+
+    let start = Instant::now();
+    for _ in 0..REQUESTS {
+        let mut header = RequestHeader::build("GET", b"/file.txt", None).unwrap();
+        header.insert_header("If-None-Match", etag).unwrap();
+        let session = create_test_session(header).await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(result.session().response_written().unwrap().status, 304);
+    }
+    start.elapsed()
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let etag = Metadata::from_path(&root_path().join("file.txt"), None)
+        .unwrap()
+        .etag;
+
+    for (name, size) in [("without cache", 0), ("with cache", 1000)] {
+        let elapsed = measure(size, &etag).await;
+        println!(
+            "{name}: {:.0} requests/s",
+            f64::from(REQUESTS) / elapsed.as_secs_f64()
+        );
+    }
+}
@@ -23,19 +23,23 @@ use crate::compression_algorithm::{find_matches, CompressionAlgorithm};
 /// Encapsulates the compression state for the current session.
 pub(crate) struct Compression<'a> {
     precompressed: &'a [CompressionAlgorithm],
+    preference: &'a [CompressionAlgorithm],
     precompressed_active: Option<CompressionAlgorithm>,
     dynamic: bool,
 }
 
 impl<'a> Compression<'a> {
     /// Creates a new compression state supporting the given compression algorithms for
-    /// pre-compressed files. *Note*: Dynamic compression is determined by the Pingora session.
+    /// pre-compressed files. `preference` decides between algorithms accepted equally by the
+    /// client. *Note*: Dynamic compression is determined by the Pingora session.
     pub(crate) fn new(
         session: &impl SessionWrapper,
         precompressed: &'a [CompressionAlgorithm],
+        preference: &'a [CompressionAlgorithm],
     ) -> Self {
         Self {
             precompressed,
+            preference,
             precompressed_active: None,
             // Remember this now, later on request header check might flip this flag
             dynamic: session
@@ -57,7 +61,11 @@ impl<'a> Compression<'a> {
 
         let filename = path.file_name()?;
         let requested = session.req_header().headers.get(header::ACCEPT_ENCODING)?;
-        let overlap = find_matches(requested.to_str().ok()?, self.precompressed);
+        let overlap = find_matches(
+            requested.to_str().ok()?,
+            self.precompressed,
+            self.preference,
+        );
 
         for algorithm in overlap {
             let mut candidate_name = filename.to_os_string();
@@ -84,6 +92,14 @@ impl<'a> Compression<'a> {
     ) -> Result<Box<ResponseHeader>, Box<Error>> {
         let mut header =
             if header.status != StatusCode::OK && header.status != StatusCode::PARTIAL_CONTENT {
+                if header.status == StatusCode::RANGE_NOT_SATISFIABLE {
+                    if let Some(algorithm) = self.precompressed_active {
+                        // No content but the size in Content-Range is that of the compressed
+                        // file, indicate which representation it refers to.
+                        header.insert_header(header::CONTENT_ENCODING, algorithm.name())?;
+                    }
+                }
+
                 // No actual content here, so no compression
                 header
             } else if let Some(algorithm) = self.precompressed_active {
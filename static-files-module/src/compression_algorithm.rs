@@ -112,6 +112,8 @@ impl Display for UnsupportedCompressionAlgorithm {
     }
 }
 
+impl std::error::Error for UnsupportedCompressionAlgorithm {}
+
 /// Parses an encoding specifier from `Accept-Encoding` HTTP header into an
 /// algorithm/quality pair.
 fn parse_encoding(encoding: &str) -> Option<(&str, u16)> {
@@ -131,10 +133,12 @@ fn parse_encoding(encoding: &str) -> Option<(&str, u16)> {
 }
 
 /// Compares the requested encodings from `Accept-Encoding` HTTP header with a list of supported
-/// algorithms and returns any matches, sorted by the respective quality value.
+/// algorithms and returns any matches, sorted by the respective quality value. Algorithms with
+/// the same quality value are sorted according to the `preference` list if it isn’t empty.
 pub(crate) fn find_matches(
     requested: &str,
     supported: &[CompressionAlgorithm],
+    preference: &[CompressionAlgorithm],
 ) -> Vec<CompressionAlgorithm> {
     let mut requested = requested
         .split(',')
@@ -142,22 +146,30 @@ pub(crate) fn find_matches(
         .collect::<Vec<_>>();
     requested.sort_by_key(|(_, quality)| -(*quality as i32));
 
-    let mut result = Vec::new();
-    for (algorithm, _) in requested {
+    let mut result: Vec<(CompressionAlgorithm, u16)> = Vec::new();
+    for (algorithm, quality) in requested {
         if algorithm == "*" {
             for algorithm in supported {
-                if !result.contains(algorithm) {
-                    result.push(*algorithm);
+                if !result.iter().any(|(a, _)| a == algorithm) {
+                    result.push((*algorithm, quality));
                 }
             }
             break;
         } else if let Some(algorithm) = CompressionAlgorithm::from_name(algorithm) {
-            if supported.contains(&algorithm) && !result.contains(&algorithm) {
-                result.push(algorithm);
+            if supported.contains(&algorithm) && !result.iter().any(|(a, _)| *a == algorithm) {
+                result.push((algorithm, quality));
             }
         }
     }
-    result
+
+    if !preference.is_empty() {
+        // Stable sort, algorithms missing from the preference list keep their relative order
+        result.sort_by_key(|(algorithm, quality)| {
+            let position = preference.iter().position(|a| a == algorithm);
+            (-(*quality as i32), position.unwrap_or(preference.len()))
+        });
+    }
+    result.into_iter().map(|(algorithm, _)| algorithm).collect()
 }
 
 #[cfg(test)]
@@ -169,7 +181,8 @@ mod tests {
         assert_eq!(
             find_matches(
                 "",
-                &[CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli]
+                &[CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli],
+                &[]
             ),
             Vec::new()
         );
@@ -177,7 +190,8 @@ mod tests {
         assert_eq!(
             find_matches(
                 "identity",
-                &[CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli]
+                &[CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli],
+                &[]
             ),
             Vec::new()
         );
@@ -185,7 +199,8 @@ mod tests {
         assert_eq!(
             find_matches(
                 "*",
-                &[CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli]
+                &[CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli],
+                &[]
             ),
             vec![CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli]
         );
@@ -193,7 +208,8 @@ mod tests {
         assert_eq!(
             find_matches(
                 "br, *",
-                &[CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli]
+                &[CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli],
+                &[]
             ),
             vec![CompressionAlgorithm::Brotli, CompressionAlgorithm::Gzip]
         );
@@ -201,7 +217,8 @@ mod tests {
         assert_eq!(
             find_matches(
                 "br;q=0.9, *",
-                &[CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli]
+                &[CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli],
+                &[]
             ),
             vec![CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli]
         );
@@ -215,7 +232,8 @@ mod tests {
                     CompressionAlgorithm::Compress,
                     CompressionAlgorithm::Brotli,
                     CompressionAlgorithm::Zstandard,
-                ]
+                ],
+                &[]
             ),
             vec![
                 CompressionAlgorithm::Brotli,
@@ -234,7 +252,8 @@ mod tests {
                     CompressionAlgorithm::Gzip,
                     CompressionAlgorithm::Brotli,
                     CompressionAlgorithm::Zstandard,
-                ]
+                ],
+                &[]
             ),
             vec![
                 CompressionAlgorithm::Brotli,
@@ -242,5 +261,35 @@ mod tests {
                 CompressionAlgorithm::Deflate,
             ]
         );
+
+        // Server preference decides between algorithms with the same quality
+        assert_eq!(
+            find_matches(
+                "gzip, br, zstd;q=0.5",
+                &[
+                    CompressionAlgorithm::Gzip,
+                    CompressionAlgorithm::Brotli,
+                    CompressionAlgorithm::Zstandard,
+                ],
+                &[
+                    CompressionAlgorithm::Zstandard,
+                    CompressionAlgorithm::Brotli
+                ]
+            ),
+            vec![
+                CompressionAlgorithm::Brotli,
+                CompressionAlgorithm::Gzip,
+                CompressionAlgorithm::Zstandard,
+            ]
+        );
+
+        assert_eq!(
+            find_matches(
+                "*",
+                &[CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli],
+                &[CompressionAlgorithm::Brotli]
+            ),
+            vec![CompressionAlgorithm::Brotli, CompressionAlgorithm::Gzip]
+        );
     }
 }
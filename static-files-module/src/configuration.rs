@@ -17,13 +17,19 @@
 use clap::Parser;
 use mime_guess::mime::FromStrError;
 use mime_guess::Mime;
+use pandora_module_utils::merger::PathMatcher;
+use pandora_module_utils::pingora::{Error, ErrorType};
 use pandora_module_utils::{DeserializeMap, OneOrMany};
 use serde::Deserialize;
-use std::ffi::OsString;
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 use crate::compression_algorithm::CompressionAlgorithm;
 
+/// Default value of the `write_chunk_size` setting
+pub const DEFAULT_WRITE_CHUNK_SIZE: usize = 64 * 1024;
+
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 #[serde(try_from = "String")]
 pub enum MimeMatch {
@@ -58,17 +64,107 @@ impl TryFrom<String> for MimeMatch {
     }
 }
 
+impl FromStr for MimeMatch {
+    type Err = FromStrError;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        value.try_into()
+    }
+}
+
+/// Handling of `Range` requests for pre-compressed files
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RangePrecompressed {
+    /// Byte ranges refer to the compressed file
+    #[default]
+    CompressedBytes,
+    /// Range requests are ignored, the full compressed file is sent
+    Disabled,
+}
+
+impl FromStr for RangePrecompressed {
+    type Err = Box<Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "compressed_bytes" => Ok(Self::CompressedBytes),
+            "disabled" => Ok(Self::Disabled),
+            _ => Err(Error::explain(
+                ErrorType::InternalError,
+                "invalid range_precompressed value",
+            )),
+        }
+    }
+}
+
+/// Handling of requests for files that don’t exist
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnMissing {
+    /// Respond with `404 Not Found` or the configured error page
+    #[default]
+    NotFound,
+    /// Leave the request to the next handler, e.g. the Upstream module
+    Next,
+}
+
+impl FromStr for OnMissing {
+    type Err = Box<Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "not_found" => Ok(Self::NotFound),
+            "next" => Ok(Self::Next),
+            _ => Err(Error::explain(
+                ErrorType::InternalError,
+                "invalid on_missing value",
+            )),
+        }
+    }
+}
+
+/// Pages to display instead of the default Not Found page
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Page404 {
+    /// A single page for all paths, e.g. `/404.html`
+    Single(String),
+    /// Pages by path or path prefix, e.g. `/docs/*: /docs/404.html`
+    ByPath(HashMap<PathMatcher, String>),
+}
+
+impl From<&str> for Page404 {
+    fn from(value: &str) -> Self {
+        Self::Single(value.to_owned())
+    }
+}
+
+impl From<String> for Page404 {
+    fn from(value: String) -> Self {
+        Self::Single(value)
+    }
+}
+
 /// Command line options of the static files module
 #[derive(Debug, Default, Parser)]
 pub struct StaticFilesOpt {
     /// The root directory.
-    #[clap(short, long, value_parser = clap::value_parser!(OsString))]
+    #[clap(short, long)]
     pub root: Option<PathBuf>,
 
     /// Redirect /file%2e.txt to /file.txt and /dir to /dir/.
     #[clap(long)]
     pub canonicalize_uri: Option<bool>,
 
+    /// Reject paths with malformed percent-encoding like %ZZ with 400 Bad Request. If false,
+    /// malformed sequences are treated literally.
+    #[clap(long)]
+    pub reject_malformed_encoding: Option<bool>,
+
+    /// Ignore ASCII letter case when looking up files, e.g. /Index.html will serve index.html.
+    #[clap(long)]
+    pub case_insensitive_paths: Option<bool>,
+
     /// Index file to look for when displaying a directory. This command line flag can be specified
     /// multiple times.
     #[clap(long)]
@@ -78,20 +174,78 @@ pub struct StaticFilesOpt {
     #[clap(long)]
     pub page_404: Option<String>,
 
+    /// Handling of requests for files that don't exist: not_found (respond with 404 Not Found)
+    /// or next (leave the request to the next handler).
+    #[clap(long)]
+    pub on_missing: Option<OnMissing>,
+
     /// File extension to check when looking for pre-compressed versions of a file. This command
     /// line flag can be specified multiple times. Supported file extensions are gz (gzip),
     /// zz (zlib deflate), z (compress), br (Brotli), zst (Zstandard).
-    #[clap(long, value_parser = clap::value_parser!(String))]
+    #[clap(long)]
     pub precompressed: Option<Vec<CompressionAlgorithm>>,
 
+    /// Compression algorithm to prefer if the client accepts multiple pre-compressed variants
+    /// equally, in the order of preference. This command line flag can be specified multiple
+    /// times.
+    #[clap(long)]
+    pub compression_preference: Option<Vec<CompressionAlgorithm>>,
+
+    /// Handling of range requests when a pre-compressed file is served: compressed_bytes (ranges
+    /// refer to the compressed file) or disabled (the full compressed file is sent).
+    #[clap(long)]
+    pub range_precompressed: Option<RangePrecompressed>,
+
+    /// If false, `416 Range Not Satisfiable` responses won't declare `Vary: Accept-Encoding` even
+    /// if compression is enabled.
+    #[clap(long)]
+    pub range_not_satisfiable_vary: Option<bool>,
+
+    /// If true, responses serving a pre-compressed file or an alternative format will have a
+    /// `Content-Location` header pointing to the file served.
+    #[clap(long)]
+    pub content_location: Option<bool>,
+
     /// The character set to declare for text files.
     #[clap(long)]
     pub declare_charset: Option<String>,
 
     /// MIME type that the `declare_charset` setting should apply to. This command line flag can be
     /// specified multiple times.
-    #[clap(long, value_parser = clap::value_parser!(String))]
+    #[clap(long)]
     pub declare_charset_types: Option<Vec<MimeMatch>>,
+
+    /// MIME type that should never have a charset declared. This command line flag can be
+    /// specified multiple times.
+    #[clap(long)]
+    pub strip_charset_types: Option<Vec<MimeMatch>>,
+
+    /// Send preload Link headers for HTML files listed in a manifest file next to them, e.g.
+    /// index.html.preload for index.html.
+    #[clap(long)]
+    pub preload_manifest: Option<bool>,
+
+    /// Send the preload links from the manifest in a 103 Early Hints response as well, before
+    /// the actual response.
+    #[clap(long)]
+    pub early_hints: Option<bool>,
+
+    /// MIME type of files to be minified when served, only text/html and text/css are
+    /// supported. This command line flag can be specified multiple times.
+    #[clap(long)]
+    pub minify: Option<Vec<MimeMatch>>,
+
+    /// Maximal number of minified files to keep in memory, 0 to disable the cache.
+    #[clap(long)]
+    pub minify_cache_size: Option<usize>,
+
+    /// Maximal number of files to cache metadata for, 0 to disable the cache.
+    #[clap(long)]
+    pub metadata_cache_size: Option<usize>,
+
+    /// Maximal size in bytes of the chunks a file is read and sent in.
+    #[clap(long)]
+    pub write_chunk_size: Option<usize>,
 }
 
 /// Configuration file settings of the static files module
@@ -103,22 +257,91 @@ pub struct StaticFilesConf {
     /// Redirect /file%2e.txt to /file.txt and /dir to /dir/.
     pub canonicalize_uri: bool,
 
+    /// Reject paths with malformed percent-encoding like %ZZ with 400 Bad Request. If false,
+    /// malformed sequences are treated literally.
+    pub reject_malformed_encoding: bool,
+
+    /// Ignore ASCII letter case when looking up files, e.g. /Index.html will serve index.html.
+    ///
+    /// This also applies to the paths in the `page_404` setting.
+    pub case_insensitive_paths: bool,
+
     /// List of index files to look for in a directory.
     pub index_file: OneOrMany<String>,
 
     /// URI path of the page to display instead of the default Not Found page, e.g. /404.html
-    pub page_404: Option<String>,
+    ///
+    /// Different pages can be configured for different parts of the site by mapping paths to
+    /// pages, e.g. `{/*: /404.html, /docs/*: /docs/404.html}`. The closest match applies.
+    pub page_404: Option<Page404>,
+
+    /// Handling of requests for files that don’t exist. With `next` the request is left to the
+    /// next handler, e.g. to forward it to an upstream server, and `page_404` has no effect.
+    pub on_missing: OnMissing,
 
     /// List of file extensions to check when looking for pre-compressed versions of a file.
     /// Supported file extensions are gz (gzip), zz (zlib deflate), z (compress), br (Brotli),
     /// zst (Zstandard).
     pub precompressed: OneOrMany<CompressionAlgorithm>,
 
+    /// Server preference among pre-compressed variants which the client accepts with the same
+    /// quality. Without it, the order of `Accept-Encoding` entries decides.
+    pub compression_preference: OneOrMany<CompressionAlgorithm>,
+
+    /// Determines how `Range` requests are handled when a pre-compressed file is served. With
+    /// `compressed_bytes` the byte ranges refer to the compressed file, `disabled` makes the
+    /// server ignore ranges and send the full compressed file.
+    pub range_precompressed: RangePrecompressed,
+
+    /// If `false`, `416 Range Not Satisfiable` responses won’t declare `Vary: Accept-Encoding`
+    /// even if compression is enabled.
+    pub range_not_satisfiable_vary: bool,
+
+    /// File extensions mapped to alternative formats to be served if the client accepts them,
+    /// e.g. `jpg: [avif, webp]` to serve `image.jpg.avif` or `image.jpg.webp` instead of
+    /// `image.jpg`. Alternatives accepted with the same quality are tried in the order listed.
+    pub content_negotiation: HashMap<String, OneOrMany<String>>,
+
+    /// If `true`, responses serving a pre-compressed file or an alternative format will have a
+    /// `Content-Location` header pointing to the file served, e.g. `/image.jpg.webp`.
+    pub content_location: bool,
+
     /// The character set to declare for text files.
     pub declare_charset: String,
 
     /// List of MIME types that the `declare_charset` setting should apply to.
     pub declare_charset_types: OneOrMany<MimeMatch>,
+
+    /// List of MIME types that should never have a charset declared, this takes precedence over
+    /// both `declare_charset_types` and `charset` parameters in `mime_types`.
+    pub strip_charset_types: OneOrMany<MimeMatch>,
+
+    /// MIME types to use for files with the given extensions instead of the guessed ones, e.g.
+    /// `md: text/markdown;charset=utf-8`. A `charset` parameter overrides `declare_charset` for
+    /// these files.
+    pub mime_types: HashMap<String, String>,
+
+    /// If `true`, preload `Link` headers will be sent for HTML files listing the resources from
+    /// a manifest file next to them, e.g. `index.html.preload` for `index.html`.
+    pub preload_manifest: bool,
+
+    /// If `true`, the links from a preload manifest will also be sent in a `103 Early Hints`
+    /// response preceding the actual response.
+    pub early_hints: bool,
+
+    /// List of MIME types of files to be minified when served. Only HTML and CSS files are
+    /// supported, minification is disabled if the list is empty.
+    pub minify: OneOrMany<MimeMatch>,
+
+    /// Maximal number of minified files to keep in memory, `0` disables the cache.
+    pub minify_cache_size: usize,
+
+    /// Maximal number of files to cache metadata for, `0` disables the cache.
+    pub metadata_cache_size: usize,
+
+    /// Maximal size in bytes of the chunks a file is read and sent in. Larger chunks mean fewer
+    /// system calls, smaller ones allow sending the first bytes of the response earlier.
+    pub write_chunk_size: usize,
 }
 
 impl StaticFilesConf {
@@ -133,18 +356,46 @@ impl StaticFilesConf {
             self.canonicalize_uri = canonicalize_uri;
         }
 
+        if let Some(reject_malformed_encoding) = opt.reject_malformed_encoding {
+            self.reject_malformed_encoding = reject_malformed_encoding;
+        }
+
+        if let Some(case_insensitive_paths) = opt.case_insensitive_paths {
+            self.case_insensitive_paths = case_insensitive_paths;
+        }
+
         if let Some(index_file) = opt.index_file {
             self.index_file = index_file.into();
         }
 
-        if opt.page_404.is_some() {
-            self.page_404 = opt.page_404;
+        if let Some(page_404) = opt.page_404 {
+            self.page_404 = Some(page_404.into());
+        }
+
+        if let Some(on_missing) = opt.on_missing {
+            self.on_missing = on_missing;
         }
 
         if let Some(precompressed) = opt.precompressed {
             self.precompressed = precompressed.into();
         }
 
+        if let Some(compression_preference) = opt.compression_preference {
+            self.compression_preference = compression_preference.into();
+        }
+
+        if let Some(range_precompressed) = opt.range_precompressed {
+            self.range_precompressed = range_precompressed;
+        }
+
+        if let Some(range_not_satisfiable_vary) = opt.range_not_satisfiable_vary {
+            self.range_not_satisfiable_vary = range_not_satisfiable_vary;
+        }
+
+        if let Some(content_location) = opt.content_location {
+            self.content_location = content_location;
+        }
+
         if let Some(declare_charset) = opt.declare_charset {
             self.declare_charset = declare_charset;
         }
@@ -152,6 +403,34 @@ impl StaticFilesConf {
         if let Some(declare_charset_types) = opt.declare_charset_types {
             self.declare_charset_types = declare_charset_types.into();
         }
+
+        if let Some(strip_charset_types) = opt.strip_charset_types {
+            self.strip_charset_types = strip_charset_types.into();
+        }
+
+        if let Some(preload_manifest) = opt.preload_manifest {
+            self.preload_manifest = preload_manifest;
+        }
+
+        if let Some(early_hints) = opt.early_hints {
+            self.early_hints = early_hints;
+        }
+
+        if let Some(minify) = opt.minify {
+            self.minify = minify.into();
+        }
+
+        if let Some(minify_cache_size) = opt.minify_cache_size {
+            self.minify_cache_size = minify_cache_size;
+        }
+
+        if let Some(metadata_cache_size) = opt.metadata_cache_size {
+            self.metadata_cache_size = metadata_cache_size;
+        }
+
+        if let Some(write_chunk_size) = opt.write_chunk_size {
+            self.write_chunk_size = write_chunk_size;
+        }
     }
 }
 
@@ -160,11 +439,27 @@ impl Default for StaticFilesConf {
         Self {
             root: None,
             canonicalize_uri: true,
+            reject_malformed_encoding: true,
+            case_insensitive_paths: false,
             index_file: Default::default(),
             page_404: None,
+            on_missing: Default::default(),
             precompressed: Default::default(),
+            compression_preference: Default::default(),
+            range_precompressed: Default::default(),
+            range_not_satisfiable_vary: true,
+            content_negotiation: HashMap::new(),
+            content_location: false,
             declare_charset: "utf-8".to_owned(),
             declare_charset_types: Default::default(),
+            strip_charset_types: Default::default(),
+            mime_types: HashMap::new(),
+            preload_manifest: false,
+            early_hints: false,
+            minify: Default::default(),
+            minify_cache_size: 100,
+            metadata_cache_size: 1000,
+            write_chunk_size: DEFAULT_WRITE_CHUNK_SIZE,
         }
     }
 }
@@ -202,4 +497,123 @@ mod test {
             MimeMatch::Exact("text/xml".parse().unwrap())
         );
     }
+
+    #[test]
+    fn merge_with_opt() {
+        let mut conf = StaticFilesConf {
+            root: Some(PathBuf::from("/conf")),
+            canonicalize_uri: false,
+            reject_malformed_encoding: false,
+            case_insensitive_paths: false,
+            index_file: vec!["index.html".to_owned()].into(),
+            page_404: Some("/404.html".into()),
+            on_missing: OnMissing::NotFound,
+            precompressed: vec![CompressionAlgorithm::Gzip].into(),
+            compression_preference: vec![CompressionAlgorithm::Gzip].into(),
+            range_precompressed: RangePrecompressed::CompressedBytes,
+            range_not_satisfiable_vary: true,
+            content_negotiation: HashMap::from([(
+                "jpg".to_owned(),
+                vec!["avif".to_owned()].into(),
+            )]),
+            content_location: false,
+            declare_charset: "latin1".to_owned(),
+            declare_charset_types: vec![MimeMatch::try_from("text/*").unwrap()].into(),
+            strip_charset_types: vec![MimeMatch::try_from("application/json").unwrap()].into(),
+            mime_types: HashMap::from([("md".to_owned(), "text/markdown".to_owned())]),
+            preload_manifest: false,
+            early_hints: false,
+            minify: vec![MimeMatch::try_from("text/css").unwrap()].into(),
+            minify_cache_size: 100,
+            metadata_cache_size: 1000,
+            write_chunk_size: 1024,
+        };
+        let orig_conf = conf.clone();
+
+        conf.merge_with_opt(StaticFilesOpt::parse_from(["test"]));
+        assert_eq!(conf, orig_conf);
+
+        conf.merge_with_opt(StaticFilesOpt::parse_from([
+            "test",
+            "--root",
+            "/opt",
+            "--canonicalize-uri",
+            "true",
+            "--reject-malformed-encoding",
+            "true",
+            "--case-insensitive-paths",
+            "true",
+            "--index-file",
+            "index.htm",
+            "--page-404",
+            "/missing.html",
+            "--on-missing",
+            "next",
+            "--precompressed",
+            "br",
+            "--compression-preference",
+            "zst",
+            "--compression-preference",
+            "br",
+            "--range-precompressed",
+            "disabled",
+            "--range-not-satisfiable-vary",
+            "false",
+            "--content-location",
+            "true",
+            "--declare-charset",
+            "utf-8",
+            "--declare-charset-types",
+            "text/html",
+            "--strip-charset-types",
+            "text/csv",
+            "--preload-manifest",
+            "true",
+            "--early-hints",
+            "true",
+            "--minify",
+            "text/html",
+            "--minify-cache-size",
+            "5",
+            "--metadata-cache-size",
+            "10",
+            "--write-chunk-size",
+            "1048576",
+        ]));
+        assert_eq!(
+            conf,
+            StaticFilesConf {
+                root: Some(PathBuf::from("/opt")),
+                canonicalize_uri: true,
+                reject_malformed_encoding: true,
+                case_insensitive_paths: true,
+                index_file: vec!["index.htm".to_owned()].into(),
+                page_404: Some("/missing.html".into()),
+                on_missing: OnMissing::Next,
+                precompressed: vec![CompressionAlgorithm::Brotli].into(),
+                compression_preference: vec![
+                    CompressionAlgorithm::Zstandard,
+                    CompressionAlgorithm::Brotli
+                ]
+                .into(),
+                range_precompressed: RangePrecompressed::Disabled,
+                range_not_satisfiable_vary: false,
+                content_negotiation: HashMap::from([(
+                    "jpg".to_owned(),
+                    vec!["avif".to_owned()].into(),
+                )]),
+                content_location: true,
+                declare_charset: "utf-8".to_owned(),
+                declare_charset_types: vec![MimeMatch::try_from("text/html").unwrap()].into(),
+                strip_charset_types: vec![MimeMatch::try_from("text/csv").unwrap()].into(),
+                mime_types: HashMap::from([("md".to_owned(), "text/markdown".to_owned())]),
+                preload_manifest: true,
+                early_hints: true,
+                minify: vec![MimeMatch::try_from("text/html").unwrap()].into(),
+                minify_cache_size: 5,
+                metadata_cache_size: 10,
+                write_chunk_size: 1048576,
+            }
+        );
+    }
 }
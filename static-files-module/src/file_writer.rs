@@ -14,7 +14,7 @@
 
 //! Writing files to Pingora session.
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use http::status::StatusCode;
 use log::error;
 use pandora_module_utils::pingora::{Error, ErrorType, SessionWrapper};
@@ -23,15 +23,15 @@ use std::fs::File;
 use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
 
-const BUFFER_SIZE: usize = 64 * 1024;
-
-/// Writes a chunk of a file as a Pingora session response. The data will be passed through the
-/// compression handler first in case dynamic compression is enabled.
+/// Writes a chunk of a file as a Pingora session response, reading and sending at most
+/// `chunk_size` bytes at a time. The data will be passed through the compression handler first
+/// in case dynamic compression is enabled.
 pub(crate) async fn file_response(
     session: &mut impl SessionWrapper,
     path: &Path,
     start: u64,
     end: u64,
+    chunk_size: usize,
 ) -> Result<(), Box<Error>> {
     let mut file = File::open(path).map_err(|err| {
         error!("failed opening file {path:?}: {err}");
@@ -51,13 +51,20 @@ pub(crate) async fn file_response(
 
     let mut remaining = (end - start + 1) as usize;
     while remaining > 0 {
-        let mut buf = BytesMut::zeroed(min(remaining, BUFFER_SIZE));
-        let len = file.read(buf.as_mut()).map_err(|err| {
-            error!("failed reading data from {path:?}: {err}");
-            Error::new(ErrorType::HTTPStatus(
-                StatusCode::INTERNAL_SERVER_ERROR.into(),
-            ))
-        })?;
+        let mut buf = BytesMut::zeroed(min(remaining, chunk_size));
+        let mut len = 0;
+        while len < buf.len() {
+            let read = file.read(&mut buf[len..]).map_err(|err| {
+                error!("failed reading data from {path:?}: {err}");
+                Error::new(ErrorType::HTTPStatus(
+                    StatusCode::INTERNAL_SERVER_ERROR.into(),
+                ))
+            })?;
+            if read == 0 {
+                break;
+            }
+            len += read;
+        }
 
         if len == 0 {
             error!("file ended with {remaining} bytes left to be written");
@@ -73,3 +80,20 @@ pub(crate) async fn file_response(
 
     Ok(())
 }
+
+/// Writes a chunk of in-memory data as a Pingora session response, like [`file_response`] does
+/// for files.
+pub(crate) async fn bytes_response(
+    session: &mut impl SessionWrapper,
+    data: Bytes,
+    start: u64,
+    end: u64,
+) -> Result<(), Box<Error>> {
+    let chunk = data.slice(start as usize..=end as usize);
+    if !chunk.is_empty() {
+        session.write_response_body(Some(chunk), false).await?;
+    }
+    session.write_response_body(None, true).await?;
+
+    Ok(())
+}
@@ -15,20 +15,31 @@
 //! Handler for the `request_filter` phase.
 
 use async_trait::async_trait;
-use http::{method::Method, status::StatusCode};
-use log::{debug, info, warn};
-use pandora_module_utils::pingora::{Error, ErrorType, SessionWrapper};
-use pandora_module_utils::standard_response::{error_response, redirect_response};
-use pandora_module_utils::{RequestFilter, RequestFilterResult};
+use http::{header, method::Method, status::StatusCode, Version};
+use log::{debug, error, info, warn};
+use mime_guess::{mime, Mime};
+use pandora_module_utils::merger::{Merger, PathMatcher};
+use pandora_module_utils::pingora::{Error, ErrorType, ResponseHeader, SessionWrapper};
+use pandora_module_utils::router::Router;
+use pandora_module_utils::standard_response::{
+    allow_header, error_response, method_not_allowed_response, redirect_response,
+};
+use pandora_module_utils::{PathCaseUsage, RequestFilter, RequestFilterResult};
+use std::collections::HashMap;
 use std::io::ErrorKind;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 
 use crate::compression::Compression;
-use crate::configuration::StaticFilesConf;
-use crate::file_writer::file_response;
-use crate::metadata::Metadata;
+use crate::configuration::{OnMissing, Page404, RangePrecompressed, StaticFilesConf};
+use crate::file_writer::{bytes_response, file_response};
+use crate::metadata::MetadataCache;
 use crate::mime_matcher::MimeMatcher;
-use crate::path::{path_to_uri, resolve_uri};
+use crate::minify::MinifyCache;
+use crate::negotiation::{Alternative, Negotiation};
+use crate::path::{has_valid_encoding, path_to_uri, resolve_uri, DirectoryCache};
+use crate::preload::preload_links;
 use crate::range::{extract_range, Range};
 use crate::CompressionAlgorithm;
 
@@ -41,16 +52,96 @@ const DEFAULT_TEXT_TYPES: &[&str] = &[
     "application/json5",
 ];
 
+/// MIME type configured for a file extension
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MimeOverride {
+    mime: Mime,
+    charset: Option<String>,
+}
+
+impl MimeOverride {
+    fn parse(extension: &str, value: &str) -> Result<Self, Box<Error>> {
+        let parsed = Mime::from_str(value).map_err(|err| {
+            error!("invalid MIME type {value} configured for extension {extension}: {err}");
+            Error::new(ErrorType::InternalError)
+        })?;
+
+        let charset = parsed
+            .get_param(mime::CHARSET)
+            .map(|charset| charset.as_str().to_owned());
+        if let Some(charset) = &charset {
+            if !is_token(charset) {
+                error!("invalid charset {charset} configured for extension {extension}");
+                return Err(Error::new(ErrorType::InternalError));
+            }
+        }
+
+        // The charset is declared separately, MIME type matching expects no parameters
+        let mime = if charset.is_some() {
+            Mime::from_str(parsed.essence_str()).unwrap()
+        } else {
+            parsed
+        };
+        Ok(Self { mime, charset })
+    }
+}
+
+/// Checks whether a value is a token as defined in RFC 9110 section 5.6.2
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c))
+}
+
 /// Static Files module handler
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct StaticFilesHandler {
     root: Option<PathBuf>,
     canonicalize_uri: bool,
+    reject_malformed_encoding: bool,
+    case_insensitive_paths: bool,
+    directory_cache: Arc<DirectoryCache>,
     index_file: Vec<String>,
-    page_404: Option<String>,
+    page_404: Router<Option<String>>,
+    on_missing: OnMissing,
     precompressed: Vec<CompressionAlgorithm>,
+    compression_preference: Vec<CompressionAlgorithm>,
+    range_precompressed: RangePrecompressed,
+    range_not_satisfiable_vary: bool,
+    content_negotiation: HashMap<String, Vec<Alternative>>,
+    content_location: bool,
     declare_charset: String,
     declare_charset_matcher: MimeMatcher,
+    strip_charset_matcher: MimeMatcher,
+    mime_types: HashMap<String, MimeOverride>,
+    preload_manifest: bool,
+    early_hints: bool,
+    minify_matcher: Option<MimeMatcher>,
+    minify_cache: Arc<MinifyCache>,
+    metadata_cache: Arc<MetadataCache>,
+    write_chunk_size: usize,
+}
+
+impl StaticFilesHandler {
+    /// Looks up the MIME type configured for the file’s extension if any
+    fn mime_override(&self, path: &Path) -> Option<&MimeOverride> {
+        if self.mime_types.is_empty() {
+            return None;
+        }
+
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        self.mime_types.get(&extension)
+    }
+
+    /// Resolves the path from a URI against the root directory
+    fn resolve_uri(&self, uri_path: &str, root: &Path) -> Result<PathBuf, std::io::Error> {
+        if self.case_insensitive_paths {
+            self.directory_cache.resolve_uri(uri_path, root)
+        } else {
+            resolve_uri(uri_path, root)
+        }
+    }
 }
 
 #[async_trait]
@@ -61,6 +152,22 @@ impl RequestFilter for StaticFilesHandler {
 
     fn new_ctx() -> Self::CTX {}
 
+    fn handles_head() -> bool {
+        // Files aren’t read for HEAD requests
+        true
+    }
+
+    fn allowed_methods(&self) -> Vec<Method> {
+        vec![Method::GET, Method::HEAD, Method::OPTIONS]
+    }
+
+    fn path_case_usage(&self) -> PathCaseUsage {
+        PathCaseUsage {
+            case_insensitive: self.root.is_some() && self.case_insensitive_paths,
+            ..Default::default()
+        }
+    }
+
     async fn request_filter(
         &self,
         session: &mut impl SessionWrapper,
@@ -76,14 +183,32 @@ impl RequestFilter for StaticFilesHandler {
         let uri = session.uri();
         debug!("received URI path {}", uri.path());
 
-        let (mut path, not_found) = match resolve_uri(uri.path(), root) {
+        if self.reject_malformed_encoding && !has_valid_encoding(uri.path()) {
+            warn!(
+                "rejecting path with malformed percent-encoding {}",
+                uri.path()
+            );
+            error_response(session, StatusCode::BAD_REQUEST).await?;
+            return Ok(RequestFilterResult::ResponseSent);
+        }
+
+        let (mut path, not_found) = match self.resolve_uri(uri.path(), root) {
             Ok(path) => (path, false),
             Err(err) if err.kind() == ErrorKind::NotFound => {
                 debug!("canonicalizing resulted in NotFound error");
 
-                let path = self.page_404.as_ref().and_then(|page_404| {
+                if self.on_missing == OnMissing::Next {
+                    debug!("leaving request for missing file to the next handler");
+                    return Ok(RequestFilterResult::Unhandled);
+                }
+
+                let page_404 = self
+                    .page_404
+                    .lookup("", uri.path())
+                    .and_then(|page_404| (*page_404).clone());
+                let path = page_404.as_ref().and_then(|page_404| {
                     debug!("error page is {page_404}");
-                    match resolve_uri(page_404, root) {
+                    match self.resolve_uri(page_404, root) {
                         Ok(path) => Some(path),
                         Err(err) => {
                             warn!("Failed resolving error page {page_404}: {err}");
@@ -154,8 +279,20 @@ impl RequestFilter for StaticFilesHandler {
             for filename in &self.index_file {
                 let candidate = path.join(filename);
                 if candidate.is_file() {
-                    debug!("using directory index file {filename}");
-                    path = candidate;
+                    // The index file might be a symlink, apply the same restrictions as
+                    // resolve_uri() does for the request path.
+                    match candidate.canonicalize() {
+                        Ok(resolved) if resolved.starts_with(root) => {
+                            debug!("using directory index file {filename}");
+                            path = candidate;
+                        }
+                        Ok(resolved) => {
+                            warn!("index file {candidate:?} resolves to {resolved:?} outside root directory, ignoring");
+                        }
+                        Err(err) => {
+                            warn!("failed canonicalizing index file {candidate:?}: {err}");
+                        }
+                    }
                 }
             }
         }
@@ -166,14 +303,31 @@ impl RequestFilter for StaticFilesHandler {
             Method::GET | Method::HEAD => {
                 // Allowed
             }
+            Method::OPTIONS => {
+                let mut header = ResponseHeader::build(StatusCode::NO_CONTENT, Some(1))?;
+                header.append_header(header::ALLOW, allow_header(session))?;
+                session
+                    .write_response_header(Box::new(header), true)
+                    .await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
             _ => {
                 warn!("Denying method {}", session.req_header().method);
-                error_response(session, StatusCode::METHOD_NOT_ALLOWED).await?;
+                method_not_allowed_response(session).await?;
                 return Ok(RequestFilterResult::ResponseSent);
             }
         }
 
-        let mut compression = Compression::new(session, &self.precompressed);
+        let negotiation = Negotiation::new(&self.content_negotiation, &path);
+        let alternative_path = negotiation.rewrite_path(session, &path);
+        let negotiated = alternative_path.is_some();
+        if let Some(alternative_path) = alternative_path {
+            debug!("serving alternative format {alternative_path:?}");
+            path = alternative_path;
+        }
+
+        let mut compression =
+            Compression::new(session, &self.precompressed, &self.compression_preference);
 
         let (path, orig_path) =
             if let Some(precompressed_path) = compression.rewrite_path(session, &path) {
@@ -182,7 +336,7 @@ impl RequestFilter for StaticFilesHandler {
                 (path, None)
             };
 
-        let meta = match Metadata::from_path(&path, orig_path.as_ref()) {
+        let mut meta = match self.metadata_cache.get(&path, orig_path.as_deref()) {
             Ok(meta) => meta,
             Err(err) if err.kind() == ErrorKind::InvalidInput => {
                 warn!("Path {path:?} is not a regular file, denying access");
@@ -196,61 +350,174 @@ impl RequestFilter for StaticFilesHandler {
             }
         };
 
-        if meta.has_failed_precondition(session) {
+        // Identify the variant served, relative to the original URI in case a prefix was removed
+        let content_location =
+            if self.content_location && !not_found && (negotiated || orig_path.is_some()) {
+                path_to_uri(&path, root).map(|location| {
+                    let prefix = session
+                        .original_uri()
+                        .path()
+                        .strip_suffix(session.uri().path())
+                        .unwrap_or_default();
+                    format!("{prefix}{location}")
+                })
+            } else {
+                None
+            };
+
+        let mime_override = self.mime_override(orig_path.as_deref().unwrap_or(&path));
+        if let Some(mime_override) = mime_override {
+            meta.mime = mime_override.mime.clone();
+        }
+
+        // Pre-compressed files cannot be minified
+        let minified = match &self.minify_matcher {
+            Some(matcher) if orig_path.is_none() && matcher.matches(&meta.mime) => {
+                self.minify_cache.get(&path, &meta.mime)
+            }
+            _ => None,
+        };
+        if let Some(minified) = &minified {
+            debug!("serving minified version of {path:?}");
+            meta.set_transformed_size(minified.len() as u64);
+        }
+
+        // Preconditions are evaluated in the order required by RFC 9110 section 13.2.2:
+        // If-Match/If-Unmodified-Since, then If-None-Match/If-Modified-Since, and only then
+        // Range along with If-Range.
+        // Error pages don’t carry validators, conditional requests don’t apply to them
+        // `Cache-Control: no-cache` and `Pragma: no-cache` request headers are directed at caches
+        // and don't change precondition evaluation, a revalidating cache sends validators.
+        if !not_found && meta.has_failed_precondition(session) {
             debug!("If-Match/If-Unmodified-Since precondition failed");
             let header = meta.to_custom_header(StatusCode::PRECONDITION_FAILED)?;
             let header = compression.transform_header(session, header)?;
+            let header = negotiation.transform_header(header)?;
             session.write_response_header(header, true).await?;
             return Ok(RequestFilterResult::ResponseSent);
         }
 
-        if meta.is_not_modified(session) {
+        if !not_found && meta.is_not_modified(session) {
             debug!("If-None-Match/If-Modified-Since check resulted in Not Modified");
             let header = meta.to_custom_header(StatusCode::NOT_MODIFIED)?;
             let header = compression.transform_header(session, header)?;
+            let mut header = negotiation.transform_header(header)?;
+            if let Some(content_location) = content_location {
+                header.insert_header(header::CONTENT_LOCATION, content_location)?;
+            }
             session.write_response_header(header, true).await?;
             return Ok(RequestFilterResult::ResponseSent);
         }
 
-        let charset = if self.declare_charset_matcher.matches(&meta.mime) {
+        // Only read the preload manifest for full responses, conditional requests are answered
+        // without it
+        let preload =
+            if self.preload_manifest && !not_found && meta.mime.essence_str() == "text/html" {
+                // Manifest entries are relative to the root, account for any prefix removed from
+                // the URI
+                let prefix = session
+                    .original_uri()
+                    .path()
+                    .strip_suffix(session.uri().path())
+                    .unwrap_or_default()
+                    .to_owned();
+                preload_links(orig_path.as_ref().unwrap_or(&path), root, &prefix)
+            } else {
+                None
+            };
+
+        // Let the browser start loading the resources before the response is produced. HTTP/1.0
+        // clients don’t expect informational responses.
+        if let Some(preload) = preload.as_ref().filter(|_| self.early_hints) {
+            let request = session.req_header();
+            if request.method == Method::GET && request.version >= Version::HTTP_11 {
+                debug!("sending 103 Early Hints response");
+                let mut hints = ResponseHeader::build(103, Some(1))?;
+                hints.insert_header(header::LINK, preload.clone())?;
+                session
+                    .write_response_header(Box::new(hints), false)
+                    .await?;
+            }
+        }
+
+        let charset = if self.strip_charset_matcher.matches(&meta.mime) {
+            None
+        } else if let Some(charset) = mime_override.and_then(|o| o.charset.as_deref()) {
+            Some(charset)
+        } else if self.declare_charset_matcher.matches(&meta.mime) {
             Some(self.declare_charset.as_str())
         } else {
             None
         };
 
-        let (mut header, start, end) = match extract_range(session, &meta) {
+        let ranges_disabled =
+            orig_path.is_some() && self.range_precompressed == RangePrecompressed::Disabled;
+        let range = if ranges_disabled || not_found {
+            None
+        } else {
+            extract_range(session, &meta)
+        };
+
+        let (mut header, start, end) = match range {
             Some(Range::Valid(start, end)) => {
                 debug!("bytes range requested: {start}-{end}");
                 let header = meta.to_partial_content_header(charset, start, end)?;
                 let header = compression.transform_header(session, header)?;
+                let header = negotiation.transform_header(header)?;
                 (header, start, end)
             }
             Some(Range::OutOfBounds) => {
                 debug!("requested bytes range is out of bounds");
                 let header = meta.to_not_satisfiable_header(charset)?;
-                let header = compression.transform_header(session, header)?;
+                let mut header = compression.transform_header(session, header)?;
+                if !self.range_not_satisfiable_vary {
+                    header.remove_header(&header::VARY);
+                }
+                let header = negotiation.transform_header(header)?;
                 session.write_response_header(header, true).await?;
                 return Ok(RequestFilterResult::ResponseSent);
             }
             None => {
                 // Range is either missing or cannot be parsed, produce the entire file.
-                let header = meta.to_response_header(charset)?;
+                let mut header = meta.to_response_header(charset)?;
+                if ranges_disabled {
+                    header.insert_header(header::ACCEPT_RANGES, "none")?;
+                }
                 let header = compression.transform_header(session, header)?;
-                (header, 0, meta.size - 1)
+                let header = negotiation.transform_header(header)?;
+                (header, 0, meta.size.saturating_sub(1))
             }
         };
 
         if not_found {
+            // Error pages shouldn’t be cached or revalidated
             header.set_status(StatusCode::NOT_FOUND)?;
+            header.remove_header(&header::ETAG);
+            header.remove_header(&header::LAST_MODIFIED);
+            header.remove_header(&header::ACCEPT_RANGES);
+            header.insert_header(header::CACHE_CONTROL, "no-store")?;
         }
 
-        let send_body = session.req_header().method != Method::HEAD;
+        if let Some(preload) = preload {
+            header.insert_header(header::LINK, preload)?;
+        }
+
+        if let Some(content_location) = content_location {
+            header.insert_header(header::CONTENT_LOCATION, content_location)?;
+        }
+
+        // Empty files and files minified down to nothing have no body to send
+        let send_body = session.req_header().method != Method::HEAD && meta.size > 0;
         session.write_response_header(header, !send_body).await?;
 
         if send_body {
             // sendfile would be nice but not currently possible within pingora-proxy (see
             // https://github.com/cloudflare/pingora/issues/160)
-            file_response(session, &path, start, end).await?;
+            if let Some(minified) = minified {
+                bytes_response(session, minified, start, end).await?;
+            } else {
+                file_response(session, &path, start, end, self.write_chunk_size).await?;
+            }
         }
         Ok(RequestFilterResult::ResponseSent)
     }
@@ -283,14 +550,97 @@ impl TryFrom<StaticFilesConf> for StaticFilesHandler {
             }
         }
 
+        let mut strip_charset_matcher = MimeMatcher::new();
+        for mime in conf.strip_charset_types {
+            strip_charset_matcher.add(mime);
+        }
+
+        let mut page_404 = Merger::new();
+        match conf.page_404 {
+            Some(Page404::Single(page)) => page_404.push(PathMatcher::from("/*"), page),
+            Some(Page404::ByPath(pages)) => {
+                for (path, page) in pages {
+                    page_404.push(path, page);
+                }
+            }
+            None => {}
+        }
+        page_404.set_case_insensitive(conf.case_insensitive_paths);
+        // Matches are sorted by increasing precedence, the closest match wins
+        let page_404 = page_404.merge(|pages| pages.last().cloned());
+
+        let mime_types = conf
+            .mime_types
+            .iter()
+            .map(|(extension, value)| {
+                let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+                MimeOverride::parse(&extension, value).map(|mime| (extension, mime))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let content_negotiation = conf
+            .content_negotiation
+            .into_iter()
+            .map(|(extension, alternatives)| {
+                let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+                let alternatives = alternatives
+                    .into_iter()
+                    .map(|alternative| {
+                        let alternative = alternative.trim_start_matches('.').to_ascii_lowercase();
+                        let Some(mime) = mime_guess::from_ext(&alternative).first() else {
+                            error!("unknown MIME type for alternative format {alternative} of extension {extension}");
+                            return Err(Error::new(ErrorType::InternalError));
+                        };
+                        Ok(Alternative {
+                            extension: alternative,
+                            mime: mime.essence_str().to_owned(),
+                        })
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok((extension, alternatives))
+            })
+            .collect::<Result<_, Box<Error>>>()?;
+
+        if conf.write_chunk_size == 0 {
+            error!("write_chunk_size has to be larger than 0");
+            return Err(Error::new(ErrorType::InternalError));
+        }
+
+        let minify_matcher = if conf.minify.is_empty() {
+            None
+        } else {
+            let mut matcher = MimeMatcher::new();
+            for mime in conf.minify {
+                matcher.add(mime);
+            }
+            Some(matcher)
+        };
+
         Ok(Self {
             root,
             canonicalize_uri: conf.canonicalize_uri,
+            reject_malformed_encoding: conf.reject_malformed_encoding,
+            case_insensitive_paths: conf.case_insensitive_paths,
+            directory_cache: Arc::new(DirectoryCache::new(conf.metadata_cache_size)),
             index_file: conf.index_file.into(),
-            page_404: conf.page_404,
+            page_404,
+            on_missing: conf.on_missing,
             precompressed: conf.precompressed.into(),
+            compression_preference: conf.compression_preference.into(),
+            range_precompressed: conf.range_precompressed,
+            range_not_satisfiable_vary: conf.range_not_satisfiable_vary,
+            content_negotiation,
+            content_location: conf.content_location,
             declare_charset: conf.declare_charset,
             declare_charset_matcher,
+            strip_charset_matcher,
+            mime_types,
+            preload_manifest: conf.preload_manifest,
+            early_hints: conf.early_hints,
+            minify_matcher,
+            minify_cache: Arc::new(MinifyCache::new(conf.minify_cache_size)),
+            metadata_cache: Arc::new(MetadataCache::new(conf.metadata_cache_size)),
+            write_chunk_size: conf.write_chunk_size,
         })
     }
 }
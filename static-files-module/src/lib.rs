@@ -21,11 +21,17 @@ mod file_writer;
 mod handler;
 pub mod metadata;
 mod mime_matcher;
+mod minify;
+mod negotiation;
 pub mod path;
+mod preload;
 pub mod range;
 #[cfg(test)]
 mod tests;
 
 pub use compression_algorithm::{CompressionAlgorithm, UnsupportedCompressionAlgorithm};
-pub use configuration::{StaticFilesConf, StaticFilesOpt};
+pub use configuration::{
+    OnMissing, Page404, RangePrecompressed, StaticFilesConf, StaticFilesOpt,
+    DEFAULT_WRITE_CHUNK_SIZE,
+};
 pub use handler::StaticFilesHandler;
@@ -18,12 +18,15 @@ use http::{header, status::StatusCode};
 use httpdate::fmt_http_date;
 use mime_guess::Mime;
 use pandora_module_utils::pingora::{ResponseHeader, SessionWrapper};
+use std::collections::HashMap;
+use std::fs::Metadata as FsMetadata;
 use std::io::{Error, ErrorKind};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::time::SystemTime;
 
 /// Helper wrapping file metadata information
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Metadata {
     /// Guessed MIME types (if any) for the file
     pub mime: Mime,
@@ -47,7 +50,14 @@ impl Metadata {
         orig_path: Option<&P>,
     ) -> Result<Self, Error> {
         let meta = path.as_ref().metadata()?;
+        Self::from_fs_metadata(&meta, path, orig_path)
+    }
 
+    fn from_fs_metadata<P: AsRef<Path> + ?Sized>(
+        meta: &FsMetadata,
+        path: &P,
+        orig_path: Option<&P>,
+    ) -> Result<Self, Error> {
         if !meta.is_file() {
             return Err(ErrorKind::InvalidInput.into());
         }
@@ -72,6 +82,30 @@ impl Metadata {
         })
     }
 
+    /// Adjusts size and ETag for a transformed version of the file, e.g. a minified one. The ETag
+    /// still depends on the original file, so that it changes whenever the file is modified.
+    pub fn set_transformed_size(&mut self, size: u64) {
+        let etag = self.etag.strip_suffix('"').unwrap_or(&self.etag);
+        self.etag = format!("{etag}-{size:x}\"");
+        self.size = size;
+    }
+
+    /// Compares an entity tag from a request header to the file’s ETag using the strong
+    /// comparison function: weak entity tags never match.
+    pub fn etag_matches_strong(&self, value: &str) -> bool {
+        !value.starts_with("W/") && value == self.etag
+    }
+
+    /// Compares an entity tag from a request header to the file’s ETag using the weak comparison
+    /// function: only the opaque tags are compared, regardless of either being weak.
+    pub fn etag_matches_weak(&self, value: &str) -> bool {
+        fn opaque_tag(etag: &str) -> &str {
+            etag.strip_prefix("W/").unwrap_or(etag)
+        }
+
+        opaque_tag(value) == opaque_tag(&self.etag)
+    }
+
     /// Checks `If-Match` and `If-Unmodified-Since` headers of the request to determine whether
     /// a `412 Precondition Failed` response should be produced.
     pub fn has_failed_precondition(&self, session: &impl SessionWrapper) -> bool {
@@ -84,7 +118,7 @@ impl Metadata {
                 && value
                     .split(',')
                     .map(str::trim)
-                    .all(|value| value != self.etag)
+                    .all(|value| !self.etag_matches_strong(value))
         } else if let Some(value) = headers
             .get(header::IF_UNMODIFIED_SINCE)
             .and_then(|value| value.to_str().ok())
@@ -109,7 +143,7 @@ impl Metadata {
                 || value
                     .split(',')
                     .map(str::trim)
-                    .any(|value| value == self.etag)
+                    .any(|value| self.etag_matches_weak(value))
         } else if let Some(value) = headers
             .get(header::IF_MODIFIED_SINCE)
             .and_then(|value| value.to_str().ok())
@@ -206,3 +240,120 @@ impl Metadata {
         Ok(Box::new(header))
     }
 }
+
+/// Cached metadata along with the file properties it was derived from
+#[derive(Debug)]
+struct CacheEntry {
+    modified: Option<SystemTime>,
+    size: u64,
+    meta: Metadata,
+}
+
+/// Bounded cache of file metadata keyed by path, an entry is reused as long as the file’s
+/// modification time and size stay unchanged. The original path is part of the key as well, the
+/// MIME type of a pre-compressed file depends on whether it is served as a variant of another file.
+///
+/// This still requires a `stat` call per request to validate the entry but saves producing MIME
+/// type, `Last-Modified` and `ETag` values.
+#[derive(Debug, Default)]
+pub(crate) struct MetadataCache {
+    capacity: usize,
+    entries: Mutex<HashMap<(PathBuf, Option<PathBuf>), CacheEntry>>,
+}
+
+impl PartialEq for MetadataCache {
+    fn eq(&self, other: &Self) -> bool {
+        // Cache contents don’t affect handler behavior
+        self.capacity == other.capacity
+    }
+}
+
+impl Eq for MetadataCache {}
+
+impl MetadataCache {
+    /// Creates a cache holding up to `capacity` entries, `0` disables caching.
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Default::default(),
+        }
+    }
+
+    /// Retrieves the metadata for a file, see [`Metadata::from_path`].
+    pub(crate) fn get(&self, path: &Path, orig_path: Option<&Path>) -> Result<Metadata, Error> {
+        let fs_meta = path.metadata()?;
+        if self.capacity == 0 {
+            return Metadata::from_fs_metadata(&fs_meta, path, orig_path);
+        }
+
+        let modified = fs_meta.modified().ok();
+        let size = fs_meta.len();
+
+        let key = (path.to_owned(), orig_path.map(Path::to_owned));
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get(&key) {
+            if entry.modified == modified && entry.size == size {
+                return Ok(entry.meta.clone());
+            }
+        }
+
+        let meta = Metadata::from_fs_metadata(&fs_meta, path, orig_path)?;
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            // Evict an arbitrary entry to stay within bounds
+            if let Some(key) = entries.keys().next().cloned() {
+                entries.remove(&key);
+            }
+        }
+        entries.insert(
+            key,
+            CacheEntry {
+                modified,
+                size,
+                meta: meta.clone(),
+            },
+        );
+        Ok(meta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    #[test]
+    fn cache() {
+        let dir = std::env::temp_dir().join(format!("metadata-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let first = dir.join("first.txt");
+        let second = dir.join("second.html");
+        std::fs::write(&first, "Hi!").unwrap();
+        std::fs::write(&second, "<p>Hi!</p>").unwrap();
+
+        let cache = MetadataCache::new(1);
+        let meta = cache.get(&first, None).unwrap();
+        assert_eq!(meta.size, 3);
+        assert_eq!(meta.mime, "text/plain");
+        assert_eq!(cache.get(&first, None).unwrap().etag, meta.etag);
+
+        // Changing the file invalidates the cache entry
+        std::fs::write(&first, "Hello!").unwrap();
+        assert_eq!(cache.get(&first, None).unwrap().size, 6);
+
+        // Cache size is bounded
+        assert_eq!(cache.get(&second, None).unwrap().mime, "text/html");
+        assert_eq!(cache.entries.lock().unwrap().len(), 1);
+
+        assert_eq!(
+            cache.get(&dir, None).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+
+        let cache = MetadataCache::new(0);
+        assert_eq!(cache.get(&second, None).unwrap().size, 10);
+        assert!(cache.entries.lock().unwrap().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
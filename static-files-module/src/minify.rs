@@ -0,0 +1,377 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! On-the-fly minification of HTML and CSS files.
+//!
+//! Only transformations that cannot change the meaning of the document are applied: comments
+//! are removed and whitespace runs are collapsed where the respective format ignores them.
+
+use bytes::Bytes;
+use log::{debug, warn};
+use mime_guess::{mime, Mime};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Average line length above which a file is considered to be minified already.
+const MINIFIED_LINE_LENGTH: usize = 200;
+
+/// HTML elements with contents that have to be kept unchanged.
+const RAW_ELEMENTS: &[&str] = &["pre", "textarea", "script", "style"];
+
+#[derive(Debug)]
+struct CacheEntry {
+    modified: Option<SystemTime>,
+    size: u64,
+    data: Option<Bytes>,
+}
+
+/// Bounded cache of minified file contents, an entry is reused as long as the file’s
+/// modification time and size stay unchanged.
+#[derive(Debug, Default)]
+pub(crate) struct MinifyCache {
+    capacity: usize,
+    entries: Mutex<HashMap<PathBuf, CacheEntry>>,
+}
+
+impl PartialEq for MinifyCache {
+    fn eq(&self, other: &Self) -> bool {
+        // Cache contents don’t affect handler behavior
+        self.capacity == other.capacity
+    }
+}
+
+impl Eq for MinifyCache {}
+
+impl MinifyCache {
+    /// Creates a cache holding up to `capacity` entries, `0` disables caching.
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Default::default(),
+        }
+    }
+
+    /// Returns the minified contents of the file or `None` if the file cannot be minified. This
+    /// is also the case for files that appear to be minified already.
+    pub(crate) fn get(&self, path: &Path, mime: &Mime) -> Option<Bytes> {
+        let minifier: fn(&str) -> String = match (mime.type_(), mime.subtype()) {
+            (mime::TEXT, mime::HTML) => minify_html,
+            (mime::TEXT, mime::CSS) => minify_css,
+            _ => return None,
+        };
+
+        let meta = path.metadata().ok()?;
+        let modified = meta.modified().ok();
+        let size = meta.len();
+
+        if let Some(entry) = self.entries.lock().unwrap().get(path) {
+            if entry.modified == modified && entry.size == size {
+                return entry.data.clone();
+            }
+        }
+
+        // The lock isn’t held while reading and minifying the file, so concurrent requests for
+        // the same file might both do it. That’s preferable to blocking requests for other files.
+        let data = match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                if is_minified(path, &contents) {
+                    debug!("file {path:?} appears to be minified already");
+                    None
+                } else {
+                    let minified = minifier(&contents);
+                    if minified.len() < contents.len() {
+                        Some(Bytes::from(minified))
+                    } else {
+                        None
+                    }
+                }
+            }
+            Err(err) => {
+                warn!("failed reading file {path:?} for minification: {err}");
+                None
+            }
+        };
+
+        if self.capacity == 0 {
+            return data;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(path) {
+            // Evict an arbitrary entry to stay within bounds
+            if let Some(key) = entries.keys().next().cloned() {
+                entries.remove(&key);
+            }
+        }
+        entries.insert(
+            path.to_owned(),
+            CacheEntry {
+                modified,
+                size,
+                data: data.clone(),
+            },
+        );
+        data
+    }
+}
+
+/// Heuristically determines whether a file is minified already: either its name contains
+/// `.min.` or its lines are very long.
+fn is_minified(path: &Path, contents: &str) -> bool {
+    if path
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().contains(".min."))
+    {
+        return true;
+    }
+
+    let lines = contents.bytes().filter(|c| *c == b'\n').count() + 1;
+    contents.len() / lines > MINIFIED_LINE_LENGTH
+}
+
+/// Copies a quoted string starting at the current position verbatim.
+fn copy_string(
+    quote: char,
+    chars: &mut std::iter::Peekable<std::str::Chars<'_>>,
+    out: &mut String,
+) {
+    out.push(quote);
+    while let Some(c) = chars.next() {
+        out.push(c);
+        if c == '\\' {
+            if let Some(c) = chars.next() {
+                out.push(c);
+            }
+        } else if c == quote {
+            break;
+        }
+    }
+}
+
+/// Minifies CSS code by removing comments (except `/*! … */` license comments) and collapsing
+/// whitespace. Whitespace next to `{`, `}`, `;` and `,` is removed entirely.
+fn minify_css(css: &str) -> String {
+    const SEPARATORS: &[char] = &['{', '}', ';', ','];
+
+    let mut out = String::with_capacity(css.len());
+    let mut chars = css.chars().peekable();
+    let mut pending_space = false;
+    while let Some(c) = chars.next() {
+        if c == '/' && chars.peek() == Some(&'*') {
+            chars.next();
+            let keep = chars.peek() == Some(&'!');
+            if keep {
+                out.push_str("/*");
+            }
+            let mut prev = '\0';
+            for c in chars.by_ref() {
+                if keep {
+                    out.push(c);
+                }
+                if prev == '*' && c == '/' {
+                    break;
+                }
+                prev = c;
+            }
+            pending_space = pending_space || !keep;
+            continue;
+        }
+
+        if c.is_ascii_whitespace() {
+            pending_space = true;
+            continue;
+        }
+
+        if pending_space {
+            if !out.is_empty() && !out.ends_with(SEPARATORS) && !SEPARATORS.contains(&c) {
+                out.push(' ');
+            }
+            pending_space = false;
+        }
+
+        if c == '"' || c == '\'' {
+            copy_string(c, &mut chars, &mut out);
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Minifies HTML code by removing comments (except conditional comments) and collapsing
+/// whitespace in text. Tags and the contents of elements like `<pre>` or `<script>` are kept
+/// unchanged.
+fn minify_html(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while !rest.is_empty() {
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            if !comment.starts_with("[if") && !comment.starts_with("<![endif]") {
+                rest = comment
+                    .find("-->")
+                    .map_or("", |end| &comment[end + "-->".len()..]);
+                continue;
+            }
+        }
+
+        let mut chars = rest.chars();
+        let c = chars.next().unwrap();
+        if c == '<'
+            && chars
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || "/!?".contains(c))
+        {
+            // Copy the tag verbatim, accounting for quoted attribute values
+            let mut quote = None;
+            let end = rest
+                .char_indices()
+                .skip(1)
+                .find(|&(_, c)| {
+                    match quote {
+                        Some(q) if q == c => quote = None,
+                        Some(_) => (),
+                        None if c == '"' || c == '\'' => quote = Some(c),
+                        None => return c == '>',
+                    }
+                    false
+                })
+                .map_or(rest.len(), |(pos, _)| pos + 1);
+            let tag = &rest[..end];
+            out.push_str(tag);
+            rest = &rest[end..];
+
+            let name: String = tag[1..]
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric())
+                .map(|c| c.to_ascii_lowercase())
+                .collect();
+            if RAW_ELEMENTS.contains(&name.as_str()) {
+                let closing = format!("</{name}");
+                let end = rest
+                    .to_ascii_lowercase()
+                    .find(&closing)
+                    .unwrap_or(rest.len());
+                out.push_str(&rest[..end]);
+                rest = &rest[end..];
+            }
+            continue;
+        }
+
+        if c.is_ascii_whitespace() {
+            let end = rest
+                .find(|c: char| !c.is_ascii_whitespace())
+                .unwrap_or(rest.len());
+            let newline = rest[..end].contains('\n');
+            rest = &rest[end..];
+
+            // Merge with preceding whitespace, e.g. if a comment was removed in between
+            if out.ends_with(' ') && newline {
+                out.pop();
+            } else if out.ends_with(|c: char| c.is_ascii_whitespace()) {
+                continue;
+            }
+            out.push(if newline { '\n' } else { ' ' });
+            continue;
+        }
+
+        let end = rest
+            .find(|c: char| c == '<' || c.is_ascii_whitespace())
+            .unwrap_or(rest.len())
+            .max(c.len_utf8());
+        out.push_str(&rest[..end]);
+        rest = &rest[end..];
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    #[test]
+    fn css() {
+        assert_eq!(
+            minify_css("/* comment */\nbody {\n  color: red;\n  font: 1em \"Times  New\";\n}\n\na:hover, b  > c { margin: 0 auto }\n"),
+            "body{color: red;font: 1em \"Times  New\";}a:hover,b > c{margin: 0 auto}"
+        );
+        assert_eq!(
+            minify_css("/*! license */\na { content: '/* x */' }"),
+            "/*! license */ a{content: '/* x */'}"
+        );
+    }
+
+    #[test]
+    fn html() {
+        assert_eq!(
+            minify_html("<!DOCTYPE html>\n<html>\n  <!-- comment -->\n  <body class=\"a  b\">\n    <p>Hello,   world!</p>\n    <pre>  keep\n    this  </pre>\n  </body>\n</html>\n"),
+            "<!DOCTYPE html>\n<html>\n<body class=\"a  b\">\n<p>Hello, world!</p>\n<pre>  keep\n    this  </pre>\n</body>\n</html>\n"
+        );
+        assert_eq!(
+            minify_html(
+                "<!--[if IE]><p>IE</p><![endif]-->\n<script>\n  if (a < b)  x();\n</script>"
+            ),
+            "<!--[if IE]><p>IE</p><![endif]-->\n<script>\n  if (a < b)  x();\n</script>"
+        );
+    }
+
+    #[test]
+    fn cache() {
+        let dir = std::env::temp_dir().join(format!("minify-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let first = dir.join("first.css");
+        let second = dir.join("second.css");
+        std::fs::write(&first, "a {\n  color: red;\n}\n").unwrap();
+        std::fs::write(&second, "b {\n  color: red;\n}\n").unwrap();
+
+        let cache = MinifyCache::new(1);
+        assert_eq!(
+            cache.get(&first, &mime::TEXT_CSS).unwrap(),
+            "a{color: red;}"
+        );
+
+        // Changing the file invalidates the cache entry
+        std::fs::write(&first, "a {\n  color: blue;\n}\n").unwrap();
+        assert_eq!(
+            cache.get(&first, &mime::TEXT_CSS).unwrap(),
+            "a{color: blue;}"
+        );
+
+        // Cache size is bounded
+        assert_eq!(
+            cache.get(&second, &mime::TEXT_CSS).unwrap(),
+            "b{color: red;}"
+        );
+        assert_eq!(cache.entries.lock().unwrap().len(), 1);
+
+        let cache = MinifyCache::new(0);
+        assert_eq!(
+            cache.get(&second, &mime::TEXT_CSS).unwrap(),
+            "b{color: red;}"
+        );
+        assert!(cache.entries.lock().unwrap().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn minified_heuristic() {
+        assert!(is_minified(Path::new("/dir/style.min.css"), "a {}\n"));
+        assert!(is_minified(Path::new("/dir/style.css"), &"a{}".repeat(100)));
+        assert!(!is_minified(Path::new("/dir/style.css"), "a {}\nb {}\n"));
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Handles content negotiation via `Accept` HTTP header, e.g. serving `image.jpg.avif` instead of
+//! `image.jpg`.
+
+use http::header;
+use pandora_module_utils::pingora::{Error, ResponseHeader, SessionWrapper};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Alternative format of a file: file extension and the corresponding MIME type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Alternative {
+    pub(crate) extension: String,
+    pub(crate) mime: String,
+}
+
+/// Encapsulates the content negotiation state for the current session.
+pub(crate) struct Negotiation<'a> {
+    alternatives: &'a [Alternative],
+}
+
+impl<'a> Negotiation<'a> {
+    /// Creates a new negotiation state for the file with the given path, considering the
+    /// alternatives configured for its extension.
+    pub(crate) fn new(
+        content_negotiation: &'a HashMap<String, Vec<Alternative>>,
+        path: &Path,
+    ) -> Self {
+        let alternatives = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| content_negotiation.get(&extension.to_ascii_lowercase()))
+            .map(|alternatives| alternatives.as_slice())
+            .unwrap_or_default();
+        Self { alternatives }
+    }
+
+    /// Checks whether the given path should be rewritten to an alternative format of the file
+    /// accepted by the client.
+    pub(crate) fn rewrite_path(
+        &self,
+        session: &impl SessionWrapper,
+        path: &Path,
+    ) -> Option<PathBuf> {
+        if self.alternatives.is_empty() {
+            return None;
+        }
+
+        let filename = path.file_name()?;
+        let requested = session
+            .req_header()
+            .headers
+            .get(header::ACCEPT)?
+            .to_str()
+            .ok()?;
+
+        let mut candidates = self
+            .alternatives
+            .iter()
+            .map(|alternative| (alternative, quality(requested, &alternative.mime)))
+            .filter(|(_, quality)| *quality > 0)
+            .collect::<Vec<_>>();
+        // Stable sort, alternatives with the same quality keep the configured order
+        candidates.sort_by_key(|(_, quality)| -(*quality as i32));
+
+        for (alternative, _) in candidates {
+            let mut candidate_name = filename.to_os_string();
+            candidate_name.push(".");
+            candidate_name.push(&alternative.extension);
+
+            let mut candidate_path = path.to_path_buf();
+            candidate_path.set_file_name(candidate_name);
+            if candidate_path.is_file() {
+                return Some(candidate_path);
+            }
+        }
+
+        None
+    }
+
+    /// Adds `Vary: Accept` HTTP header if the response depends on the `Accept` header.
+    pub(crate) fn transform_header(
+        &self,
+        mut header: Box<ResponseHeader>,
+    ) -> Result<Box<ResponseHeader>, Box<Error>> {
+        if !self.alternatives.is_empty() {
+            let vary = match header.headers.get(header::VARY) {
+                Some(vary) => format!("{}, Accept", vary.to_str().unwrap_or_default()),
+                None => "Accept".to_owned(),
+            };
+            header.insert_header(header::VARY, vary)?;
+        }
+        Ok(header)
+    }
+}
+
+/// Determines the quality value of the MIME type in an `Accept` header value, `0` if the type
+/// isn’t listed explicitly. Wildcards are ignored as these also match the original file.
+fn quality(requested: &str, mime: &str) -> u16 {
+    for entry in requested.split(',') {
+        let mut params = entry.split(';');
+        if !params
+            .next()
+            .is_some_and(|requested| requested.trim().eq_ignore_ascii_case(mime))
+        {
+            continue;
+        }
+
+        let mut quality = 1000;
+        for param in params {
+            if let Some((name, value)) = param.split_once('=') {
+                if name.trim() == "q" {
+                    if let Ok(value) = f64::from_str(value.trim()) {
+                        quality = (value * 1000.0) as u16;
+                    }
+                }
+            }
+        }
+        return quality;
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    #[test]
+    fn accept_quality() {
+        assert_eq!(quality("image/avif,image/webp,*/*", "image/avif"), 1000);
+        assert_eq!(quality("image/webp;q=0.8, image/avif", "image/webp"), 800);
+        assert_eq!(quality("IMAGE/AVIF", "image/avif"), 1000);
+        assert_eq!(quality("image/avif;q=0", "image/avif"), 0);
+        assert_eq!(quality("image/*,*/*;q=0.8", "image/avif"), 0);
+        assert_eq!(quality("", "image/avif"), 0);
+    }
+}
@@ -15,12 +15,20 @@
 //! Path resolution logic
 
 use percent_encoding::{percent_decode_str, percent_encode, AsciiSet, CONTROLS};
+use std::collections::HashMap;
+use std::ffi::OsString;
 use std::io::{Error, ErrorKind};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 // This matches pingora logic, see https://github.com/cloudflare/pingora/blob/2501d4adb038d93613c0edbd7c1e3b3de9b415b1/pingora-core/src/protocols/http/v1/server.rs#L934
 const URI_ESC_CHARSET: &AsciiSet = &CONTROLS.add(b' ').add(b'<').add(b'>').add(b'"');
 
+/// Maximal number of entries in a directory for case-insensitive lookups, only exact matches are
+/// possible in larger directories
+const MAX_CASE_INSENSITIVE_ENTRIES: usize = 10_000;
+
 #[cfg(unix)]
 fn path_from_bytes(bytes: &[u8]) -> &std::ffi::OsStr {
     use std::ffi::OsStr;
@@ -36,6 +44,25 @@ fn path_from_bytes(bytes: &[u8]) -> String {
     String::from_utf8_lossy(bytes).into_owned()
 }
 
+/// Checks whether all percent-encoded sequences in a URI path are valid.
+///
+/// A valid sequence consists of a percent sign (%) followed by two hexadecimal digits. Malformed
+/// sequences like `%ZZ` or a truncated `%A` at the end of the path will be treated literally by
+/// [`resolve_uri`], callers should use this function if such paths are to be rejected instead.
+pub fn has_valid_encoding(uri_path: &str) -> bool {
+    let mut bytes = uri_path.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let valid = bytes.next().is_some_and(|b| b.is_ascii_hexdigit())
+                && bytes.next().is_some_and(|b| b.is_ascii_hexdigit());
+            if !valid {
+                return false;
+            }
+        }
+    }
+    true
+}
+
 /// Resolves the path from a URI against the path to a root directory.
 ///
 /// This will return an error under the following conditions:
@@ -45,6 +72,126 @@ fn path_from_bytes(bytes: &[u8]) -> String {
 /// * [`std::fs::canonicalize()`] failed: results in [`ErrorKind::NotFound`],
 ///   [`ErrorKind::PermissionDenied`] and other errors
 pub fn resolve_uri(uri_path: &str, root: &Path) -> Result<PathBuf, Error> {
+    resolve(uri_path, root, None)
+}
+
+/// Resolves the path from a URI against the path to a root directory like [`resolve_uri`] but
+/// ignores ASCII letter case of file and directory names.
+///
+/// A name matching exactly is always preferred. Otherwise, if a directory contains multiple
+/// entries differing only in case, the one sorted first is used. Directories with more than
+/// 10,000 entries only allow exact matches.
+pub fn resolve_uri_case_insensitive(uri_path: &str, root: &Path) -> Result<PathBuf, Error> {
+    resolve(uri_path, root, Some(&DirectoryCache::new(0)))
+}
+
+/// Directory entries keyed by their lower-case name. `None` for directories that are too large.
+type DirectoryListing = Option<HashMap<Vec<u8>, OsString>>;
+
+/// Cached directory listing along with the directory’s modification time
+#[derive(Debug)]
+struct CacheEntry {
+    modified: Option<SystemTime>,
+    listing: Arc<DirectoryListing>,
+}
+
+/// Bounded cache of directory listings for case-insensitive lookups, keyed by directory path. An
+/// entry is reused as long as the directory’s modification time stays unchanged.
+#[derive(Debug, Default)]
+pub(crate) struct DirectoryCache {
+    capacity: usize,
+    entries: Mutex<HashMap<PathBuf, CacheEntry>>,
+}
+
+impl PartialEq for DirectoryCache {
+    fn eq(&self, other: &Self) -> bool {
+        // Cache contents don’t affect handler behavior
+        self.capacity == other.capacity
+    }
+}
+
+impl Eq for DirectoryCache {}
+
+impl DirectoryCache {
+    /// Creates a cache holding up to `capacity` directory listings, `0` disables caching.
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Default::default(),
+        }
+    }
+
+    /// Resolves the path from a URI like [`resolve_uri_case_insensitive`], using the cache.
+    pub(crate) fn resolve_uri(&self, uri_path: &str, root: &Path) -> Result<PathBuf, Error> {
+        resolve(uri_path, root, Some(self))
+    }
+
+    fn read_listing(dir: &Path) -> DirectoryListing {
+        let mut listing = HashMap::new();
+        for (index, entry) in dir
+            .read_dir()
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .enumerate()
+        {
+            if index >= MAX_CASE_INSENSITIVE_ENTRIES {
+                return None;
+            }
+
+            let name = entry.file_name();
+            let key = name.as_encoded_bytes().to_ascii_lowercase();
+            match listing.get(&key) {
+                Some(existing) if *existing <= name => {}
+                _ => {
+                    listing.insert(key, name);
+                }
+            }
+        }
+        Some(listing)
+    }
+
+    fn listing(&self, dir: &Path) -> Arc<DirectoryListing> {
+        if self.capacity == 0 {
+            return Arc::new(Self::read_listing(dir));
+        }
+
+        let modified = dir.metadata().and_then(|meta| meta.modified()).ok();
+        if let Some(entry) = self.entries.lock().unwrap().get(dir) {
+            if entry.modified == modified {
+                return entry.listing.clone();
+            }
+        }
+
+        let listing = Arc::new(Self::read_listing(dir));
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(dir) {
+            // Evict an arbitrary entry to stay within bounds
+            if let Some(key) = entries.keys().next().cloned() {
+                entries.remove(&key);
+            }
+        }
+        entries.insert(
+            dir.to_owned(),
+            CacheEntry {
+                modified,
+                listing: listing.clone(),
+            },
+        );
+        listing
+    }
+
+    /// Looks up a directory entry with the given name, ignoring ASCII letter case.
+    fn find(&self, dir: &Path, name: &Path) -> Option<OsString> {
+        let key = name.as_os_str().as_encoded_bytes().to_ascii_lowercase();
+        (*self.listing(dir)).as_ref()?.get(&key).cloned()
+    }
+}
+
+fn resolve(
+    uri_path: &str,
+    root: &Path,
+    case_insensitive: Option<&DirectoryCache>,
+) -> Result<PathBuf, Error> {
     let uri_path = uri_path.strip_prefix('/').ok_or(ErrorKind::InvalidInput)?;
 
     let uri_path = uri_path.strip_suffix('/').unwrap_or(uri_path);
@@ -52,7 +199,17 @@ pub fn resolve_uri(uri_path: &str, root: &Path) -> Result<PathBuf, Error> {
     let mut path = root.to_path_buf();
     for component in uri_path.split('/') {
         let decoded = percent_decode_str(component).collect::<Vec<_>>();
-        path.push(path_from_bytes(&decoded))
+        let name = path_from_bytes(&decoded);
+        let name = Path::new(&name);
+        if let Some(cache) =
+            case_insensitive.filter(|_| path.join(name).symlink_metadata().is_err())
+        {
+            if let Some(name) = cache.find(&path, name) {
+                path.push(name);
+                continue;
+            }
+        }
+        path.push(name)
     }
 
     let path = path.canonicalize()?;
@@ -82,3 +239,58 @@ pub fn path_to_uri(path: &Path, root: &Path) -> Option<String> {
     }
     Some(uri)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_encoding() {
+        assert!(has_valid_encoding("/"));
+        assert!(has_valid_encoding("/file.txt"));
+        assert!(has_valid_encoding("/file%2etxt"));
+        assert!(has_valid_encoding("/%C3%A4%c3%a4/"));
+
+        assert!(!has_valid_encoding("/%ZZ"));
+        assert!(!has_valid_encoding("/file%2"));
+        assert!(!has_valid_encoding("/file%"));
+        assert!(!has_valid_encoding("/%A/file.txt"));
+        assert!(!has_valid_encoding("/%%41"));
+    }
+
+    #[test]
+    fn directory_cache() {
+        let root = std::env::temp_dir().join(format!("directory-cache-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("Subdir")).unwrap();
+        let root = root.canonicalize().unwrap();
+        std::fs::write(root.join("Subdir").join("File.txt"), "").unwrap();
+
+        let cache = DirectoryCache::new(10);
+        assert_eq!(
+            cache.resolve_uri("/subdir/file.TXT", &root).unwrap(),
+            root.join("Subdir").join("File.txt")
+        );
+        assert_eq!(cache.entries.lock().unwrap().len(), 2);
+        assert!(cache.resolve_uri("/subdir/other.txt", &root).is_err());
+
+        // New entries are picked up once the directory is modified
+        let modified = || root.join("Subdir").metadata().unwrap().modified().unwrap();
+        let before = modified();
+        std::fs::write(root.join("Subdir").join("Other.txt"), "").unwrap();
+        if modified() == before {
+            // File system time resolution too low to notice the change
+            cache.entries.lock().unwrap().clear();
+        }
+        assert_eq!(
+            cache.resolve_uri("/SUBDIR/other.txt", &root).unwrap(),
+            root.join("Subdir").join("Other.txt")
+        );
+
+        // Cache size is bounded
+        let cache = DirectoryCache::new(1);
+        cache.resolve_uri("/subdir/file.txt", &root).unwrap();
+        assert_eq!(cache.entries.lock().unwrap().len(), 1);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
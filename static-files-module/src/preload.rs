@@ -0,0 +1,99 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Produces preload `Link` headers from per-page manifest files.
+
+use http::HeaderValue;
+use log::{debug, warn};
+use mime_guess::mime;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::path::resolve_uri;
+
+/// File extension of preload manifests, `index.html.preload` lists dependencies of `index.html`.
+const MANIFEST_EXTENSION: &str = "preload";
+
+/// Determines the value of the `as` attribute for the resource and whether it needs to be
+/// requested in CORS mode.
+fn destination(path: &Path) -> (&'static str, bool) {
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    match (mime.type_(), mime.subtype()) {
+        (mime::TEXT, mime::CSS) => ("style", false),
+        (mime::TEXT | mime::APPLICATION, mime::JAVASCRIPT) => ("script", false),
+        (mime::FONT, _) => ("font", true),
+        (mime::IMAGE, _) => ("image", false),
+        _ => ("fetch", true),
+    }
+}
+
+/// Reads the preload manifest of the given file if it exists and produces the corresponding
+/// `Link` header value.
+///
+/// Manifest entries are URI paths like `/style.css`, one per line. These are resolved against
+/// the root directory just like request paths, so that entries not mapping to existing files
+/// can be skipped. `prefix` is prepended to the entries, it should be set if a prefix was
+/// removed from the request URI before passing it to this handler.
+pub(crate) fn preload_links(path: &Path, root: &Path, prefix: &str) -> Option<HeaderValue> {
+    let mut manifest_name = path.file_name()?.to_os_string();
+    manifest_name.push(".");
+    manifest_name.push(MANIFEST_EXTENSION);
+    let manifest_path = path.with_file_name(manifest_name);
+
+    let manifest = match std::fs::read_to_string(&manifest_path) {
+        Ok(manifest) => manifest,
+        Err(err) if err.kind() == ErrorKind::NotFound => return None,
+        Err(err) => {
+            warn!("failed reading preload manifest {manifest_path:?}: {err}");
+            return None;
+        }
+    };
+
+    let mut links = Vec::new();
+    for entry in manifest.lines().map(str::trim) {
+        if entry.is_empty() || entry.starts_with('#') {
+            continue;
+        }
+
+        if !entry.starts_with('/')
+            || !entry
+                .bytes()
+                .all(|c| c.is_ascii_graphic() && c != b'<' && c != b'>')
+        {
+            warn!("ignoring invalid entry {entry} in preload manifest {manifest_path:?}");
+            continue;
+        }
+
+        let resolved = match resolve_uri(entry, root) {
+            Ok(resolved) if resolved.is_file() => resolved,
+            _ => {
+                debug!("preload manifest entry {entry} doesn't resolve to a file, skipping");
+                continue;
+            }
+        };
+
+        let (destination, crossorigin) = destination(&resolved);
+        let mut link = format!("<{prefix}{entry}>; rel=preload; as={destination}");
+        if crossorigin {
+            link.push_str("; crossorigin");
+        }
+        links.push(link);
+    }
+
+    if links.is_empty() {
+        None
+    } else {
+        HeaderValue::from_str(&links.join(", ")).ok()
+    }
+}
@@ -76,7 +76,7 @@ pub fn extract_range(session: &impl SessionWrapper, meta: &Metadata) -> Option<R
         .get(header::IF_RANGE)
         .and_then(|value| value.to_str().ok())
     {
-        if value != meta.etag
+        if !meta.etag_matches_strong(value)
             && !meta
                 .modified
                 .as_ref()
@@ -17,9 +17,9 @@ use crate::metadata::Metadata;
 
 use compression_module::CompressionHandler;
 use const_format::{concatcp, str_repeat};
-use http::status::StatusCode;
+use http::{status::StatusCode, Version};
 use pandora_module_utils::pingora::{
-    create_test_session, ErrorType, RequestHeader, Session, SessionWrapper,
+    create_test_session, ErrorType, RequestHeader, ResponseHeader, Session, SessionWrapper,
 };
 use pandora_module_utils::standard_response::response_text;
 use pandora_module_utils::{FromYaml, RequestFilter};
@@ -27,6 +27,7 @@ use rewrite_module::RewriteHandler;
 use startup_module::{AppResult, DefaultApp};
 use std::path::PathBuf;
 use test_log::test;
+use upstream_module::UpstreamHandler;
 
 #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
 struct Handler {
@@ -316,6 +317,38 @@ async fn complex_path() {
     assert_body(&result, &text);
 }
 
+#[test(tokio::test)]
+async fn plus_in_path() {
+    let mut app = make_app(default_conf());
+
+    // `+` is a literal character in paths, not an encoded space
+    let session = make_session("GET", "/a+b.txt?q=a+b").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "plus\n");
+
+    let text = response_text(StatusCode::PERMANENT_REDIRECT);
+    let session = make_session("GET", "/a%2Bb.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 308);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &text.len().to_string()),
+            ("Content-Type", "text/html;charset=utf-8"),
+            ("location", "/a+b.txt"),
+        ],
+    );
+    assert_body(&result, &text);
+
+    let session = make_session("GET", "/a%20b.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 404);
+}
+
 #[test(tokio::test)]
 async fn utf8_path() {
     let meta = Metadata::from_path(&root_path("subdir/файл söndärzeichen.txt"), None).unwrap();
@@ -375,13 +408,196 @@ async fn no_file_with_page_404() {
         &mut result,
         vec![
             ("Content-Length", &meta.size.to_string()),
-            ("accept-ranges", "bytes"),
             ("Content-Type", "text/plain;charset=utf-8"),
-            ("last-modified", meta.modified.as_ref().unwrap()),
-            ("etag", &meta.etag),
+            ("Cache-Control", "no-store"),
         ],
     );
     assert_body(&result, "Hi!\n");
+
+    // Conditional and range requests don’t apply to error pages
+    let mut session = make_session("GET", "/missing.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("If-None-Match", &meta.etag)
+        .unwrap();
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=0-1")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 404);
+    assert_body(&result, "Hi!\n");
+
+    let mut session = make_session("GET", "/missing.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("If-Match", "\"xyz\"")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 404);
+    assert_body(&result, "Hi!\n");
+}
+
+#[test(tokio::test)]
+async fn case_insensitive_paths() {
+    let mut app = make_app(extended_conf(
+        "page_404: {/subdir/*: /subdir/404.html}\ncanonicalize_uri: false",
+    ));
+
+    let session = make_session("GET", "/File.TXT").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 404);
+
+    let session = make_session("GET", "/SubDir/missing.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 404);
+    assert_body(&result, &response_text(StatusCode::NOT_FOUND));
+
+    let mut app = make_app(extended_conf(
+        "page_404: {/subdir/*: /subdir/404.html}\ncanonicalize_uri: false\ncase_insensitive_paths: true",
+    ));
+
+    let session = make_session("GET", "/File.TXT").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "Hi!\n");
+
+    let session = make_session("GET", "/SUBDIR/404.Html").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "<p>Nothing here.</p>\n");
+
+    let session = make_session("GET", "/SubDir/missing.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 404);
+    assert_body(&result, "<p>Nothing here.</p>\n");
+
+    // With canonicalize_uri the request is redirected to the actual file name
+    let mut app = make_app(extended_conf("case_insensitive_paths: true"));
+
+    let session = make_session("GET", "/SubDir/404.Html?x").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 308);
+    assert_eq!(
+        result
+            .session()
+            .response_written()
+            .unwrap()
+            .headers
+            .get("Location")
+            .unwrap(),
+        "/subdir/404.html?x"
+    );
+}
+
+#[test(tokio::test)]
+async fn on_missing_next() {
+    #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
+    struct FallbackHandler {
+        static_files: StaticFilesHandler,
+        upstream: UpstreamHandler,
+    }
+
+    let conf = <FallbackHandler as RequestFilter>::Conf::from_yaml(extended_conf(
+        "on_missing: next\npage_404: /file.txt\nupstream: http://127.0.0.1:8081",
+    ))
+    .unwrap();
+    let mut app = DefaultApp::<FallbackHandler>::from_conf(conf).unwrap();
+
+    // Existing files are served without contacting the upstream server
+    let session = make_session("GET", "/file.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "Hi!\n");
+
+    // Missing files are left to the upstream handler, error page is ignored
+    let session = make_session("GET", "/missing.txt").await;
+    let mut result = app
+        .handle_request_with_upstream(session, |session, peer| {
+            assert_eq!(peer._address.to_string(), "127.0.0.1:8081");
+            assert_eq!(session.req_header().uri.path(), "/missing.txt");
+            ResponseHeader::build(204, None)
+        })
+        .await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 204);
+}
+
+#[test(tokio::test)]
+async fn page_404_by_path() {
+    let mut app = make_app(extended_conf(
+        "page_404: {/*: /file.txt, /subdir/*: /subdir/404.html}",
+    ));
+
+    let session = make_session("GET", "/missing.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 404);
+    assert_body(&result, "Hi!\n");
+
+    let session = make_session("GET", "/subdir/missing.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 404);
+    assert_body(&result, "<p>Nothing here.</p>\n");
+
+    // Paths outside any configured prefix get the standard error page
+    let mut app = make_app(extended_conf("page_404: {/subdir/*: /subdir/404.html}"));
+
+    let text = response_text(StatusCode::NOT_FOUND);
+    let session = make_session("GET", "/missing.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 404);
+    assert_body(&result, &text);
+}
+
+#[test(tokio::test)]
+async fn page_404_json() {
+    let mut app = make_app(extended_conf(
+        "page_404: {/*: /subdir/404.html, /api/*: /api/404.json}",
+    ));
+
+    let session = make_session("GET", "/api/users/missing").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 404);
+    assert_eq!(
+        result
+            .session()
+            .response_written()
+            .unwrap()
+            .headers
+            .get("Content-Type")
+            .unwrap(),
+        "application/json;charset=utf-8"
+    );
+    assert_body(&result, "{\"error\":\"not found\"}\n");
+
+    let session = make_session("GET", "/missing.html").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 404);
+    assert_eq!(
+        result
+            .session()
+            .response_written()
+            .unwrap()
+            .headers
+            .get("Content-Type")
+            .unwrap(),
+        "text/html;charset=utf-8"
+    );
+    assert_body(&result, "<p>Nothing here.</p>\n");
 }
 
 #[test(tokio::test)]
@@ -403,6 +619,32 @@ async fn no_index() {
     assert_body(&result, &text);
 }
 
+#[test(tokio::test)]
+async fn symlinked_index() {
+    let mut app = make_app(extended_conf("index_file: [index.html]"));
+
+    // Symlink to a file inside the root directory is fine
+    let session = make_session("GET", "/linked_index/").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "<html>Hi!</html>\n");
+
+    // Symlink pointing outside the root directory is refused, both when requested directly and
+    // as directory index
+    let session = make_session("GET", "/escaping_index/index.html").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 400);
+
+    let text = response_text(StatusCode::FORBIDDEN);
+    let session = make_session("GET", "/escaping_index/").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 403);
+    assert_body(&result, &text);
+}
+
 #[test(tokio::test)]
 async fn wrong_method() {
     let mut app = make_app(default_conf());
@@ -417,9 +659,37 @@ async fn wrong_method() {
         vec![
             ("Content-Length", &text.len().to_string()),
             ("Content-Type", "text/html;charset=utf-8"),
+            ("Allow", "GET, HEAD, OPTIONS"),
+        ],
+    );
+    assert_body(&result, &text);
+
+    let session = make_session("PUT", "/file.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 405);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &text.len().to_string()),
+            ("Content-Type", "text/html;charset=utf-8"),
+            ("Allow", "GET, HEAD, OPTIONS"),
         ],
     );
     assert_body(&result, &text);
+
+    let session = make_session("OPTIONS", "/file.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 204);
+    assert_headers(&mut result, vec![("Allow", "GET, HEAD, OPTIONS")]);
+    assert_body(&result, "");
+
+    // Method names are case-sensitive
+    let session = make_session("get", "/file.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 405);
 }
 
 #[test(tokio::test)]
@@ -524,6 +794,38 @@ async fn bad_request() {
     assert_body(&result, &text);
 }
 
+#[test(tokio::test)]
+async fn malformed_encoding() {
+    let mut app = make_app(default_conf());
+    let text = response_text(StatusCode::BAD_REQUEST);
+
+    for path in ["/file%ZZ.txt", "/file.txt%A", "/%A/file.txt"] {
+        let session = make_session("GET", path).await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_status(&mut result, 400);
+        assert_headers(
+            &mut result,
+            vec![
+                ("Content-Length", &text.len().to_string()),
+                ("Content-Type", "text/html;charset=utf-8"),
+            ],
+        );
+        assert_body(&result, &text);
+    }
+
+    let mut app = make_app(extended_conf("reject_malformed_encoding: false"));
+    let text = response_text(StatusCode::NOT_FOUND);
+
+    for path in ["/file%ZZ.txt", "/file.txt%A", "/%A/file.txt"] {
+        let session = make_session("GET", path).await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_status(&mut result, 404);
+        assert_body(&result, &text);
+    }
+}
+
 #[test(tokio::test)]
 async fn if_none_match() {
     let meta = Metadata::from_path(&root_path("file.txt"), None).unwrap();
@@ -643,34 +945,56 @@ async fn if_none_match() {
 }
 
 #[test(tokio::test)]
-async fn if_match() {
+async fn no_cache_request() {
     let meta = Metadata::from_path(&root_path("file.txt"), None).unwrap();
 
     let mut app = make_app(default_conf());
+
+    // A revalidating cache receives 304 if its copy is still current
     let mut session = make_session("GET", "/file.txt").await;
     session
         .req_header_mut()
-        .insert_header("If-Match", &meta.etag)
+        .insert_header("Cache-Control", "no-cache")
+        .unwrap();
+    session
+        .req_header_mut()
+        .insert_header("If-None-Match", &meta.etag)
         .unwrap();
     let mut result = app.handle_request(session).await;
     assert!(result.err().is_none());
-    assert_status(&mut result, 200);
+    assert_status(&mut result, 304);
     assert_headers(
         &mut result,
         vec![
-            ("Content-Length", &meta.size.to_string()),
-            ("accept-ranges", "bytes"),
-            ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
         ],
     );
-    assert_body(&result, "Hi!\n");
+    assert_body(&result, "");
 
     let mut session = make_session("GET", "/file.txt").await;
     session
         .req_header_mut()
-        .insert_header("If-Match", "*")
+        .insert_header("Pragma", "no-cache")
+        .unwrap();
+    session
+        .req_header_mut()
+        .insert_header("If-Modified-Since", meta.modified.as_ref().unwrap())
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 304);
+    assert_body(&result, "");
+
+    // A stale copy is replaced by the full response
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Cache-Control", "no-cache")
+        .unwrap();
+    session
+        .req_header_mut()
+        .insert_header("If-None-Match", "\"xyz\"")
         .unwrap();
     let mut result = app.handle_request(session).await;
     assert!(result.err().is_none());
@@ -687,10 +1011,31 @@ async fn if_match() {
     );
     assert_body(&result, "Hi!\n");
 
+    // Without validators the full response is sent
     let mut session = make_session("GET", "/file.txt").await;
     session
         .req_header_mut()
-        .insert_header("If-Match", format!("\"xyz\", {}", &meta.etag))
+        .insert_header("Cache-Control", "no-cache, max-age=0")
+        .unwrap();
+    session
+        .req_header_mut()
+        .insert_header("Pragma", "no-cache")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "Hi!\n");
+}
+
+#[test(tokio::test)]
+async fn if_match() {
+    let meta = Metadata::from_path(&root_path("file.txt"), None).unwrap();
+
+    let mut app = make_app(default_conf());
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("If-Match", &meta.etag)
         .unwrap();
     let mut result = app.handle_request(session).await;
     assert!(result.err().is_none());
@@ -710,11 +1055,7 @@ async fn if_match() {
     let mut session = make_session("GET", "/file.txt").await;
     session
         .req_header_mut()
-        .insert_header("If-Match", &meta.etag)
-        .unwrap();
-    session
-        .req_header_mut()
-        .insert_header("If-Unmodified-Since", "Thu, 01 Jan 1970 00:00:00 GTM")
+        .insert_header("If-Match", "*")
         .unwrap();
     let mut result = app.handle_request(session).await;
     assert!(result.err().is_none());
@@ -734,7 +1075,51 @@ async fn if_match() {
     let mut session = make_session("GET", "/file.txt").await;
     session
         .req_header_mut()
-        .insert_header("If-Match", "\"xyz\"")
+        .insert_header("If-Match", format!("\"xyz\", {}", &meta.etag))
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+        ],
+    );
+    assert_body(&result, "Hi!\n");
+
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("If-Match", &meta.etag)
+        .unwrap();
+    session
+        .req_header_mut()
+        .insert_header("If-Unmodified-Since", "Thu, 01 Jan 1970 00:00:00 GTM")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+        ],
+    );
+    assert_body(&result, "Hi!\n");
+
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("If-Match", "\"xyz\"")
         .unwrap();
     let mut result = app.handle_request(session).await;
     assert!(result.err().is_none());
@@ -769,6 +1154,84 @@ async fn if_match() {
     assert_body(&result, "");
 }
 
+#[test(tokio::test)]
+async fn weak_etags() {
+    let meta = Metadata::from_path(&root_path("file.txt"), None).unwrap();
+    let weak_etag = format!("W/{}", meta.etag);
+
+    // If-Match uses strong comparison, weak ETags never match
+    let mut app = make_app(default_conf());
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("If-Match", &weak_etag)
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 412);
+    assert_headers(
+        &mut result,
+        vec![
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+        ],
+    );
+    assert_body(&result, "");
+
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("If-Match", format!("{weak_etag}, {}", meta.etag))
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "Hi!\n");
+
+    // If-None-Match uses weak comparison
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("If-None-Match", &weak_etag)
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 304);
+    assert_headers(
+        &mut result,
+        vec![
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+        ],
+    );
+    assert_body(&result, "");
+
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("If-None-Match", "W/\"xyz\"")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "Hi!\n");
+
+    // If-Range uses strong comparison, a weak ETag results in the full file being sent
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=0-1")
+        .unwrap();
+    session
+        .req_header_mut()
+        .insert_header("If-Range", &weak_etag)
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "Hi!\n");
+}
+
 #[test(tokio::test)]
 async fn if_modified_since() {
     let meta = Metadata::from_path(&root_path("file.txt"), None).unwrap();
@@ -1052,161 +1515,546 @@ async fn ranged_request() {
 }
 
 #[test(tokio::test)]
-async fn dynamic_compression() {
+async fn conditional_ranged_request() {
     let meta = Metadata::from_path(&root_path("large.txt"), None).unwrap();
-    let mut app = make_app(extended_conf("compression_level_gzip: 3"));
 
-    // Regular request should result in compressed response
+    let mut app = make_app(default_conf());
+
+    // Matching If-None-Match takes precedence over Range
     let mut session = make_session("GET", "/large.txt").await;
     session
         .req_header_mut()
-        .insert_header("Accept-Encoding", "gzip")
+        .insert_header("If-None-Match", &meta.etag)
+        .unwrap();
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=2-5")
         .unwrap();
     let mut result = app.handle_request(session).await;
     assert!(result.err().is_none());
-    assert_status(&mut result, 200);
+    assert_status(&mut result, 304);
     assert_headers(
         &mut result,
         vec![
-            ("Content-Encoding", "gzip"),
-            ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
-            ("Transfer-Encoding", "chunked"),
-            ("vary", "Accept-Encoding"),
         ],
     );
+    assert_body(&result, "");
 
-    // Request without matching encodings should result in uncompressed response
+    // Non-matching If-None-Match leaves Range to be processed
     let mut session = make_session("GET", "/large.txt").await;
     session
         .req_header_mut()
-        .insert_header("Accept-Encoding", "unsupported")
+        .insert_header("If-None-Match", "\"xyz\"")
+        .unwrap();
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=2-5")
         .unwrap();
     let mut result = app.handle_request(session).await;
     assert!(result.err().is_none());
-    assert_status(&mut result, 200);
+    assert_status(&mut result, 206);
     assert_headers(
         &mut result,
         vec![
-            ("Content-Length", &meta.size.to_string()),
-            ("accept-ranges", "bytes"),
+            ("Content-Length", "4"),
+            ("content-range", "bytes 2-5/100001"),
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
-            ("vary", "Accept-Encoding"),
         ],
     );
+    assert_body(&result, "2345");
 
-    // We shouldn’t get ranged requests in practice but Pingora will compress even these responses.
+    // If-Range is still honored then
     let mut session = make_session("GET", "/large.txt").await;
     session
         .req_header_mut()
-        .insert_header("Accept-Encoding", "gzip")
+        .insert_header("If-None-Match", "\"xyz\"")
         .unwrap();
     session
         .req_header_mut()
-        .insert_header("Range", "bytes=0-10000")
+        .insert_header("Range", "bytes=2-5")
+        .unwrap();
+    session
+        .req_header_mut()
+        .insert_header("If-Range", "\"xyz\"")
         .unwrap();
     let mut result = app.handle_request(session).await;
     assert!(result.err().is_none());
-    assert_status(&mut result, 206);
-    assert_headers(
-        &mut result,
-        vec![
-            ("Content-Encoding", "gzip"),
-            ("content-range", "bytes 0-10000/100001"),
-            ("Content-Type", "text/plain;charset=utf-8"),
-            ("last-modified", meta.modified.as_ref().unwrap()),
-            ("etag", &meta.etag),
-            ("Transfer-Encoding", "chunked"),
-            ("vary", "Accept-Encoding"),
-        ],
-    );
-}
-
-#[test(tokio::test)]
-async fn static_compression() {
-    let meta = Metadata::from_path(&root_path("large_precompressed.txt"), None).unwrap();
-    let meta_compressed =
-        Metadata::from_path(&root_path("large_precompressed.txt.gz"), None).unwrap();
-    let mut app = make_app(extended_conf("precompressed: [gz, br]"));
+    assert_status(&mut result, 200);
+    assert_eq!(result.body().len(), 100001);
 
-    // Regular request should result in compressed response
-    let mut session = make_session("GET", "/large_precompressed.txt").await;
+    // Failed If-Match takes precedence over both If-None-Match and Range
+    let mut session = make_session("GET", "/large.txt").await;
     session
         .req_header_mut()
-        .insert_header("Accept-Encoding", "br, gzip")
+        .insert_header("If-Match", "\"xyz\"")
+        .unwrap();
+    session
+        .req_header_mut()
+        .insert_header("If-None-Match", &meta.etag)
+        .unwrap();
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=2-5")
         .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 412);
+    assert_body(&result, "");
 
+    // Successful If-Match leaves Range to be processed
+    let mut session = make_session("GET", "/large.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("If-Match", &meta.etag)
+        .unwrap();
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=2-5")
+        .unwrap();
     let mut result = app.handle_request(session).await;
     assert!(result.err().is_none());
+    assert_status(&mut result, 206);
+    assert_body(&result, "2345");
+}
 
-    assert_status(&mut result, 200);
-    assert_headers(
-        &mut result,
-        vec![
-            ("Content-Length", &meta_compressed.size.to_string()),
-            ("accept-ranges", "bytes"),
-            ("Content-Type", "text/plain;charset=utf-8"),
-            ("last-modified", meta_compressed.modified.as_ref().unwrap()),
-            ("etag", &meta_compressed.etag),
-            ("Content-Encoding", "gzip"),
-            ("vary", "Accept-Encoding"),
-        ],
-    );
+#[test(tokio::test)]
+async fn ranged_head_request() {
+    let meta = Metadata::from_path(&root_path("large.txt"), None).unwrap();
 
-    // Static compression should take precedence over dynamic
-    let mut session = make_session("GET", "/large_precompressed.txt").await;
+    let mut app = make_app(default_conf());
+    let mut session = make_session("HEAD", "/large.txt").await;
     session
         .req_header_mut()
-        .insert_header("Accept-Encoding", "br, gzip")
+        .insert_header("Range", "bytes=2-5")
         .unwrap();
-
     let mut result = app.handle_request(session).await;
     assert!(result.err().is_none());
-
-    assert_status(&mut result, 200);
+    assert_status(&mut result, 206);
     assert_headers(
         &mut result,
         vec![
-            ("Content-Length", &meta_compressed.size.to_string()),
-            ("accept-ranges", "bytes"),
+            ("Content-Length", "4"),
+            ("content-range", "bytes 2-5/100001"),
             ("Content-Type", "text/plain;charset=utf-8"),
-            ("last-modified", meta_compressed.modified.as_ref().unwrap()),
-            ("etag", &meta_compressed.etag),
-            ("Content-Encoding", "gzip"),
-            ("vary", "Accept-Encoding"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
         ],
     );
+    assert_body(&result, "");
 
-    // Request without matching encodings should result in uncompressed response
-    let mut session = make_session("GET", "/large_precompressed.txt").await;
+    let mut session = make_session("HEAD", "/large.txt").await;
     session
         .req_header_mut()
-        .insert_header("Accept-Encoding", "zstd")
+        .insert_header("Range", "bytes=200000-")
         .unwrap();
-
     let mut result = app.handle_request(session).await;
     assert!(result.err().is_none());
-
-    assert_status(&mut result, 200);
+    assert_status(&mut result, 416);
     assert_headers(
         &mut result,
         vec![
-            ("Content-Length", &meta.size.to_string()),
-            ("accept-ranges", "bytes"),
             ("Content-Type", "text/plain;charset=utf-8"),
+            ("Content-Range", "bytes */100001"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
-            ("vary", "Accept-Encoding"),
         ],
     );
+    assert_body(&result, "");
+}
 
-    // Ranged response should be compressed
-    let mut session = make_session("GET", "/large_precompressed.txt").await;
-    session
-        .req_header_mut()
+#[test(tokio::test)]
+async fn not_satisfiable_compression() {
+    async fn request(app: &mut DefaultApp<Handler>, path: &str, encoding: &str) -> AppResult {
+        let mut session = make_session("GET", path).await;
+        session
+            .req_header_mut()
+            .insert_header("Accept-Encoding", encoding)
+            .unwrap();
+        session
+            .req_header_mut()
+            .insert_header("Range", "bytes=200000-")
+            .unwrap();
+        let result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        result
+    }
+
+    let meta = Metadata::from_path(&root_path("large.txt"), None).unwrap();
+
+    // Total size is the one of the uncompressed file, with or without dynamic compression
+    let mut app = make_app(default_conf());
+    let mut result = request(&mut app, "/large.txt", "gzip").await;
+    assert_status(&mut result, 416);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("Content-Range", "bytes */100001"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+        ],
+    );
+    assert_body(&result, "");
+
+    let mut app = make_app(extended_conf("compression_level_gzip: 3"));
+    let mut result = request(&mut app, "/large.txt", "gzip").await;
+    assert_status(&mut result, 416);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("Content-Range", "bytes */100001"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("vary", "Accept-Encoding"),
+        ],
+    );
+    assert_body(&result, "");
+
+    // Vary header can be suppressed
+    let mut app = make_app(extended_conf(
+        "compression_level_gzip: 3\nrange_not_satisfiable_vary: false",
+    ));
+    let mut result = request(&mut app, "/large.txt", "gzip").await;
+    assert_status(&mut result, 416);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("Content-Range", "bytes */100001"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+        ],
+    );
+    assert_body(&result, "");
+
+    // With a pre-compressed file ranges refer to the compressed file, so does the total size
+    let meta = Metadata::from_path(&root_path("large_precompressed.txt"), None).unwrap();
+    let meta_compressed =
+        Metadata::from_path(&root_path("large_precompressed.txt.gz"), None).unwrap();
+    let mut app = make_app(extended_conf("precompressed: [gz, br]"));
+    let mut result = request(&mut app, "/large_precompressed.txt", "gzip").await;
+    assert_status(&mut result, 416);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Type", "text/plain;charset=utf-8"),
+            (
+                "Content-Range",
+                &format!("bytes */{}", meta_compressed.size),
+            ),
+            ("last-modified", meta_compressed.modified.as_ref().unwrap()),
+            ("etag", &meta_compressed.etag),
+            ("Content-Encoding", "gzip"),
+            ("vary", "Accept-Encoding"),
+        ],
+    );
+    assert_body(&result, "");
+
+    let mut result = request(&mut app, "/large_precompressed.txt", "unsupported").await;
+    assert_status(&mut result, 416);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("Content-Range", &format!("bytes */{}", meta.size)),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("vary", "Accept-Encoding"),
+        ],
+    );
+    assert_body(&result, "");
+}
+
+#[test(tokio::test)]
+async fn dynamic_compression() {
+    let meta = Metadata::from_path(&root_path("large.txt"), None).unwrap();
+    let mut app = make_app(extended_conf("compression_level_gzip: 3"));
+
+    // Regular request should result in compressed response
+    let mut session = make_session("GET", "/large.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", "gzip")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Encoding", "gzip"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("Transfer-Encoding", "chunked"),
+            ("vary", "Accept-Encoding"),
+        ],
+    );
+
+    // Request without matching encodings should result in uncompressed response
+    let mut session = make_session("GET", "/large.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", "unsupported")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("vary", "Accept-Encoding"),
+        ],
+    );
+
+    // We shouldn’t get ranged requests in practice but Pingora will compress even these responses.
+    let mut session = make_session("GET", "/large.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", "gzip")
+        .unwrap();
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=0-10000")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 206);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Encoding", "gzip"),
+            ("content-range", "bytes 0-10000/100001"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("Transfer-Encoding", "chunked"),
+            ("vary", "Accept-Encoding"),
+        ],
+    );
+}
+
+#[test(tokio::test)]
+async fn static_compression() {
+    let meta = Metadata::from_path(&root_path("large_precompressed.txt"), None).unwrap();
+    let meta_compressed =
+        Metadata::from_path(&root_path("large_precompressed.txt.gz"), None).unwrap();
+    let mut app = make_app(extended_conf("precompressed: [gz, br]"));
+
+    // Regular request should result in compressed response
+    let mut session = make_session("GET", "/large_precompressed.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", "br, gzip")
+        .unwrap();
+
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta_compressed.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", meta_compressed.modified.as_ref().unwrap()),
+            ("etag", &meta_compressed.etag),
+            ("Content-Encoding", "gzip"),
+            ("vary", "Accept-Encoding"),
+        ],
+    );
+
+    // Static compression should take precedence over dynamic
+    let mut session = make_session("GET", "/large_precompressed.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", "br, gzip")
+        .unwrap();
+
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta_compressed.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", meta_compressed.modified.as_ref().unwrap()),
+            ("etag", &meta_compressed.etag),
+            ("Content-Encoding", "gzip"),
+            ("vary", "Accept-Encoding"),
+        ],
+    );
+
+    // Request without matching encodings should result in uncompressed response
+    let mut session = make_session("GET", "/large_precompressed.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", "zstd")
+        .unwrap();
+
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("vary", "Accept-Encoding"),
+        ],
+    );
+
+    // Ranged response should be compressed
+    let mut session = make_session("GET", "/large_precompressed.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", "gzip")
+        .unwrap();
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=0-10")
+        .unwrap();
+
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+
+    assert_status(&mut result, 206);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", "11"),
+            (
+                "content-range",
+                &format!("bytes 0-10/{}", meta_compressed.size),
+            ),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", meta_compressed.modified.as_ref().unwrap()),
+            ("etag", &meta_compressed.etag),
+            ("Content-Encoding", "gzip"),
+            ("vary", "Accept-Encoding"),
+        ],
+    );
+}
+
+#[test(tokio::test)]
+async fn static_compression_metadata_cache() {
+    let mut app = make_app(extended_conf("precompressed: [gz]"));
+
+    async fn content_type(app: &mut DefaultApp<Handler>, path: &str, gzip: bool) -> String {
+        let mut session = make_session("GET", path).await;
+        if gzip {
+            session
+                .req_header_mut()
+                .insert_header("Accept-Encoding", "gzip")
+                .unwrap();
+        }
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_status(&mut result, 200);
+        let content_type = result
+            .session()
+            .response_written()
+            .unwrap()
+            .headers
+            .get("Content-Type")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned();
+        content_type
+    }
+
+    // Same file served both directly and as a pre-compressed variant
+    assert_eq!(
+        content_type(&mut app, "/preload/style.css.gz", false).await,
+        "application/gzip"
+    );
+    assert_eq!(
+        content_type(&mut app, "/preload/style.css", true).await,
+        "text/css;charset=utf-8"
+    );
+    assert_eq!(
+        content_type(&mut app, "/preload/style.css.gz", true).await,
+        "application/gzip"
+    );
+}
+
+#[test(tokio::test)]
+async fn compression_preference() {
+    async fn content_encoding(app: &mut DefaultApp<Handler>, encoding: &str) -> Option<String> {
+        let mut session = make_session("GET", "/preference/file.txt").await;
+        session
+            .req_header_mut()
+            .insert_header("Accept-Encoding", encoding)
+            .unwrap();
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_status(&mut result, 200);
+        let session = result.session();
+        session
+            .response_written()
+            .unwrap()
+            .headers
+            .get("Content-Encoding")
+            .map(|value| value.to_str().unwrap().to_owned())
+    }
+
+    // Without a preference the client’s order decides
+    let mut app = make_app(extended_conf("precompressed: [gz, br]"));
+    assert_eq!(
+        content_encoding(&mut app, "gzip, br").await.as_deref(),
+        Some("gzip")
+    );
+
+    let mut app = make_app(extended_conf(
+        "precompressed: [gz, br]\ncompression_preference: [br, gz]",
+    ));
+    assert_eq!(
+        content_encoding(&mut app, "gzip, br").await.as_deref(),
+        Some("br")
+    );
+    assert_eq!(content_encoding(&mut app, "*").await.as_deref(), Some("br"));
+
+    // Client’s quality values take precedence
+    assert_eq!(
+        content_encoding(&mut app, "gzip, br;q=0.8")
+            .await
+            .as_deref(),
+        Some("gzip")
+    );
+}
+
+#[test(tokio::test)]
+async fn static_compression_range_disabled() {
+    let meta = Metadata::from_path(&root_path("large_precompressed.txt"), None).unwrap();
+    let meta_compressed =
+        Metadata::from_path(&root_path("large_precompressed.txt.gz"), None).unwrap();
+    let mut app = make_app(extended_conf(
+        "precompressed: [gz, br]\nrange_precompressed: disabled",
+    ));
+
+    // Ranged request should result in the full compressed file
+    let mut session = make_session("GET", "/large_precompressed.txt").await;
+    session
+        .req_header_mut()
         .insert_header("Accept-Encoding", "gzip")
         .unwrap();
     session
@@ -1217,15 +2065,12 @@ async fn static_compression() {
     let mut result = app.handle_request(session).await;
     assert!(result.err().is_none());
 
-    assert_status(&mut result, 206);
+    assert_status(&mut result, 200);
     assert_headers(
         &mut result,
         vec![
-            ("Content-Length", "11"),
-            (
-                "content-range",
-                &format!("bytes 0-10/{}", meta_compressed.size),
-            ),
+            ("Content-Length", &meta_compressed.size.to_string()),
+            ("accept-ranges", "none"),
             ("Content-Type", "text/plain;charset=utf-8"),
             ("last-modified", meta_compressed.modified.as_ref().unwrap()),
             ("etag", &meta_compressed.etag),
@@ -1233,6 +2078,34 @@ async fn static_compression() {
             ("vary", "Accept-Encoding"),
         ],
     );
+    assert_eq!(
+        result.body(),
+        std::fs::read(root_path("large_precompressed.txt.gz")).unwrap()
+    );
+
+    // Ranges still apply to uncompressed responses
+    let mut session = make_session("GET", "/large_precompressed.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=0-10")
+        .unwrap();
+
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+
+    assert_status(&mut result, 206);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", "11"),
+            ("content-range", &format!("bytes 0-10/{}", meta.size)),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("vary", "Accept-Encoding"),
+        ],
+    );
+    assert_body(&result, "01234567890");
 }
 
 #[test(tokio::test)]
@@ -1297,4 +2170,459 @@ async fn charset() {
             ("etag", &meta.etag),
         ],
     );
+
+    // Charset removed for specific MIME types even if it would be declared by default
+    let mut app = make_app(extended_conf(
+        "declare_charset_types: '*'\nstrip_charset_types: [application/gzip, text/markdown]\nmime_types: {md: text/markdown;charset=windows-1252}",
+    ));
+    for (path, expected) in [
+        ("/large_precompressed.txt.gz", "application/gzip"),
+        ("/notes.md", "text/markdown"),
+        ("/file.txt", "text/plain;charset=utf-8"),
+    ] {
+        let session = make_session("GET", path).await;
+
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_status(&mut result, 200);
+        assert_eq!(
+            result
+                .session()
+                .response_written()
+                .unwrap()
+                .headers
+                .get("Content-Type")
+                .unwrap(),
+            expected
+        );
+    }
+}
+
+#[test(tokio::test)]
+async fn preload_manifest() {
+    fn header(result: &mut AppResult, name: &str) -> Option<String> {
+        result
+            .session()
+            .response_written()
+            .unwrap()
+            .headers
+            .get(name)
+            .map(|value| value.to_str().unwrap().to_owned())
+    }
+
+    let mut app = make_app(extended_conf("precompressed: gz\npreload_manifest: true"));
+
+    let mut session = make_session("GET", "/preload/page.html").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", "gzip")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_eq!(
+        header(&mut result, "Link").as_deref(),
+        Some("</preload/style.css>; rel=preload; as=style, </preload/app.js>; rel=preload; as=script")
+    );
+
+    // Preloaded resource resolves to the precompressed variant accepted by the client
+    let mut session = make_session("GET", "/preload/style.css").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", "gzip")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_eq!(
+        header(&mut result, "Content-Encoding").as_deref(),
+        Some("gzip")
+    );
+    assert_eq!(header(&mut result, "Link"), None);
+
+    // No preload headers unless enabled
+    let mut app = make_app(default_conf());
+    let session = make_session("GET", "/preload/page.html").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_eq!(header(&mut result, "Link"), None);
+}
+
+#[test(tokio::test)]
+async fn early_hints() {
+    let links =
+        "</preload/style.css>; rel=preload; as=style, </preload/app.js>; rel=preload; as=script";
+    let mut app = make_app(extended_conf(
+        "precompressed: gz\npreload_manifest: true\nearly_hints: true",
+    ));
+
+    let session = make_session("GET", "/preload/page.html").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    let hints = result.informational_responses();
+    assert_eq!(hints.len(), 1);
+    assert_eq!(hints[0].status.as_u16(), 103);
+    assert_eq!(hints[0].headers.get("Link").unwrap(), links);
+
+    // Final response still carries the links
+    assert_eq!(
+        result
+            .session()
+            .response_written()
+            .unwrap()
+            .headers
+            .get("Link")
+            .unwrap(),
+        links
+    );
+
+    // No hints for HEAD requests, HTTP/1.0 clients or pages without manifest
+    let session = make_session("HEAD", "/preload/page.html").await;
+    let result = app.handle_request(session).await;
+    assert!(result.informational_responses().is_empty());
+
+    let mut session = make_session("GET", "/preload/page.html").await;
+    session.req_header_mut().set_version(Version::HTTP_10);
+    let result = app.handle_request(session).await;
+    assert!(result.informational_responses().is_empty());
+
+    let session = make_session("GET", "/index.html").await;
+    let result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert!(result.informational_responses().is_empty());
+
+    // No hints unless enabled
+    let mut app = make_app(extended_conf("preload_manifest: true"));
+    let session = make_session("GET", "/preload/page.html").await;
+    let result = app.handle_request(session).await;
+    assert!(result.informational_responses().is_empty());
+}
+
+#[test(tokio::test)]
+async fn minify() {
+    const MINIFIED: &str = "<!DOCTYPE html>\n<html>\n<head>\n<title>Minified page</title>\n</head>\n<body>\n<p>Hi!</p>\n</body>\n</html>\n";
+
+    let mut meta = Metadata::from_path(&root_path("minify/page.html"), None).unwrap();
+    meta.set_transformed_size(MINIFIED.len() as u64);
+    let mut app = make_app(extended_conf("minify: [text/html, text/css]"));
+
+    // The ETag of the minified file is stable across requests
+    for _ in 0..2 {
+        let session = make_session("GET", "/minify/page.html").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_status(&mut result, 200);
+        assert_headers(
+            &mut result,
+            vec![
+                ("Content-Length", &MINIFIED.len().to_string()),
+                ("accept-ranges", "bytes"),
+                ("Content-Type", "text/html;charset=utf-8"),
+                ("last-modified", meta.modified.as_ref().unwrap()),
+                ("etag", &meta.etag),
+            ],
+        );
+        assert_body(&result, MINIFIED);
+    }
+
+    let mut session = make_session("GET", "/minify/page.html").await;
+    session
+        .req_header_mut()
+        .insert_header("If-None-Match", &meta.etag)
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 304);
+
+    // Ranges apply to the minified data
+    let mut session = make_session("GET", "/minify/page.html").await;
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=16-22")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 206);
+    assert_body(&result, "<html>\n");
+
+    // Files that appear minified already are served unchanged
+    let meta = Metadata::from_path(&root_path("minify/style.min.css"), None).unwrap();
+    let session = make_session("GET", "/minify/style.min.css").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/css;charset=utf-8"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+        ],
+    );
+    assert_body(&result, "p {\n  color: red;\n}\n");
+
+    // Minification might produce an empty file
+    let mut meta = Metadata::from_path(&root_path("minify/empty.html"), None).unwrap();
+    meta.set_transformed_size(0);
+    let session = make_session("GET", "/minify/empty.html").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", "0"),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/html;charset=utf-8"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+        ],
+    );
+    assert_body(&result, "");
+}
+
+#[test(tokio::test)]
+async fn empty_file() {
+    let meta = Metadata::from_path(&root_path("empty.txt"), None).unwrap();
+    let mut app = make_app(default_conf());
+    let session = make_session("GET", "/empty.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", "0"),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+        ],
+    );
+    assert_body(&result, "");
+}
+
+#[test(tokio::test)]
+async fn mime_types() {
+    let meta = Metadata::from_path(&root_path("notes.md"), None).unwrap();
+
+    let mut app = make_app(extended_conf(
+        "mime_types: {.md: text/markdown;charset=windows-1252}",
+    ));
+    let session = make_session("GET", "/notes.md").await;
+
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/markdown;charset=windows-1252"),
+            ("last-modified", &meta.modified.unwrap()),
+            ("etag", &meta.etag),
+        ],
+    );
+    assert_body(&result, "# Notes\n\nSome *Markdown* text.\n");
+
+    // Without a charset parameter the declare_charset setting applies
+    let mut app = make_app(extended_conf("mime_types: {MD: text/x-markdown}"));
+    let session = make_session("GET", "/notes.md").await;
+
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_eq!(
+        result
+            .session()
+            .response_written()
+            .unwrap()
+            .headers
+            .get("Content-Type")
+            .unwrap(),
+        "text/x-markdown;charset=utf-8"
+    );
+
+    // Invalid MIME types and charsets are rejected
+    for conf in [
+        "mime_types: {md: markdown}",
+        "mime_types: {md: 'text/markdown;charset=\"utf 8\"'}",
+    ] {
+        let conf = <Handler as RequestFilter>::Conf::from_yaml(extended_conf(conf)).unwrap();
+        assert!(Handler::try_from(conf).is_err());
+    }
+}
+
+#[test(tokio::test)]
+async fn content_negotiation() {
+    async fn request(app: &mut DefaultApp<Handler>, accept: &str) -> AppResult {
+        let mut session = make_session("GET", "/image.jpg").await;
+        session
+            .req_header_mut()
+            .insert_header("Accept", accept)
+            .unwrap();
+        let result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        result
+    }
+
+    let meta = Metadata::from_path(&root_path("image.jpg"), None).unwrap();
+    let meta_avif = Metadata::from_path(&root_path("image.jpg.avif"), None).unwrap();
+
+    let mut app = make_app(extended_conf("content_negotiation: {jpg: [avif, webp]}"));
+    let mut result = request(&mut app, "image/avif,image/webp,*/*").await;
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta_avif.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "image/avif"),
+            ("last-modified", meta_avif.modified.as_ref().unwrap()),
+            ("etag", &meta_avif.etag),
+            ("vary", "Accept"),
+        ],
+    );
+    assert_body(&result, "avif\n");
+
+    // Quality values take precedence over the configured order
+    let mut result = request(&mut app, "image/avif;q=0.5,image/webp").await;
+    assert_status(&mut result, 200);
+    assert_body(&result, "webp\n");
+
+    // Wildcards don’t select alternatives
+    let mut result = request(&mut app, "image/*,*/*;q=0.8").await;
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "image/jpeg"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("vary", "Accept"),
+        ],
+    );
+    assert_body(&result, "jpg\n");
+
+    // Alternatives missing on disk are skipped
+    let mut app = make_app(extended_conf("content_negotiation: {jpg: [jxl, webp]}"));
+    let mut result = request(&mut app, "image/jxl,image/webp").await;
+    assert_status(&mut result, 200);
+    assert_body(&result, "webp\n");
+
+    // Combined with compression, both headers are listed
+    let mut app = make_app(extended_conf(
+        "content_negotiation: {jpg: avif}\nprecompressed: gz",
+    ));
+    let mut result = request(&mut app, "image/avif").await;
+    assert_status(&mut result, 200);
+    assert_eq!(
+        result
+            .session()
+            .response_written()
+            .unwrap()
+            .headers
+            .get("Vary")
+            .unwrap(),
+        "Accept-Encoding, Accept"
+    );
+
+    let conf = <Handler as RequestFilter>::Conf::from_yaml(extended_conf(
+        "content_negotiation: {jpg: unknown_format}",
+    ))
+    .unwrap();
+    assert!(Handler::try_from(conf).is_err());
+}
+
+#[test(tokio::test)]
+async fn content_location() {
+    async fn location(app: &mut DefaultApp<Handler>, path: &str, accept: &str) -> Option<String> {
+        let mut session = make_session("GET", path).await;
+        session
+            .req_header_mut()
+            .insert_header("Accept", accept)
+            .unwrap();
+        session
+            .req_header_mut()
+            .insert_header("Accept-Encoding", "gzip")
+            .unwrap();
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_status(&mut result, 200);
+        let session = result.session();
+        session
+            .response_written()
+            .unwrap()
+            .headers
+            .get("Content-Location")
+            .map(|value| value.to_str().unwrap().to_owned())
+    }
+
+    let conf = "content_negotiation: {jpg: avif}\nprecompressed: gz";
+    let mut app = make_app(extended_conf(conf));
+    assert_eq!(location(&mut app, "/image.jpg", "image/avif").await, None);
+
+    let mut app = make_app(extended_conf(format!("{conf}\ncontent_location: true")));
+    assert_eq!(
+        location(&mut app, "/image.jpg", "image/avif")
+            .await
+            .as_deref(),
+        Some("/image.jpg.avif")
+    );
+    assert_eq!(
+        location(&mut app, "/large_precompressed.txt", "*/*")
+            .await
+            .as_deref(),
+        Some("/large_precompressed.txt.gz")
+    );
+
+    // No header if the requested file itself is served
+    assert_eq!(location(&mut app, "/image.jpg", "image/*").await, None);
+    assert_eq!(location(&mut app, "/file.txt", "*/*").await, None);
+}
+
+#[test(tokio::test)]
+async fn write_chunk_size() {
+    async fn chunk_sizes(app: &mut DefaultApp<Handler>) -> Vec<(usize, bool)> {
+        let session = make_session("GET", "/large.txt").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_status(&mut result, 200);
+        assert_eq!(result.body().len(), 100001);
+        result
+            .body_chunks()
+            .iter()
+            .map(|(chunk, end_of_stream)| (chunk.len(), *end_of_stream))
+            .collect()
+    }
+
+    let mut app = make_app(default_conf());
+    assert_eq!(
+        chunk_sizes(&mut app).await,
+        vec![(65536, false), (34465, false), (0, true)]
+    );
+
+    let mut app = make_app(extended_conf("write_chunk_size: 1048576"));
+    assert_eq!(
+        chunk_sizes(&mut app).await,
+        vec![(100001, false), (0, true)]
+    );
+
+    let mut app = make_app(extended_conf("write_chunk_size: 40000"));
+    assert_eq!(
+        chunk_sizes(&mut app).await,
+        vec![(40000, false), (40000, false), (20001, false), (0, true)]
+    );
+
+    let conf =
+        <Handler as RequestFilter>::Conf::from_yaml(extended_conf("write_chunk_size: 0")).unwrap();
+    assert!(Handler::try_from(conf).is_err());
 }
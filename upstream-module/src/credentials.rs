@@ -0,0 +1,93 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Removal of request credentials before forwarding requests to the upstream server
+
+use http::{header, HeaderValue};
+use pandora_module_utils::pingora::{AuthCredentials, Error, RequestHeader};
+use serde::Deserialize;
+
+/// Handling of request credentials when forwarding requests to the upstream server
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardCredentials {
+    /// Credentials consumed by Pandora’s own authentication are removed, others are forwarded
+    #[default]
+    StripOwn,
+    /// `Authorization` and `Cookie` headers are always removed
+    StripAll,
+    /// All credentials are forwarded unchanged
+    Forward,
+}
+
+/// Removes the cookie with the given name from the request’s `Cookie` headers. Headers left
+/// without any cookies are removed.
+fn remove_cookie(header: &mut RequestHeader, name: &str) -> Result<(), Box<Error>> {
+    let mut values = Vec::new();
+    for value in header.headers.get_all(header::COOKIE) {
+        let Ok(value) = value.to_str() else {
+            // The authentication handler ignores such values as well, nothing to remove here
+            values.push(value.clone());
+            continue;
+        };
+
+        let value = value
+            .split(';')
+            .map(str::trim)
+            .filter(|pair| {
+                !pair.is_empty() && pair.split('=').next().unwrap_or_default().trim() != name
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        if !value.is_empty() {
+            // Parts of a valid header value are always a valid header value
+            values.push(HeaderValue::from_str(&value).unwrap());
+        }
+    }
+
+    header.remove_header(&header::COOKIE);
+    for value in values {
+        header.append_header(header::COOKIE, value)?;
+    }
+    Ok(())
+}
+
+/// Removes request credentials according to the configured mode and adds the configured
+/// `Authorization` header if any.
+pub(crate) fn apply(
+    header: &mut RequestHeader,
+    mode: ForwardCredentials,
+    consumed: Option<&AuthCredentials>,
+    authorization: Option<&HeaderValue>,
+) -> Result<(), Box<Error>> {
+    match mode {
+        ForwardCredentials::StripOwn => match consumed {
+            Some(AuthCredentials::Header(name)) => {
+                header.remove_header(name);
+            }
+            Some(AuthCredentials::Cookie(name)) => remove_cookie(header, name)?,
+            None => {}
+        },
+        ForwardCredentials::StripAll => {
+            header.remove_header(&header::AUTHORIZATION);
+            header.remove_header(&header::COOKIE);
+        }
+        ForwardCredentials::Forward => {}
+    }
+
+    if let Some(authorization) = authorization {
+        header.insert_header(header::AUTHORIZATION, authorization)?;
+    }
+    Ok(())
+}
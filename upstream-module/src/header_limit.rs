@@ -0,0 +1,156 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Size limit for the response headers received from the upstream server
+
+use async_trait::async_trait;
+use http::{header, HeaderName, HeaderValue};
+use log::{debug, warn};
+use pandora_module_utils::pingora::{
+    Error, ErrorType, HttpModule, HttpModuleBuilder, ResponseHeader,
+};
+use serde::Deserialize;
+use std::any::Any;
+use std::cmp::Reverse;
+
+/// Headers that are never modified because the response framing depends on them
+const PROTECTED_HEADERS: &[HeaderName] = &[header::CONTENT_LENGTH, header::TRANSFER_ENCODING];
+
+/// Handling of upstream responses with headers exceeding the size limit
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OversizedHeaders {
+    /// The response is discarded, the client receives a `502 Bad Gateway` response instead
+    #[default]
+    Reject,
+    /// Values of the largest headers are shortened until the limit is met
+    Truncate,
+    /// The largest headers are removed until the limit is met
+    Drop,
+}
+
+/// Size of a header line as sent via HTTP/1, e.g. `Name: value\r\n`
+fn entry_size(name: &HeaderName, value: &HeaderValue) -> usize {
+    name.as_str().len() + value.len() + 4
+}
+
+/// Makes sure that the response headers don’t exceed the given size limit, either by modifying
+/// the headers or by producing an error.
+fn enforce_limit(
+    resp: &mut ResponseHeader,
+    limit: usize,
+    action: OversizedHeaders,
+) -> Result<(), Box<Error>> {
+    let mut entries = resp
+        .headers
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect::<Vec<_>>();
+    let mut size = entries
+        .iter()
+        .map(|(name, value)| entry_size(name, value))
+        .sum::<usize>();
+    if size <= limit {
+        return Ok(());
+    }
+
+    warn!("upstream response headers of {size} bytes exceed the limit of {limit} bytes, handling: {action:?}");
+    if action == OversizedHeaders::Reject {
+        return Err(Error::explain(
+            ErrorType::HTTPStatus(502),
+            "upstream response headers too large",
+        ));
+    }
+
+    let mut candidates = (0..entries.len())
+        .filter(|&i| !PROTECTED_HEADERS.contains(&entries[i].0))
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|&i| Reverse(entry_size(&entries[i].0, &entries[i].1)));
+
+    let mut removed = vec![false; entries.len()];
+    let mut modified = Vec::new();
+    for i in candidates {
+        if size <= limit {
+            break;
+        }
+
+        let (name, value) = &mut entries[i];
+        let excess = size - limit;
+        let truncated = if action == OversizedHeaders::Truncate && value.len() > excess {
+            HeaderValue::from_bytes(&value.as_bytes()[..value.len() - excess]).ok()
+        } else {
+            None
+        };
+
+        if let Some(truncated) = truncated {
+            debug!("truncating upstream response header {name}");
+            *value = truncated;
+            size -= excess;
+        } else {
+            debug!("dropping upstream response header {name}");
+            removed[i] = true;
+            size -= entry_size(name, value);
+        }
+
+        if !modified.contains(name) {
+            modified.push(name.clone());
+        }
+    }
+
+    for name in &modified {
+        resp.remove_header(name);
+    }
+    for (i, (name, value)) in entries.into_iter().enumerate() {
+        if !removed[i] && modified.contains(&name) {
+            resp.append_header(name, value)?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) struct HeaderLimitHttpModuleBuilder {}
+
+impl HttpModuleBuilder for HeaderLimitHttpModuleBuilder {
+    fn init(&self) -> Box<dyn HttpModule + Sync + Send> {
+        Box::new(HeaderLimitHttpModule { limit: None })
+    }
+}
+
+/// Downstream module checking the size of upstream response headers, only active for requests
+/// forwarded by the upstream handler.
+pub(crate) struct HeaderLimitHttpModule {
+    pub(crate) limit: Option<(usize, OversizedHeaders)>,
+}
+
+#[async_trait]
+impl HttpModule for HeaderLimitHttpModule {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    async fn response_header_filter(
+        &mut self,
+        resp: &mut ResponseHeader,
+        _end_of_stream: bool,
+    ) -> Result<(), Box<Error>> {
+        if let Some((limit, action)) = self.limit {
+            enforce_limit(resp, limit, action)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Removal of hop-by-hop headers, see RFC 7230 section 6.1
+
+use async_trait::async_trait;
+use http::{header, HeaderMap, HeaderName};
+use log::{info, trace};
+use pandora_module_utils::pingora::{
+    Error, HttpModule, HttpModuleBuilder, RequestHeader, ResponseHeader,
+};
+use std::any::Any;
+
+/// Headers that only apply to a single connection, regardless of whether `Connection` lists them
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "upgrade",
+];
+
+/// Headers that are never removed, even if `Connection` lists them. Pingora takes care of the
+/// message framing, and the upstream handler sets `Host`.
+const PROTECTED_HEADERS: &[HeaderName] = &[
+    header::CONTENT_LENGTH,
+    header::TRANSFER_ENCODING,
+    header::HOST,
+];
+
+/// Lists the options in the `Connection` header, these are header names or keywords like `close`.
+fn connection_options(headers: &HeaderMap) -> Vec<HeaderName> {
+    headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+        .filter_map(|option| HeaderName::from_bytes(option.trim().as_bytes()).ok())
+        .collect()
+}
+
+/// Determines the hop-by-hop headers present. With `upgrade` being `true` the `Upgrade` header is
+/// kept since the connection switches protocols.
+fn hop_by_hop_headers(headers: &HeaderMap, upgrade: bool) -> Vec<HeaderName> {
+    let mut result = Vec::new();
+    for name in HOP_BY_HOP_HEADERS
+        .iter()
+        .map(|name| HeaderName::from_static(name))
+        .chain(connection_options(headers))
+    {
+        if headers.contains_key(&name)
+            && !PROTECTED_HEADERS.contains(&name)
+            && (!upgrade || name != header::UPGRADE)
+            && !result.contains(&name)
+        {
+            result.push(name);
+        }
+    }
+    result
+}
+
+fn log_stripped(name: &HeaderName, message: &str, log: bool) {
+    if log {
+        info!("stripping hop-by-hop header {name} from {message}");
+    } else {
+        trace!("stripping hop-by-hop header {name} from {message}");
+    }
+}
+
+/// Removes hop-by-hop headers from the request before it is forwarded to the upstream server.
+///
+/// Protocol upgrades like WebSocket are preserved: if `Connection` lists `upgrade`, both the
+/// `Upgrade` header and `Connection: upgrade` are passed on.
+pub(crate) fn strip_request(header: &mut RequestHeader, log: bool) -> Result<(), Box<Error>> {
+    let upgrade = header.headers.contains_key(header::UPGRADE)
+        && connection_options(&header.headers).contains(&header::UPGRADE);
+
+    for name in hop_by_hop_headers(&header.headers, upgrade) {
+        log_stripped(&name, "upstream request", log);
+        header.remove_header(&name);
+    }
+
+    if upgrade {
+        header.insert_header(header::CONNECTION, "upgrade")?;
+    }
+    Ok(())
+}
+
+/// Removes hop-by-hop headers from the upstream response. For `101 Switching Protocols` responses
+/// `Upgrade` and `Connection: upgrade` are kept.
+fn strip_response(header: &mut ResponseHeader, log: bool) -> Result<(), Box<Error>> {
+    let upgrade = header.status.as_u16() == 101;
+
+    for name in hop_by_hop_headers(&header.headers, upgrade) {
+        log_stripped(&name, "upstream response", log);
+        header.remove_header(&name);
+    }
+
+    if upgrade {
+        header.insert_header(header::CONNECTION, "upgrade")?;
+    }
+    Ok(())
+}
+
+pub(crate) struct HopByHopHttpModuleBuilder {}
+
+impl HttpModuleBuilder for HopByHopHttpModuleBuilder {
+    fn init(&self) -> Box<dyn HttpModule + Sync + Send> {
+        Box::new(HopByHopHttpModule { active: None })
+    }
+}
+
+/// Downstream module removing hop-by-hop headers from upstream responses, only active for
+/// requests forwarded by the upstream handler. The value determines whether removed headers
+/// should be logged.
+pub(crate) struct HopByHopHttpModule {
+    pub(crate) active: Option<bool>,
+}
+
+#[async_trait]
+impl HttpModule for HopByHopHttpModule {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    async fn response_header_filter(
+        &mut self,
+        resp: &mut ResponseHeader,
+        _end_of_stream: bool,
+    ) -> Result<(), Box<Error>> {
+        if let Some(log) = self.active {
+            strip_response(resp, log)?;
+        }
+        Ok(())
+    }
+}
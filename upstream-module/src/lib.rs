@@ -15,23 +15,103 @@
 #![doc = include_str!("../README.md")]
 
 use async_trait::async_trait;
-use clap::{value_parser, Parser};
-use http::header;
+use clap::Parser;
 use http::uri::{Scheme, Uri};
-use log::error;
-use pandora_module_utils::pingora::{Error, ErrorType, HttpPeer, SessionWrapper};
+use http::{header, HeaderName, HeaderValue, Method, StatusCode, Version};
+use log::{debug, error, warn};
+use pandora_module_utils::pingora::{
+    add_module_once, Error, ErrorSource, ErrorType, HeaderMap, HttpModules, HttpPeer,
+    RequestHeader, SessionWrapper,
+};
+use pandora_module_utils::standard_response::error_response;
 use pandora_module_utils::{DeserializeMap, RequestFilter, RequestFilterResult};
 use serde::de::{Deserializer, Error as _};
-use serde::Deserialize as _;
-use std::net::{SocketAddr, ToSocketAddrs};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+mod credentials;
+mod header_limit;
+mod hop_by_hop;
+mod resolver;
+
+pub use credentials::ForwardCredentials;
+pub use header_limit::OversizedHeaders;
+use header_limit::{HeaderLimitHttpModule, HeaderLimitHttpModuleBuilder};
+use hop_by_hop::{HopByHopHttpModule, HopByHopHttpModuleBuilder};
+use resolver::{resolve_non_empty, ResolvedAddrs};
+pub use resolver::{IpVersion, Resolver, SystemResolver};
+
+/// Casing of the request header names forwarded to the upstream server
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderCase {
+    /// Header names are forwarded with the casing received from the client
+    #[default]
+    Preserve,
+    /// Header names are converted to title case like `X-My-Header`
+    TitleCase,
+}
+
+/// Converts a header name like `x-my-header` into title case: `X-My-Header`.
+fn title_case(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    let mut capitalize = true;
+    for c in name.chars() {
+        result.push(if capitalize {
+            c.to_ascii_uppercase()
+        } else {
+            c
+        });
+        capitalize = c == '-';
+    }
+    result
+}
+
+/// Connection errors that trigger a retry of the upstream request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryCondition {
+    /// The upstream server refused the connection or could not be reached
+    Refused,
+    /// Connecting to the upstream server or the TLS handshake timed out
+    Timeout,
+    /// Any other error while establishing the connection, e.g. a TLS handshake failure
+    Error,
+}
+
+impl RetryCondition {
+    /// Checks whether a connection error falls under this condition.
+    fn matches(&self, etype: &ErrorType) -> bool {
+        match self {
+            Self::Refused => matches!(etype, ErrorType::ConnectRefused | ErrorType::ConnectNoRoute),
+            Self::Timeout => matches!(
+                etype,
+                ErrorType::ConnectTimedout | ErrorType::TLSHandshakeTimedout
+            ),
+            Self::Error => !Self::Refused.matches(etype) && !Self::Timeout.matches(etype),
+        }
+    }
+}
 
 /// Command line options of the compression module
 #[derive(Debug, Default, Parser)]
 pub struct UpstreamOpt {
     /// http:// or https:// URL identifying the server that requests should be forwarded for.
     /// Path and query parts of the URL have no effect.
-    #[clap(long, value_parser = value_parser!(String))]
+    #[clap(long)]
     pub upstream: Option<Uri>,
+
+    /// Interval in seconds after which the upstream host name should be resolved again. By
+    /// default, the host name is only resolved once on startup.
+    #[clap(long)]
+    pub upstream_dns_ttl: Option<u64>,
+
+    /// Allow HTTP/2 for connections to an https:// upstream server. This is required for
+    /// response trailers to be forwarded.
+    #[clap(long)]
+    pub upstream_http2: bool,
 }
 
 fn deserialize_uri<'de, D>(d: D) -> Result<Option<Uri>, D::Error>
@@ -46,12 +126,115 @@ where
 }
 
 /// Configuration settings of the compression module
-#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct UpstreamConf {
     /// http:// or https:// URL identifying the server that requests should be forwarded for.
     /// Path and query parts of the URL have no effect.
     #[pandora(deserialize_with = "deserialize_uri")]
     pub upstream: Option<Uri>,
+
+    /// Interval in seconds after which the upstream host name should be resolved again. By
+    /// default, the host name is only resolved once on startup.
+    pub upstream_dns_ttl: Option<u64>,
+
+    /// IP protocol version of the upstream addresses to be used, one of `any` (default), `ipv4`
+    /// or `ipv6`. This is relevant if the upstream host name resolves to both IPv4 and IPv6
+    /// addresses.
+    pub upstream_ip_version: IpVersion,
+
+    /// If `true`, HTTP/2 will be negotiated for connections to an https:// upstream server. This
+    /// is required for response trailers to be forwarded.
+    pub upstream_http2: bool,
+
+    /// If `true` (default), response trailers received from the upstream server are forwarded to
+    /// the client and a `TE: trailers` request header is passed on to the upstream server.
+    pub upstream_trailers: bool,
+
+    /// HTTP status code to respond with if the upstream server refused the connection or could
+    /// not be reached.
+    pub upstream_refused_status: u16,
+
+    /// HTTP status code to respond with if connecting to the upstream server or waiting for its
+    /// response timed out.
+    pub upstream_timeout_status: u16,
+
+    /// Extension methods like `PROPFIND` that should be forwarded in addition to the standard
+    /// HTTP methods. Matching is case-insensitive, the method is normalized to the configured
+    /// spelling. If set, requests with other methods are rejected.
+    pub upstream_extension_methods: Vec<String>,
+
+    /// Casing of the header names in requests forwarded to the upstream server, one of
+    /// `preserve` (default) or `title_case`.
+    pub upstream_header_case: HeaderCase,
+
+    /// Maximum size in bytes of the response headers received from the upstream server, counting
+    /// names and values of all headers. By default, there is no limit beyond the one enforced by
+    /// Pingora.
+    pub upstream_header_size_limit: Option<usize>,
+
+    /// Handling of upstream responses exceeding `upstream_header_size_limit`, one of `reject`
+    /// (default), `truncate` or `drop`.
+    pub upstream_oversized_headers: OversizedHeaders,
+
+    /// Handling of request credentials, one of `strip_own` (default), `strip_all` or `forward`.
+    /// By default, the `Authorization` header or cookie used by Pandora’s own authentication is
+    /// removed. `strip_all` removes all `Authorization` and `Cookie` headers, `forward` passes
+    /// everything on for upstream servers which need the credentials.
+    pub upstream_credentials: ForwardCredentials,
+
+    /// Value of the `Authorization` header to be sent to the upstream server, replacing any
+    /// `Authorization` header sent by the client.
+    pub upstream_authorization: Option<String>,
+
+    /// Number of times a failed connection attempt to the upstream server is retried, with the
+    /// next address if the host name resolved to multiple addresses. Retries are disabled by
+    /// default.
+    pub upstream_retries: usize,
+
+    /// Connection errors that should be retried, any of `refused`, `timeout` and `error`. If
+    /// empty (default), `refused` and `timeout` errors are retried.
+    pub upstream_retry_on: Vec<RetryCondition>,
+
+    /// If `true`, requests with non-idempotent methods like `POST` are retried as well.
+    pub upstream_retry_non_idempotent: bool,
+
+    /// If `true`, hop-by-hop headers removed from requests and responses are logged. This is
+    /// meant for debugging.
+    pub upstream_log_stripped_headers: bool,
+
+    /// Names of the request headers to be forwarded to the upstream server, matched
+    /// case-insensitively. If set, all other request headers are removed.
+    pub upstream_forward_headers_allowlist: Vec<String>,
+
+    /// Names of the request headers that should not be forwarded to the upstream server, matched
+    /// case-insensitively.
+    pub upstream_forward_headers_denylist: Vec<String>,
+}
+
+impl Default for UpstreamConf {
+    fn default() -> Self {
+        Self {
+            upstream: None,
+            upstream_dns_ttl: None,
+            upstream_ip_version: IpVersion::Any,
+            upstream_http2: false,
+            upstream_trailers: true,
+            upstream_refused_status: 502,
+            upstream_timeout_status: 504,
+            upstream_extension_methods: Vec::new(),
+            upstream_header_case: HeaderCase::Preserve,
+            upstream_header_size_limit: None,
+            upstream_oversized_headers: OversizedHeaders::Reject,
+            upstream_credentials: ForwardCredentials::StripOwn,
+            upstream_authorization: None,
+            upstream_retries: 0,
+            upstream_retry_on: Vec::new(),
+            upstream_retry_non_idempotent: false,
+            upstream_log_stripped_headers: false,
+            upstream_forward_headers_allowlist: Vec::new(),
+            upstream_forward_headers_denylist: Vec::new(),
+        }
+    }
 }
 
 impl UpstreamConf {
@@ -61,6 +244,14 @@ impl UpstreamConf {
         if opt.upstream.is_some() {
             self.upstream = opt.upstream;
         }
+
+        if opt.upstream_dns_ttl.is_some() {
+            self.upstream_dns_ttl = opt.upstream_dns_ttl;
+        }
+
+        if opt.upstream_http2 {
+            self.upstream_http2 = true;
+        }
     }
 }
 
@@ -69,20 +260,106 @@ impl UpstreamConf {
 pub struct UpstreamContext {
     addr: SocketAddr,
     tls: bool,
+    http2: bool,
+    trailers: bool,
+    sni: String,
+    retries: usize,
+}
+
+/// Upstream server data
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Upstream {
+    addrs: Arc<ResolvedAddrs>,
+    tls: bool,
+    http2: bool,
+    trailers: bool,
     sni: String,
+    refused_status: StatusCode,
+    timeout_status: StatusCode,
+    extension_methods: Vec<Method>,
+    header_case: HeaderCase,
+    header_limit: Option<(usize, OversizedHeaders)>,
+    credentials: ForwardCredentials,
+    authorization: Option<HeaderValue>,
+    retries: usize,
+    retry_on: Vec<RetryCondition>,
+    retry_non_idempotent: bool,
+    log_stripped_headers: bool,
+    forward_headers_allowlist: Vec<HeaderName>,
+    forward_headers_denylist: Vec<HeaderName>,
+}
+
+/// Checks whether a request method is one of the methods defined by the HTTP specification.
+/// Method names are case-sensitive, so `get` is an extension method rather than `GET`.
+fn is_standard_method(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET
+            | Method::HEAD
+            | Method::POST
+            | Method::PUT
+            | Method::DELETE
+            | Method::CONNECT
+            | Method::OPTIONS
+            | Method::TRACE
+            | Method::PATCH
+    )
+}
+
+/// Checks whether the client waits for a `100 Continue` response before sending the request body.
+/// HTTP/1.0 clients cannot process interim responses, their expectations are ignored.
+fn expects_continue(header: &RequestHeader) -> bool {
+    header.version > Version::HTTP_10
+        && header
+            .headers
+            .get(header::EXPECT)
+            .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+}
+
+/// Checks whether the client accepts response trailers, meaning that the `TE` header lists the
+/// `trailers` value.
+fn accepts_trailers(header: &RequestHeader) -> bool {
+    header.headers.get_all(header::TE).iter().any(|value| {
+        value.as_bytes().split(|c| *c == b',').any(|entry| {
+            let coding = entry.split(|c| *c == b';').next().unwrap_or_default();
+            String::from_utf8_lossy(coding)
+                .trim()
+                .eq_ignore_ascii_case("trailers")
+        })
+    })
+}
+
+/// Removes request headers that aren't on the allowlist (if not empty) or are on the denylist.
+/// The headers responsible for message framing are always kept.
+fn filter_headers(header: &mut RequestHeader, allowlist: &[HeaderName], denylist: &[HeaderName]) {
+    let removed = header
+        .headers
+        .keys()
+        .filter(|name| **name != header::CONTENT_LENGTH && **name != header::TRANSFER_ENCODING)
+        .filter(|name| {
+            (!allowlist.is_empty() && !allowlist.contains(name)) || denylist.contains(name)
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    for name in removed {
+        header.remove_header(&name);
+    }
 }
 
 /// Upstream module handler
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UpstreamHandler {
     host_port: String,
-    context: Option<UpstreamContext>,
+    upstream: Option<Upstream>,
 }
 
-impl TryFrom<UpstreamConf> for UpstreamHandler {
-    type Error = Box<Error>;
-
-    fn try_from(conf: UpstreamConf) -> Result<Self, Self::Error> {
+impl UpstreamHandler {
+    /// Creates a new handler from the configuration, using the given resolver to look up the
+    /// upstream host name.
+    pub fn with_resolver(
+        conf: UpstreamConf,
+        resolver: Arc<dyn Resolver>,
+    ) -> Result<Self, Box<Error>> {
         if let Some(upstream) = conf.upstream {
             let scheme = upstream.scheme().ok_or_else(|| {
                 error!("provided upstream URL has no scheme: {upstream}");
@@ -103,19 +380,80 @@ impl TryFrom<UpstreamConf> for UpstreamHandler {
                 Error::new(ErrorType::InternalError)
             })?;
 
+            let status = |status| {
+                StatusCode::from_u16(status).map_err(|_| {
+                    error!("invalid HTTP status code {status} configured for upstream errors");
+                    Error::new(ErrorType::InternalError)
+                })
+            };
+            let refused_status = status(conf.upstream_refused_status)?;
+            let timeout_status = status(conf.upstream_timeout_status)?;
+
+            let extension_methods = conf
+                .upstream_extension_methods
+                .iter()
+                .map(|method| {
+                    Method::from_bytes(method.as_bytes()).map_err(|_| {
+                        error!(
+                            "invalid HTTP method {method} configured in upstream_extension_methods"
+                        );
+                        Error::new(ErrorType::InternalError)
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+
+            let authorization = conf
+                .upstream_authorization
+                .as_deref()
+                .map(|value| {
+                    HeaderValue::from_str(value).map_err(|err| {
+                        error!("invalid value configured in upstream_authorization: {err}");
+                        Error::new(ErrorType::InternalError)
+                    })
+                })
+                .transpose()?;
+
+            let header_names = |names: &[String], setting| {
+                names
+                    .iter()
+                    .map(|name| {
+                        HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+                            error!("invalid header name {name} configured in {setting}");
+                            Error::new(ErrorType::InternalError)
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            };
+            let forward_headers_allowlist = header_names(
+                &conf.upstream_forward_headers_allowlist,
+                "upstream_forward_headers_allowlist",
+            )?;
+            let forward_headers_denylist = header_names(
+                &conf.upstream_forward_headers_denylist,
+                "upstream_forward_headers_denylist",
+            )?;
+
+            let retry_on = if conf.upstream_retry_on.is_empty() {
+                vec![RetryCondition::Refused, RetryCondition::Timeout]
+            } else {
+                conf.upstream_retry_on
+            };
+
             let port = upstream.port_u16().unwrap_or(if tls { 443 } else { 80 });
 
-            let addr = (host, port)
-                .to_socket_addrs()
-                .map_err(|err| {
+            let ip_version = conf.upstream_ip_version;
+            let addrs =
+                resolve_non_empty(resolver.as_ref(), host, port, ip_version).map_err(|err| {
                     error!("failed resolving upstream host name {host}: {err}");
                     Error::new(ErrorType::InternalError)
-                })?
-                .next()
-                .ok_or_else(|| {
-                    error!("DNS lookup of upstream host name {host} didn't produce any results");
-                    Error::new(ErrorType::InternalError)
                 })?;
+            if conf.upstream_dns_ttl == Some(0) {
+                error!("upstream_dns_ttl has to be positive");
+                return Err(Error::new(ErrorType::InternalError));
+            }
+            let refresh = conf
+                .upstream_dns_ttl
+                .map(|ttl| (resolver, Duration::from_secs(ttl)));
 
             let mut host_port = host.to_owned();
             if let Some(port) = upstream.port() {
@@ -125,21 +463,46 @@ impl TryFrom<UpstreamConf> for UpstreamHandler {
 
             Ok(Self {
                 host_port,
-                context: Some(UpstreamContext {
+                upstream: Some(Upstream {
+                    addrs: Arc::new(ResolvedAddrs::new(host, port, ip_version, addrs, refresh)),
                     tls,
-                    addr,
+                    http2: conf.upstream_http2,
+                    trailers: conf.upstream_trailers,
                     sni: host.to_owned(),
+                    refused_status,
+                    timeout_status,
+                    extension_methods,
+                    header_case: conf.upstream_header_case,
+                    header_limit: conf
+                        .upstream_header_size_limit
+                        .map(|limit| (limit, conf.upstream_oversized_headers)),
+                    credentials: conf.upstream_credentials,
+                    authorization,
+                    retries: conf.upstream_retries,
+                    retry_on,
+                    retry_non_idempotent: conf.upstream_retry_non_idempotent,
+                    log_stripped_headers: conf.upstream_log_stripped_headers,
+                    forward_headers_allowlist,
+                    forward_headers_denylist,
                 }),
             })
         } else {
             Ok(Self {
                 host_port: Default::default(),
-                context: None,
+                upstream: None,
             })
         }
     }
 }
 
+impl TryFrom<UpstreamConf> for UpstreamHandler {
+    type Error = Box<Error>;
+
+    fn try_from(conf: UpstreamConf) -> Result<Self, Self::Error> {
+        Self::with_resolver(conf, Arc::new(SystemResolver))
+    }
+}
+
 #[async_trait]
 impl RequestFilter for UpstreamHandler {
     type Conf = UpstreamConf;
@@ -148,17 +511,109 @@ impl RequestFilter for UpstreamHandler {
         None
     }
 
+    fn init_downstream_modules(modules: &mut HttpModules) {
+        modules.add_module(Box::new(HeaderLimitHttpModuleBuilder {}));
+        // The upstream handler can be configured both globally and per host
+        add_module_once(modules, Box::new(HopByHopHttpModuleBuilder {}));
+    }
+
     async fn request_filter(
         &self,
         session: &mut impl SessionWrapper,
         ctx: &mut Self::CTX,
     ) -> Result<RequestFilterResult, Box<Error>> {
-        if let Some(context) = &self.context {
+        if let Some(upstream) = &self.upstream {
+            let method = &session.req_header().method;
+            if !upstream.extension_methods.is_empty() && !is_standard_method(method) {
+                if let Some(normalized) = upstream
+                    .extension_methods
+                    .iter()
+                    .find(|m| m.as_str().eq_ignore_ascii_case(method.as_str()))
+                {
+                    let normalized = normalized.clone();
+                    session.req_header_mut().set_method(normalized);
+                } else {
+                    warn!("Denying method {method}");
+                    error_response(session, StatusCode::METHOD_NOT_ALLOWED).await?;
+                    return Ok(RequestFilterResult::ResponseSent);
+                }
+            }
+
+            if upstream.header_case == HeaderCase::TitleCase {
+                // Rebuild the header, HTTP/2 requests don't keep header name casing at all
+                let header = session.req_header();
+                let mut titled = RequestHeader::build(
+                    header.method.clone(),
+                    header.raw_path(),
+                    Some(header.headers.len()),
+                )?;
+                titled.set_uri(header.uri.clone());
+                titled.set_version(header.version);
+                for (name, value) in &header.headers {
+                    titled.append_header(title_case(name.as_str()), value.clone())?;
+                }
+                *session.req_header_mut() = titled;
+            }
+
+            filter_headers(
+                session.req_header_mut(),
+                &upstream.forward_headers_allowlist,
+                &upstream.forward_headers_denylist,
+            );
+
             session
                 .req_header_mut()
                 .insert_header(header::HOST, &self.host_port)?;
 
-            *ctx = Some(context.clone());
+            // `TE` is a hop-by-hop header. Transfer codings other than `trailers` aren't supported
+            // by Pingora, HTTP/2 doesn't even allow any other value.
+            let te = accepts_trailers(session.req_header());
+            hop_by_hop::strip_request(session.req_header_mut(), upstream.log_stripped_headers)?;
+            if te && upstream.trailers {
+                session
+                    .req_header_mut()
+                    .insert_header(header::TE, "trailers")?;
+            }
+
+            let consumed = session.auth_credentials().cloned();
+            credentials::apply(
+                session.req_header_mut(),
+                upstream.credentials,
+                consumed.as_ref(),
+                upstream.authorization.as_ref(),
+            )?;
+
+            // Handlers running earlier had their chance to reject the request, so the client can
+            // send the body now. The expectation is fulfilled here rather than by the upstream
+            // server since Pingora starts reading the request body right away.
+            if expects_continue(session.req_header()) {
+                session.req_header_mut().remove_header(&header::EXPECT);
+                session.write_continue_response().await?;
+            }
+
+            if let Some(module) = session
+                .downstream_modules_ctx
+                .get_mut::<HopByHopHttpModule>()
+            {
+                module.active = Some(upstream.log_stripped_headers);
+            }
+
+            if upstream.header_limit.is_some() {
+                session
+                    .downstream_modules_ctx
+                    .get_mut::<HeaderLimitHttpModule>()
+                    .unwrap()
+                    .limit = upstream.header_limit;
+            }
+
+            *ctx = Some(UpstreamContext {
+                addr: upstream.addrs.select(),
+                tls: upstream.tls,
+                http2: upstream.http2,
+                trailers: upstream.trailers,
+                sni: upstream.sni.clone(),
+                retries: 0,
+            });
 
             Ok(RequestFilterResult::Handled)
         } else {
@@ -172,28 +627,113 @@ impl RequestFilter for UpstreamHandler {
         ctx: &mut Self::CTX,
     ) -> Result<Option<Box<HttpPeer>>, Box<Error>> {
         if let Some(context) = ctx {
-            Ok(Some(Box::new(HttpPeer::new(
-                context.addr,
-                context.tls,
-                context.sni.clone(),
-            ))))
+            let mut peer = HttpPeer::new(context.addr, context.tls, context.sni.clone());
+            if context.http2 {
+                peer.options.set_http_version(2, 1);
+            }
+            Ok(Some(Box::new(peer)))
         } else {
             Ok(None)
         }
     }
+
+    fn upstream_response_trailer_filter(
+        &self,
+        _session: &mut impl SessionWrapper,
+        trailers: &mut HeaderMap,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        if ctx.as_ref().is_some_and(|context| !context.trailers) {
+            debug!("dropping upstream response trailers: {trailers:?}");
+            trailers.clear();
+        }
+        Ok(())
+    }
+
+    fn fail_to_connect(
+        &self,
+        session: &mut impl SessionWrapper,
+        peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<Error>,
+    ) -> Box<Error> {
+        let (Some(upstream), Some(context)) = (&self.upstream, ctx) else {
+            return e;
+        };
+
+        if context.retries >= upstream.retries
+            || !upstream.retry_on.iter().any(|c| c.matches(e.etype()))
+        {
+            return e;
+        }
+
+        let method = &session.req_header().method;
+        if !upstream.retry_non_idempotent && !method.is_idempotent() {
+            debug!("not retrying {method} request after upstream error");
+            return e;
+        }
+
+        context.retries += 1;
+        context.addr = upstream.addrs.select();
+        debug!(
+            "connecting to upstream peer {peer} failed with {:?}, retry {} using address {}",
+            e.etype(),
+            context.retries,
+            context.addr
+        );
+        e.set_retry(true);
+        e
+    }
+
+    async fn fail_to_proxy(
+        &self,
+        session: &mut impl SessionWrapper,
+        e: &Error,
+        ctx: &mut Self::CTX,
+    ) -> Option<u16> {
+        // Only handle errors of requests forwarded by this handler
+        ctx.as_ref()?;
+        let upstream = self.upstream.as_ref()?;
+
+        // Too late to respond if the upstream response is already being forwarded
+        if e.esource() != &ErrorSource::Upstream || session.response_written().is_some() {
+            return None;
+        }
+
+        let status = match e.etype() {
+            ErrorType::ConnectRefused | ErrorType::ConnectNoRoute => upstream.refused_status,
+            ErrorType::ConnectTimedout
+            | ErrorType::TLSHandshakeTimedout
+            | ErrorType::ReadTimedout
+            | ErrorType::WriteTimedout => upstream.timeout_status,
+            _ => return None,
+        };
+
+        debug!(
+            "upstream error {:?}, responding with status {status}",
+            e.etype()
+        );
+        if let Err(err) = error_response(session, status).await {
+            warn!("failed sending error response: {err}");
+        }
+        Some(status.as_u16())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use http::HeaderValue;
+    use auth_module::AuthHandler;
+    use http::{HeaderMap, HeaderValue};
     use pandora_module_utils::pingora::{
-        create_test_session, RequestHeader, ResponseHeader, Session,
+        create_test_session, AuthCredentials, RequestHeader, ResponseHeader, Session,
     };
     use pandora_module_utils::FromYaml;
-    use startup_module::DefaultApp;
+    use startup_module::{AppResult, DefaultApp};
+    use std::sync::Mutex;
     use test_log::test;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
     fn make_app(configured: bool) -> DefaultApp<UpstreamHandler> {
         let conf = if configured {
@@ -243,4 +783,901 @@ mod tests {
             .await;
         assert!(result.err().is_none());
     }
+
+    #[test]
+    fn merge_with_opt() {
+        let mut conf = UpstreamConf {
+            upstream: Some("http://127.0.0.1:8080".try_into().unwrap()),
+            ..Default::default()
+        };
+        conf.merge_with_opt(UpstreamOpt::parse_from(["test"]));
+        assert_eq!(conf.upstream.unwrap(), "http://127.0.0.1:8080");
+
+        let mut conf = UpstreamConf {
+            upstream: Some("http://127.0.0.1:8080".try_into().unwrap()),
+            ..Default::default()
+        };
+        conf.merge_with_opt(UpstreamOpt::parse_from([
+            "test",
+            "--upstream",
+            "https://127.0.0.1",
+        ]));
+        assert_eq!(conf.upstream.unwrap(), "https://127.0.0.1");
+
+        let mut conf = UpstreamConf {
+            upstream_dns_ttl: Some(60),
+            ..Default::default()
+        };
+        conf.merge_with_opt(UpstreamOpt::parse_from(["test", "--upstream-dns-ttl", "5"]));
+        assert_eq!(conf.upstream_dns_ttl, Some(5));
+
+        let mut conf = UpstreamConf::default();
+        conf.merge_with_opt(UpstreamOpt::parse_from(["test", "--upstream-http2"]));
+        assert!(conf.upstream_http2);
+    }
+
+    #[derive(Debug, Default)]
+    struct TestResolver {
+        addrs: Mutex<Option<Vec<SocketAddr>>>,
+    }
+
+    impl TestResolver {
+        fn set(&self, addrs: Option<&[&str]>) {
+            *self.addrs.lock().unwrap() =
+                addrs.map(|addrs| addrs.iter().map(|addr| addr.parse().unwrap()).collect());
+        }
+    }
+
+    impl Resolver for TestResolver {
+        fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, std::io::Error> {
+            assert_eq!(host, "example.com");
+            assert_eq!(port, 443);
+            self.addrs
+                .lock()
+                .unwrap()
+                .clone()
+                .ok_or_else(|| std::io::Error::other("resolution failed"))
+        }
+    }
+
+    async fn selected_addrs(app: &mut DefaultApp<UpstreamHandler>, count: usize) -> Vec<String> {
+        let selected = Mutex::new(Vec::new());
+        for _ in 0..count {
+            let session = make_session().await;
+            let result = app
+                .handle_request_with_upstream(session, |_, peer| {
+                    selected.lock().unwrap().push(peer._address.to_string());
+                    ResponseHeader::build(200, None)
+                })
+                .await;
+            assert!(result.err().is_none());
+        }
+        selected.into_inner().unwrap()
+    }
+
+    /// Resolves the upstream host name again, as the refresh thread would once the TTL expires.
+    fn refresh(handler: &UpstreamHandler) {
+        handler.upstream.as_ref().unwrap().addrs.refresh();
+    }
+
+    #[test(tokio::test)]
+    async fn dns_refresh() {
+        let resolver = Arc::new(TestResolver::default());
+        resolver.set(Some(&["127.0.0.1:443", "127.0.0.2:443"]));
+
+        // The TTL is long enough for the refresh thread not to interfere
+        let conf = UpstreamConf::from_yaml(
+            r#"
+                upstream: https://example.com
+                upstream_dns_ttl: 3600
+            "#,
+        )
+        .unwrap();
+        let handler = UpstreamHandler::with_resolver(conf.clone(), resolver.clone()).unwrap();
+        let mut app = DefaultApp::new(handler.clone());
+
+        // All resolved addresses are being used
+        assert_eq!(
+            selected_addrs(&mut app, 3).await,
+            vec!["127.0.0.1:443", "127.0.0.2:443", "127.0.0.1:443"]
+        );
+
+        // Addresses are updated once the TTL expires
+        resolver.set(Some(&["127.0.0.3:443"]));
+        assert_eq!(selected_addrs(&mut app, 1).await, vec!["127.0.0.2:443"]);
+        refresh(&handler);
+        assert_eq!(selected_addrs(&mut app, 2).await, vec!["127.0.0.3:443"; 2]);
+
+        // Resolution failures keep the last known addresses
+        resolver.set(None);
+        refresh(&handler);
+        assert_eq!(selected_addrs(&mut app, 1).await, vec!["127.0.0.3:443"]);
+        resolver.set(Some(&[]));
+        refresh(&handler);
+        assert_eq!(selected_addrs(&mut app, 1).await, vec!["127.0.0.3:443"]);
+
+        // Without a TTL the addresses resolved initially are kept
+        resolver.set(Some(&["127.0.0.1:443"]));
+        let handler = UpstreamHandler::with_resolver(
+            UpstreamConf {
+                upstream_dns_ttl: None,
+                ..conf.clone()
+            },
+            resolver.clone(),
+        )
+        .unwrap();
+        let mut app = DefaultApp::new(handler.clone());
+        assert_eq!(selected_addrs(&mut app, 1).await, vec!["127.0.0.1:443"]);
+        resolver.set(Some(&["127.0.0.2:443"]));
+        refresh(&handler);
+        assert_eq!(selected_addrs(&mut app, 1).await, vec!["127.0.0.1:443"]);
+
+        // A zero TTL is rejected
+        assert!(UpstreamHandler::with_resolver(
+            UpstreamConf {
+                upstream_dns_ttl: Some(0),
+                ..conf
+            },
+            resolver,
+        )
+        .is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn ip_version() {
+        let resolver = Arc::new(TestResolver::default());
+        resolver.set(Some(&["[::1]:443", "127.0.0.1:443", "[::2]:443"]));
+
+        let make_app = |ip_version| {
+            let conf = UpstreamConf::from_yaml(format!(
+                r#"
+                    upstream: https://example.com
+                    upstream_ip_version: {ip_version}
+                "#
+            ))
+            .unwrap();
+            UpstreamHandler::with_resolver(conf, resolver.clone()).map(DefaultApp::new)
+        };
+
+        let mut app = make_app("any").unwrap();
+        assert_eq!(
+            selected_addrs(&mut app, 3).await,
+            vec!["[::1]:443", "127.0.0.1:443", "[::2]:443"]
+        );
+
+        let mut app = make_app("ipv4").unwrap();
+        assert_eq!(selected_addrs(&mut app, 2).await, vec!["127.0.0.1:443"; 2]);
+
+        let mut app = make_app("ipv6").unwrap();
+        assert_eq!(
+            selected_addrs(&mut app, 2).await,
+            vec!["[::1]:443", "[::2]:443"]
+        );
+
+        // No addresses of the requested version is a configuration error
+        resolver.set(Some(&["[::1]:443"]));
+        assert!(make_app("ipv4").is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn trailers() {
+        let resolver = Arc::new(TestResolver::default());
+        resolver.set(Some(&["127.0.0.1:443"]));
+
+        let conf = UpstreamConf::from_yaml(
+            r#"
+                upstream: https://example.com
+                upstream_http2: true
+            "#,
+        )
+        .unwrap();
+        let mut app = DefaultApp::new(UpstreamHandler::with_resolver(conf, resolver).unwrap());
+
+        let mut session = make_session().await;
+        session
+            .req_header_mut()
+            .insert_header(header::TE, "trailers, deflate;q=0.5")
+            .unwrap();
+        let mut result = app
+            .handle_request_with_upstream_trailers(session, |session, peer| {
+                assert_eq!(peer.options.alpn.get_max_http_version(), 2);
+                assert_eq!(
+                    session.req_header().headers.get(header::TE),
+                    Some(&HeaderValue::from_static("trailers"))
+                );
+
+                let mut trailers = HeaderMap::new();
+                trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                Ok((ResponseHeader::build(200, None)?, trailers))
+            })
+            .await;
+        assert!(result.err().is_none());
+        assert_eq!(
+            result.session().response_written().unwrap().status.as_u16(),
+            200
+        );
+        assert_eq!(
+            result
+                .trailers()
+                .and_then(|trailers| trailers.get("grpc-status")),
+            Some(&HeaderValue::from_static("0"))
+        );
+
+        // Other transfer codings aren't forwarded
+        let mut session = make_session().await;
+        session
+            .req_header_mut()
+            .insert_header(header::TE, "deflate")
+            .unwrap();
+        let result = app
+            .handle_request_with_upstream(session, |session, _| {
+                assert!(session.req_header().headers.get(header::TE).is_none());
+                ResponseHeader::build(200, None)
+            })
+            .await;
+        assert!(result.err().is_none());
+    }
+
+    #[test(tokio::test)]
+    async fn trailers_disabled() {
+        let resolver = Arc::new(TestResolver::default());
+        resolver.set(Some(&["127.0.0.1:443"]));
+
+        let conf = UpstreamConf::from_yaml(
+            r#"
+                upstream: https://example.com
+                upstream_http2: true
+                upstream_trailers: false
+            "#,
+        )
+        .unwrap();
+        let mut app = DefaultApp::new(UpstreamHandler::with_resolver(conf, resolver).unwrap());
+
+        let mut session = make_session().await;
+        session
+            .req_header_mut()
+            .insert_header(header::TE, "trailers")
+            .unwrap();
+        let mut result = app
+            .handle_request_with_upstream_trailers(session, |session, _| {
+                assert!(session.req_header().headers.get(header::TE).is_none());
+
+                let mut trailers = HeaderMap::new();
+                trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                Ok((ResponseHeader::build(200, None)?, trailers))
+            })
+            .await;
+        assert!(result.err().is_none());
+        assert_eq!(
+            result.session().response_written().unwrap().status.as_u16(),
+            200
+        );
+        assert!(result
+            .trailers()
+            .and_then(|trailers| trailers.get("grpc-status"))
+            .is_none());
+    }
+
+    #[test(tokio::test)]
+    async fn extension_methods() {
+        let resolver = Arc::new(TestResolver::default());
+        resolver.set(Some(&["127.0.0.1:443"]));
+
+        let conf = UpstreamConf::from_yaml(
+            r#"
+                upstream: https://example.com
+                upstream_extension_methods: [PROPFIND, REPORT]
+            "#,
+        )
+        .unwrap();
+        let mut app = DefaultApp::new(UpstreamHandler::with_resolver(conf, resolver).unwrap());
+
+        for (method, expected) in [
+            ("GET", Some("GET")),
+            ("PROPFIND", Some("PROPFIND")),
+            ("report", Some("REPORT")),
+            ("get", None),
+            ("MKCOL", None),
+        ] {
+            let header = RequestHeader::build(method, b"/", None).unwrap();
+            let session = create_test_session(header).await;
+            let mut result = app
+                .handle_request_with_upstream(session, |session, _| {
+                    assert_eq!(Some(session.req_header().method.as_str()), expected);
+                    ResponseHeader::build(200, None)
+                })
+                .await;
+            assert!(result.err().is_none());
+            let status = result.session().response_written().unwrap().status;
+            if expected.is_some() {
+                assert_eq!(status, StatusCode::OK);
+            } else {
+                assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+            }
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn header_case() {
+        let resolver = Arc::new(TestResolver::default());
+        resolver.set(Some(&["127.0.0.1:443"]));
+
+        let make_app = |header_case| {
+            let conf = UpstreamConf::from_yaml(format!(
+                r#"
+                    upstream: https://example.com
+                    upstream_header_case: {header_case}
+                "#
+            ))
+            .unwrap();
+            DefaultApp::new(UpstreamHandler::with_resolver(conf, resolver.clone()).unwrap())
+        };
+
+        async fn forwarded_header(app: &mut DefaultApp<UpstreamHandler>) -> String {
+            let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+            header.insert_header("X-My-Header", "1").unwrap();
+            header.insert_header("x-lower-header", "2").unwrap();
+            let session = create_test_session(header).await;
+
+            let wire = Mutex::new(Vec::new());
+            let result = app
+                .handle_request_with_upstream(session, |session, _| {
+                    session
+                        .req_header()
+                        .header_to_h1_wire(&mut *wire.lock().unwrap());
+                    ResponseHeader::build(200, None)
+                })
+                .await;
+            assert!(result.err().is_none());
+            String::from_utf8(wire.into_inner().unwrap()).unwrap()
+        }
+
+        let wire = forwarded_header(&mut make_app("preserve")).await;
+        assert!(wire.contains("X-My-Header: 1\r\n"));
+        assert!(wire.contains("x-lower-header: 2\r\n"));
+
+        let wire = forwarded_header(&mut make_app("title_case")).await;
+        assert!(wire.contains("X-My-Header: 1\r\n"));
+        assert!(wire.contains("X-Lower-Header: 2\r\n"));
+        assert!(wire.contains("Host: example.com\r\n"));
+    }
+
+    #[test(tokio::test)]
+    async fn hop_by_hop_headers() {
+        let resolver = Arc::new(TestResolver::default());
+        resolver.set(Some(&["127.0.0.1:443"]));
+
+        let conf = UpstreamConf::from_yaml(
+            r#"
+                upstream: https://example.com
+                upstream_log_stripped_headers: true
+            "#,
+        )
+        .unwrap();
+        let mut app = DefaultApp::new(UpstreamHandler::with_resolver(conf, resolver).unwrap());
+
+        let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+        header
+            .insert_header("Connection", "keep-alive, X-Custom")
+            .unwrap();
+        header.insert_header("Keep-Alive", "timeout=5").unwrap();
+        header.insert_header("X-Custom", "1").unwrap();
+        header.insert_header("X-Other", "2").unwrap();
+        header
+            .insert_header("Proxy-Authorization", "Basic abc")
+            .unwrap();
+        let session = create_test_session(header).await;
+
+        let mut result = app
+            .handle_request_with_upstream(session, |session, _| {
+                let headers = &session.req_header().headers;
+                for name in [
+                    "Connection",
+                    "Keep-Alive",
+                    "X-Custom",
+                    "Proxy-Authorization",
+                ] {
+                    assert!(headers.get(name).is_none(), "{name}");
+                }
+                assert_eq!(headers.get("X-Other").unwrap(), "2");
+
+                let mut header = ResponseHeader::build(200, None)?;
+                header.insert_header("Connection", "X-Secret")?;
+                header.insert_header("X-Secret", "3")?;
+                header.insert_header("X-Other", "4")?;
+                header.insert_header("Content-Length", "0")?;
+                Ok(header)
+            })
+            .await;
+        assert!(result.err().is_none());
+        let headers = result.session().response_written().unwrap().headers.clone();
+        assert!(headers.get("X-Secret").is_none());
+        assert_eq!(headers.get("X-Other").unwrap(), "4");
+        assert_eq!(headers.get("Content-Length").unwrap(), "0");
+
+        // Framing headers are never removed, protocol upgrades are kept
+        let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+        header
+            .insert_header("Connection", "Upgrade, Content-Length")
+            .unwrap();
+        header.insert_header("Upgrade", "websocket").unwrap();
+        header.insert_header("Content-Length", "0").unwrap();
+        let session = create_test_session(header).await;
+
+        let mut result = app
+            .handle_request_with_upstream(session, |session, _| {
+                let headers = &session.req_header().headers;
+                assert_eq!(headers.get("Connection").unwrap(), "upgrade");
+                assert_eq!(headers.get("Upgrade").unwrap(), "websocket");
+                assert_eq!(headers.get("Content-Length").unwrap(), "0");
+
+                let mut header = ResponseHeader::build(101, None)?;
+                header.insert_header("Connection", "Upgrade")?;
+                header.insert_header("Upgrade", "websocket")?;
+                Ok(header)
+            })
+            .await;
+        assert!(result.err().is_none());
+        let headers = result.session().response_written().unwrap().headers.clone();
+        assert_eq!(headers.get("Connection").unwrap(), "upgrade");
+        assert_eq!(headers.get("Upgrade").unwrap(), "websocket");
+    }
+
+    #[test(tokio::test)]
+    async fn expect_continue() {
+        #[derive(Debug, RequestFilter)]
+        struct Handler {
+            auth: AuthHandler,
+            upstream: UpstreamHandler,
+        }
+
+        let conf = <Handler as RequestFilter>::Conf::from_yaml(
+            r#"
+                upstream: http://127.0.0.1:8080
+                auth_mode: http
+                auth_credentials:
+                    me: $2y$12$iuKHb5UsRqktrWYdGKGFPu9TtQGe7S6OcWfqUd7lHNJdTgOUaBnuq
+                auth_rules:
+                - path: /public/*
+                  auth_credentials: {}
+            "#,
+        )
+        .unwrap();
+        let mut app = DefaultApp::new(Handler::try_from(conf).unwrap());
+
+        async fn send_request(app: &mut DefaultApp<Handler>, path: &str) -> String {
+            let (mut client, server) = tokio::io::duplex(4096);
+            client
+                .write_all(
+                    format!(
+                        "PUT {path} HTTP/1.1\r\n\
+                         Host: localhost\r\n\
+                         Content-Length: 4\r\n\
+                         Expect: 100-continue\r\n\r\n"
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+
+            let mut session = Session::new_h1(Box::new(server));
+            assert!(session.read_request().await.unwrap());
+            let result = app
+                .handle_request_with_upstream(session, |session, _| {
+                    assert!(session.req_header().headers.get("Expect").is_none());
+                    ResponseHeader::build(201, None)
+                })
+                .await;
+            drop(result);
+
+            // The request body has never been sent, so everything written to the client has been
+            // written before the body could be consumed.
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            response
+        }
+
+        let response = send_request(&mut app, "/public/file").await;
+        assert!(
+            response.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\n"),
+            "{response}"
+        );
+
+        let response = send_request(&mut app, "/private/file").await;
+        assert!(
+            response.starts_with("HTTP/1.1 401 Unauthorized\r\n"),
+            "{response}"
+        );
+        assert!(!response.contains("100 Continue"), "{response}");
+    }
+
+    #[test(tokio::test)]
+    async fn header_size_limit() {
+        let resolver = Arc::new(TestResolver::default());
+        resolver.set(Some(&["127.0.0.1:443"]));
+
+        let make_app = |action| {
+            let conf = UpstreamConf::from_yaml(format!(
+                r#"
+                    upstream: https://example.com
+                    upstream_header_size_limit: 100
+                    upstream_oversized_headers: {action}
+                "#
+            ))
+            .unwrap();
+            DefaultApp::new(UpstreamHandler::with_resolver(conf, resolver.clone()).unwrap())
+        };
+
+        async fn response(app: &mut DefaultApp<UpstreamHandler>, cookie_size: usize) -> AppResult {
+            let session = make_session().await;
+            app.handle_request_with_upstream(session, |_, _| {
+                let mut header = ResponseHeader::build(200, None)?;
+                header.append_header("Content-Length", "0")?;
+                header.append_header("Set-Cookie", "a=1")?;
+                header.append_header("Set-Cookie", "b=".to_owned() + &"x".repeat(cookie_size))?;
+                header.append_header("X-Other", "value")?;
+                Ok(header)
+            })
+            .await
+        }
+
+        fn set_cookies(result: &mut AppResult) -> Vec<String> {
+            result
+                .session()
+                .response_written()
+                .unwrap()
+                .headers
+                .get_all("Set-Cookie")
+                .iter()
+                .map(|value| value.to_str().unwrap().to_owned())
+                .collect()
+        }
+
+        // Headers within the limit are unchanged
+        let mut result = response(&mut make_app("reject"), 10).await;
+        assert!(result.err().is_none());
+        assert_eq!(
+            set_cookies(&mut result),
+            vec!["a=1", &format!("b={}", "x".repeat(10))]
+        );
+
+        let mut result = response(&mut make_app("truncate"), 10).await;
+        assert!(result.err().is_none());
+        assert_eq!(
+            set_cookies(&mut result),
+            vec!["a=1", &format!("b={}", "x".repeat(10))]
+        );
+
+        // Oversized headers are handled as configured
+        let result = response(&mut make_app("reject"), 200).await;
+        assert_eq!(
+            result.err().as_ref().map(|err| &err.etype),
+            Some(&ErrorType::HTTPStatus(502))
+        );
+
+        let mut result = response(&mut make_app("truncate"), 200).await;
+        assert!(result.err().is_none());
+        let cookies = set_cookies(&mut result);
+        assert_eq!(cookies.len(), 2);
+        assert_eq!(cookies[0], "a=1");
+        assert!(cookies[1].starts_with("b=xxx"));
+        assert!(cookies[1].len() < 100);
+        let session = result.session();
+        let header = session.response_written().unwrap();
+        assert!(header.headers.get("X-Other").is_some());
+        assert!(header.headers.get("Content-Length").is_some());
+
+        let mut result = response(&mut make_app("drop"), 200).await;
+        assert!(result.err().is_none());
+        assert_eq!(set_cookies(&mut result), vec!["a=1"]);
+        let session = result.session();
+        let header = session.response_written().unwrap();
+        assert!(header.headers.get("X-Other").is_some());
+    }
+
+    #[test(tokio::test)]
+    async fn credentials() {
+        #[derive(Debug, RequestFilter)]
+        struct Handler {
+            auth: AuthHandler,
+            upstream: UpstreamHandler,
+        }
+
+        let make_app = |settings: &str| {
+            let conf = <Handler as RequestFilter>::Conf::from_yaml(format!(
+                r#"
+                    upstream: http://127.0.0.1:8080
+                    auth_mode: http
+                    auth_credentials:
+                        # test
+                        me: $2y$04$V15kxj8/a7JsIb6lXkcK7ex.IiNSM3.nbLJaLbkAi10iVXUip/JoC
+                    {settings}
+                "#
+            ))
+            .unwrap();
+            DefaultApp::new(Handler::try_from(conf).unwrap())
+        };
+
+        async fn forwarded_headers(app: &mut DefaultApp<Handler>) -> HeaderMap {
+            let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+            header
+                .insert_header("Authorization", "Basic bWU6dGVzdA==")
+                .unwrap();
+            header.insert_header("Cookie", "a=b; c=d").unwrap();
+            let session = create_test_session(header).await;
+
+            let headers = Mutex::new(HeaderMap::new());
+            let result = app
+                .handle_request_with_upstream(session, |session, _| {
+                    *headers.lock().unwrap() = session.req_header().headers.clone();
+                    ResponseHeader::build(200, None)
+                })
+                .await;
+            assert!(result.err().is_none());
+            headers.into_inner().unwrap()
+        }
+
+        let headers = forwarded_headers(&mut make_app("")).await;
+        assert!(headers.get("Authorization").is_none());
+        assert_eq!(headers.get("Cookie").unwrap(), "a=b; c=d");
+
+        let headers = forwarded_headers(&mut make_app("upstream_credentials: strip_all")).await;
+        assert!(headers.get("Authorization").is_none());
+        assert!(headers.get("Cookie").is_none());
+
+        let headers = forwarded_headers(&mut make_app("upstream_credentials: forward")).await;
+        assert_eq!(headers.get("Authorization").unwrap(), "Basic bWU6dGVzdA==");
+        assert_eq!(headers.get("Cookie").unwrap(), "a=b; c=d");
+
+        let headers =
+            forwarded_headers(&mut make_app("upstream_authorization: Bearer backend")).await;
+        assert_eq!(headers.get("Authorization").unwrap(), "Bearer backend");
+        assert_eq!(headers.get("Cookie").unwrap(), "a=b; c=d");
+    }
+
+    #[test(tokio::test)]
+    async fn forward_headers() {
+        let make_app = |settings: &str| {
+            let conf = <UpstreamHandler as RequestFilter>::Conf::from_yaml(format!(
+                r#"
+                    upstream: http://127.0.0.1:8080
+                    upstream_credentials: forward
+                    {settings}
+                "#
+            ))
+            .unwrap();
+            DefaultApp::new(UpstreamHandler::try_from(conf).unwrap())
+        };
+
+        async fn forwarded_headers(app: &mut DefaultApp<UpstreamHandler>) -> HeaderMap {
+            let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+            header.insert_header("Authorization", "Bearer xyz").unwrap();
+            header.insert_header("Cookie", "a=b").unwrap();
+            header.insert_header("Accept", "text/html").unwrap();
+            header.insert_header("X-Custom", "1").unwrap();
+            let session = create_test_session(header).await;
+
+            let headers = Mutex::new(HeaderMap::new());
+            let result = app
+                .handle_request_with_upstream(session, |session, _| {
+                    *headers.lock().unwrap() = session.req_header().headers.clone();
+                    ResponseHeader::build(200, None)
+                })
+                .await;
+            assert!(result.err().is_none());
+            headers.into_inner().unwrap()
+        }
+
+        let headers = forwarded_headers(&mut make_app(
+            "upstream_forward_headers_denylist: [authorization, COOKIE]",
+        ))
+        .await;
+        assert!(headers.get("Authorization").is_none());
+        assert!(headers.get("Cookie").is_none());
+        assert_eq!(headers.get("Accept").unwrap(), "text/html");
+        assert_eq!(headers.get("X-Custom").unwrap(), "1");
+        assert_eq!(headers.get("Host").unwrap(), "127.0.0.1:8080");
+
+        let headers = forwarded_headers(&mut make_app(
+            "upstream_forward_headers_allowlist: [Accept, x-custom, cookie]\n                    upstream_forward_headers_denylist: [cookie]",
+        ))
+        .await;
+        assert!(headers.get("Authorization").is_none());
+        assert!(headers.get("Cookie").is_none());
+        assert_eq!(headers.get("Accept").unwrap(), "text/html");
+        assert_eq!(headers.get("X-Custom").unwrap(), "1");
+        assert_eq!(headers.get("Host").unwrap(), "127.0.0.1:8080");
+    }
+
+    #[test]
+    fn strip_cookie() {
+        let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+        header.append_header("Cookie", "a=b; token=xyz").unwrap();
+        header.append_header("Cookie", "token=abc").unwrap();
+        header.append_header("Cookie", " c=d;token=1;e=f").unwrap();
+        credentials::apply(
+            &mut header,
+            ForwardCredentials::StripOwn,
+            Some(&AuthCredentials::Cookie("token".to_owned())),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            header.headers.get_all("Cookie").iter().collect::<Vec<_>>(),
+            ["a=b", "c=d; e=f"]
+        );
+        assert!(header.headers.get("Authorization").is_none());
+    }
+
+    #[test(tokio::test)]
+    async fn upstream_errors() {
+        async fn error_status(app: &mut DefaultApp<UpstreamHandler>, etype: ErrorType) -> u16 {
+            let session = make_session().await;
+            let mut result = app
+                .handle_request_with_upstream(session, |_, _| Err(Error::new_up(etype.clone())))
+                .await;
+            assert!(result.err().is_some());
+            let status = result.session().response_written().unwrap().status.as_u16();
+            status
+        }
+
+        let resolver = Arc::new(TestResolver::default());
+        resolver.set(Some(&["127.0.0.1:443"]));
+
+        let conf = UpstreamConf::from_yaml("upstream: https://example.com").unwrap();
+        let mut app = DefaultApp::new(
+            UpstreamHandler::with_resolver(conf.clone(), resolver.clone()).unwrap(),
+        );
+        assert_eq!(error_status(&mut app, ErrorType::ConnectRefused).await, 502);
+        assert_eq!(
+            error_status(&mut app, ErrorType::ConnectTimedout).await,
+            504
+        );
+        assert_eq!(error_status(&mut app, ErrorType::ReadTimedout).await, 504);
+
+        let conf = UpstreamConf::from_yaml(
+            r#"
+                upstream: https://example.com
+                upstream_refused_status: 503
+                upstream_timeout_status: 500
+            "#,
+        )
+        .unwrap();
+        let mut app = DefaultApp::new(
+            UpstreamHandler::with_resolver(conf.clone(), resolver.clone()).unwrap(),
+        );
+        assert_eq!(error_status(&mut app, ErrorType::ConnectRefused).await, 503);
+        assert_eq!(error_status(&mut app, ErrorType::ConnectNoRoute).await, 503);
+        assert_eq!(error_status(&mut app, ErrorType::WriteTimedout).await, 500);
+
+        // Other errors are left to the default handling
+        let session = make_session().await;
+        let mut result = app
+            .handle_request_with_upstream(session, |_, _| {
+                Err(Error::new_up(ErrorType::ConnectError))
+            })
+            .await;
+        assert!(result.err().is_some());
+        assert!(result.session().response_written().is_none());
+
+        assert!(UpstreamHandler::with_resolver(
+            UpstreamConf {
+                upstream_timeout_status: 42,
+                ..conf
+            },
+            resolver,
+        )
+        .is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn retries() {
+        async fn attempts(
+            app: &mut DefaultApp<UpstreamHandler>,
+            method: &str,
+            etype: ErrorType,
+        ) -> (Vec<String>, Option<u16>) {
+            let header = RequestHeader::build(method, b"/", None).unwrap();
+            let session = create_test_session(header).await;
+            let attempts = Mutex::new(Vec::new());
+            let mut result = app
+                .handle_request_with_upstream(session, |_, peer| {
+                    let addr = peer._address.to_string();
+                    attempts.lock().unwrap().push(addr.clone());
+                    if addr == "127.0.0.2:443" {
+                        ResponseHeader::build(200, None)
+                    } else {
+                        Err(Error::new_up(etype.clone()))
+                    }
+                })
+                .await;
+            let status = result
+                .session()
+                .response_written()
+                .map(|header| header.status.as_u16());
+            (attempts.into_inner().unwrap(), status)
+        }
+
+        let resolver = Arc::new(TestResolver::default());
+        resolver.set(Some(&["127.0.0.1:443", "127.0.0.2:443"]));
+
+        let make_app = |yaml: &str| {
+            let conf = UpstreamConf::from_yaml(yaml).unwrap();
+            DefaultApp::new(UpstreamHandler::with_resolver(conf, resolver.clone()).unwrap())
+        };
+
+        // No retries by default
+        let mut app = make_app("upstream: https://example.com");
+        assert_eq!(
+            attempts(&mut app, "GET", ErrorType::ConnectRefused).await,
+            (vec!["127.0.0.1:443".to_owned()], Some(502))
+        );
+
+        // Failed attempt is retried with the next address
+        let conf = r#"
+            upstream: https://example.com
+            upstream_retries: 2
+        "#;
+        let mut app = make_app(conf);
+        assert_eq!(
+            attempts(&mut app, "GET", ErrorType::ConnectRefused).await,
+            (
+                vec!["127.0.0.1:443".to_owned(), "127.0.0.2:443".to_owned()],
+                Some(200)
+            )
+        );
+        let mut app = make_app(conf);
+        assert_eq!(
+            attempts(&mut app, "DELETE", ErrorType::ConnectTimedout).await,
+            (
+                vec!["127.0.0.1:443".to_owned(), "127.0.0.2:443".to_owned()],
+                Some(200)
+            )
+        );
+
+        // Other connection errors aren't retried by default
+        let mut app = make_app(conf);
+        let (addrs, _) = attempts(&mut app, "GET", ErrorType::TLSHandshakeFailure).await;
+        assert_eq!(addrs, vec!["127.0.0.1:443"]);
+
+        // Non-idempotent methods aren't retried by default
+        let mut app = make_app(conf);
+        assert_eq!(
+            attempts(&mut app, "POST", ErrorType::ConnectRefused).await,
+            (vec!["127.0.0.1:443".to_owned()], Some(502))
+        );
+
+        let mut app = make_app(
+            r#"
+                upstream: https://example.com
+                upstream_retries: 1
+                upstream_retry_on: [error]
+                upstream_retry_non_idempotent: true
+            "#,
+        );
+        assert_eq!(
+            attempts(&mut app, "POST", ErrorType::TLSHandshakeFailure).await,
+            (
+                vec!["127.0.0.1:443".to_owned(), "127.0.0.2:443".to_owned()],
+                Some(200)
+            )
+        );
+        let mut app = make_app(
+            r#"
+                upstream: https://example.com
+                upstream_retries: 1
+                upstream_retry_on: [error]
+            "#,
+        );
+        let (addrs, _) = attempts(&mut app, "GET", ErrorType::ConnectRefused).await;
+        assert_eq!(addrs, vec!["127.0.0.1:443"]);
+
+        // Retries are limited
+        resolver.set(Some(&["127.0.0.1:443"]));
+        let mut app = make_app(conf);
+        assert_eq!(
+            attempts(&mut app, "GET", ErrorType::ConnectRefused).await,
+            (vec!["127.0.0.1:443".to_owned(); 3], Some(502))
+        );
+    }
 }
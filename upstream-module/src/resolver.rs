@@ -0,0 +1,186 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Host name resolution for upstream servers
+
+use log::{info, warn};
+use serde::Deserialize;
+use std::fmt::Debug;
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Once, RwLock, Weak};
+use std::time::Duration;
+
+/// Resolves upstream host names to socket addresses
+pub trait Resolver: Debug + Send + Sync {
+    /// Produces all addresses the given host name and port resolve to.
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Error>;
+}
+
+/// Resolver relying on the host name resolution of the operating system
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+}
+
+/// IP protocol version of the upstream addresses to be used
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpVersion {
+    /// Use both IPv4 and IPv6 addresses
+    #[default]
+    Any,
+    /// Use IPv4 addresses only
+    Ipv4,
+    /// Use IPv6 addresses only
+    Ipv6,
+}
+
+impl IpVersion {
+    fn matches(&self, addr: &SocketAddr) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Ipv4 => addr.is_ipv4(),
+            Self::Ipv6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// Resolves a host name, keeping only the addresses of the requested IP version. An empty result
+/// is treated as an error.
+pub(crate) fn resolve_non_empty(
+    resolver: &dyn Resolver,
+    host: &str,
+    port: u16,
+    ip_version: IpVersion,
+) -> Result<Vec<SocketAddr>, Error> {
+    let mut addrs = resolver.resolve(host, port)?;
+    addrs.retain(|addr| ip_version.matches(addr));
+    if addrs.is_empty() {
+        Err(Error::new(
+            ErrorKind::NotFound,
+            "DNS lookup didn't produce any results",
+        ))
+    } else {
+        Ok(addrs)
+    }
+}
+
+/// Addresses of an upstream server, shared between handler clones and the refresh thread
+#[derive(Debug)]
+pub(crate) struct ResolvedAddrs {
+    host: String,
+    port: u16,
+    ip_version: IpVersion,
+    addrs: RwLock<Vec<SocketAddr>>,
+    next: AtomicUsize,
+    refresh: Option<(Arc<dyn Resolver>, Duration)>,
+    refresh_started: Once,
+}
+
+impl ResolvedAddrs {
+    /// Creates a new address list. If `refresh` is given, the host name will be resolved again
+    /// with the given resolver whenever the interval expires.
+    pub(crate) fn new(
+        host: &str,
+        port: u16,
+        ip_version: IpVersion,
+        addrs: Vec<SocketAddr>,
+        refresh: Option<(Arc<dyn Resolver>, Duration)>,
+    ) -> Self {
+        Self {
+            host: host.to_owned(),
+            port,
+            ip_version,
+            addrs: RwLock::new(addrs),
+            next: AtomicUsize::new(0),
+            refresh,
+            refresh_started: Once::new(),
+        }
+    }
+
+    /// Returns the current list of addresses.
+    pub(crate) fn addrs(&self) -> Vec<SocketAddr> {
+        self.addrs
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    /// Selects the address to be used for the next request, rotating through all known
+    /// addresses.
+    pub(crate) fn select(self: &Arc<Self>) -> SocketAddr {
+        // The refresh thread is only started once requests are being handled. Threads started
+        // during configuration processing wouldn't survive the server daemonizing.
+        self.refresh_started.call_once(|| self.spawn_refresh());
+
+        let addrs = self.addrs.read().unwrap_or_else(|err| err.into_inner());
+        let index = self.next.fetch_add(1, Ordering::Relaxed);
+        addrs[index % addrs.len()]
+    }
+
+    /// Resolves the host name again if refreshing is enabled, keeping the previous addresses if
+    /// resolution fails.
+    pub(crate) fn refresh(&self) {
+        let Some((resolver, _)) = &self.refresh else {
+            return;
+        };
+
+        match resolve_non_empty(resolver.as_ref(), &self.host, self.port, self.ip_version) {
+            Ok(addrs) => {
+                let mut current = self.addrs.write().unwrap_or_else(|err| err.into_inner());
+                if *current != addrs {
+                    info!("upstream host name {} now resolves to {addrs:?}", self.host);
+                    *current = addrs;
+                }
+            }
+            Err(err) => warn!(
+                "failed resolving upstream host name {}, keeping previous addresses: {err}",
+                self.host
+            ),
+        }
+    }
+
+    fn spawn_refresh(self: &Arc<Self>) {
+        let Some((_, interval)) = self.refresh else {
+            return;
+        };
+
+        // Only a weak reference is kept, the thread exits once the handler is gone.
+        let weak: Weak<Self> = Arc::downgrade(self);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let Some(this) = weak.upgrade() else {
+                break;
+            };
+            this.refresh();
+        });
+    }
+}
+
+impl PartialEq for ResolvedAddrs {
+    fn eq(&self, other: &Self) -> bool {
+        self.host == other.host
+            && self.port == other.port
+            && self.ip_version == other.ip_version
+            && self.addrs() == other.addrs()
+    }
+}
+
+impl Eq for ResolvedAddrs {}
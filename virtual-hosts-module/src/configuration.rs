@@ -35,6 +35,9 @@ pub struct VirtualHostConf<C: Default> {
     /// If true, this virtual host should be used as fallback when no other virtual host
     /// configuration applies
     pub default: bool,
+    /// If set, requests for any other host name handled by this configuration will be redirected
+    /// to this host name, e.g. `example.com` to consolidate `www.example.com` requests there
+    pub canonical_name: Option<String>,
     /// Maps virtual host's paths to their special configurations
     pub subpaths: HashMap<PathMatcher, SubPathConf<C>>,
     /// Generic handler settings
@@ -46,8 +49,42 @@ pub struct VirtualHostConf<C: Default> {
 }
 
 /// Virtual hosts configuration
-#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct VirtualHostsConf<C: Default> {
     /// Maps virtual host names to their configuration
     pub vhosts: HashMap<OneOrMany<String>, VirtualHostConf<C>>,
+    /// Maximal number of virtual host names that can be configured
+    pub max_vhosts: usize,
+    /// Maximal number of subpaths that can be configured for a single virtual host
+    pub max_subpaths: usize,
+    /// If `true`, absolute-form request targets like `http://example.com/path` will be converted
+    /// to origin-form with the host name moved into the `Host` header
+    pub normalize_absolute_form: bool,
+    /// If non-empty, only requests for these host names are accepted. Entries like
+    /// `*.example.com` match any subdomain, a port number is only compared if the entry has one.
+    pub allowed_hosts: OneOrMany<String>,
+    /// Status code of the response to requests for hosts not listed in `allowed_hosts`, either
+    /// 421 or 400
+    pub unknown_host_status: u16,
+    /// If `true`, the `Host` header will be converted to lower case and default ports will be
+    /// removed from it before any further processing
+    pub normalize_host: bool,
+    /// If `true`, ASCII letter case is ignored when matching subpaths, e.g. `/Dir/file` will be
+    /// handled by the configuration for `/dir/*`
+    pub case_insensitive_paths: bool,
+}
+
+impl<C: Default> Default for VirtualHostsConf<C> {
+    fn default() -> Self {
+        Self {
+            vhosts: HashMap::new(),
+            max_vhosts: 10000,
+            max_subpaths: 1000,
+            normalize_absolute_form: false,
+            allowed_hosts: OneOrMany::default(),
+            unknown_host_status: 421,
+            normalize_host: false,
+            case_insensitive_paths: false,
+        }
+    }
 }
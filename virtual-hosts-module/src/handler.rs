@@ -13,11 +13,15 @@
 // limitations under the License.
 
 use async_trait::async_trait;
-use http::uri::Uri;
-use log::warn;
-use pandora_module_utils::pingora::{Error, HttpModules, HttpPeer, SessionWrapper};
+use http::uri::{Authority, Uri};
+use http::{header, StatusCode};
+use log::{error, info, warn};
+use pandora_module_utils::pingora::{
+    Error, ErrorType, HeaderMap, HttpModules, HttpPeer, SessionWrapper,
+};
 use pandora_module_utils::router::{Path, Router};
-use pandora_module_utils::{RequestFilter, RequestFilterResult};
+use pandora_module_utils::standard_response::{error_response, redirect_response};
+use pandora_module_utils::{PathCaseUsage, RequestFilter, RequestFilterResult};
 use std::collections::BTreeSet;
 use std::fmt::Debug;
 use std::ops::{Deref, DerefMut};
@@ -39,10 +43,130 @@ fn set_uri_path(uri: &Uri, path: &[u8]) -> Uri {
     parts.try_into().unwrap_or_else(|_| uri.clone())
 }
 
+/// Converts an absolute-form request target like `http://example.com/path` into origin-form like
+/// `/path`, replacing the `Host` header by the authority of the request target.
+fn normalize_absolute_form(session: &mut impl SessionWrapper) -> Result<(), Box<Error>> {
+    let uri = session.uri();
+
+    // Authority-form targets of CONNECT requests have no scheme and are left alone
+    if uri.scheme().is_none() {
+        return Ok(());
+    }
+    let Some(authority) = uri.authority() else {
+        return Ok(());
+    };
+
+    let mut host = authority.host().to_owned();
+    if let Some(port) = authority.port() {
+        host.push(':');
+        host.push_str(port.as_str());
+    }
+    let origin = uri
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str())
+        .parse::<Uri>()
+        .map_err(|err| Error::because(ErrorType::InternalError, "failed normalizing URI", err))?;
+
+    let header = session.req_header_mut();
+    header.insert_header(header::HOST, host)?;
+    header.set_uri(origin);
+    Ok(())
+}
+
+/// Converts the `Host` header to lower case and removes the port from it if it is the default
+/// port for the connection.
+fn normalize_host(session: &mut impl SessionWrapper) -> Result<(), Box<Error>> {
+    let Some(host) = session
+        .get_header(header::HOST)
+        .and_then(|host| host.to_str().ok())
+    else {
+        return Ok(());
+    };
+
+    let default_port = if session
+        .digest()
+        .and_then(|digest| digest.ssl_digest.as_ref())
+        .is_some()
+    {
+        ":443"
+    } else {
+        ":80"
+    };
+
+    let mut normalized = host.to_ascii_lowercase();
+    if let Some(stripped) = normalized.strip_suffix(default_port) {
+        normalized.truncate(stripped.len());
+    }
+    if normalized != host {
+        session
+            .req_header_mut()
+            .insert_header(header::HOST, normalized)?;
+    }
+    Ok(())
+}
+
+/// Parses a host name with optional port, rejecting anything containing user info.
+fn parse_host(host: &str) -> Option<Authority> {
+    if host.contains('@') {
+        return None;
+    }
+    let authority: Authority = host.parse().ok()?;
+    // Anything following the host name has to be a valid port number
+    if authority.as_str() != authority.host() && authority.port_u16().is_none() {
+        return None;
+    }
+    Some(authority)
+}
+
+/// An entry of the `allowed_hosts` setting
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct HostPattern {
+    /// Host name, for wildcard patterns this is the suffix including the leading dot
+    host: String,
+    wildcard: bool,
+    port: Option<u16>,
+}
+
+impl HostPattern {
+    fn parse(pattern: &str) -> Option<Self> {
+        let (wildcard, host) = match pattern.strip_prefix("*.") {
+            Some(host) => (true, host),
+            None => (false, pattern),
+        };
+        let authority = parse_host(host)?;
+        let host = if wildcard {
+            format!(".{}", authority.host())
+        } else {
+            authority.host().to_owned()
+        };
+        Some(Self {
+            host,
+            wildcard,
+            port: authority.port_u16(),
+        })
+    }
+
+    fn matches(&self, host: &Authority) -> bool {
+        if self.port.is_some() && host.port_u16() != self.port {
+            return false;
+        }
+
+        let name = host.host().as_bytes();
+        let pattern = self.host.as_bytes();
+        if self.wildcard {
+            name.len() > pattern.len()
+                && name[name.len() - pattern.len()..].eq_ignore_ascii_case(pattern)
+        } else {
+            name.eq_ignore_ascii_case(pattern)
+        }
+    }
+}
+
 /// Context for the virtual hosts handler
 #[derive(Debug)]
 pub struct VirtualHostsCtx<Ctx> {
     index: Option<usize>,
+    unknown_host: bool,
     handler: Ctx,
 }
 
@@ -63,7 +187,13 @@ impl<Ctx> DerefMut for VirtualHostsCtx<Ctx> {
 /// Virtual Hosts module handler
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct VirtualHostsHandler<H: Debug> {
-    handlers: Router<(Option<Path>, H)>,
+    handlers: Router<(Option<Path>, Option<String>, H)>,
+    normalize_absolute_form: bool,
+    allowed_hosts: Vec<HostPattern>,
+    unknown_host_status: StatusCode,
+    normalize_host: bool,
+    case_insensitive_paths: bool,
+    path_case_usage: PathCaseUsage,
 }
 
 impl<H: Debug> VirtualHostsHandler<H> {
@@ -77,7 +207,34 @@ impl<H: Debug> VirtualHostsHandler<H> {
         H::Conf: Default,
         H::CTX: Send,
     {
-        self.handlers.retrieve(ctx.index?).map(|(_, h)| h)
+        self.handlers.retrieve(ctx.index?).map(|(_, _, h)| h)
+    }
+
+    /// Retrieves the canonical name of the virtual host which was previously matched for this
+    /// context if any.
+    fn canonical_name(&self, ctx: &<Self as RequestFilter>::CTX) -> Option<&str>
+    where
+        H: RequestFilter + Sync,
+        H::Conf: Default,
+        H::CTX: Send,
+    {
+        self.handlers
+            .retrieve(ctx.index?)
+            .and_then(|(_, name, _)| name.as_deref())
+    }
+
+    /// Checks whether the host name is accepted by the `allowed_hosts` setting.
+    fn is_allowed_host(&self, host: Option<&str>) -> bool {
+        if self.allowed_hosts.is_empty() {
+            return true;
+        }
+
+        let Some(host) = host.and_then(parse_host) else {
+            return false;
+        };
+        self.allowed_hosts
+            .iter()
+            .any(|pattern| pattern.matches(&host))
     }
 }
 
@@ -95,6 +252,7 @@ where
     fn new_ctx() -> Self::CTX {
         Self::CTX {
             index: None,
+            unknown_host: false,
             handler: H::new_ctx(),
         }
     }
@@ -103,20 +261,44 @@ where
         H::init_downstream_modules(modules);
     }
 
+    fn handles_head() -> bool {
+        H::handles_head()
+    }
+
+    fn path_case_usage(&self) -> PathCaseUsage {
+        self.path_case_usage
+    }
+
     async fn early_request_filter(
         &self,
         session: &mut impl SessionWrapper,
         ctx: &mut Self::CTX,
     ) -> Result<(), Box<Error>> {
+        if self.normalize_absolute_form {
+            normalize_absolute_form(session)?;
+        }
+        if self.normalize_host {
+            normalize_host(session)?;
+        }
+
+        if !self.is_allowed_host(session.host().as_deref()) {
+            ctx.unknown_host = true;
+            return Ok(());
+        }
+
         let path = session.uri().path();
         let host = session.host().unwrap_or_default();
 
         if let Some(result) = self.handlers.lookup(host.as_ref(), &path) {
-            let (strip_path, handler) = result.as_value();
+            let (strip_path, _, handler) = result.as_value();
             let index = result.index();
-            let new_path = strip_path
-                .as_ref()
-                .and_then(|p| p.remove_prefix_from(&path));
+            let new_path = strip_path.as_ref().and_then(|p| {
+                if self.case_insensitive_paths {
+                    p.remove_prefix_from_ignore_case(&path)
+                } else {
+                    p.remove_prefix_from(&path)
+                }
+            });
 
             ctx.index = Some(index);
 
@@ -135,7 +317,31 @@ where
         session: &mut impl SessionWrapper,
         ctx: &mut Self::CTX,
     ) -> Result<RequestFilterResult, Box<Error>> {
+        if ctx.unknown_host {
+            info!(
+                "rejecting request for unknown host {}",
+                session.host().unwrap_or_default()
+            );
+            error_response(session, self.unknown_host_status).await?;
+            return Ok(RequestFilterResult::ResponseSent);
+        }
+
+        if let Some(canonical_name) = self.canonical_name(ctx) {
+            let host = session.host().unwrap_or_default();
+            if !host.eq_ignore_ascii_case(canonical_name) {
+                // Protocol-relative location, this keeps the scheme of the original request
+                let mut location = format!("//{canonical_name}{}", session.original_uri().path());
+                if let Some(query) = session.original_uri().query() {
+                    location.push('?');
+                    location.push_str(query);
+                }
+                redirect_response(session, StatusCode::MOVED_PERMANENTLY, &location).await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
+        }
+
         if let Some(handler) = self.as_inner(ctx) {
+            session.add_allowed_methods(handler.allowed_methods());
             handler.request_filter(session, ctx).await
         } else {
             Ok(RequestFilterResult::Unhandled)
@@ -154,6 +360,46 @@ where
         }
     }
 
+    fn upstream_response_trailer_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        trailers: &mut HeaderMap,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        if let Some(handler) = self.as_inner(ctx) {
+            handler.upstream_response_trailer_filter(session, trailers, ctx)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn fail_to_connect(
+        &self,
+        session: &mut impl SessionWrapper,
+        peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        e: Box<Error>,
+    ) -> Box<Error> {
+        if let Some(handler) = self.as_inner(ctx) {
+            handler.fail_to_connect(session, peer, ctx, e)
+        } else {
+            e
+        }
+    }
+
+    async fn fail_to_proxy(
+        &self,
+        session: &mut impl SessionWrapper,
+        e: &Error,
+        ctx: &mut Self::CTX,
+    ) -> Option<u16> {
+        if let Some(handler) = self.as_inner(ctx) {
+            handler.fail_to_proxy(session, e, ctx).await
+        } else {
+            None
+        }
+    }
+
     async fn logging(
         &self,
         session: &mut impl SessionWrapper,
@@ -168,16 +414,65 @@ where
 
 impl<C, H> TryFrom<VirtualHostsConf<C>> for VirtualHostsHandler<H>
 where
-    H: Debug + Clone + Eq,
+    H: RequestFilter + Debug + Clone + Eq,
     C: TryInto<H, Error = Box<Error>> + Default,
 {
     type Error = Box<Error>;
 
     fn try_from(conf: VirtualHostsConf<C>) -> Result<Self, Box<Error>> {
+        let host_count: usize = conf.vhosts.keys().map(|hosts| hosts.len()).sum();
+        if host_count > conf.max_vhosts {
+            error!(
+                "{host_count} virtual host names configured, exceeding the limit of {} set via max_vhosts",
+                conf.max_vhosts
+            );
+            return Err(Error::new(ErrorType::InternalError));
+        }
+
+        let unknown_host_status = match StatusCode::from_u16(conf.unknown_host_status) {
+            Ok(status)
+                if status == StatusCode::MISDIRECTED_REQUEST
+                    || status == StatusCode::BAD_REQUEST =>
+            {
+                status
+            }
+            _ => {
+                error!(
+                    "unknown_host_status has to be either 421 or 400, got {}",
+                    conf.unknown_host_status
+                );
+                return Err(Error::new(ErrorType::InternalError));
+            }
+        };
+
+        let mut allowed_hosts = Vec::new();
+        for host in &conf.allowed_hosts {
+            let Some(pattern) = HostPattern::parse(host) else {
+                error!("invalid host name in allowed_hosts setting: {host}");
+                return Err(Error::new(ErrorType::InternalError));
+            };
+            allowed_hosts.push(pattern);
+        }
+
         let mut handlers = Router::builder();
+        handlers.set_case_insensitive(conf.case_insensitive_paths);
         let mut default: Option<Vec<String>> = None;
+        let mut path_case_usage = PathCaseUsage::default();
         for (mut hosts, host_conf) in conf.vhosts.into_iter() {
-            let handler = host_conf.config.try_into()?;
+            if host_conf.subpaths.len() > conf.max_subpaths {
+                error!(
+                    "{} subpaths configured for virtual host [{}], exceeding the limit of {} set via max_subpaths",
+                    host_conf.subpaths.len(),
+                    hosts.join(", "),
+                    conf.max_subpaths
+                );
+                return Err(Error::new(ErrorType::InternalError));
+            }
+
+            let label = hosts.join(", ");
+            let handler: H = host_conf.config.try_into()?;
+            let mut usages = vec![handler.path_case_usage()];
+            let canonical_name = host_conf.canonical_name.filter(|name| !name.is_empty());
 
             let mut names = BTreeSet::new();
             if host_conf.default {
@@ -207,8 +502,8 @@ where
                 if handlers.push(
                     host,
                     "",
-                    (None, handler.clone()),
-                    Some((None, handler.clone())),
+                    (None, canonical_name.clone(), handler.clone()),
+                    Some((None, canonical_name.clone(), handler.clone())),
                 ) {
                     warn!("overriding existing entry for virtual host {host}");
                 }
@@ -222,7 +517,8 @@ where
             subpaths.sort_by_key(|(rule, _)| rule.exact);
 
             for (rule, conf) in subpaths {
-                let handler = conf.config.try_into()?;
+                let handler: H = conf.config.try_into()?;
+                usages.push(handler.path_case_usage());
                 let strip_path = if conf.strip_prefix {
                     Some(&rule.path)
                 } else {
@@ -232,19 +528,49 @@ where
                     handlers.push(
                         host,
                         &*rule.path,
-                        (strip_path.cloned(), handler.clone()),
+                        (strip_path.cloned(), canonical_name.clone(), handler.clone()),
                         if rule.exact {
                             None
                         } else {
-                            Some((strip_path.cloned(), handler.clone()))
+                            Some((strip_path.cloned(), canonical_name.clone(), handler.clone()))
                         },
                     );
                 }
             }
+
+            // Subpaths are matched case-sensitively unless case_insensitive_paths is enabled,
+            // so a subpath restricting access is a case-sensitive rule for the host’s handlers.
+            PathCaseUsage::check(&usages)?;
+            let host_usage = PathCaseUsage::merge(&usages);
+            let subpaths_restrict_access = !conf.case_insensitive_paths
+                && usages[1..].iter().any(|usage| usage.restricts_access);
+            if host_usage.case_insensitive && subpaths_restrict_access {
+                error!(
+                    "virtual host [{label}] ignores letter case in paths but restricts access for some subpaths, please enable case_insensitive_paths setting"
+                );
+                return Err(Error::new(ErrorType::InternalError));
+            }
+            path_case_usage = PathCaseUsage::merge(&[
+                path_case_usage,
+                PathCaseUsage {
+                    case_insensitive: host_usage.case_insensitive,
+                    case_sensitive_rules: host_usage.case_sensitive_rules
+                        || subpaths_restrict_access,
+                    restricts_access: false,
+                },
+            ]);
         }
         let handlers = handlers.build();
 
-        Ok(Self { handlers })
+        Ok(Self {
+            handlers,
+            normalize_absolute_form: conf.normalize_absolute_form,
+            allowed_hosts,
+            unknown_host_status,
+            normalize_host: conf.normalize_host,
+            case_insensitive_paths: conf.case_insensitive_paths,
+            path_case_usage,
+        })
     }
 }
 
@@ -280,6 +606,13 @@ mod tests {
                             upstream: http://127.0.0.5
                         example.info:
                             upstream: http://127.0.0.6
+                        [example.org, www.example.org]:
+                            canonical_name: example.org
+                            upstream: http://127.0.0.7
+                            subpaths:
+                                /subdir/*:
+                                    strip_prefix: true
+                                    upstream: http://127.0.0.8
                 "#
             ))
             .unwrap()
@@ -560,4 +893,313 @@ mod tests {
         assert_eq!(result.session().uri(), "/file.txt/xyz");
         assert_eq!(result.session().original_uri(), "/subdir/file.txt/xyz");
     }
+
+    #[test(tokio::test)]
+    async fn canonical_name_redirect() {
+        let mut app = make_app(false);
+        let session = make_session("/subdir/file.txt?a=b", Some("www.example.org")).await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(
+            result.session().response_written().map(|r| r.status),
+            Some(StatusCode::MOVED_PERMANENTLY)
+        );
+        assert_eq!(
+            result
+                .session()
+                .response_written()
+                .and_then(|r| r.headers.get("Location"))
+                .map(|h| h.to_str().unwrap()),
+            Some("//example.org/subdir/file.txt?a=b")
+        );
+
+        let session = make_session("/subdir/file.txt?a=b", Some("example.org")).await;
+        let mut result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.8");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+        assert_eq!(result.session().uri(), "/file.txt?a=b");
+    }
+
+    #[test(tokio::test)]
+    async fn absolute_form() {
+        let make_app = |normalize: bool| -> DefaultApp<VirtualHostsHandler<UpstreamHandler>> {
+            DefaultApp::new(
+                <VirtualHostsHandler<UpstreamHandler> as RequestFilter>::Conf::from_yaml(format!(
+                    r#"
+                        normalize_absolute_form: {normalize}
+                        vhosts:
+                            localhost:8080:
+                                upstream: http://127.0.0.1
+                            example.com:
+                                upstream: http://127.0.0.5
+                                subpaths:
+                                    /subdir/*:
+                                        strip_prefix: true
+                                        upstream: http://127.0.0.8
+                    "#
+                ))
+                .unwrap()
+                .try_into()
+                .unwrap(),
+            )
+        };
+
+        let mut app = make_app(true);
+        let session = make_session(
+            "http://example.com/subdir/file.txt?a=b",
+            Some("localhost:8080"),
+        )
+        .await;
+        let mut result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.8");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+        assert_eq!(result.session().uri(), "/file.txt?a=b");
+        assert_eq!(result.session().original_uri(), "/subdir/file.txt?a=b");
+
+        // Host header is adjusted even if no virtual host matches
+        let session =
+            make_session("http://example.net:8081/file.txt", Some("localhost:8080")).await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_some());
+        assert_eq!(result.session().uri(), "/file.txt");
+        assert_eq!(
+            result.session().req_header().headers.get("Host").unwrap(),
+            "example.net:8081"
+        );
+
+        // Authority-form is kept for CONNECT requests
+        let mut header = RequestHeader::build("CONNECT", b"example.com:443", None).unwrap();
+        header.set_uri("example.com:443".try_into().unwrap());
+        header.insert_header("Host", "example.com").unwrap();
+        let session = create_test_session(header).await;
+        let mut result = app
+            .handle_request_with_upstream(session, |_, _| Ok(response_header()))
+            .await;
+        assert!(result.err().is_none());
+        assert_eq!(result.session().uri(), "example.com:443");
+
+        // Without normalization the Host header determines the virtual host
+        let mut app = make_app(false);
+        let session = make_session(
+            "http://example.com/subdir/file.txt?a=b",
+            Some("localhost:8080"),
+        )
+        .await;
+        let mut result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.1");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+        assert_eq!(
+            result.session().uri(),
+            "http://example.com/subdir/file.txt?a=b"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn allowed_hosts() {
+        let make_app = |extra: &str| -> DefaultApp<VirtualHostsHandler<UpstreamHandler>> {
+            DefaultApp::new(
+                <VirtualHostsHandler<UpstreamHandler> as RequestFilter>::Conf::from_yaml(format!(
+                    r#"
+                        allowed_hosts: [example.com, "*.example.org", "localhost:8080"]
+                        {extra}
+                        vhosts:
+                            [example.com, localhost:8080]:
+                                default: true
+                                upstream: http://127.0.0.1
+                            www.example.org:
+                                upstream: http://127.0.0.2
+                    "#
+                ))
+                .unwrap()
+                .try_into()
+                .unwrap(),
+            )
+        };
+
+        let mut app = make_app("");
+        for (host, upstream) in [
+            ("example.com", "127.0.0.1"),
+            ("EXAMPLE.com:8081", "127.0.0.1"),
+            ("www.example.org", "127.0.0.2"),
+            ("sub.www.example.org", "127.0.0.1"),
+            ("localhost:8080", "127.0.0.1"),
+        ] {
+            let session = make_session("/", Some(host)).await;
+            let result = app
+                .handle_request_with_upstream(session, |_, peer| {
+                    assert_eq!(peer.sni, upstream);
+                    Ok(response_header())
+                })
+                .await;
+            assert!(result.err().is_none(), "{host}");
+        }
+
+        for host in [
+            Some("example.net"),
+            Some("www.example.com"),
+            Some("example.org"),
+            Some("evilexample.org"),
+            Some("localhost"),
+            Some("localhost:8081"),
+            Some("user@example.com"),
+            None,
+        ] {
+            let session = make_session("/", host).await;
+            let mut result = app.handle_request(session).await;
+            assert!(result.err().is_none(), "{host:?}");
+            assert_eq!(
+                result.session().response_written().map(|r| r.status),
+                Some(StatusCode::MISDIRECTED_REQUEST),
+                "{host:?}"
+            );
+        }
+
+        let mut app = make_app("unknown_host_status: 400");
+        let session = make_session("/", Some("example.net")).await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(
+            result.session().response_written().map(|r| r.status),
+            Some(StatusCode::BAD_REQUEST)
+        );
+
+        let conf = <VirtualHostsHandler<UpstreamHandler> as RequestFilter>::Conf::from_yaml(
+            "unknown_host_status: 404",
+        )
+        .unwrap();
+        assert!(VirtualHostsHandler::<UpstreamHandler>::try_from(conf).is_err());
+
+        let conf = <VirtualHostsHandler<UpstreamHandler> as RequestFilter>::Conf::from_yaml(
+            "allowed_hosts: \"example.com:port\"",
+        )
+        .unwrap();
+        assert!(VirtualHostsHandler::<UpstreamHandler>::try_from(conf).is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn host_normalization() {
+        let make_app = |normalize: bool| -> DefaultApp<VirtualHostsHandler<UpstreamHandler>> {
+            DefaultApp::new(
+                <VirtualHostsHandler<UpstreamHandler> as RequestFilter>::Conf::from_yaml(format!(
+                    r#"
+                        normalize_host: {normalize}
+                        allowed_hosts: example.com
+                        vhosts:
+                            example.com:
+                                upstream: http://127.0.0.1
+                    "#
+                ))
+                .unwrap()
+                .try_into()
+                .unwrap(),
+            )
+        };
+
+        let mut app = make_app(true);
+        let session = make_session("/", Some("Example.COM:80")).await;
+        let result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.1");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+
+        // Without normalization the allowlist accepts the host but no virtual host matches
+        let mut app = make_app(false);
+        let session = make_session("/", Some("Example.COM:80")).await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(
+            result.err().as_ref().map(|err| &err.etype),
+            Some(&ErrorType::HTTPStatus(404))
+        );
+        assert_eq!(
+            result.session().req_header().headers.get("Host").unwrap(),
+            "Example.COM:80"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn case_insensitive_paths() {
+        let make_app = |enabled: bool| -> DefaultApp<VirtualHostsHandler<UpstreamHandler>> {
+            DefaultApp::new(
+                <VirtualHostsHandler<UpstreamHandler> as RequestFilter>::Conf::from_yaml(format!(
+                    r#"
+                        case_insensitive_paths: {enabled}
+                        vhosts:
+                            localhost:
+                                upstream: http://127.0.0.1
+                                subpaths:
+                                    /subdir/*:
+                                        strip_prefix: true
+                                        upstream: http://127.0.0.2
+                    "#
+                ))
+                .unwrap()
+                .try_into()
+                .unwrap(),
+            )
+        };
+
+        let mut app = make_app(true);
+        let session = make_session("/SubDir/Xyz", Some("localhost")).await;
+        let mut result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.2");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+        assert_eq!(result.session().uri(), "/Xyz");
+
+        let mut app = make_app(false);
+        let session = make_session("/SubDir/Xyz", Some("localhost")).await;
+        let mut result = app
+            .handle_request_with_upstream(session, |_, peer| {
+                assert_eq!(peer.sni, "127.0.0.1");
+                Ok(response_header())
+            })
+            .await;
+        assert!(result.err().is_none());
+        assert_eq!(result.session().uri(), "/SubDir/Xyz");
+    }
+
+    #[test]
+    fn limits() {
+        let conf = |max_vhosts: usize, max_subpaths: usize| {
+            <VirtualHostsHandler<UpstreamHandler> as RequestFilter>::Conf::from_yaml(format!(
+                r#"
+                    max_vhosts: {max_vhosts}
+                    max_subpaths: {max_subpaths}
+                    vhosts:
+                        [localhost, 127.0.0.1]:
+                            upstream: http://127.0.0.1
+                            subpaths:
+                                /subdir/*:
+                                    upstream: http://127.0.0.2
+                                /subdir/file.txt:
+                                    upstream: http://127.0.0.3
+                        example.com:
+                            upstream: http://127.0.0.4
+                "#
+            ))
+            .unwrap()
+        };
+
+        assert!(VirtualHostsHandler::<UpstreamHandler>::try_from(conf(3, 2)).is_ok());
+        assert!(VirtualHostsHandler::<UpstreamHandler>::try_from(conf(2, 2)).is_err());
+        assert!(VirtualHostsHandler::<UpstreamHandler>::try_from(conf(3, 1)).is_err());
+    }
 }